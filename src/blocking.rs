@@ -0,0 +1,127 @@
+use std::future::Future;
+use quaint::prelude::Select;
+use sqlx::{FromRow, Pool, Postgres, postgres::{PgConnectOptions, PgRow}};
+use uuid::{Uuid};
+use crate::postgres::{BurchillPostgresError, access::Actor, criteria::FindManyOptions, dsn::connect_dsn, entity::{AccessControlledEntity, PostgresEntity}, fetch_all, get_connection_pool, repository::PostgresRepository};
+
+
+// One small single threaded runtime per handle, enough for scripts and CLI tools that run one
+// query at a time. Don't use it from inside async code, block_on panics under another runtime.
+#[cfg(feature = "tokio")]
+struct BlockingRuntime(tokio::runtime::Runtime);
+
+#[cfg(feature = "tokio")]
+impl BlockingRuntime {
+    fn new() -> Result<Self, BurchillPostgresError> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|err| BurchillPostgresError::AnyhowError(err.into()))?;
+        Ok(BlockingRuntime(runtime))
+    }
+
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.0.block_on(future)
+    }
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+struct BlockingRuntime;
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+impl BlockingRuntime {
+    fn new() -> Result<Self, BurchillPostgresError> {
+        Ok(BlockingRuntime)
+    }
+
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        async_std::task::block_on(future)
+    }
+}
+
+pub struct BlockingPool {
+    runtime: BlockingRuntime,
+    pool: Pool<Postgres>,
+}
+
+impl BlockingPool {
+    pub fn connect_with(options: PgConnectOptions, max_connections: u32) -> Result<Self, BurchillPostgresError> {
+        let runtime = BlockingRuntime::new()?;
+        let pool = runtime.block_on(get_connection_pool(options, max_connections))?;
+        Ok(BlockingPool { runtime, pool })
+    }
+
+    // Takes the same URL and key/value forms as the dsn module.
+    pub fn connect(dsn: &str) -> Result<Self, BurchillPostgresError> {
+        let runtime = BlockingRuntime::new()?;
+        let pool = runtime.block_on(connect_dsn(dsn, 2))?;
+        Ok(BlockingPool { runtime, pool })
+    }
+
+    pub fn get_pool(&self) -> &Pool<Postgres> {
+        &self.pool
+    }
+
+    // For anything async in the crate that doesn't have a wrapper here.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    // Simple protocol, so the SQL can hold several statements, e.g. a schema file.
+    pub fn execute(&self, sql: &str) -> Result<u64, BurchillPostgresError> {
+        let result = self.block_on(sqlx::Executor::execute(&self.pool, sql))?;
+        Ok(result.rows_affected())
+    }
+
+    pub fn fetch_all<T>(&self, query: Select<'_>) -> Result<Vec<T>, BurchillPostgresError>
+    where T: for<'r> FromRow<'r, PgRow> + Send + Unpin {
+        self.block_on(fetch_all(query, &self.pool))
+    }
+
+    pub fn find_one<R, T>(&self, repository: &R, id: &Uuid) -> anyhow::Result<T>
+    where R: PostgresRepository<T> + Sync {
+        self.block_on(repository.find_one(&self.pool, id))
+    }
+
+    pub fn find_one_as<R, T>(&self, repository: &R, id: &Uuid, actor: &Actor) -> Result<T, BurchillPostgresError>
+    where
+        R: PostgresRepository<T> + Sync,
+        T: Send + Sync
+    {
+        self.block_on(repository.find_one_as(&self.pool, id, actor))
+    }
+
+    pub fn find_many<R, T>(&self, repository: &R, options: &FindManyOptions) -> Result<Vec<T>, BurchillPostgresError>
+    where
+        R: PostgresRepository<T> + Sync,
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin
+    {
+        self.block_on(repository.find_many(&self.pool, options))
+    }
+
+    pub fn find_many_as<R, T>(&self, repository: &R, options: &FindManyOptions, actor: &Actor) -> Result<Vec<T>, BurchillPostgresError>
+    where
+        R: PostgresRepository<T> + Sync,
+        T: for<'r> FromRow<'r, PgRow> + Send + Sync + Unpin
+    {
+        self.block_on(repository.find_many_as(&self.pool, options, actor))
+    }
+
+    pub fn save<E, D>(&self, entity: &mut E, user_id: &Uuid) -> Result<(), BurchillPostgresError>
+    where E: PostgresEntity<D> + Send {
+        self.block_on(entity.save(&self.pool, user_id))
+    }
+
+    pub fn save_as<E, D>(&self, entity: &mut E, actor: &Actor) -> Result<(), BurchillPostgresError>
+    where E: AccessControlledEntity<D> {
+        self.block_on(async {
+            let mut connection = self.pool.acquire().await?;
+            entity.save_as(&mut *connection, actor).await
+        })
+    }
+
+    pub fn close(self) {
+        let BlockingPool { runtime, pool } = self;
+        runtime.block_on(pool.close());
+    }
+}
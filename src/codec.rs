@@ -0,0 +1,214 @@
+use std::{fmt, str::FromStr};
+use serde::{Serialize, de::DeserializeOwned};
+use thiserror::Error;
+
+
+#[derive(Error, Debug)]
+pub enum CodecError {
+    #[error("Could not encode the value as {format}. {message}")]
+    Encode {
+        format: CodecFormat,
+        message: String
+    },
+    #[error("Could not decode the value as {format}. {message}")]
+    Decode {
+        format: CodecFormat,
+        message: String
+    },
+    #[error("Unknown or disabled serialization format. (Value: {0:?})")]
+    UnknownFormat(String),
+}
+
+pub trait Codec {
+    fn get_content_type(&self) -> &'static str;
+
+    fn encode<T>(&self, value: &T) -> Result<Vec<u8>, CodecError>
+    where T: Serialize + ?Sized;
+
+    fn decode<T>(&self, bytes: &[u8]) -> Result<T, CodecError>
+    where T: DeserializeOwned;
+}
+
+// The binary formats are behind the msgpack and cbor features so apps only pull in what they use.
+// Used for remote entity bodies and, through encode_tagged, for whatever the app caches or publishes
+// itself. NOTIFY payloads stay JSON (see notify.rs), postgres only takes text there.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum CodecFormat {
+    #[default]
+    Json,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl CodecFormat {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            CodecFormat::Json => "json",
+            #[cfg(feature = "msgpack")]
+            CodecFormat::MessagePack => "msgpack",
+            #[cfg(feature = "cbor")]
+            CodecFormat::Cbor => "cbor",
+        }
+    }
+
+    // Parameters like "; charset=utf-8" are ignored.
+    pub fn from_content_type(content_type: &str) -> Result<Self, CodecError> {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim().to_lowercase();
+        match media_type.as_str() {
+            "application/json" => Ok(CodecFormat::Json),
+            #[cfg(feature = "msgpack")]
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => Ok(CodecFormat::MessagePack),
+            #[cfg(feature = "cbor")]
+            "application/cbor" => Ok(CodecFormat::Cbor),
+            _ => Err(CodecError::UnknownFormat(content_type.to_owned()))
+        }
+    }
+}
+
+impl Codec for CodecFormat {
+    fn get_content_type(&self) -> &'static str {
+        match self {
+            CodecFormat::Json => "application/json",
+            #[cfg(feature = "msgpack")]
+            CodecFormat::MessagePack => "application/msgpack",
+            #[cfg(feature = "cbor")]
+            CodecFormat::Cbor => "application/cbor",
+        }
+    }
+
+    fn encode<T>(&self, value: &T) -> Result<Vec<u8>, CodecError>
+    where T: Serialize + ?Sized {
+        let encode_error = |message: String| CodecError::Encode { format: *self, message };
+        match self {
+            CodecFormat::Json => serde_json::to_vec(value).map_err(|err| encode_error(err.to_string())),
+            // Named so structs round trip as maps, the compact array form breaks as soon as a field is added.
+            #[cfg(feature = "msgpack")]
+            CodecFormat::MessagePack => rmp_serde::to_vec_named(value).map_err(|err| encode_error(err.to_string())),
+            #[cfg(feature = "cbor")]
+            CodecFormat::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(value, &mut bytes).map_err(|err| encode_error(err.to_string()))?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    fn decode<T>(&self, bytes: &[u8]) -> Result<T, CodecError>
+    where T: DeserializeOwned {
+        let decode_error = |message: String| CodecError::Decode { format: *self, message };
+        match self {
+            CodecFormat::Json => serde_json::from_slice(bytes).map_err(|err| decode_error(err.to_string())),
+            #[cfg(feature = "msgpack")]
+            CodecFormat::MessagePack => rmp_serde::from_slice(bytes).map_err(|err| decode_error(err.to_string())),
+            #[cfg(feature = "cbor")]
+            CodecFormat::Cbor => ciborium::de::from_reader(bytes).map_err(|err| decode_error(err.to_string())),
+        }
+    }
+}
+
+impl FromStr for CodecFormat {
+    type Err = CodecError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "json" => Ok(CodecFormat::Json),
+            #[cfg(feature = "msgpack")]
+            "msgpack" | "messagepack" => Ok(CodecFormat::MessagePack),
+            #[cfg(feature = "cbor")]
+            "cbor" => Ok(CodecFormat::Cbor),
+            _ => Err(CodecError::UnknownFormat(value.to_owned()))
+        }
+    }
+}
+
+impl fmt::Display for CodecFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.get_name())
+    }
+}
+
+// Prefixes the payload with the format name so stored or published bytes can be read back after
+// the app switches formats, e.g. b"msgpack:" followed by the encoded value.
+pub fn encode_tagged<T>(format: CodecFormat, value: &T) -> Result<Vec<u8>, CodecError>
+where T: Serialize + ?Sized {
+    let mut bytes = format.get_name().as_bytes().to_vec();
+    bytes.push(b':');
+    bytes.extend(format.encode(value)?);
+    Ok(bytes)
+}
+
+pub fn decode_tagged<T>(bytes: &[u8]) -> Result<T, CodecError>
+where T: DeserializeOwned {
+    let separator = bytes.iter().position(|byte| *byte == b':')
+        .ok_or_else(|| CodecError::UnknownFormat(String::from_utf8_lossy(&bytes[..bytes.len().min(16)]).into_owned()))?;
+    let format: CodecFormat = String::from_utf8_lossy(&bytes[..separator]).parse()?;
+    format.decode(&bytes[separator + 1..])
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Order {
+        id: u32,
+        note: Option<String>,
+        tags: Vec<String>,
+    }
+
+    fn order() -> Order {
+        Order {
+            id: 7,
+            note: Some(String::from("rush")),
+            tags: vec![String::from("a"), String::from("b")]
+        }
+    }
+
+    fn formats() -> Vec<CodecFormat> {
+        vec![
+            CodecFormat::Json,
+            #[cfg(feature = "msgpack")]
+            CodecFormat::MessagePack,
+            #[cfg(feature = "cbor")]
+            CodecFormat::Cbor,
+        ]
+    }
+
+    #[test]
+    fn round_trips_every_format() {
+        for format in formats() {
+            let bytes = format.encode(&order()).unwrap();
+            assert_eq!(format.decode::<Order>(&bytes).unwrap(), order(), "{}", format);
+            assert_eq!(CodecFormat::from_content_type(format.get_content_type()).unwrap(), format);
+            assert_eq!(format.get_name().parse::<CodecFormat>().unwrap(), format);
+        }
+    }
+
+    #[test]
+    fn round_trips_tagged() {
+        for format in formats() {
+            let bytes = encode_tagged(format, &order()).unwrap();
+            assert!(bytes.starts_with(format!("{}:", format).as_bytes()));
+            assert_eq!(decode_tagged::<Order>(&bytes).unwrap(), order(), "{}", format);
+        }
+        assert_eq!(encode_tagged(CodecFormat::Json, &1).unwrap(), b"json:1");
+    }
+
+    #[test]
+    fn rejects_unknown_tags() {
+        assert!(matches!(decode_tagged::<Order>(b"xml:<order/>"), Err(CodecError::UnknownFormat(format)) if format == "xml"));
+        assert!(matches!(decode_tagged::<Order>(b"{\"id\":7}"), Err(CodecError::UnknownFormat(_))));
+        assert!(matches!(decode_tagged::<Order>(b"json:{\"id\":"), Err(CodecError::Decode { format: CodecFormat::Json, .. })));
+    }
+
+    #[test]
+    fn parses_content_types() {
+        assert_eq!(CodecFormat::from_content_type("Application/JSON; charset=utf-8").unwrap(), CodecFormat::Json);
+        assert!(matches!(CodecFormat::from_content_type("text/html"), Err(CodecError::UnknownFormat(_))));
+        assert_eq!(" JSON ".parse::<CodecFormat>().unwrap(), CodecFormat::Json);
+        assert_eq!(CodecFormat::default(), CodecFormat::Json);
+    }
+}
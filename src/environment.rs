@@ -0,0 +1,90 @@
+use std::{env, fmt, str::FromStr};
+use thiserror::Error;
+
+pub const DEFAULT_ENVIRONMENT_VARIABLE: &str = "APP_ENVIRONMENT";
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Environment {
+    Development,
+    Test,
+    Staging,
+    Production,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum EnvironmentError {
+    #[error("The environment variable {0} is not set.")]
+    Missing(String),
+    #[error("Unknown environment name. (Value: {0:?})")]
+    Unknown(String),
+    #[error("Refusing to run {operation:?} in the {environment} environment.")]
+    Refused {
+        operation: String,
+        environment: Environment
+    },
+}
+
+impl Environment {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Environment::Development => "development",
+            Environment::Test => "test",
+            Environment::Staging => "staging",
+            Environment::Production => "production",
+        }
+    }
+
+    // There is deliberately no fallback when the variable is missing, a guard that guesses
+    // "development" is exactly how destructive tools end up running against prod.
+    pub fn from_env_var(variable: &str) -> Result<Self, EnvironmentError> {
+        match env::var(variable) {
+            Ok(value) => value.parse(),
+            Err(_) => Err(EnvironmentError::Missing(variable.to_owned()))
+        }
+    }
+
+    pub fn from_env() -> Result<Self, EnvironmentError> {
+        Environment::from_env_var(DEFAULT_ENVIRONMENT_VARIABLE)
+    }
+
+    pub fn is_production(&self) -> bool {
+        *self == Environment::Production
+    }
+
+    pub fn refuse_in_production(&self, operation: &str) -> Result<(), EnvironmentError> {
+        self.require_one_of(&[Environment::Development, Environment::Test, Environment::Staging], operation)
+    }
+
+    pub fn require_one_of(&self, allowed: &[Environment], operation: &str) -> Result<(), EnvironmentError> {
+        if allowed.contains(self) {
+            return Ok(());
+        }
+
+        tracing::warn!(operation = %operation, environment = %self, "refused to run operation in this environment");
+        Err(EnvironmentError::Refused {
+            operation: operation.to_owned(),
+            environment: *self
+        })
+    }
+}
+
+impl FromStr for Environment {
+    type Err = EnvironmentError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "dev" | "development" | "local" => Ok(Environment::Development),
+            "test" | "testing" | "ci" => Ok(Environment::Test),
+            "stage" | "staging" => Ok(Environment::Staging),
+            "prod" | "production" => Ok(Environment::Production),
+            _ => Err(EnvironmentError::Unknown(value.to_owned()))
+        }
+    }
+}
+
+impl fmt::Display for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.get_name())
+    }
+}
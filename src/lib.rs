@@ -1,4 +1,17 @@
+#[cfg(all(feature = "database", any(feature = "tokio", feature = "async-std")))]
+pub mod blocking;
+pub mod codec;
+pub mod environment;
 pub mod postgres;
+pub mod prelude;
+pub mod reference_data;
+pub mod runtime;
+pub mod strings;
+#[cfg(feature = "database")]
+pub mod testing;
+pub mod time_utils;
+pub mod validation;
+pub mod web;
 
 // TODO
 // controller abstraction (REST)
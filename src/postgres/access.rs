@@ -0,0 +1,198 @@
+use std::{fmt, sync::Arc};
+use async_trait::async_trait;
+use anyhow::Result;
+use uuid::{Uuid};
+use crate::postgres::BurchillPostgresError;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Actor {
+    pub user_id: Uuid,
+    pub roles: Vec<String>,
+    // Background jobs and migrations act as the system and skip policies entirely.
+    pub is_system: bool,
+}
+
+impl Actor {
+    pub fn new(user_id: Uuid) -> Self {
+        Actor {
+            user_id,
+            roles: Vec::new(),
+            is_system: false
+        }
+    }
+
+    pub fn system(user_id: Uuid) -> Self {
+        Actor {
+            user_id,
+            roles: Vec::new(),
+            is_system: true
+        }
+    }
+
+    pub fn with_roles(mut self, roles: Vec<String>) -> Self {
+        self.roles = roles;
+        self
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|actor_role| actor_role == role)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AccessAction {
+    Read,
+    Create,
+    Update,
+    Delete,
+}
+
+impl fmt::Display for AccessAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AccessAction::Read => "read",
+            AccessAction::Create => "create",
+            AccessAction::Update => "update",
+            AccessAction::Delete => "delete",
+        })
+    }
+}
+
+#[async_trait]
+pub trait AccessPolicy<T>: Send + Sync
+where T: Sync {
+    async fn can_read(&self, actor: &Actor, entity: &T) -> Result<bool>;
+    async fn can_update(&self, actor: &Actor, entity: &T) -> Result<bool>;
+    async fn can_delete(&self, actor: &Actor, entity: &T) -> Result<bool>;
+
+    async fn can_create(&self, actor: &Actor, entity: &T) -> Result<bool> {
+        self.can_update(actor, entity).await
+    }
+}
+
+pub struct AllowAll;
+
+#[async_trait]
+impl<T> AccessPolicy<T> for AllowAll
+where T: Sync {
+    async fn can_read(&self, _actor: &Actor, _entity: &T) -> Result<bool> {
+        Ok(true)
+    }
+
+    async fn can_update(&self, _actor: &Actor, _entity: &T) -> Result<bool> {
+        Ok(true)
+    }
+
+    async fn can_delete(&self, _actor: &Actor, _entity: &T) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+// Anyone can read, only the owner (or one of the admin roles) can change or delete.
+// Rows without an owner are left to the admin roles.
+pub struct OwnerPolicy<T> {
+    get_owner: fn(&T) -> Option<Uuid>,
+    admin_roles: Vec<String>,
+}
+
+impl<T> OwnerPolicy<T> {
+    pub fn new(get_owner: fn(&T) -> Option<Uuid>) -> Self {
+        OwnerPolicy {
+            get_owner,
+            admin_roles: Vec::new()
+        }
+    }
+
+    pub fn with_admin_role(mut self, role: &str) -> Self {
+        self.admin_roles.push(role.to_owned());
+        self
+    }
+
+    fn is_owner_or_admin(&self, actor: &Actor, entity: &T) -> bool {
+        self.admin_roles.iter().any(|role| actor.has_role(role))
+            || (self.get_owner)(entity).is_some_and(|owner| owner == actor.user_id)
+    }
+}
+
+#[async_trait]
+impl<T> AccessPolicy<T> for OwnerPolicy<T>
+where T: Sync {
+    async fn can_read(&self, _actor: &Actor, _entity: &T) -> Result<bool> {
+        Ok(true)
+    }
+
+    async fn can_update(&self, actor: &Actor, entity: &T) -> Result<bool> {
+        Ok(self.is_owner_or_admin(actor, entity))
+    }
+
+    async fn can_delete(&self, actor: &Actor, entity: &T) -> Result<bool> {
+        Ok(self.is_owner_or_admin(actor, entity))
+    }
+}
+
+// Update and delete checks are given the row as stored, see AccessControlledEntity::find_stored_for_update.
+// Without a policy the entity is allowed unless deny_by_default is set, which is what tables
+// holding anything sensitive should use so a missing policy fails closed.
+pub async fn check_access<T>(
+    policy: Option<&Arc<dyn AccessPolicy<T>>>,
+    deny_by_default: bool,
+    actor: &Actor,
+    action: AccessAction,
+    table: &str,
+    id: Option<Uuid>,
+    entity: &T
+) -> Result<(), BurchillPostgresError>
+where T: Sync {
+    if actor.is_system {
+        return Ok(());
+    }
+
+    let allowed = match policy {
+        Some(policy) => match action {
+            AccessAction::Read => policy.can_read(actor, entity).await?,
+            AccessAction::Create => policy.can_create(actor, entity).await?,
+            AccessAction::Update => policy.can_update(actor, entity).await?,
+            AccessAction::Delete => policy.can_delete(actor, entity).await?,
+        },
+        None => !deny_by_default
+    };
+
+    if allowed {
+        return Ok(());
+    }
+
+    tracing::info!(user_id = %actor.user_id, action = %action, table = %table, id = ?id, "access denied");
+    Err(BurchillPostgresError::AccessDenied {
+        action,
+        table: table.to_owned(),
+        id
+    })
+}
+
+// Lists drop what the actor can't read rather than failing the whole request.
+pub async fn filter_readable<T>(
+    policy: Option<&Arc<dyn AccessPolicy<T>>>,
+    deny_by_default: bool,
+    actor: &Actor,
+    entities: Vec<T>
+) -> Result<Vec<T>, BurchillPostgresError>
+where T: Sync {
+    if actor.is_system {
+        return Ok(entities);
+    }
+
+    let policy = match policy {
+        Some(policy) => policy,
+        None if deny_by_default => return Ok(Vec::new()),
+        None => return Ok(entities)
+    };
+
+    let mut readable = Vec::with_capacity(entities.len());
+    for entity in entities {
+        if policy.can_read(actor, &entity).await? {
+            readable.push(entity);
+        }
+    }
+    Ok(readable)
+}
@@ -0,0 +1,110 @@
+use std::ops::Deref;
+use serde::de::DeserializeOwned;
+use sqlx::{Decode, Postgres, Type, ValueRef, error::BoxDynError, postgres::{PgHasArrayType, PgTypeInfo, PgValueRef}, types::Json};
+use crate::strings::quote_identifier;
+
+
+// array_agg over a LEFT JOIN with no matches gives {NULL} and over no rows gives NULL,
+// both of those decode to an empty Vec here instead of an error.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ArrayAgg<T>(pub Vec<T>);
+
+impl<T> ArrayAgg<T> {
+    pub fn into_inner(self) -> Vec<T> {
+        self.0
+    }
+}
+
+impl<T> Deref for ArrayAgg<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        &self.0
+    }
+}
+
+impl<T> From<ArrayAgg<T>> for Vec<T> {
+    fn from(aggregate: ArrayAgg<T>) -> Self {
+        aggregate.0
+    }
+}
+
+impl<T> Type<Postgres> for ArrayAgg<T>
+where T: PgHasArrayType {
+    fn type_info() -> PgTypeInfo {
+        <Vec<T> as Type<Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <Vec<T> as Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl<'r, T> Decode<'r, Postgres> for ArrayAgg<T>
+where T: for<'a> Decode<'a, Postgres> + Type<Postgres> {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        if value.is_null() {
+            return Ok(ArrayAgg(Vec::new()));
+        }
+
+        let items = <Vec<Option<T>> as Decode<Postgres>>::decode(value)?;
+        Ok(ArrayAgg(items.into_iter().flatten().collect()))
+    }
+}
+
+// Same null handling as ArrayAgg but for json_agg/jsonb_agg of rows or objects.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct JsonAgg<T>(pub Vec<T>);
+
+impl<T> JsonAgg<T> {
+    pub fn into_inner(self) -> Vec<T> {
+        self.0
+    }
+}
+
+impl<T> Deref for JsonAgg<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        &self.0
+    }
+}
+
+impl<T> From<JsonAgg<T>> for Vec<T> {
+    fn from(aggregate: JsonAgg<T>) -> Self {
+        aggregate.0
+    }
+}
+
+impl<T> Type<Postgres> for JsonAgg<T> {
+    fn type_info() -> PgTypeInfo {
+        <Json<T> as Type<Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <Json<T> as Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl<'r, T> Decode<'r, Postgres> for JsonAgg<T>
+where T: DeserializeOwned {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        if value.is_null() {
+            return Ok(JsonAgg(Vec::new()));
+        }
+
+        let Json(items) = <Json<Vec<Option<T>>> as Decode<Postgres>>::decode(value)?;
+        Ok(JsonAgg(items.into_iter().flatten().collect()))
+    }
+}
+
+// Builds a correlated subquery selecting the active children of a parent row as a json array,
+// for use as a column next to the parent fields: SELECT "parent".*, <subquery> AS "children" FROM "parent".
+pub fn json_agg_children_subquery(parent_table: &str, child_table: &str, foreign_key: &str) -> String {
+    format!(
+        "(SELECT coalesce(json_agg(child ORDER BY child.created_time), '[]'::json) FROM {child_table} child WHERE child.{foreign_key} = {parent_table}.id AND child.active)",
+        child_table = quote_identifier(child_table),
+        foreign_key = quote_identifier(foreign_key),
+        parent_table = quote_identifier(parent_table)
+    )
+}
@@ -0,0 +1,144 @@
+use std::time::{Duration, Instant};
+use futures::future::BoxFuture;
+use futures_timer::Delay;
+use sqlx::{Executor, Pool, Postgres};
+use crate::postgres::BurchillPostgresError;
+
+
+#[derive(Clone, Debug)]
+pub struct BackpressureConfig {
+    pub initial_batch_size: usize,
+    pub min_batch_size: usize,
+    pub max_batch_size: usize,
+    // Batches slower than this shrink the batch size and grow the delay, much faster ones do the opposite.
+    pub target_latency: Duration,
+    // Replica lag above this is treated like a slow batch, above twice this writing pauses until it recovers.
+    pub max_replication_lag: Duration,
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+    // The longest a single pause for replication lag may last before the write fails.
+    pub max_pause: Duration,
+}
+
+impl Default for BackpressureConfig {
+    fn default() -> Self {
+        BackpressureConfig {
+            initial_batch_size: 500,
+            min_batch_size: 10,
+            max_batch_size: 5000,
+            target_latency: Duration::from_millis(200),
+            max_replication_lag: Duration::from_secs(5),
+            min_delay: Duration::from_millis(0),
+            max_delay: Duration::from_secs(10),
+            max_pause: Duration::from_secs(300)
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BulkWriteReport {
+    pub batches: usize,
+    pub written: u64,
+    pub final_batch_size: usize,
+    pub total_time: Duration,
+    // Time spent sleeping between batches and waiting for replicas to catch up.
+    pub throttled_time: Duration,
+}
+
+// Worst replay lag across the streaming replicas, zero when there are none. Must run on the primary.
+pub async fn get_replication_lag<'a, E>(executor: E) -> Result<Duration, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let (lag,): (f64,) = sqlx::query_as(
+        "SELECT coalesce(max(extract(epoch FROM replay_lag)), 0)::float8 FROM pg_stat_replication"
+    )
+        .fetch_one(executor).await?;
+    Ok(Duration::from_secs_f64(lag.max(0.0)))
+}
+
+pub struct BulkWriteCoordinator {
+    config: BackpressureConfig,
+    batch_size: usize,
+    delay: Duration,
+}
+
+impl BulkWriteCoordinator {
+    pub fn new(config: BackpressureConfig) -> Self {
+        let batch_size = config.initial_batch_size.max(config.min_batch_size).min(config.max_batch_size);
+        let delay = config.min_delay;
+        BulkWriteCoordinator {
+            config,
+            batch_size,
+            delay
+        }
+    }
+
+    pub fn get_batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    pub fn get_delay(&self) -> Duration {
+        self.delay
+    }
+
+    // Multiplicative decrease on pressure, slower increase when there is clearly room.
+    pub fn adjust(&mut self, latency: Duration, replication_lag: Duration) {
+        let config = &self.config;
+        if latency > config.target_latency || replication_lag > config.max_replication_lag {
+            self.batch_size = (self.batch_size / 2).max(config.min_batch_size);
+            self.delay = (self.delay * 2).max(Duration::from_millis(50)).min(config.max_delay);
+        } else if latency < config.target_latency / 2 {
+            self.batch_size = (self.batch_size + self.batch_size / 4 + 1).min(config.max_batch_size);
+            self.delay = (self.delay / 2).max(config.min_delay);
+        }
+    }
+
+    // The write closure gets each batch and returns how many rows it wrote, e.g. through merge_many.
+    pub async fn run<T, F>(&mut self, items: Vec<T>, pool: &Pool<Postgres>, mut write: F) -> Result<BulkWriteReport, BurchillPostgresError>
+    where F: FnMut(Vec<T>) -> BoxFuture<'static, Result<u64, BurchillPostgresError>> {
+        let started = Instant::now();
+        let mut report = BulkWriteReport::default();
+        let mut remaining = items.into_iter().peekable();
+
+        while remaining.peek().is_some() {
+            let batch: Vec<T> = remaining.by_ref().take(self.batch_size).collect();
+
+            let batch_started = Instant::now();
+            report.written += write(batch).await?;
+            let latency = batch_started.elapsed();
+            report.batches += 1;
+
+            let mut replication_lag = get_replication_lag(pool).await?;
+            let mut paused = Duration::from_secs(0);
+            while replication_lag > self.config.max_replication_lag * 2 {
+                if paused >= self.config.max_pause {
+                    return Err(BurchillPostgresError::ReplicationLagTimeout {
+                        lag: replication_lag,
+                        waited: paused
+                    });
+                }
+
+                tracing::warn!(lag_ms = replication_lag.as_millis() as u64, "pausing bulk write until replicas catch up");
+                let wait = self.config.max_delay.min(self.config.max_pause - paused).max(Duration::from_millis(1));
+                Delay::new(wait).await;
+                paused += wait;
+                report.throttled_time += wait;
+                replication_lag = get_replication_lag(pool).await?;
+            }
+
+            self.adjust(latency, replication_lag);
+            tracing::debug!(
+                batch = report.batches, latency_ms = latency.as_millis() as u64, lag_ms = replication_lag.as_millis() as u64,
+                batch_size = self.batch_size, delay_ms = self.delay.as_millis() as u64, "bulk write batch finished"
+            );
+
+            if remaining.peek().is_some() && self.delay > Duration::from_millis(0) {
+                Delay::new(self.delay).await;
+                report.throttled_time += self.delay;
+            }
+        }
+
+        report.final_batch_size = self.batch_size;
+        report.total_time = started.elapsed();
+        Ok(report)
+    }
+}
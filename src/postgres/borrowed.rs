@@ -0,0 +1,112 @@
+use std::borrow::Cow;
+use sqlx::{Executor, FromRow, Postgres, Row, postgres::PgRow};
+use crate::postgres::{BurchillPostgresError, create_sqlx_row_query, finish_query_recording, start_query_recording};
+
+
+// Rows kept around so DTOs can borrow their text and bytea columns straight out of the row
+// buffers instead of allocating a String or Vec per field. Decode with a FromRow<'r> type whose
+// fields are &'r str, &'r [u8] or Cow<'r, _>, serialize the response, then drop the rows.
+pub struct BorrowedRows {
+    rows: Vec<PgRow>,
+}
+
+impl BorrowedRows {
+    pub fn new(rows: Vec<PgRow>) -> Self {
+        BorrowedRows { rows }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    pub fn get_rows(&self) -> &[PgRow] {
+        &self.rows
+    }
+
+    pub fn decode<'r, T>(&'r self) -> Result<Vec<T>, BurchillPostgresError>
+    where T: FromRow<'r, PgRow> {
+        let mut decoded = Vec::with_capacity(self.rows.len());
+        for row in &self.rows {
+            decoded.push(T::from_row(row)?);
+        }
+        Ok(decoded)
+    }
+
+    // For when the values have to outlive the rows after all, e.g. to put them in a cache.
+    pub fn decode_owned<'r, T>(&'r self) -> Result<Vec<T::Owned>, BurchillPostgresError>
+    where T: FromRow<'r, PgRow> + IntoOwned {
+        Ok(self.decode::<T>()?.into_iter().map(IntoOwned::into_owned).collect())
+    }
+}
+
+pub trait IntoOwned {
+    type Owned: 'static;
+
+    fn into_owned(self) -> Self::Owned;
+}
+
+impl<'r> IntoOwned for Cow<'r, str> {
+    type Owned = Cow<'static, str>;
+
+    fn into_owned(self) -> Self::Owned {
+        Cow::Owned(Cow::into_owned(self))
+    }
+}
+
+impl<'r> IntoOwned for Cow<'r, [u8]> {
+    type Owned = Cow<'static, [u8]>;
+
+    fn into_owned(self) -> Self::Owned {
+        Cow::Owned(Cow::into_owned(self))
+    }
+}
+
+impl<T> IntoOwned for Option<T>
+where T: IntoOwned {
+    type Owned = Option<T::Owned>;
+
+    fn into_owned(self) -> Self::Owned {
+        self.map(IntoOwned::into_owned)
+    }
+}
+
+// Helpers for hand written FromRow impls on DTOs with Cow fields.
+pub fn get_str<'r>(row: &'r PgRow, column: &str) -> Result<Cow<'r, str>, sqlx::Error> {
+    Ok(Cow::Borrowed(row.try_get::<&'r str, _>(column)?))
+}
+
+pub fn get_optional_str<'r>(row: &'r PgRow, column: &str) -> Result<Option<Cow<'r, str>>, sqlx::Error> {
+    Ok(row.try_get::<Option<&'r str>, _>(column)?.map(Cow::Borrowed))
+}
+
+pub fn get_bytes<'r>(row: &'r PgRow, column: &str) -> Result<Cow<'r, [u8]>, sqlx::Error> {
+    Ok(Cow::Borrowed(row.try_get::<&'r [u8], _>(column)?))
+}
+
+pub fn get_optional_bytes<'r>(row: &'r PgRow, column: &str) -> Result<Option<Cow<'r, [u8]>>, sqlx::Error> {
+    Ok(row.try_get::<Option<&'r [u8]>, _>(column)?.map(Cow::Borrowed))
+}
+
+pub async fn fetch_borrowed_rows<'a, Q, E>(query: Q, executor: E) -> Result<BorrowedRows, BurchillPostgresError>
+where
+    Q: Into<quaint::prelude::Query<'a>>,
+    E: Executor<'a, Database = Postgres>
+{
+    let (query, bindings) = match quaint::visitor::Postgres::build(query) {
+        Ok(query_and_bindings) => query_and_bindings,
+        Err(err) => return Err(BurchillPostgresError::QuaintError(err))
+    };
+
+    let recording = start_query_recording(&query, &bindings);
+    let query = create_sqlx_row_query(query.as_str(), bindings)?;
+    let result = query.fetch_all(executor).await;
+    finish_query_recording(recording, result.as_ref().map_or(0, |rows| rows.len() as u64));
+    match result {
+        Ok(rows) => Ok(BorrowedRows::new(rows)),
+        Err(err) => Err(BurchillPostgresError::from(err))
+    }
+}
@@ -0,0 +1,99 @@
+use std::{fmt, ops::Deref};
+use quaint::{Value, prelude::{Column, Comparable, ConditionTree, Expression}};
+use sqlx::{Decode, Encode, Postgres, Type, encode::IsNull, error::BoxDynError, postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef}};
+use crate::postgres::criteria::Criteria;
+
+
+#[derive(Clone, Debug, Default, Eq)]
+pub struct CiText(pub String);
+
+impl CiText {
+    pub fn new(text: &str) -> Self {
+        CiText(text.to_owned())
+    }
+}
+
+impl PartialEq for CiText {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_lowercase() == other.0.to_lowercase()
+    }
+}
+
+impl Deref for CiText {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for CiText {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<String> for CiText {
+    fn from(text: String) -> Self {
+        CiText(text)
+    }
+}
+
+impl From<CiText> for Value<'static> {
+    fn from(text: CiText) -> Self {
+        Value::text(text.0)
+    }
+}
+
+impl Type<Postgres> for CiText {
+    fn type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("citext")
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        *ty == PgTypeInfo::with_name("citext") || <String as Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl Encode<'_, Postgres> for CiText {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+        <&str as Encode<Postgres>>::encode_by_ref(&self.0.as_str(), buf)
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for CiText {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        Ok(CiText(<String as Decode<Postgres>>::decode(value)?))
+    }
+}
+
+// Parameters are bound as text, and citext = text silently falls back to a case sensitive text comparison.
+// ILIKE with an escaped pattern keeps the comparison case insensitive without needing a cast.
+fn escape_like_pattern(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+fn ci_equals(field: &str, value: &str) -> Expression<'static> {
+    Column::from(field.to_owned()).compare_raw("ILIKE", escape_like_pattern(value)).into()
+}
+
+impl Criteria {
+    pub fn ci_equals(self, field: &str, value: &str) -> Self {
+        self.and(ci_equals(field, value))
+    }
+
+    pub fn ci_not_equals(self, field: &str, value: &str) -> Self {
+        let condition = Column::from(field.to_owned()).compare_raw("NOT ILIKE", escape_like_pattern(value));
+        self.and(condition)
+    }
+
+    pub fn ci_like(self, field: &str, pattern: &str) -> Self {
+        let condition = Column::from(field.to_owned()).compare_raw("ILIKE", pattern.to_owned());
+        self.and(condition)
+    }
+
+    pub fn ci_in_values(self, field: &str, values: &[&str]) -> Self {
+        let conditions = values.iter().map(|value| ci_equals(field, value)).collect();
+        self.and(ConditionTree::Or(conditions))
+    }
+}
@@ -0,0 +1,268 @@
+use quaint::{Value, prelude::{Column, Comparable, ConditionTree, Expression, Orderable, Select}};
+
+
+#[derive(Clone, Debug, Default)]
+pub struct Criteria {
+    conditions: Vec<Expression<'static>>,
+}
+
+impl Criteria {
+    pub fn new() -> Self {
+        Criteria {
+            conditions: Vec::new()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conditions.is_empty()
+    }
+
+    pub fn and<C>(mut self, condition: C) -> Self
+    where C: Into<Expression<'static>> {
+        self.conditions.push(condition.into());
+        self
+    }
+
+    pub fn equals<V>(self, field: &str, value: V) -> Self
+    where V: Into<Value<'static>> {
+        self.and(column(field).equals(value.into()))
+    }
+
+    pub fn not_equals<V>(self, field: &str, value: V) -> Self
+    where V: Into<Value<'static>> {
+        self.and(column(field).not_equals(value.into()))
+    }
+
+    pub fn greater_than<V>(self, field: &str, value: V) -> Self
+    where V: Into<Value<'static>> {
+        self.and(column(field).greater_than(value.into()))
+    }
+
+    pub fn greater_than_or_equals<V>(self, field: &str, value: V) -> Self
+    where V: Into<Value<'static>> {
+        self.and(column(field).greater_than_or_equals(value.into()))
+    }
+
+    pub fn less_than<V>(self, field: &str, value: V) -> Self
+    where V: Into<Value<'static>> {
+        self.and(column(field).less_than(value.into()))
+    }
+
+    pub fn less_than_or_equals<V>(self, field: &str, value: V) -> Self
+    where V: Into<Value<'static>> {
+        self.and(column(field).less_than_or_equals(value.into()))
+    }
+
+    pub fn like(self, field: &str, pattern: &str) -> Self {
+        self.and(column(field).like(pattern.to_owned()))
+    }
+
+    pub fn in_values<V>(self, field: &str, values: Vec<V>) -> Self
+    where V: Into<Value<'static>> {
+        let values: Vec<Value<'static>> = values.into_iter().map(|value| value.into()).collect();
+        self.and(column(field).in_selection(values))
+    }
+
+    pub fn is_null(self, field: &str) -> Self {
+        self.and(column(field).is_null())
+    }
+
+    pub fn is_not_null(self, field: &str) -> Self {
+        self.and(column(field).is_not_null())
+    }
+
+    pub fn into_condition_tree(self) -> ConditionTree<'static> {
+        if self.conditions.is_empty() {
+            ConditionTree::NoCondition
+        } else {
+            ConditionTree::And(self.conditions)
+        }
+    }
+}
+
+fn column(field: &str) -> Column<'static> {
+    Column::from(field.to_owned())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortDirection {
+    Ascending,
+    Descending,
+}
+
+#[derive(Clone, Debug)]
+pub struct Sort {
+    pub field: String,
+    pub direction: SortDirection,
+}
+
+impl Sort {
+    pub fn ascending(field: &str) -> Self {
+        Sort {
+            field: field.to_owned(),
+            direction: SortDirection::Ascending
+        }
+    }
+
+    pub fn descending(field: &str) -> Self {
+        Sort {
+            field: field.to_owned(),
+            direction: SortDirection::Descending
+        }
+    }
+
+    fn add_to_select<'a>(&self, query: Select<'a>) -> Select<'a> {
+        match self.direction {
+            SortDirection::Ascending => query.order_by(column(&self.field).ascend()),
+            SortDirection::Descending => query.order_by(column(&self.field).descend()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum PageRequest {
+    Offset {
+        page: usize,
+        size: usize
+    },
+    // Keyset pages continue after the last seen value of a single, unique and sortable field.
+    Keyset {
+        field: String,
+        direction: SortDirection,
+        after: Option<Value<'static>>,
+        size: usize
+    },
+}
+
+impl PageRequest {
+    pub fn offset(page: usize, size: usize) -> Self {
+        PageRequest::Offset { page, size }
+    }
+
+    pub fn keyset(field: &str, direction: SortDirection, after: Option<Value<'static>>, size: usize) -> Self {
+        PageRequest::Keyset {
+            field: field.to_owned(),
+            direction,
+            after,
+            size
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            PageRequest::Offset { size, .. } => *size,
+            PageRequest::Keyset { size, .. } => *size,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: Option<i64>,
+    pub page: Option<usize>,
+    pub size: Option<usize>,
+}
+
+impl<T> Page<T> {
+    pub fn map<U, F>(self, f: F) -> Page<U>
+    where F: FnMut(T) -> U {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            total: self.total,
+            page: self.page,
+            size: self.size
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FindManyOptions {
+    pub criteria: Criteria,
+    pub sort: Vec<Sort>,
+    pub page: Option<PageRequest>,
+    pub include_inactive: bool,
+    pub with_total: bool,
+}
+
+impl FindManyOptions {
+    pub fn new() -> Self {
+        FindManyOptions::default()
+    }
+
+    pub fn criteria(mut self, criteria: Criteria) -> Self {
+        self.criteria = criteria;
+        self
+    }
+
+    pub fn sort(mut self, sort: Sort) -> Self {
+        self.sort.push(sort);
+        self
+    }
+
+    pub fn page(mut self, page: PageRequest) -> Self {
+        self.page = Some(page);
+        self
+    }
+
+    pub fn include_inactive(mut self, include_inactive: bool) -> Self {
+        self.include_inactive = include_inactive;
+        self
+    }
+
+    pub fn with_total(mut self, with_total: bool) -> Self {
+        self.with_total = with_total;
+        self
+    }
+
+    pub fn create_condition_tree(&self) -> ConditionTree<'static> {
+        let mut criteria = self.criteria.clone();
+        if !self.include_inactive {
+            criteria = criteria.equals("active", true);
+        }
+        criteria.into_condition_tree()
+    }
+
+    pub fn add_criteria_to_select<'a>(&self, query: Select<'a>) -> Select<'a> {
+        query.so_that(self.create_condition_tree())
+    }
+
+    pub fn add_to_select<'a>(&self, query: Select<'a>) -> Select<'a> {
+        let mut query = self.add_criteria_to_select(query);
+
+        if let Some(PageRequest::Keyset { field, direction, after, .. }) = &self.page {
+            if let Some(after) = after {
+                query = match direction {
+                    SortDirection::Ascending => query.and_where(column(field).greater_than(after.to_owned())),
+                    SortDirection::Descending => query.and_where(column(field).less_than(after.to_owned())),
+                };
+            }
+            query = Sort { field: field.to_owned(), direction: *direction }.add_to_select(query);
+        }
+
+        for sort in self.sort.iter() {
+            query = sort.add_to_select(query);
+        }
+
+        match &self.page {
+            // An offset that overflows is past any table, so it becomes an empty page rather than a panic.
+            Some(PageRequest::Offset { page, size }) => query.limit(*size).offset(page.checked_mul(*size).map_or(i64::MAX as usize, |offset| offset.min(i64::MAX as usize))),
+            Some(PageRequest::Keyset { size, .. }) => query.limit(*size),
+            None => query
+        }
+    }
+
+    pub fn create_page<T>(&self, items: Vec<T>, total: Option<i64>) -> Page<T> {
+        let page = match &self.page {
+            Some(PageRequest::Offset { page, .. }) => Some(*page),
+            _ => None
+        };
+
+        Page {
+            items,
+            total,
+            page,
+            size: self.page.as_ref().map(|page| page.size())
+        }
+    }
+}
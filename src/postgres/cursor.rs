@@ -0,0 +1,84 @@
+use std::marker::PhantomData;
+use futures::{StreamExt, TryStreamExt, stream::{self, BoxStream}};
+use quaint::Value;
+use sqlx::{FromRow, Postgres, Transaction, postgres::PgRow};
+use uuid::{Uuid};
+use crate::postgres::{BurchillPostgresError, create_sqlx_arguments};
+
+pub const DEFAULT_FETCH_SIZE: i64 = 1000;
+
+
+// A server side cursor read in batches of fetch_size. Cursors only live as long as their
+// transaction, so the cursor borrows it and the rows stay consistent for the whole export.
+pub struct ServerCursor<'t, 'c, T> {
+    transaction: &'t mut Transaction<'c, Postgres>,
+    name: String,
+    fetch_size: i64,
+    done: bool,
+    row: PhantomData<T>,
+}
+
+impl<'t, 'c, T> ServerCursor<'t, 'c, T>
+where T: for<'r> FromRow<'r, PgRow> + Send + Unpin
+{
+    pub async fn declare<'a, Q>(transaction: &'t mut Transaction<'c, Postgres>, query: Q, fetch_size: i64) -> Result<ServerCursor<'t, 'c, T>, BurchillPostgresError>
+    where Q: Into<quaint::prelude::Query<'a>> {
+        let (sql, bindings) = match quaint::visitor::Postgres::build(query) {
+            Ok(query_and_bindings) => query_and_bindings,
+            Err(err) => return Err(BurchillPostgresError::QuaintError(err))
+        };
+        ServerCursor::declare_sql(transaction, &sql, bindings, fetch_size).await
+    }
+
+    pub async fn declare_sql(transaction: &'t mut Transaction<'c, Postgres>, sql: &str, bindings: Vec<Value<'_>>, fetch_size: i64) -> Result<ServerCursor<'t, 'c, T>, BurchillPostgresError> {
+        let name = format!("cursor_{}", Uuid::new_v4().to_simple());
+        let declare = format!("DECLARE {} NO SCROLL CURSOR FOR {}", name, sql);
+        sqlx::query_with(&declare, create_sqlx_arguments(bindings)?)
+            .execute(&mut *transaction).await?;
+
+        Ok(ServerCursor {
+            transaction,
+            name,
+            fetch_size: fetch_size.max(1),
+            done: false,
+            row: PhantomData
+        })
+    }
+
+    // None once the cursor is exhausted.
+    pub async fn next_batch(&mut self) -> Result<Option<Vec<T>>, BurchillPostgresError> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let rows = sqlx::query_as::<Postgres, T>(&format!("FETCH FORWARD {} FROM {}", self.fetch_size, self.name))
+            .fetch_all(&mut *self.transaction).await?;
+        if (rows.len() as i64) < self.fetch_size {
+            self.done = true;
+        }
+        if rows.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(rows))
+    }
+
+    // Closing is optional, the cursor goes away with the transaction anyway.
+    pub async fn close(self) -> Result<(), BurchillPostgresError> {
+        sqlx::query(&format!("CLOSE {}", self.name))
+            .execute(&mut *self.transaction).await?;
+        Ok(())
+    }
+
+    pub fn into_stream(self) -> BoxStream<'t, Result<T, BurchillPostgresError>>
+    where
+        T: 't,
+        'c: 't
+    {
+        stream::try_unfold(self, |mut cursor| async move {
+            Ok(cursor.next_batch().await?.map(|batch| (batch, cursor)))
+        })
+            .map_ok(|batch| stream::iter(batch.into_iter().map(Ok)))
+            .try_flatten()
+            .boxed()
+    }
+}
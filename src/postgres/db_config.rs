@@ -0,0 +1,47 @@
+use serde::{Serialize, de::DeserializeOwned};
+use sqlx::{Executor, Postgres, types::Json};
+use uuid::{Uuid};
+use crate::postgres::BurchillPostgresError;
+
+pub const DB_CONFIG_CHANNEL: &str = "db_config_changed";
+
+pub const CREATE_DB_CONFIG_TABLE: &str = "CREATE TABLE IF NOT EXISTS db_config (
+    key text PRIMARY KEY,
+    value jsonb NOT NULL,
+    last_updated_time timestamptz NOT NULL DEFAULT now(),
+    last_updated_by uuid
+)";
+
+
+pub async fn get_config_value<'a, T, E>(key: &str, executor: E) -> Result<Option<T>, BurchillPostgresError>
+where
+    T: DeserializeOwned + Send + Unpin,
+    E: Executor<'a, Database = Postgres>
+{
+    let value: Option<(Json<T>,)> = sqlx::query_as("SELECT value FROM db_config WHERE key = $1")
+        .bind(key)
+        .fetch_optional(executor).await?;
+    Ok(value.map(|(Json(value),)| value))
+}
+
+// Upserts and notifies in one statement so listeners hear about it exactly when it commits.
+pub async fn set_config_value<'a, T, E>(key: &str, value: &T, user_id: &Uuid, executor: E) -> Result<(), BurchillPostgresError>
+where
+    T: Serialize + Sync,
+    E: Executor<'a, Database = Postgres>
+{
+    sqlx::query(
+        "WITH changed AS (
+            INSERT INTO db_config (key, value, last_updated_time, last_updated_by) VALUES ($1, $2, now(), $3)
+            ON CONFLICT (key) DO UPDATE SET value = excluded.value, last_updated_time = excluded.last_updated_time, last_updated_by = excluded.last_updated_by
+            RETURNING key
+        )
+        SELECT pg_notify($4, key) FROM changed"
+    )
+        .bind(key)
+        .bind(Json(value))
+        .bind(user_id)
+        .bind(DB_CONFIG_CHANNEL)
+        .execute(executor).await?;
+    Ok(())
+}
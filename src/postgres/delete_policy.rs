@@ -0,0 +1,272 @@
+use std::fmt;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{Executor, PgConnection, Pool, Postgres};
+use uuid::{Uuid};
+use crate::postgres::{BurchillPostgresError, rbac::Rbac};
+use crate::strings::quote_qualified_identifier;
+
+pub const CREATE_DELETE_AUDIT_TABLE: &str = "CREATE TABLE IF NOT EXISTS delete_audit (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    table_name text NOT NULL,
+    entity_id uuid NOT NULL,
+    action text NOT NULL,
+    policy text NOT NULL,
+    approved_by uuid,
+    reason text,
+    created_time timestamptz NOT NULL DEFAULT now(),
+    created_by uuid NOT NULL
+);
+CREATE INDEX IF NOT EXISTS delete_audit_entity ON delete_audit (table_name, entity_id)";
+
+// Approvals are single use and only valid for the row they were given for.
+pub const CREATE_DELETE_APPROVALS_TABLE: &str = "CREATE TABLE IF NOT EXISTS delete_approvals (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    table_name text NOT NULL,
+    entity_id uuid NOT NULL,
+    reason text NOT NULL,
+    expires_time timestamptz NOT NULL,
+    used_time timestamptz,
+    used_by uuid,
+    created_time timestamptz NOT NULL DEFAULT now(),
+    created_by uuid NOT NULL
+);
+CREATE INDEX IF NOT EXISTS delete_approvals_entity ON delete_approvals (table_name, entity_id)";
+
+pub const CREATE_TOMBSTONES_TABLE: &str = "CREATE TABLE IF NOT EXISTS tombstones (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    entity_type text NOT NULL,
+    entity_id uuid NOT NULL,
+    deleted_by uuid NOT NULL,
+    deleted_time timestamptz NOT NULL DEFAULT now(),
+    reason text,
+    snapshot jsonb NOT NULL
+);
+CREATE INDEX IF NOT EXISTS tombstones_entity_type_deleted_time ON tombstones (entity_type, deleted_time)";
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DeletePolicy {
+    SoftOnly,
+    HardAllowed,
+    // A second person has to sign off, the approver can't be the one deleting.
+    HardWithApproval,
+}
+
+impl DeletePolicy {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            DeletePolicy::SoftOnly => "soft_only",
+            DeletePolicy::HardAllowed => "hard_allowed",
+            DeletePolicy::HardWithApproval => "hard_with_approval",
+        }
+    }
+
+    // The reason a hard delete isn't allowed, if it isn't. An approval still has to be verified
+    // against delete_approvals, this only checks one was given.
+    pub fn check_hard_delete(&self, approval: Option<&DeleteApproval>) -> Result<(), String> {
+        match (self, approval) {
+            (DeletePolicy::SoftOnly, _) => Err(String::from("only soft deletes are allowed")),
+            (DeletePolicy::HardAllowed, _) => Ok(()),
+            (DeletePolicy::HardWithApproval, None) => Err(String::from("a hard delete needs an approval")),
+            (DeletePolicy::HardWithApproval, Some(_)) => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for DeletePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.get_name())
+    }
+}
+
+// Only the id is trusted, everything else is read back from delete_approvals when it is used.
+#[derive(Clone, Debug, PartialEq, sqlx::FromRow)]
+pub struct DeleteApproval {
+    pub id: Uuid,
+    pub table_name: String,
+    pub entity_id: Uuid,
+    pub reason: String,
+    pub expires_time: DateTime<Utc>,
+    pub created_by: Uuid,
+}
+
+// The approver needs the "<table>.approve_delete" permission, checked through the RBAC tables.
+pub async fn approve_hard_delete(
+    rbac: &Rbac,
+    pool: &Pool<Postgres>,
+    table: &str,
+    entity_id: &Uuid,
+    approved_by: &Uuid,
+    reason: &str,
+    valid_for: Duration
+) -> Result<DeleteApproval, BurchillPostgresError> {
+    rbac.require_permission(approved_by, &format!("{}.approve_delete", table), pool).await?;
+
+    let approval = sqlx::query_as::<_, DeleteApproval>(
+        "INSERT INTO delete_approvals (table_name, entity_id, reason, expires_time, created_by) VALUES ($1, $2, $3, $4, $5)
+        RETURNING id, table_name, entity_id, reason, expires_time, created_by"
+    )
+        .bind(table)
+        .bind(entity_id)
+        .bind(reason)
+        .bind(Utc::now() + valid_for)
+        .bind(approved_by)
+        .fetch_one(pool).await?;
+
+    tracing::info!(table = %table, id = %entity_id, approved_by = %approved_by, approval_id = %approval.id, "approved hard delete");
+    Ok(approval)
+}
+
+// Marks the approval used if it is for this row, unexpired, unused and from someone other than the
+// user deleting. Nothing about the passed approval is trusted besides its id.
+async fn use_approval(connection: &mut PgConnection, table: &str, id: &Uuid, user_id: &Uuid, approval: &DeleteApproval) -> Result<Option<DeleteApproval>, BurchillPostgresError> {
+    let approval = sqlx::query_as::<_, DeleteApproval>(
+        "UPDATE delete_approvals SET used_time = now(), used_by = $4
+        WHERE id = $1 AND table_name = $2 AND entity_id = $3 AND created_by <> $4 AND used_time IS NULL AND expires_time > now()
+        RETURNING id, table_name, entity_id, reason, expires_time, created_by"
+    )
+        .bind(approval.id)
+        .bind(table)
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(connection).await?;
+    Ok(approval)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeleteAuditAction {
+    SoftDelete,
+    HardDelete,
+    Denied,
+}
+
+impl DeleteAuditAction {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            DeleteAuditAction::SoftDelete => "soft_delete",
+            DeleteAuditAction::HardDelete => "hard_delete",
+            DeleteAuditAction::Denied => "denied",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, sqlx::FromRow)]
+pub struct Tombstone {
+    pub id: Uuid,
+    pub entity_type: String,
+    pub entity_id: Uuid,
+    pub deleted_by: Uuid,
+    pub deleted_time: DateTime<Utc>,
+    pub reason: Option<String>,
+    pub snapshot: serde_json::Value,
+}
+
+// Oldest first, so sync consumers can page through with the last deleted_time they saw.
+pub async fn get_tombstones_since<'a, E>(entity_type: &str, since: &DateTime<Utc>, limit: i64, executor: E) -> Result<Vec<Tombstone>, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let tombstones = sqlx::query_as::<_, Tombstone>(
+        "SELECT id, entity_type, entity_id, deleted_by, deleted_time, reason, snapshot FROM tombstones WHERE entity_type = $1 AND deleted_time > $2 ORDER BY deleted_time, id LIMIT $3"
+    )
+        .bind(entity_type)
+        .bind(since)
+        .bind(limit)
+        .fetch_all(executor).await?;
+    Ok(tombstones)
+}
+
+pub async fn get_tombstone<'a, E>(entity_type: &str, entity_id: &Uuid, executor: E) -> Result<Option<Tombstone>, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let tombstone = sqlx::query_as::<_, Tombstone>(
+        "SELECT id, entity_type, entity_id, deleted_by, deleted_time, reason, snapshot FROM tombstones WHERE entity_type = $1 AND entity_id = $2 ORDER BY deleted_time DESC LIMIT 1"
+    )
+        .bind(entity_type)
+        .bind(entity_id)
+        .fetch_optional(executor).await?;
+    Ok(tombstone)
+}
+
+pub struct DeleteAuditEntry<'a> {
+    pub table: &'a str,
+    pub id: Uuid,
+    pub action: DeleteAuditAction,
+    pub policy: DeletePolicy,
+    pub user_id: Uuid,
+    pub approved_by: Option<Uuid>,
+    // The approval's reason for deletes, and why it was refused for denied attempts.
+    pub reason: Option<&'a str>,
+}
+
+pub async fn record_delete_audit(connection: &mut PgConnection, entry: &DeleteAuditEntry<'_>) -> Result<(), BurchillPostgresError> {
+    sqlx::query(
+        "INSERT INTO delete_audit (table_name, entity_id, action, policy, approved_by, reason, created_by) VALUES ($1, $2, $3, $4, $5, $6, $7)"
+    )
+        .bind(entry.table)
+        .bind(entry.id)
+        .bind(entry.action.get_name())
+        .bind(entry.policy.get_name())
+        .bind(entry.approved_by)
+        .bind(entry.reason)
+        .bind(entry.user_id)
+        .execute(connection).await?;
+    Ok(())
+}
+
+// Run inside a transaction, the approval, delete, tombstone and audit row all go together. A refused
+// delete returns DeletePolicyViolation without writing anything, the caller audits it once the
+// transaction is rolled back, see PostgresEntity::hard_delete.
+pub(crate) async fn hard_delete_row(
+    connection: &mut PgConnection,
+    table: &str,
+    id: &Uuid,
+    policy: DeletePolicy,
+    tombstone: bool,
+    user_id: &Uuid,
+    approval: Option<&DeleteApproval>
+) -> Result<(), BurchillPostgresError> {
+    let refuse = |reason: String| {
+        tracing::warn!(table = %table, id = %id, user_id = %user_id, policy = %policy, reason = %reason, "refused hard delete");
+        BurchillPostgresError::DeletePolicyViolation {
+            table: table.to_owned(),
+            id: Some(*id),
+            policy,
+            reason
+        }
+    };
+
+    policy.check_hard_delete(approval).map_err(refuse)?;
+    let approval = match (policy, approval) {
+        (DeletePolicy::HardWithApproval, Some(approval)) => match use_approval(&mut *connection, table, id, user_id, approval).await? {
+            Some(approval) => Some(approval),
+            None => return Err(refuse(String::from("the approval is expired, already used, for another row or from the user deleting")))
+        },
+        _ => None
+    };
+    let reason = approval.as_ref().map(|approval| approval.reason.as_str());
+
+    let snapshot: Option<(serde_json::Value,)> = sqlx::query_as(&format!("DELETE FROM {} t WHERE t.id = $1 RETURNING to_jsonb(t.*)", quote_qualified_identifier(table)))
+        .bind(id)
+        .fetch_optional(&mut *connection).await?;
+    let (snapshot,) = snapshot.ok_or(BurchillPostgresError::SqlxError(sqlx::Error::RowNotFound))?;
+
+    if tombstone {
+        sqlx::query("INSERT INTO tombstones (entity_type, entity_id, deleted_by, reason, snapshot) VALUES ($1, $2, $3, $4, $5)")
+            .bind(table)
+            .bind(id)
+            .bind(user_id)
+            .bind(reason)
+            .bind(snapshot)
+            .execute(&mut *connection).await?;
+    }
+
+    record_delete_audit(connection, &DeleteAuditEntry {
+        table,
+        id: *id,
+        action: DeleteAuditAction::HardDelete,
+        policy,
+        user_id: *user_id,
+        approved_by: approval.as_ref().map(|approval| approval.created_by),
+        reason
+    }).await?;
+    tracing::info!(table = %table, id = %id, user_id = %user_id, policy = %policy, tombstone, "hard deleted entity");
+    Ok(())
+}
@@ -1,10 +1,12 @@
-use sqlx::{Executor, Postgres};
+use std::{any::type_name, sync::Arc, time::Instant};
+use sqlx::{Connection, Executor, FromRow, PgConnection, Postgres, postgres::PgRow};
 use async_trait::async_trait;
 use anyhow::Result;
 use uuid::{Uuid};
-use quaint::prelude::{Insert, SingleRowInsert, Update, default_value};
+use quaint::prelude::{Comparable, ConditionTree, Expression, Insert, SingleRowInsert, Update, default_value};
 use chrono::{DateTime, Utc};
-use crate::postgres::{PostgresBaseEntityData, fetch_one, update_and_fetch_one, BurchillPostgresError};
+use crate::postgres::{PostgresBaseEntityData, access::{AccessAction, AccessPolicy, Actor, check_access}, delete_policy::{DeleteApproval, DeleteAuditAction, DeleteAuditEntry, DeletePolicy, hard_delete_row, record_delete_audit}, entity_stats::{EntityOperation, EntityStatsRegistry}, fetch_one_row, update_and_fetch_one_row, BurchillPostgresError, etag::create_etag};
+use crate::strings::{quote_identifier, quote_qualified_identifier};
 
 
 #[derive(Clone)]
@@ -123,9 +125,86 @@ pub trait PostgresEntity<D> {
         self.get_mutable_entity_manager().set_active(active);
     }
 
+    fn get_last_modified_time(&self) -> Option<DateTime<Utc>> {
+        self.get_last_updated_time().or_else(|| self.get_created_time())
+    }
+
+    fn get_etag(&self) -> Option<String> {
+        match (self.get_id(), self.get_last_modified_time()) {
+            (Some(id), Some(last_modified)) => Some(create_etag(&id, &last_modified)),
+            _ => None
+        }
+    }
+
     fn create_insert_query<'b>(&self) -> Result<SingleRowInsert<'b>>;
     fn create_update_query<'b>(&self) -> Result<Update<'b>>;
 
+    // Database computed columns to return from an insert on top of the audit fields.
+    fn get_insert_returning_fields(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+    fn apply_insert_returning(&mut self, _row: &PgRow) -> Result<(), BurchillPostgresError> {
+        Ok(())
+    }
+
+    // Opt in to lost update protection for tables without a version column, updates then only
+    // match the row if last_updated_time is unchanged since this entity loaded it.
+    fn uses_concurrency_token(&self) -> bool {
+        false
+    }
+
+    // Extra conditions ANDed with the id and token match, e.g. tenant or owner scoping. Only used
+    // with the concurrency token, otherwise create_update_query sets its own conditions.
+    fn create_update_conditions<'b>(&self) -> Vec<Expression<'b>> {
+        Vec::new()
+    }
+
+    // Same as above for updates, e.g. a version column bumped by a trigger.
+    fn get_update_returning_fields(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+    fn apply_update_returning(&mut self, _row: &PgRow) -> Result<(), BurchillPostgresError> {
+        Ok(())
+    }
+
+    fn get_access_policy(&self) -> Option<Arc<dyn AccessPolicy<Self>>>
+    where Self: Sized {
+        None
+    }
+
+    fn is_deny_by_default(&self) -> bool {
+        false
+    }
+
+    fn get_stats_registry(&self) -> Option<Arc<EntityStatsRegistry>> {
+        None
+    }
+
+    // Needed by the delete APIs, entities without it can only be deactivated through save.
+    fn get_table_name(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn get_delete_policy(&self) -> DeletePolicy {
+        DeletePolicy::SoftOnly
+    }
+
+    // Hard deletes leave a row in tombstones, keyed by the table name.
+    fn is_tombstone_enabled(&self) -> bool {
+        false
+    }
+
+    // The table name when there is one, so writes are counted together with the repository's reads.
+    fn get_stats_name(&self) -> &'static str {
+        self.get_table_name().unwrap_or_else(type_name::<Self>)
+    }
+
+    fn record_stats(&self, operation: EntityOperation, started: Instant, success: bool) {
+        if let Some(registry) = self.get_stats_registry() {
+            registry.record(self.get_stats_name(), operation, started.elapsed(), 0, success);
+        }
+    }
+
     async fn post_save_hook(&mut self) -> Result<()> {
         Ok(())
     }
@@ -152,19 +231,31 @@ pub trait PostgresEntity<D> {
             return Err(BurchillPostgresError::AnyhowError(err));
         }
 
+        let started = Instant::now();
         let result = if let Some(_) = self.get_id() {
-            self.update(executor, &user_id)
+            self.update(executor, user_id).await
         } else {
-            self.insert(executor, &user_id)
+            self.insert(executor, user_id).await
         };
-
-        let result = result.await?;
+        self.record_stats(EntityOperation::Write, started, result.is_ok());
+        result?;
 
         if let Err(err) = self.post_save_hook().await {
             return Err(BurchillPostgresError::AnyhowError(err));
         }
         
-        Ok(result)
+        Ok(())
+    }
+
+    fn get_delete_target(&self) -> Result<(&'static str, Uuid), BurchillPostgresError> {
+        match (self.get_table_name(), self.get_id()) {
+            (Some(table), Some(id)) => Ok((table, id)),
+            (table, id) => Err(BurchillPostgresError::EntityMissingValue {
+                table: table.unwrap_or_else(type_name::<Self>).to_owned(),
+                field: String::from(if table.is_none() { "table_name" } else { "id" }),
+                id
+            })
+        }
     }
 
     async fn insert<'b, E>(&mut self, executor: E, user_id: &Uuid) -> Result<(), BurchillPostgresError>
@@ -174,15 +265,19 @@ pub trait PostgresEntity<D> {
         }
 
         let query = self.create_audited_insert_query(user_id)?;
-        let query = Insert::from(query).returning(vec!["id", "created_by", "created_time", "active"]);
+        let mut returning = vec!["id", "created_by", "created_time", "active"];
+        returning.extend(self.get_insert_returning_fields());
+        let query = Insert::from(query).returning(returning);
 
-        let result: InsertReturn = fetch_one(query, executor).await?;
+        let row = fetch_one_row(query, executor).await?;
+        let result = InsertReturn::from_row(&row)?;
 
         let entity_manager = self.get_mutable_entity_manager();
         entity_manager.set_id(result.id);
         entity_manager.set_created_by(result.created_by);
         entity_manager.set_created_time(result.created_time);
         entity_manager.set_active(result.active);
+        self.apply_insert_returning(&row)?;
 
         if let Err(err) = self.post_insert_hook().await {
             return Err(BurchillPostgresError::AnyhowError(err));
@@ -198,11 +293,28 @@ pub trait PostgresEntity<D> {
         }
 
         let query = self.create_audited_update_query(user_id)?;
-        let result: UpdateReturn = update_and_fetch_one(query, vec!["last_updated_by, last_updated_time"], executor).await?;
+        let mut returning = vec!["last_updated_by", "last_updated_time", "active"];
+        returning.extend(self.get_update_returning_fields());
+        let returning: Vec<String> = returning.into_iter().map(quote_identifier).collect();
+
+        let row = match update_and_fetch_one_row(query, returning.iter().map(String::as_str).collect(), executor).await {
+            Ok(row) => row,
+            Err(BurchillPostgresError::SqlxError(sqlx::Error::RowNotFound)) if self.uses_concurrency_token() => {
+                return Err(BurchillPostgresError::StaleEntity {
+                    table: self.get_table_name().unwrap_or_else(type_name::<Self>).to_owned(),
+                    id: self.get_id(),
+                    last_updated_time: self.get_last_updated_time()
+                });
+            }
+            Err(err) => return Err(err)
+        };
+        let result = UpdateReturn::from_row(&row)?;
 
         let entity_manager = self.get_mutable_entity_manager();
         entity_manager.set_last_updated_by(result.last_updated_by);
         entity_manager.set_last_updated_time(result.last_updated_time);
+        entity_manager.set_active(result.active);
+        self.apply_update_returning(&row)?;
 
         if let Err(err) = self.post_update_hook().await {
             return Err(BurchillPostgresError::AnyhowError(err));
@@ -212,9 +324,41 @@ pub trait PostgresEntity<D> {
     }
     
     fn create_audited_update_query<'b>(&self, user_id: &Uuid) -> Result<Update<'b>, BurchillPostgresError> {
-        Ok(self.create_update_query()?
+        let query = self.create_update_query()?
             .set("last_updated_time", Utc::now())
-            .set("last_updated_by", user_id.to_owned()))
+            .set("last_updated_by", user_id.to_owned());
+
+        if !self.uses_concurrency_token() {
+            return Ok(query);
+        }
+
+        // Quaint can't append to conditions already on the query, so the token check is built from the id and
+        // create_update_conditions instead, and an entity scoping its update in create_update_query is refused
+        // rather than having that scoping silently dropped.
+        let table = self.get_table_name().unwrap_or_else(type_name::<Self>);
+        let (sql, _) = quaint::visitor::Postgres::build(query.clone()).map_err(BurchillPostgresError::QuaintError)?;
+        if sql.contains(" WHERE ") {
+            return Err(BurchillPostgresError::AnyhowError(anyhow::anyhow!(
+                "{} uses the concurrency token so its update conditions have to come from create_update_conditions, not create_update_query.", table
+            )));
+        }
+
+        let id = match self.get_id() {
+            Some(id) => id,
+            None => return Err(BurchillPostgresError::EntityMissingValue {
+                table: table.to_owned(),
+                field: String::from("id"),
+                id: None
+            })
+        };
+        let token = match self.get_last_updated_time() {
+            Some(last_updated_time) => "last_updated_time".equals(last_updated_time),
+            None => "last_updated_time".is_null()
+        };
+
+        let mut conditions: Vec<Expression<'b>> = vec!["id".equals(id).into(), token.into()];
+        conditions.extend(self.create_update_conditions());
+        Ok(query.so_that(ConditionTree::And(conditions)))
     }
     
     fn create_audited_insert_query<'b>(&self, user_id: &Uuid) -> Result<SingleRowInsert<'b>, BurchillPostgresError> {
@@ -224,6 +368,127 @@ pub trait PostgresEntity<D> {
     }
 }
 
+// The access checked writes. Policies judge the row as it is stored rather than this copy, which the
+// caller may have changed, so entities using these have to be able to load themselves.
+#[async_trait]
+pub trait AccessControlledEntity<D>: PostgresEntity<D> + Sized + Send + Sync {
+    // Select it FOR UPDATE so the row can't change between the check and the write.
+    async fn find_stored_for_update(&self, connection: &mut PgConnection) -> Result<Option<Self>, BurchillPostgresError>;
+
+    async fn check_stored_access(&self, connection: &mut PgConnection, actor: &Actor, action: AccessAction) -> Result<(), BurchillPostgresError> {
+        let table = self.get_table_name().unwrap_or_else(type_name::<Self>);
+        let policy = match self.get_access_policy() {
+            Some(policy) if !actor.is_system => policy,
+            policy => return check_access(policy.as_ref(), self.is_deny_by_default(), actor, action, table, self.get_id(), self).await
+        };
+
+        let stored = match self.find_stored_for_update(connection).await? {
+            Some(stored) => stored,
+            None => return Err(BurchillPostgresError::SqlxError(sqlx::Error::RowNotFound))
+        };
+        check_access(Some(&policy), self.is_deny_by_default(), actor, action, table, self.get_id(), &stored).await
+    }
+
+    // save checked against the entity's access policy, background jobs pass Actor::system to skip it.
+    // Only a policy checked update runs in a transaction (a savepoint inside the caller's) so the checked row stays locked.
+    async fn save_as(&mut self, connection: &mut PgConnection, actor: &Actor) -> Result<(), BurchillPostgresError> {
+        let policy = self.get_access_policy();
+        if self.get_id().is_none() || policy.is_none() || actor.is_system {
+            // Nothing is stored yet, so creating is judged on the new entity itself.
+            let (action, id) = match self.get_id() {
+                Some(id) => (AccessAction::Update, Some(id)),
+                None => (AccessAction::Create, None)
+            };
+            let table = self.get_table_name().unwrap_or_else(type_name::<Self>);
+            check_access(policy.as_ref(), self.is_deny_by_default(), actor, action, table, id, &*self).await?;
+            return self.save(connection, &actor.user_id).await;
+        }
+
+        let mut transaction = connection.begin().await?;
+        self.check_stored_access(&mut *transaction, actor, AccessAction::Update).await?;
+        self.save(&mut *transaction, &actor.user_id).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    // Deactivates the row directly rather than through update, create_update_query may not write active.
+    async fn soft_delete(&mut self, connection: &mut PgConnection, actor: &Actor) -> Result<(), BurchillPostgresError> {
+        let started = Instant::now();
+        let result: Result<(), BurchillPostgresError> = async {
+            let (table, id) = self.get_delete_target()?;
+            let mut transaction = connection.begin().await?;
+            self.check_stored_access(&mut *transaction, actor, AccessAction::Delete).await?;
+
+            let result: Option<(DateTime<Utc>,)> = sqlx::query_as(&format!(
+                "UPDATE {} SET active = false, last_updated_time = now(), last_updated_by = $2 WHERE id = $1 RETURNING last_updated_time",
+                quote_qualified_identifier(table)
+            ))
+                .bind(id)
+                .bind(actor.user_id)
+                .fetch_optional(&mut *transaction).await?;
+            let (last_updated_time,) = result.ok_or(BurchillPostgresError::SqlxError(sqlx::Error::RowNotFound))?;
+
+            record_delete_audit(&mut *transaction, &DeleteAuditEntry {
+                table,
+                id,
+                action: DeleteAuditAction::SoftDelete,
+                policy: self.get_delete_policy(),
+                user_id: actor.user_id,
+                approved_by: None,
+                reason: None
+            }).await?;
+            transaction.commit().await?;
+
+            let entity_manager = self.get_mutable_entity_manager();
+            entity_manager.set_active(false);
+            entity_manager.set_last_updated_by(actor.user_id);
+            entity_manager.set_last_updated_time(last_updated_time);
+            Ok(())
+        }.await;
+
+        self.record_stats(EntityOperation::Write, started, result.is_ok());
+        result
+    }
+
+    // Refused unless the entity's delete policy allows it, a refusal is audited after the rollback so
+    // the record of the attempt is kept.
+    async fn hard_delete(&mut self, connection: &mut PgConnection, actor: &Actor, approval: Option<&DeleteApproval>) -> Result<(), BurchillPostgresError> {
+        let started = Instant::now();
+        let result: Result<(), BurchillPostgresError> = async {
+            let (table, id) = self.get_delete_target()?;
+            let policy = self.get_delete_policy();
+            let mut transaction = connection.begin().await?;
+            self.check_stored_access(&mut *transaction, actor, AccessAction::Delete).await?;
+
+            match hard_delete_row(&mut *transaction, table, &id, policy, self.is_tombstone_enabled(), &actor.user_id, approval).await {
+                Ok(()) => {
+                    transaction.commit().await?;
+                    Ok(())
+                }
+                Err(BurchillPostgresError::DeletePolicyViolation { table, id, policy, reason }) => {
+                    transaction.rollback().await?;
+                    if let Some(id) = id {
+                        record_delete_audit(connection, &DeleteAuditEntry {
+                            table: &table,
+                            id,
+                            action: DeleteAuditAction::Denied,
+                            policy,
+                            user_id: actor.user_id,
+                            approved_by: None,
+                            reason: Some(&reason)
+                        }).await?;
+                    }
+                    Err(BurchillPostgresError::DeletePolicyViolation { table, id, policy, reason })
+                }
+                Err(err) => Err(err)
+            }
+        }.await;
+
+        self.record_stats(EntityOperation::Write, started, result.is_ok());
+        result
+    }
+}
+
 #[derive(sqlx::FromRow)]
 struct InsertReturn {
     id: Uuid,
@@ -235,5 +500,6 @@ struct InsertReturn {
 #[derive(sqlx::FromRow)]
 struct UpdateReturn {
     last_updated_by: Uuid,
-    last_updated_time: DateTime<Utc>
+    last_updated_time: DateTime<Utc>,
+    active: bool
 }
@@ -0,0 +1,123 @@
+use std::{collections::HashMap, future::Future, sync::{Mutex, PoisonError}, time::{Duration, Instant}};
+use serde::Serialize;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EntityOperation {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EntityStats {
+    pub entity: String,
+    pub reads: u64,
+    pub writes: u64,
+    pub errors: u64,
+    pub rows_read: u64,
+    pub read_time_micros: u64,
+    pub write_time_micros: u64,
+}
+
+impl EntityStats {
+    pub fn get_average_read_latency(&self) -> Duration {
+        average(self.read_time_micros, self.reads)
+    }
+
+    pub fn get_average_write_latency(&self) -> Duration {
+        average(self.write_time_micros, self.writes)
+    }
+
+    pub fn get_total_time(&self) -> Duration {
+        Duration::from_micros(self.read_time_micros + self.write_time_micros)
+    }
+}
+
+fn average(total_micros: u64, count: u64) -> Duration {
+    if count == 0 {
+        Duration::from_micros(0)
+    } else {
+        Duration::from_micros(total_micros / count)
+    }
+}
+
+// Implemented by the app's metrics backend, called once per entity by export().
+pub trait EntityStatsMetrics: Send + Sync {
+    fn record_entity_stats(&self, stats: &EntityStats);
+}
+
+// Optional, repositories and entities only record into it when they return one from get_stats_registry.
+#[derive(Default)]
+pub struct EntityStatsRegistry {
+    entries: Mutex<HashMap<String, EntityStats>>,
+}
+
+impl EntityStatsRegistry {
+    pub fn new() -> Self {
+        EntityStatsRegistry::default()
+    }
+
+    pub fn record(&self, entity: &str, operation: EntityOperation, duration: Duration, rows: u64, success: bool) {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let stats = entries.entry(entity.to_owned()).or_insert_with(|| EntityStats {
+            entity: entity.to_owned(),
+            ..EntityStats::default()
+        });
+
+        let micros = duration.as_micros() as u64;
+        match operation {
+            EntityOperation::Read => {
+                stats.reads += 1;
+                stats.rows_read += rows;
+                stats.read_time_micros += micros;
+            }
+            EntityOperation::Write => {
+                stats.writes += 1;
+                stats.write_time_micros += micros;
+            }
+        }
+        if !success {
+            stats.errors += 1;
+        }
+    }
+
+    pub async fn measure<T, E, F>(&self, entity: &str, operation: EntityOperation, future: F) -> Result<T, E>
+    where F: Future<Output = Result<T, E>> {
+        let started = Instant::now();
+        let result = future.await;
+        let rows = if operation == EntityOperation::Read && result.is_ok() { 1 } else { 0 };
+        self.record(entity, operation, started.elapsed(), rows, result.is_ok());
+        result
+    }
+
+    pub async fn measure_many<T, E, F>(&self, entity: &str, future: F) -> Result<Vec<T>, E>
+    where F: Future<Output = Result<Vec<T>, E>> {
+        let started = Instant::now();
+        let result = future.await;
+        let rows = result.as_ref().map(|rows| rows.len() as u64).unwrap_or(0);
+        self.record(entity, EntityOperation::Read, started.elapsed(), rows, result.is_ok());
+        result
+    }
+
+    // Busiest first by total time spent, which is what answers "who is loading the database".
+    pub fn get_stats(&self) -> Vec<EntityStats> {
+        let mut stats: Vec<EntityStats> = self.entries.lock().unwrap_or_else(PoisonError::into_inner).values().cloned().collect();
+        stats.sort_by(|a, b| b.get_total_time().cmp(&a.get_total_time()).then_with(|| a.entity.cmp(&b.entity)));
+        stats
+    }
+
+    pub fn get_entity_stats(&self, entity: &str) -> Option<EntityStats> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner).get(entity).cloned()
+    }
+
+    pub fn export(&self, metrics: &dyn EntityStatsMetrics) {
+        for stats in self.get_stats() {
+            metrics.record_entity_stats(&stats);
+        }
+    }
+
+    pub fn reset(&self) {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+}
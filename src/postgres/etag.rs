@@ -0,0 +1,28 @@
+use chrono::{DateTime, Utc};
+use uuid::{Uuid};
+
+// Weak validator built from the id and the last write time, entities that were never updated use their created time.
+pub fn create_etag(id: &Uuid, last_modified: &DateTime<Utc>) -> String {
+    // Microseconds built from the seconds, timestamp_nanos panics outside roughly 1677 to 2262.
+    let micros = last_modified.timestamp() * 1_000_000 + last_modified.timestamp_subsec_micros() as i64;
+    format!("W/\"{}-{:x}\"", id.to_simple(), micros)
+}
+
+fn strip_weak_prefix(tag: &str) -> &str {
+    tag.trim().trim_start_matches("W/")
+}
+
+// Handles "*" and comma separated lists from If-Match / If-None-Match headers. The comparison is weak since
+// the generated tags are weak, which is what optimistic locking on last_updated_time needs anyway.
+pub fn etag_matches(header: &str, etag: &str) -> bool {
+    let etag = strip_weak_prefix(etag);
+    header.split(',').any(|candidate| {
+        let candidate = candidate.trim();
+        candidate == "*" || strip_weak_prefix(candidate) == etag
+    })
+}
+
+// HTTP dates only have second precision so anything within the same second counts as unmodified.
+pub fn is_modified_since(last_modified: &DateTime<Utc>, since: &DateTime<Utc>) -> bool {
+    last_modified.timestamp() > since.timestamp()
+}
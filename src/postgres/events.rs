@@ -0,0 +1,125 @@
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use async_trait::async_trait;
+use anyhow::Result;
+use sqlx::{Pool, Postgres, Transaction};
+use crate::postgres::BurchillPostgresError;
+
+
+#[async_trait]
+pub trait EventDispatcher<E> {
+    async fn dispatch(&self, event: E) -> Result<()>;
+}
+
+// Cheap to clone so entities can keep a handle and emit from their save hooks.
+pub struct EventBuffer<E> {
+    events: Arc<Mutex<Vec<E>>>
+}
+
+impl<E> Clone for EventBuffer<E> {
+    fn clone(&self) -> Self {
+        EventBuffer {
+            events: self.events.clone()
+        }
+    }
+}
+
+impl<E> Default for EventBuffer<E> {
+    fn default() -> Self {
+        EventBuffer {
+            events: Arc::new(Mutex::new(Vec::new()))
+        }
+    }
+}
+
+impl<E> EventBuffer<E> {
+    pub fn new() -> Self {
+        EventBuffer::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<E>> {
+        self.events.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn emit(&self, event: E) {
+        self.lock().push(event);
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    pub fn take(&self) -> Vec<E> {
+        std::mem::take(&mut *self.lock())
+    }
+
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+}
+
+// Events emitted while the transaction is open are only dispatched once it has committed,
+// a rollback (or dropping the transaction) throws them away.
+pub struct EventTransaction<'c, E> {
+    transaction: Transaction<'c, Postgres>,
+    buffer: EventBuffer<E>
+}
+
+impl<E> EventTransaction<'static, E>
+where E: Send {
+    pub async fn begin(pool: &Pool<Postgres>) -> Result<Self, BurchillPostgresError> {
+        let transaction = pool.begin().await?;
+        Ok(EventTransaction {
+            transaction,
+            buffer: EventBuffer::new()
+        })
+    }
+}
+
+impl<'c, E> EventTransaction<'c, E>
+where E: Send {
+    pub fn from_transaction(transaction: Transaction<'c, Postgres>) -> Self {
+        EventTransaction {
+            transaction,
+            buffer: EventBuffer::new()
+        }
+    }
+
+    pub fn get_buffer(&self) -> EventBuffer<E> {
+        self.buffer.clone()
+    }
+
+    pub fn emit(&self, event: E) {
+        self.buffer.emit(event);
+    }
+
+    pub fn get_transaction(&mut self) -> &mut Transaction<'c, Postgres> {
+        &mut self.transaction
+    }
+
+    // The transaction is already committed when dispatching starts, so a failed dispatch reports how far it got.
+    pub async fn commit<D>(self, dispatcher: &D) -> Result<(), BurchillPostgresError>
+    where D: EventDispatcher<E> + Sync {
+        self.transaction.commit().await?;
+
+        let events = self.buffer.take();
+        let total = events.len();
+        for (index, event) in events.into_iter().enumerate() {
+            if let Err(err) = dispatcher.dispatch(event).await {
+                return Err(BurchillPostgresError::AnyhowError(
+                    err.context(format!("Failed to dispatch event {} of {} after commit.", index + 1, total))
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn rollback(self) -> Result<(), BurchillPostgresError> {
+        self.buffer.clear();
+        self.transaction.rollback().await?;
+        Ok(())
+    }
+}
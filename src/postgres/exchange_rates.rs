@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use rust_decimal::{Decimal, RoundingStrategy};
+use sqlx::{Executor, Pool, Postgres};
+use uuid::{Uuid};
+use crate::postgres::BurchillPostgresError;
+
+pub const CREATE_EXCHANGE_RATES_TABLE: &str = "CREATE TABLE IF NOT EXISTS exchange_rates (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    base_currency char(3) NOT NULL,
+    quote_currency char(3) NOT NULL,
+    rate numeric NOT NULL CHECK (rate > 0),
+    effective_time timestamptz NOT NULL,
+    source text NOT NULL,
+    created_time timestamptz NOT NULL DEFAULT now(),
+    created_by uuid NOT NULL,
+    last_updated_time timestamptz,
+    last_updated_by uuid,
+    active boolean NOT NULL DEFAULT true,
+    UNIQUE (base_currency, quote_currency, effective_time)
+);
+CREATE INDEX IF NOT EXISTS exchange_rates_quote_time ON exchange_rates (quote_currency, effective_time DESC)";
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FetchedRate {
+    pub base_currency: String,
+    pub quote_currency: String,
+    pub rate: Decimal,
+    pub effective_time: DateTime<Utc>,
+}
+
+// Implemented by the consuming app with whatever HTTP client and provider it already uses.
+#[async_trait]
+pub trait ExchangeRateSource: Send + Sync {
+    fn get_name(&self) -> &str;
+
+    async fn fetch_rates(&self, base_currency: &str) -> anyhow::Result<Vec<FetchedRate>>;
+}
+
+pub fn normalize_currency(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+// Rates already stored for the same effective time are left alone so a refresh can be retried safely.
+pub async fn store_exchange_rates<'a, E>(rates: &[FetchedRate], source: &str, user_id: &Uuid, executor: E) -> Result<usize, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let base_currencies: Vec<String> = rates.iter().map(|rate| normalize_currency(&rate.base_currency)).collect();
+    let quote_currencies: Vec<String> = rates.iter().map(|rate| normalize_currency(&rate.quote_currency)).collect();
+    let values: Vec<Decimal> = rates.iter().map(|rate| rate.rate).collect();
+    let effective_times: Vec<DateTime<Utc>> = rates.iter().map(|rate| rate.effective_time).collect();
+
+    let result = sqlx::query(
+        "INSERT INTO exchange_rates (base_currency, quote_currency, rate, effective_time, source, created_by)
+        SELECT base_currency, quote_currency, rate, effective_time, $5, $6
+        FROM UNNEST($1::text[], $2::text[], $3::numeric[], $4::timestamptz[]) AS fetched (base_currency, quote_currency, rate, effective_time)
+        ON CONFLICT (base_currency, quote_currency, effective_time) DO NOTHING"
+    )
+        .bind(base_currencies)
+        .bind(quote_currencies)
+        .bind(values)
+        .bind(effective_times)
+        .bind(source)
+        .bind(user_id)
+        .execute(executor).await?;
+    Ok(result.rows_affected() as usize)
+}
+
+pub async fn refresh_exchange_rates<S>(source: &S, base_currency: &str, user_id: &Uuid, pool: &Pool<Postgres>) -> Result<usize, BurchillPostgresError>
+where S: ExchangeRateSource + ?Sized {
+    let rates = source.fetch_rates(&normalize_currency(base_currency)).await?;
+    store_exchange_rates(&rates, source.get_name(), user_id, pool).await
+}
+
+pub async fn get_last_refresh_time<'a, E>(base_currency: &str, executor: E) -> Result<Option<DateTime<Utc>>, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let (last_refresh,): (Option<DateTime<Utc>>,) = sqlx::query_as(
+        "SELECT max(created_time) FROM exchange_rates WHERE base_currency = $1 AND active"
+    )
+        .bind(normalize_currency(base_currency))
+        .fetch_one(executor).await?;
+    Ok(last_refresh)
+}
+
+// Meant to be called from whatever scheduler the app runs, refreshes only when the stored rates are too old.
+pub async fn refresh_if_stale<S>(source: &S, base_currency: &str, max_age: Duration, user_id: &Uuid, pool: &Pool<Postgres>) -> Result<Option<usize>, BurchillPostgresError>
+where S: ExchangeRateSource + ?Sized {
+    let last_refresh = get_last_refresh_time(base_currency, pool).await?;
+    match last_refresh {
+        Some(last_refresh) if Utc::now() - last_refresh < max_age => Ok(None),
+        _ => Ok(Some(refresh_exchange_rates(source, base_currency, user_id, pool).await?))
+    }
+}
+
+// The most recent rate effective at the given time, going through a shared base currency when
+// there is no direct (or inverse) pair stored.
+pub async fn get_exchange_rate(from: &str, to: &str, at: &DateTime<Utc>, pool: &Pool<Postgres>) -> Result<Decimal, BurchillPostgresError> {
+    let from = normalize_currency(from);
+    let to = normalize_currency(to);
+    if from == to {
+        return Ok(Decimal::ONE);
+    }
+
+    let direct: Option<(String, Decimal)> = sqlx::query_as(
+        "SELECT base_currency::text, rate FROM exchange_rates
+        WHERE active AND effective_time <= $3
+        AND ((base_currency = $1 AND quote_currency = $2) OR (base_currency = $2 AND quote_currency = $1))
+        ORDER BY effective_time DESC LIMIT 1"
+    )
+        .bind(&from)
+        .bind(&to)
+        .bind(at)
+        .fetch_optional(pool).await?;
+
+    if let Some((base_currency, rate)) = direct {
+        return if base_currency == from {
+            Ok(rate)
+        } else {
+            invert_rate(rate, &from, &to, at)
+        };
+    }
+
+    let quoted: Vec<(String, String, Decimal)> = sqlx::query_as(
+        "SELECT DISTINCT ON (base_currency, quote_currency) base_currency::text, quote_currency::text, rate FROM exchange_rates
+        WHERE active AND effective_time <= $2 AND quote_currency = ANY($1)
+        ORDER BY base_currency, quote_currency, effective_time DESC"
+    )
+        .bind(vec![from.to_owned(), to.to_owned()])
+        .bind(at)
+        .fetch_all(pool).await?;
+
+    let mut by_base: HashMap<String, (Option<Decimal>, Option<Decimal>)> = HashMap::new();
+    for (base_currency, quote_currency, rate) in quoted {
+        let entry = by_base.entry(base_currency).or_default();
+        if quote_currency == from {
+            entry.0 = Some(rate);
+        } else {
+            entry.1 = Some(rate);
+        }
+    }
+
+    let mut bases: Vec<(String, Decimal, Decimal)> = by_base.into_iter()
+        .filter_map(|(base, rates)| match rates {
+            (Some(from_rate), Some(to_rate)) => Some((base, from_rate, to_rate)),
+            _ => None
+        })
+        .collect();
+    bases.sort_by(|a, b| a.0.cmp(&b.0));
+
+    match bases.first() {
+        Some((_, from_rate, to_rate)) => to_rate.checked_div(*from_rate).ok_or_else(|| missing_rate(&from, &to, at)),
+        None => Err(missing_rate(&from, &to, at))
+    }
+}
+
+// Unrounded, round with round_currency once the final amount is known to avoid compounding errors.
+pub async fn convert(amount: Decimal, from: &str, to: &str, at: &DateTime<Utc>, pool: &Pool<Postgres>) -> Result<Decimal, BurchillPostgresError> {
+    let rate = get_exchange_rate(from, to, at, pool).await?;
+    amount.checked_mul(rate).ok_or_else(|| missing_rate(from, to, at))
+}
+
+pub fn round_currency(amount: Decimal, decimal_places: u32) -> Decimal {
+    amount.round_dp_with_strategy(decimal_places, RoundingStrategy::MidpointNearestEven)
+}
+
+fn invert_rate(rate: Decimal, from: &str, to: &str, at: &DateTime<Utc>) -> Result<Decimal, BurchillPostgresError> {
+    Decimal::ONE.checked_div(rate).ok_or_else(|| missing_rate(from, to, at))
+}
+
+fn missing_rate(from: &str, to: &str, at: &DateTime<Utc>) -> BurchillPostgresError {
+    BurchillPostgresError::MissingExchangeRate {
+        from: normalize_currency(from),
+        to: normalize_currency(to),
+        at: *at
+    }
+}
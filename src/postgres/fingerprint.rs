@@ -0,0 +1,314 @@
+use std::{collections::HashMap, sync::{Mutex, PoisonError, atomic::{AtomicU64, Ordering}}, time::Duration};
+
+// Replaces literals and parameters with ?, collapses IN lists and whitespace and lowercases
+// everything outside quoted identifiers so queries that only differ by values compare equal.
+pub fn normalize_query(sql: &str) -> String {
+    let chars: Vec<char> = sql.chars().collect();
+    let mut output = String::with_capacity(sql.len());
+    let mut index = 0;
+
+    let push_token = |output: &mut String, token: &str| {
+        let needs_space = output.chars().last().is_some_and(|last| is_word_char(last) || last == '?')
+            && token.chars().next().is_some_and(|first| is_word_char(first) || first == '?' || first == '"');
+        if needs_space {
+            output.push(' ');
+        }
+        output.push_str(token);
+    };
+
+    while index < chars.len() {
+        let c = chars[index];
+
+        if c.is_whitespace() {
+            index += 1;
+        } else if c == '-' && chars.get(index + 1) == Some(&'-') {
+            while index < chars.len() && chars[index] != '\n' {
+                index += 1;
+            }
+        } else if c == '/' && chars.get(index + 1) == Some(&'*') {
+            index += 2;
+            while index < chars.len() && !(chars[index] == '*' && chars.get(index + 1) == Some(&'/')) {
+                index += 1;
+            }
+            index += 2;
+        } else if c == '\'' || ((c == 'E' || c == 'e') && chars.get(index + 1) == Some(&'\'') && !previous_is_word(&chars, index)) {
+            let escapes = c != '\'';
+            index += if escapes { 2 } else { 1 };
+            while index < chars.len() {
+                // A backslash escape in E'' strings or a doubled quote both skip two characters.
+                if (escapes && chars[index] == '\\') || (chars[index] == '\'' && chars.get(index + 1) == Some(&'\'')) {
+                    index += 2;
+                } else if chars[index] == '\'' {
+                    index += 1;
+                    break;
+                } else {
+                    index += 1;
+                }
+            }
+            push_token(&mut output, "?");
+        } else if c == '$' && chars.get(index + 1).is_some_and(|next| next.is_ascii_digit()) {
+            index += 1;
+            while index < chars.len() && chars[index].is_ascii_digit() {
+                index += 1;
+            }
+            push_token(&mut output, "?");
+        } else if c == '$' {
+            // Dollar quoted string, $$...$$ or $tag$...$tag$.
+            let tag_end = chars[index + 1..].iter().position(|next| *next == '$').map(|end| index + 1 + end);
+            match tag_end {
+                Some(tag_end) if chars[index + 1..tag_end].iter().all(|next| is_word_char(*next)) => {
+                    let tag: String = chars[index..=tag_end].iter().collect();
+                    let body: String = chars[tag_end + 1..].iter().collect();
+                    let body_length = body.find(&tag).map(|end| body[..end].chars().count()).unwrap_or_else(|| chars.len() - tag_end - 1);
+                    index = (tag_end + 1 + body_length + tag.chars().count()).min(chars.len());
+                    push_token(&mut output, "?");
+                }
+                _ => {
+                    push_token(&mut output, "$");
+                    index += 1;
+                }
+            }
+        } else if c == '"' {
+            let start = index;
+            index += 1;
+            while index < chars.len() {
+                if chars[index] == '"' && chars.get(index + 1) == Some(&'"') {
+                    index += 2;
+                } else if chars[index] == '"' {
+                    index += 1;
+                    break;
+                } else {
+                    index += 1;
+                }
+            }
+            let identifier: String = chars[start..index].iter().collect();
+            push_token(&mut output, &identifier);
+        } else if c.is_ascii_digit() || (c == '.' && chars.get(index + 1).is_some_and(|next| next.is_ascii_digit())) {
+            if previous_is_word(&chars, index) {
+                let start = index;
+                while index < chars.len() && is_word_char(chars[index]) {
+                    index += 1;
+                }
+                let word: String = chars[start..index].iter().collect();
+                output.push_str(&word.to_lowercase());
+            } else {
+                while index < chars.len() && (chars[index].is_ascii_alphanumeric() || chars[index] == '.') {
+                    index += 1;
+                }
+                push_token(&mut output, "?");
+            }
+        } else if is_word_char(c) {
+            let start = index;
+            while index < chars.len() && is_word_char(chars[index]) {
+                index += 1;
+            }
+            let word: String = chars[start..index].iter().collect();
+            push_token(&mut output, &word.to_lowercase());
+        } else {
+            let mut symbol = [0; 4];
+            push_token(&mut output, c.encode_utf8(&mut symbol));
+            index += 1;
+        }
+    }
+
+    collapse_lists(&output)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn previous_is_word(chars: &[char], index: usize) -> bool {
+    index > 0 && is_word_char(chars[index - 1])
+}
+
+// "(?,?,?)" and "ARRAY[?,?]" become "(...)" and "[...]" so list lengths don't create new shapes.
+fn collapse_lists(normalized: &str) -> String {
+    let mut collapsed = normalized.to_owned();
+    for (open, close) in [("(", ")"), ("[", "]")].iter() {
+        let mut search_from = 0;
+        while let Some(start) = collapsed[search_from..].find(open).map(|start| search_from + start) {
+            let end = match collapsed[start..].find(close) {
+                Some(end) => start + end,
+                None => break
+            };
+            let inner = &collapsed[start + 1..end];
+            if inner.contains(',') && inner.split(',').all(|item| item == "?") {
+                collapsed.replace_range(start + 1..end, "...");
+            }
+            search_from = start + 1;
+        }
+    }
+    collapsed
+}
+
+// FNV-1a so the fingerprint is stable across processes and releases, unlike DefaultHasher.
+pub fn fingerprint(sql: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in normalize_query(sql).bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryShapeStats {
+    pub fingerprint: String,
+    pub normalized: String,
+    pub count: u64,
+    pub total_duration: Duration,
+    pub max_duration: Duration,
+    // Runs with exactly the same SQL text and bindings as an earlier run, i.e. wasted round trips.
+    pub duplicates: u64,
+}
+
+pub const DEFAULT_MAX_TRACKED_SHAPES: usize = 1_000;
+pub const DEFAULT_MAX_TRACKED_QUERIES: usize = 10_000;
+
+// Aggregates by shape, meant to be scoped to whatever unit of work duplicates matter for (a request, a job).
+// Both maps are capped so a long lived tracker can't grow without bound, see get_untracked_count.
+pub struct QueryShapeTracker {
+    shapes: Mutex<HashMap<String, QueryShapeStats>>,
+    exact: Mutex<HashMap<String, u64>>,
+    untracked: AtomicU64,
+    max_shapes: usize,
+    max_queries: usize,
+}
+
+impl Default for QueryShapeTracker {
+    fn default() -> Self {
+        QueryShapeTracker {
+            shapes: Mutex::new(HashMap::new()),
+            exact: Mutex::new(HashMap::new()),
+            untracked: AtomicU64::new(0),
+            max_shapes: DEFAULT_MAX_TRACKED_SHAPES,
+            max_queries: DEFAULT_MAX_TRACKED_QUERIES
+        }
+    }
+}
+
+impl QueryShapeTracker {
+    pub fn new() -> Self {
+        QueryShapeTracker::default()
+    }
+
+    // Once max_queries distinct SQL and bindings pairs are held new ones are no longer checked for duplicates,
+    // once max_shapes shapes are held runs of new shapes are only counted in get_untracked_count.
+    pub fn with_limits(mut self, max_shapes: usize, max_queries: usize) -> Self {
+        self.max_shapes = max_shapes;
+        self.max_queries = max_queries;
+        self
+    }
+
+    pub fn record(&self, sql: &str, bindings_key: &str, duration: Duration) {
+        let exact_key = format!("{}\u{0}{}", sql, bindings_key);
+        let is_duplicate = {
+            let mut exact = self.exact.lock().unwrap_or_else(PoisonError::into_inner);
+            match exact.get_mut(&exact_key) {
+                Some(runs) => {
+                    *runs += 1;
+                    true
+                }
+                None => {
+                    if exact.len() < self.max_queries {
+                        exact.insert(exact_key, 1);
+                    }
+                    false
+                }
+            }
+        };
+
+        let fingerprint = fingerprint(sql);
+        let mut shapes = self.shapes.lock().unwrap_or_else(PoisonError::into_inner);
+        if !shapes.contains_key(&fingerprint) && shapes.len() >= self.max_shapes {
+            self.untracked.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let stats = shapes.entry(fingerprint.to_owned()).or_insert_with(|| QueryShapeStats {
+            fingerprint,
+            normalized: normalize_query(sql),
+            count: 0,
+            total_duration: Duration::from_secs(0),
+            max_duration: Duration::from_secs(0),
+            duplicates: 0
+        });
+
+        stats.count += 1;
+        stats.total_duration += duration;
+        stats.max_duration = stats.max_duration.max(duration);
+        if is_duplicate {
+            stats.duplicates += 1;
+        }
+    }
+
+    // Most expensive shapes first.
+    pub fn get_stats(&self) -> Vec<QueryShapeStats> {
+        let shapes = self.shapes.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut stats: Vec<QueryShapeStats> = shapes.values().cloned().collect();
+        stats.sort_by(|a, b| b.total_duration.cmp(&a.total_duration).then_with(|| a.fingerprint.cmp(&b.fingerprint)));
+        stats
+    }
+
+    // Shapes that ran at least the given number of times, the usual sign of an N+1 loop.
+    pub fn find_repeated(&self, min_count: u64) -> Vec<QueryShapeStats> {
+        self.get_stats().into_iter().filter(|stats| stats.count >= min_count).collect()
+    }
+
+    pub fn find_duplicated(&self) -> Vec<QueryShapeStats> {
+        self.get_stats().into_iter().filter(|stats| stats.duplicates > 0).collect()
+    }
+
+    // Runs that weren't aggregated because max_shapes was reached.
+    pub fn get_untracked_count(&self) -> u64 {
+        self.untracked.load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.shapes.lock().unwrap_or_else(PoisonError::into_inner).clear();
+        self.exact.lock().unwrap_or_else(PoisonError::into_inner).clear();
+        self.untracked.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_string_literals() {
+        assert_eq!(normalize_query("SELECT * FROM t WHERE a = E'it\\'s' AND b = 'x'"), "select*from t where a=? and b=?");
+        assert_eq!(normalize_query("SELECT 'it''s', 1"), "select ?,?");
+    }
+
+    #[test]
+    fn replaces_dollar_quoted_bodies() {
+        assert_eq!(normalize_query("SELECT $$a 'b' $$ || $tag$x$$y$tag$"), "select ?||?");
+    }
+
+    #[test]
+    fn replaces_parameters_and_strips_comments() {
+        assert_eq!(normalize_query("SELECT * FROM t WHERE id = $1 -- c\n AND x = $12 /* hi */"), "select*from t where id=? and x=?");
+    }
+
+    #[test]
+    fn collapses_lists() {
+        assert_eq!(normalize_query("SELECT * FROM t WHERE id IN (1, 2, 3)"), "select*from t where id in(...)");
+        assert_eq!(normalize_query("SELECT * FROM t WHERE id IN ($1,$2)"), "select*from t where id in(...)");
+        assert_eq!(normalize_query("SELECT * FROM t WHERE id = ANY(ARRAY[1,2])"), "select*from t where id=any(array[...])");
+        assert_eq!(normalize_query("SELECT f(a, b)"), "select f(a,b)");
+    }
+
+    #[test]
+    fn keeps_identifiers_with_digits() {
+        assert_eq!(normalize_query("SELECT col1, t2.x FROM table2 t2"), "select col1,t2.x from table2 t2");
+    }
+
+    #[test]
+    fn fingerprint_is_stable() {
+        let fingerprint_value = fingerprint("SELECT * FROM t WHERE id = 1");
+        assert_eq!(fingerprint_value, "21b6c00e69fbb54f");
+        assert_eq!(fingerprint_value, fingerprint("select *\n  from t where id = $1"));
+        assert_ne!(fingerprint_value, fingerprint("SELECT * FROM u WHERE id = 1"));
+    }
+}
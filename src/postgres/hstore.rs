@@ -0,0 +1,136 @@
+use std::{collections::BTreeMap, convert::TryInto};
+use quaint::prelude::{Column, Comparable, ConditionTree, Expression};
+use sqlx::{Decode, Encode, Executor, Postgres, Type, encode::IsNull, error::BoxDynError, postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef}};
+use uuid::Uuid;
+use crate::postgres::{BurchillPostgresError, criteria::Criteria};
+use crate::strings::{quote_identifier, quote_qualified_identifier};
+
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Hstore(pub BTreeMap<String, Option<String>>);
+
+impl Hstore {
+    pub fn new() -> Self {
+        Hstore(BTreeMap::new())
+    }
+
+    // Input syntax of the hstore type. There is no cast from text so a bound string needs an explicit ::hstore.
+    pub fn to_text(&self) -> String {
+        let pairs: Vec<String> = self.0.iter()
+            .map(|(key, value)| match value {
+                Some(value) => format!("{}=>{}", quote_hstore_text(key), quote_hstore_text(value)),
+                None => format!("{}=>NULL", quote_hstore_text(key)),
+            })
+            .collect();
+        pairs.join(", ")
+    }
+}
+
+fn quote_hstore_text(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl From<BTreeMap<String, Option<String>>> for Hstore {
+    fn from(map: BTreeMap<String, Option<String>>) -> Self {
+        Hstore(map)
+    }
+}
+
+impl Type<Postgres> for Hstore {
+    fn type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("hstore")
+    }
+}
+
+impl Encode<'_, Postgres> for Hstore {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+        buf.extend_from_slice(&(self.0.len() as i32).to_be_bytes());
+        for (key, value) in self.0.iter() {
+            buf.extend_from_slice(&(key.len() as i32).to_be_bytes());
+            buf.extend_from_slice(key.as_bytes());
+            match value {
+                Some(value) => {
+                    buf.extend_from_slice(&(value.len() as i32).to_be_bytes());
+                    buf.extend_from_slice(value.as_bytes());
+                }
+                None => buf.extend_from_slice(&(-1i32).to_be_bytes()),
+            }
+        }
+        IsNull::No
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for Hstore {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        let mut bytes = <&[u8] as Decode<Postgres>>::decode(value)?;
+        let count = read_i32(&mut bytes)?;
+
+        let mut map = BTreeMap::new();
+        for _ in 0..count {
+            let key = read_text(&mut bytes)?.ok_or("hstore keys can not be null")?;
+            let value = read_text(&mut bytes)?;
+            map.insert(key, value);
+        }
+        Ok(Hstore(map))
+    }
+}
+
+fn read_i32(bytes: &mut &[u8]) -> Result<i32, BoxDynError> {
+    if bytes.len() < 4 {
+        return Err("unexpected end of hstore value".into());
+    }
+    let (head, tail) = bytes.split_at(4);
+    *bytes = tail;
+    Ok(i32::from_be_bytes(head.try_into()?))
+}
+
+fn read_text(bytes: &mut &[u8]) -> Result<Option<String>, BoxDynError> {
+    let length = read_i32(bytes)?;
+    if length < 0 {
+        return Ok(None);
+    }
+
+    let length = length as usize;
+    if bytes.len() < length {
+        return Err("unexpected end of hstore value".into());
+    }
+    let (head, tail) = bytes.split_at(length);
+    *bytes = tail;
+    Ok(Some(String::from_utf8(head.to_vec())?))
+}
+
+// quaint has no hstore value and would send it as text, so leave hstore columns out of the entity's insert
+// and update queries and write them with this after save, on the same connection. Bound as hstore by the Encode above.
+pub async fn update_hstore<'a, E>(executor: E, table: &str, id: &Uuid, column: &str, hstore: &Hstore) -> Result<(), BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let sql = format!("UPDATE {} SET {} = $2 WHERE id = $1", quote_qualified_identifier(table), quote_identifier(column));
+    let result = sqlx::query(&sql)
+        .bind(id)
+        .bind(hstore)
+        .execute(executor).await?;
+
+    match result.rows_affected() {
+        0 => Err(BurchillPostgresError::SqlxError(sqlx::Error::RowNotFound)),
+        _ => Ok(())
+    }
+}
+
+fn has_key(field: &str, key: &str) -> Expression<'static> {
+    Column::from(field.to_owned()).compare_raw("?", key.to_owned()).into()
+}
+
+impl Criteria {
+    pub fn hstore_has_key(self, field: &str, key: &str) -> Self {
+        self.and(has_key(field, key))
+    }
+
+    pub fn hstore_has_any_key(self, field: &str, keys: &[&str]) -> Self {
+        let conditions = keys.iter().map(|key| has_key(field, key)).collect();
+        self.and(ConditionTree::Or(conditions))
+    }
+
+    pub fn hstore_has_all_keys(self, field: &str, keys: &[&str]) -> Self {
+        let conditions = keys.iter().map(|key| has_key(field, key)).collect();
+        self.and(ConditionTree::And(conditions))
+    }
+}
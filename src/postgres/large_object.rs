@@ -0,0 +1,360 @@
+use std::{cmp::min, io, pin::Pin, sync::Arc, task::{Context, Poll}};
+use futures::{FutureExt, future::BoxFuture, io::{AsyncRead, AsyncWrite}, ready};
+use sqlx::{Executor, Pool, Postgres, Transaction};
+use uuid::{Uuid};
+use crate::postgres::BurchillPostgresError;
+use crate::strings::{quote_identifier, quote_qualified_identifier};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+// Dropped with the writer's transaction, temporary tables are per connection so writers don't collide.
+const CREATE_STAGING_TABLE: &str = "CREATE TEMPORARY TABLE bytea_writer_chunks (
+    sequence integer PRIMARY KEY,
+    data bytea NOT NULL
+) ON COMMIT DROP";
+const INSERT_STAGED_CHUNK: &str = "INSERT INTO bytea_writer_chunks (sequence, data) VALUES ($1, $2)";
+
+#[derive(Clone, Debug)]
+pub struct ByteaLocation {
+    pub table: String,
+    pub column: String,
+    pub id: Uuid,
+}
+
+impl ByteaLocation {
+    pub fn new(table: &str, column: &str, id: Uuid) -> Self {
+        ByteaLocation {
+            table: table.to_owned(),
+            column: column.to_owned(),
+            id
+        }
+    }
+}
+
+impl ByteaLocation {
+    fn create_length_query(&self) -> String {
+        format!("SELECT coalesce(octet_length({column}), 0)::bigint FROM {table} WHERE id = $1", column = self.get_column(), table = self.get_table())
+    }
+
+    fn create_read_query(&self) -> String {
+        format!("SELECT substring({column} FROM $2 FOR $3) FROM {table} WHERE id = $1", column = self.get_column(), table = self.get_table())
+    }
+
+    fn create_append_query(&self) -> String {
+        format!("UPDATE {table} SET {column} = coalesce({column}, ''::bytea) || $2 WHERE id = $1", column = self.get_column(), table = self.get_table())
+    }
+
+    fn create_truncate_query(&self) -> String {
+        format!("UPDATE {table} SET {column} = ''::bytea WHERE id = $1", column = self.get_column(), table = self.get_table())
+    }
+
+    // string_agg keeps the join to one pass over the staged chunks.
+    fn create_join_staged_query(&self) -> String {
+        format!(
+            "UPDATE {table} SET {column} = coalesce({column}, ''::bytea) || coalesce((SELECT string_agg(data, ''::bytea ORDER BY sequence) FROM bytea_writer_chunks), ''::bytea) WHERE id = $1",
+            column = self.get_column(),
+            table = self.get_table()
+        )
+    }
+
+    fn get_column(&self) -> String {
+        quote_identifier(&self.column)
+    }
+
+    fn get_table(&self) -> String {
+        quote_qualified_identifier(&self.table)
+    }
+
+    fn missing(&self) -> BurchillPostgresError {
+        BurchillPostgresError::EntityMissingValue {
+            table: self.table.to_owned(),
+            field: self.column.to_owned(),
+            id: Some(self.id)
+        }
+    }
+}
+
+pub async fn bytea_length<'a, E>(location: &ByteaLocation, executor: E) -> Result<i64, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let (length,): (i64,) = sqlx::query_as(location.create_length_query().as_str())
+        .bind(location.id)
+        .fetch_one(executor).await?;
+    Ok(length)
+}
+
+// Postgres substrings are 1 indexed so the offset is shifted here instead of at every call site.
+pub async fn read_bytea_chunk<'a, E>(location: &ByteaLocation, offset: i64, length: i64, executor: E) -> Result<Vec<u8>, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let (chunk,): (Option<Vec<u8>>,) = sqlx::query_as(location.create_read_query().as_str())
+        .bind(location.id)
+        .bind(offset + 1)
+        .bind(length)
+        .fetch_one(executor).await?;
+    Ok(chunk.unwrap_or_default())
+}
+
+// Postgres rewrites the whole value on every append, use ByteaWriter for anything written in many chunks.
+pub async fn append_bytea_chunk<'a, E>(location: &ByteaLocation, chunk: Vec<u8>, executor: E) -> Result<(), BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let result = sqlx::query(location.create_append_query().as_str())
+        .bind(location.id)
+        .bind(chunk)
+        .execute(executor).await?;
+
+    if result.rows_affected() == 0 {
+        return Err(location.missing());
+    }
+    Ok(())
+}
+
+pub async fn truncate_bytea<'a, E>(location: &ByteaLocation, executor: E) -> Result<(), BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    sqlx::query(location.create_truncate_query().as_str())
+        .bind(location.id)
+        .execute(executor).await?;
+    Ok(())
+}
+
+fn to_io_error(err: BurchillPostgresError) -> io::Error {
+    io::Error::other(err)
+}
+
+pub struct ByteaReader {
+    pool: Pool<Postgres>,
+    location: Arc<ByteaLocation>,
+    chunk_size: usize,
+    offset: i64,
+    buffer: Vec<u8>,
+    position: usize,
+    finished: bool,
+    pending: Option<BoxFuture<'static, Result<Vec<u8>, BurchillPostgresError>>>
+}
+
+impl ByteaReader {
+    pub fn new(pool: Pool<Postgres>, location: ByteaLocation) -> Self {
+        ByteaReader {
+            pool,
+            location: Arc::new(location),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            offset: 0,
+            buffer: Vec::new(),
+            position: 0,
+            finished: false,
+            pending: None
+        }
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+}
+
+impl AsyncRead for ByteaReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.position < this.buffer.len() {
+                let count = min(buf.len(), this.buffer.len() - this.position);
+                buf[..count].copy_from_slice(&this.buffer[this.position..this.position + count]);
+                this.position += count;
+                return Poll::Ready(Ok(count));
+            }
+
+            if this.finished {
+                return Poll::Ready(Ok(0));
+            }
+
+            if this.pending.is_none() {
+                let pool = this.pool.clone();
+                let location = this.location.clone();
+                let offset = this.offset;
+                let length = this.chunk_size as i64;
+                this.pending = Some(async move {
+                    read_bytea_chunk(&location, offset, length, &pool).await
+                }.boxed());
+            }
+
+            let chunk = ready!(this.pending.as_mut().unwrap().poll_unpin(cx));
+            this.pending = None;
+
+            let chunk = chunk.map_err(to_io_error)?;
+            if chunk.len() < this.chunk_size {
+                this.finished = true;
+            }
+            this.offset += chunk.len() as i64;
+            this.buffer = chunk;
+            this.position = 0;
+        }
+    }
+}
+
+type PendingWrite = BoxFuture<'static, Result<Option<Transaction<'static, Postgres>>, BurchillPostgresError>>;
+
+// Writes are appended to the existing value, call truncate_bytea first to replace it.
+// Chunks are staged in a temporary table inside one transaction and joined onto the column on close,
+// so nothing is visible until close succeeds and dropping the writer before then writes nothing.
+pub struct ByteaWriter {
+    pool: Pool<Postgres>,
+    location: Arc<ByteaLocation>,
+    chunk_size: usize,
+    buffer: Vec<u8>,
+    sequence: i32,
+    transaction: Option<Transaction<'static, Postgres>>,
+    failed: bool,
+    closed: bool,
+    pending: Option<PendingWrite>
+}
+
+impl ByteaWriter {
+    pub fn new(pool: Pool<Postgres>, location: ByteaLocation) -> Self {
+        ByteaWriter {
+            pool,
+            location: Arc::new(location),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            buffer: Vec::new(),
+            sequence: 0,
+            transaction: None,
+            failed: false,
+            closed: false,
+            pending: None
+        }
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    fn start_flush(&mut self) {
+        if self.pending.is_some() || self.buffer.is_empty() {
+            return;
+        }
+
+        let chunk = std::mem::take(&mut self.buffer);
+        let sequence = self.sequence;
+        self.sequence += 1;
+        let transaction = self.transaction.take();
+        let pool = self.pool.clone();
+        self.pending = Some(async move {
+            let mut transaction = match transaction {
+                Some(transaction) => transaction,
+                None => {
+                    let mut transaction = pool.begin().await?;
+                    (&mut *transaction).execute(CREATE_STAGING_TABLE).await?;
+                    transaction
+                }
+            };
+
+            sqlx::query(INSERT_STAGED_CHUNK)
+                .bind(sequence)
+                .bind(chunk)
+                .execute(&mut *transaction).await?;
+            Ok(Some(transaction))
+        }.boxed());
+    }
+
+    fn start_close(&mut self) {
+        if self.pending.is_some() || self.closed {
+            return;
+        }
+
+        self.closed = true;
+        let mut transaction = match self.transaction.take() {
+            Some(transaction) => transaction,
+            None => return
+        };
+        let location = self.location.clone();
+        self.pending = Some(async move {
+            let result = sqlx::query(location.create_join_staged_query().as_str())
+                .bind(location.id)
+                .execute(&mut *transaction).await?;
+            if result.rows_affected() == 0 {
+                return Err(location.missing());
+            }
+
+            transaction.commit().await?;
+            Ok(None)
+        }.boxed());
+    }
+
+    // A failed chunk rolls the transaction back, so every later call fails too rather than writing part of the value.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(pending) = self.pending.as_mut() {
+            let result = ready!(pending.poll_unpin(cx));
+            self.pending = None;
+            match result {
+                Ok(transaction) => self.transaction = transaction,
+                Err(err) => {
+                    self.failed = true;
+                    return Poll::Ready(Err(to_io_error(err)));
+                }
+            }
+        }
+
+        if self.failed {
+            return Poll::Ready(Err(io::Error::other("an earlier chunk failed and the write was rolled back")));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ByteaWriter {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        if this.closed {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "the writer is closed")));
+        }
+
+        if this.buffer.len() >= this.chunk_size {
+            this.start_flush();
+            ready!(this.poll_pending(cx))?;
+        }
+
+        let count = min(buf.len(), this.chunk_size - this.buffer.len());
+        this.buffer.extend_from_slice(&buf[..count]);
+        Poll::Ready(Ok(count))
+    }
+
+    // Only stages the buffered chunk, the value itself changes on close.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        this.start_flush();
+        this.poll_pending(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        this.start_flush();
+        ready!(this.poll_pending(cx))?;
+        this.start_close();
+        this.poll_pending(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queries_quote_the_schema_and_table_separately() {
+        let location = ByteaLocation::new("files.blobs", "data", Uuid::nil());
+        assert_eq!(location.create_length_query(), "SELECT coalesce(octet_length(\"data\"), 0)::bigint FROM \"files\".\"blobs\" WHERE id = $1");
+        assert_eq!(location.create_read_query(), "SELECT substring(\"data\" FROM $2 FOR $3) FROM \"files\".\"blobs\" WHERE id = $1");
+        assert_eq!(location.create_append_query(), "UPDATE \"files\".\"blobs\" SET \"data\" = coalesce(\"data\", ''::bytea) || $2 WHERE id = $1");
+        assert_eq!(location.create_truncate_query(), "UPDATE \"files\".\"blobs\" SET \"data\" = ''::bytea WHERE id = $1");
+    }
+
+    #[test]
+    fn join_staged_query_appends_the_chunks_in_order() {
+        let location = ByteaLocation::new("blobs", "da\"ta", Uuid::nil());
+        assert_eq!(
+            location.create_join_staged_query(),
+            "UPDATE \"blobs\" SET \"da\"\"ta\" = coalesce(\"da\"\"ta\", ''::bytea) || coalesce((SELECT string_agg(data, ''::bytea ORDER BY sequence) FROM bytea_writer_chunks), ''::bytea) WHERE id = $1"
+        );
+    }
+}
@@ -0,0 +1,426 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{Executor, Pool, Postgres};
+use uuid::{Uuid};
+use crate::postgres::{BurchillPostgresError, delete_policy::{DeletePolicy, hard_delete_row}};
+use crate::strings::{quote_identifier, quote_literal, quote_qualified_identifier};
+
+pub const DEFAULT_BATCH_SIZE: i64 = 1000;
+
+pub const CREATE_LIFECYCLE_LOG_TABLE: &str = "CREATE TABLE IF NOT EXISTS lifecycle_log (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    table_name text NOT NULL,
+    action text NOT NULL,
+    cutoff_time timestamptz NOT NULL,
+    affected_rows bigint NOT NULL,
+    dry_run boolean NOT NULL,
+    created_time timestamptz NOT NULL DEFAULT now(),
+    created_by uuid NOT NULL
+);
+CREATE INDEX IF NOT EXISTS lifecycle_log_table_time ON lifecycle_log (table_name, created_time DESC)";
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AnonymizedValue {
+    Null,
+    Text(String),
+}
+
+impl AnonymizedValue {
+    fn to_sql(&self) -> String {
+        match self {
+            AnonymizedValue::Null => String::from("NULL"),
+            AnonymizedValue::Text(text) => quote_literal(text),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LifecycleAction {
+    // Moves rows into a table with the same columns.
+    Archive(String),
+    Anonymize(Vec<(String, AnonymizedValue)>),
+    Deactivate,
+    Purge,
+}
+
+impl LifecycleAction {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            LifecycleAction::Archive(_) => "archive",
+            LifecycleAction::Anonymize(_) => "anonymize",
+            LifecycleAction::Deactivate => "deactivate",
+            LifecycleAction::Purge => "purge",
+        }
+    }
+
+    pub fn is_hard_delete(&self) -> bool {
+        matches!(self, LifecycleAction::Archive(_) | LifecycleAction::Purge)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LifecycleRule {
+    pub action: LifecycleAction,
+    pub age: Duration,
+}
+
+// Everything the app keeps about one table's data lifetime in one place. Rules run in the order
+// they were added, so e.g. anonymize after 90 days then purge after 2 years.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LifecyclePolicy {
+    pub table: String,
+    pub time_column: String,
+    // Extra raw SQL condition, e.g. "status = 'closed'", trusted as written by the app.
+    pub where_sql: Option<String>,
+    pub rules: Vec<LifecycleRule>,
+    // Archive and purge are hard deletes, they only run on tables declared HardAllowed. Approvals are
+    // per row, so a scheduled job can't satisfy HardWithApproval either.
+    pub delete_policy: DeletePolicy,
+    // Should match is_tombstone_enabled of the table's entity.
+    pub tombstone: bool,
+}
+
+impl LifecyclePolicy {
+    pub fn new(table: &str) -> Self {
+        LifecyclePolicy {
+            table: table.to_owned(),
+            time_column: String::from("created_time"),
+            where_sql: None,
+            rules: Vec::new(),
+            delete_policy: DeletePolicy::SoftOnly,
+            tombstone: false
+        }
+    }
+
+    // Should match the delete policy of the table's entity.
+    pub fn delete_policy(mut self, delete_policy: DeletePolicy) -> Self {
+        self.delete_policy = delete_policy;
+        self
+    }
+
+    pub fn tombstone(mut self, tombstone: bool) -> Self {
+        self.tombstone = tombstone;
+        self
+    }
+
+    pub fn time_column(mut self, time_column: &str) -> Self {
+        self.time_column = time_column.to_owned();
+        self
+    }
+
+    pub fn where_sql(mut self, where_sql: &str) -> Self {
+        self.where_sql = Some(where_sql.to_owned());
+        self
+    }
+
+    pub fn archive_after(mut self, age: Duration, archive_table: &str) -> Self {
+        self.rules.push(LifecycleRule { action: LifecycleAction::Archive(archive_table.to_owned()), age });
+        self
+    }
+
+    pub fn anonymize_after(mut self, age: Duration, columns: Vec<(&str, AnonymizedValue)>) -> Result<Self, BurchillPostgresError> {
+        let columns = columns.into_iter().map(|(column, value)| (column.to_owned(), value)).collect();
+        let rule = LifecycleRule { action: LifecycleAction::Anonymize(columns), age };
+        self.check_rule(&rule)?;
+        self.rules.push(rule);
+        Ok(self)
+    }
+
+    pub fn deactivate_after(mut self, age: Duration) -> Self {
+        self.rules.push(LifecycleRule { action: LifecycleAction::Deactivate, age });
+        self
+    }
+
+    pub fn purge_after(mut self, age: Duration) -> Self {
+        self.rules.push(LifecycleRule { action: LifecycleAction::Purge, age });
+        self
+    }
+
+    // Rules pushed onto the public rules field skip the builders, run checks them again.
+    fn check_rule(&self, rule: &LifecycleRule) -> Result<(), BurchillPostgresError> {
+        match &rule.action {
+            LifecycleAction::Anonymize(columns) if columns.is_empty() => Err(BurchillPostgresError::InvalidLifecycleRule {
+                table: self.table.to_owned(),
+                reason: String::from("anonymize needs at least one column")
+            }),
+            _ => Ok(())
+        }
+    }
+
+    fn check_delete_policy(&self, rule: &LifecycleRule) -> Result<(), BurchillPostgresError> {
+        if !rule.action.is_hard_delete() || self.delete_policy == DeletePolicy::HardAllowed {
+            return Ok(());
+        }
+
+        let reason = format!("the lifecycle rule {} is a hard delete", rule.action.get_name());
+        tracing::warn!(table = %self.table, policy = %self.delete_policy, reason = %reason, "refused lifecycle rule");
+        Err(BurchillPostgresError::DeletePolicyViolation {
+            table: self.table.to_owned(),
+            id: None,
+            policy: self.delete_policy,
+            reason
+        })
+    }
+
+    // Selects one batch of ids due for the rule, skipping rows the app currently has locked.
+    fn create_due_query(&self, rule: &LifecycleRule) -> String {
+        let mut conditions = vec![format!("{} < $1", quote_identifier(&self.time_column))];
+        if let Some(where_sql) = &self.where_sql {
+            conditions.push(format!("({})", where_sql));
+        }
+        match &rule.action {
+            LifecycleAction::Deactivate => conditions.push(String::from("active")),
+            LifecycleAction::Anonymize(columns) => {
+                let changed: Vec<String> = columns.iter()
+                    .map(|(column, value)| format!("{} IS DISTINCT FROM {}", quote_identifier(column), value.to_sql()))
+                    .collect();
+                conditions.push(format!("({})", changed.join(" OR ")));
+            }
+            _ => {}
+        }
+
+        format!(
+            "SELECT id FROM {} WHERE {} LIMIT $2 FOR UPDATE SKIP LOCKED",
+            quote_qualified_identifier(&self.table),
+            conditions.join(" AND ")
+        )
+    }
+
+    // Archive and purge delete row by row through hard_delete_row instead, see apply_hard_delete_rule.
+    fn create_update_query(&self, rule: &LifecycleRule) -> Option<String> {
+        let table = quote_qualified_identifier(&self.table);
+        let due = self.create_due_query(rule);
+        match &rule.action {
+            LifecycleAction::Anonymize(columns) => {
+                let assignments: Vec<String> = columns.iter()
+                    .map(|(column, value)| format!("{} = {}", quote_identifier(column), value.to_sql()))
+                    .collect();
+                Some(format!(
+                    "UPDATE {} SET {}, last_updated_time = now(), last_updated_by = $3 WHERE id IN ({})",
+                    table, assignments.join(", "), due
+                ))
+            }
+            LifecycleAction::Deactivate => Some(format!(
+                "UPDATE {} SET active = false, last_updated_time = now(), last_updated_by = $3 WHERE id IN ({})",
+                table, due
+            )),
+            LifecycleAction::Archive(_) | LifecycleAction::Purge => None
+        }
+    }
+
+    fn create_archive_query(&self, archive_table: &str) -> String {
+        format!(
+            "INSERT INTO {} SELECT * FROM {} WHERE id = ANY($1)",
+            quote_qualified_identifier(archive_table),
+            quote_qualified_identifier(&self.table)
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LifecycleActionReport {
+    pub table: String,
+    pub action: &'static str,
+    pub cutoff_time: DateTime<Utc>,
+    pub affected_rows: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LifecycleReport {
+    pub actions: Vec<LifecycleActionReport>,
+    pub dry_run: bool,
+}
+
+impl LifecycleReport {
+    pub fn get_total_affected(&self) -> u64 {
+        self.actions.iter().map(|action| action.affected_rows).sum()
+    }
+}
+
+// The one scheduled job that applies every registered policy, call run() from whatever scheduler the app has.
+pub struct LifecycleRegistry {
+    policies: Vec<LifecyclePolicy>,
+    batch_size: i64,
+    dry_run: bool,
+}
+
+impl LifecycleRegistry {
+    pub fn new() -> Self {
+        LifecycleRegistry {
+            policies: Vec::new(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            dry_run: false
+        }
+    }
+
+    pub fn register(mut self, policy: LifecyclePolicy) -> Self {
+        self.policies.push(policy);
+        self
+    }
+
+    pub fn with_batch_size(mut self, batch_size: i64) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    // Counts what would be affected without changing anything.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn get_policies(&self) -> &[LifecyclePolicy] {
+        &self.policies
+    }
+
+    pub fn get_policy(&self, table: &str) -> Option<&LifecyclePolicy> {
+        self.policies.iter().find(|policy| policy.table == table)
+    }
+
+    // Each batch commits on its own along with its log row, so a long purge doesn't hold locks
+    // for its whole run and an interrupted run just picks up where it stopped.
+    pub async fn run(&self, user_id: &Uuid, pool: &Pool<Postgres>) -> Result<LifecycleReport, BurchillPostgresError> {
+        let mut report = LifecycleReport {
+            actions: Vec::new(),
+            dry_run: self.dry_run
+        };
+
+        for policy in &self.policies {
+            for rule in &policy.rules {
+                policy.check_rule(rule)?;
+                policy.check_delete_policy(rule)?;
+                let cutoff_time = Utc::now() - rule.age;
+                let affected_rows = if self.dry_run {
+                    self.count_due(policy, rule, &cutoff_time, pool).await?
+                } else {
+                    self.apply_rule(policy, rule, &cutoff_time, user_id, pool).await?
+                };
+
+                if self.dry_run {
+                    log_action(policy, rule, &cutoff_time, affected_rows, true, user_id, pool).await?;
+                }
+                tracing::info!(table = %policy.table, action = rule.action.get_name(), affected_rows, dry_run = self.dry_run, "applied lifecycle rule");
+                report.actions.push(LifecycleActionReport {
+                    table: policy.table.to_owned(),
+                    action: rule.action.get_name(),
+                    cutoff_time,
+                    affected_rows
+                });
+            }
+        }
+        Ok(report)
+    }
+
+    async fn count_due(&self, policy: &LifecyclePolicy, rule: &LifecycleRule, cutoff_time: &DateTime<Utc>, pool: &Pool<Postgres>) -> Result<u64, BurchillPostgresError> {
+        // FOR UPDATE isn't allowed under an aggregate, the count only needs the conditions.
+        let due = policy.create_due_query(rule).replace(" LIMIT $2 FOR UPDATE SKIP LOCKED", "");
+        let (count,): (i64,) = sqlx::query_as(&format!("SELECT count(*) FROM ({}) AS due", due))
+            .bind(cutoff_time)
+            .fetch_one(pool).await?;
+        Ok(count as u64)
+    }
+
+    async fn apply_rule(&self, policy: &LifecyclePolicy, rule: &LifecycleRule, cutoff_time: &DateTime<Utc>, user_id: &Uuid, pool: &Pool<Postgres>) -> Result<u64, BurchillPostgresError> {
+        let sql = match policy.create_update_query(rule) {
+            Some(sql) => sql,
+            None => return self.apply_hard_delete_rule(policy, rule, cutoff_time, user_id, pool).await
+        };
+        let mut total = 0;
+
+        loop {
+            let mut transaction = pool.begin().await?;
+            let affected = sqlx::query(&sql)
+                .bind(cutoff_time)
+                .bind(self.batch_size)
+                .bind(user_id)
+                .execute(&mut transaction).await?.rows_affected();
+            if affected > 0 {
+                log_action(policy, rule, cutoff_time, affected, false, user_id, &mut transaction).await?;
+            }
+            transaction.commit().await?;
+
+            total += affected;
+            if affected < self.batch_size as u64 {
+                return Ok(total);
+            }
+        }
+    }
+
+    // Deleted through hard_delete_row one row at a time, so lifecycle deletes leave the same
+    // tombstones and delete_audit rows as deletes made through the entity.
+    async fn apply_hard_delete_rule(&self, policy: &LifecyclePolicy, rule: &LifecycleRule, cutoff_time: &DateTime<Utc>, user_id: &Uuid, pool: &Pool<Postgres>) -> Result<u64, BurchillPostgresError> {
+        let due = policy.create_due_query(rule);
+        let mut total = 0;
+
+        loop {
+            let mut transaction = pool.begin().await?;
+            let ids: Vec<(Uuid,)> = sqlx::query_as(&due)
+                .bind(cutoff_time)
+                .bind(self.batch_size)
+                .fetch_all(&mut transaction).await?;
+            let ids: Vec<Uuid> = ids.into_iter().map(|(id,)| id).collect();
+
+            match &rule.action {
+                LifecycleAction::Archive(archive_table) if !ids.is_empty() => {
+                    sqlx::query(&policy.create_archive_query(archive_table))
+                        .bind(ids.to_vec())
+                        .execute(&mut transaction).await?;
+                }
+                _ => {}
+            }
+            for id in ids.iter() {
+                hard_delete_row(&mut *transaction, &policy.table, id, policy.delete_policy, policy.tombstone, user_id, None).await?;
+            }
+
+            let affected = ids.len() as u64;
+            if affected > 0 {
+                log_action(policy, rule, cutoff_time, affected, false, user_id, &mut transaction).await?;
+            }
+            transaction.commit().await?;
+
+            total += affected;
+            if affected < self.batch_size as u64 {
+                return Ok(total);
+            }
+        }
+    }
+}
+
+impl Default for LifecycleRegistry {
+    fn default() -> Self {
+        LifecycleRegistry::new()
+    }
+}
+
+async fn log_action<'a, E>(policy: &LifecyclePolicy, rule: &LifecycleRule, cutoff_time: &DateTime<Utc>, affected_rows: u64, dry_run: bool, user_id: &Uuid, executor: E) -> Result<(), BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    sqlx::query(
+        "INSERT INTO lifecycle_log (table_name, action, cutoff_time, affected_rows, dry_run, created_by) VALUES ($1, $2, $3, $4, $5, $6)"
+    )
+        .bind(&policy.table)
+        .bind(rule.action.get_name())
+        .bind(cutoff_time)
+        .bind(affected_rows as i64)
+        .bind(dry_run)
+        .bind(user_id)
+        .execute(executor).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anonymize_needs_columns() {
+        let result = LifecyclePolicy::new("orders").anonymize_after(Duration::days(90), Vec::new());
+        assert!(matches!(result, Err(BurchillPostgresError::InvalidLifecycleRule { table, .. }) if table == "orders"));
+
+        let policy = LifecyclePolicy::new("orders").anonymize_after(Duration::days(90), vec![("email", AnonymizedValue::Null)]).unwrap();
+        assert_eq!(
+            policy.create_update_query(&policy.rules[0]).unwrap(),
+            "UPDATE \"orders\" SET \"email\" = NULL, last_updated_time = now(), last_updated_by = $3 \
+            WHERE id IN (SELECT id FROM \"orders\" WHERE \"created_time\" < $1 AND (\"email\" IS DISTINCT FROM NULL) LIMIT $2 FOR UPDATE SKIP LOCKED)"
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use std::sync::{Arc, RwLock};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres, postgres::PgListener};
+use uuid::{Uuid};
+use crate::postgres::{BurchillPostgresError, db_config::{DB_CONFIG_CHANNEL, get_config_value, set_config_value}};
+
+pub const MAINTENANCE_CONFIG_KEY: &str = "maintenance_mode";
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenanceLevel {
+    Off,
+    ReadOnly,
+    Full,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MaintenanceState {
+    pub level: MaintenanceLevel,
+    pub message: Option<String>,
+}
+
+impl Default for MaintenanceState {
+    fn default() -> Self {
+        MaintenanceState {
+            level: MaintenanceLevel::Off,
+            message: None
+        }
+    }
+}
+
+// Shared handle to the current maintenance state, kept up to date by listen().
+#[derive(Clone, Default)]
+pub struct MaintenanceMode {
+    state: Arc<RwLock<MaintenanceState>>
+}
+
+impl MaintenanceMode {
+    pub async fn load(pool: &Pool<Postgres>) -> Result<Self, BurchillPostgresError> {
+        let mode = MaintenanceMode::default();
+        mode.refresh(pool).await?;
+        Ok(mode)
+    }
+
+    pub fn get_state(&self) -> MaintenanceState {
+        match self.state.read() {
+            Ok(state) => state.clone(),
+            Err(poisoned) => poisoned.into_inner().clone()
+        }
+    }
+
+    fn set_state(&self, state: MaintenanceState) {
+        match self.state.write() {
+            Ok(mut current) => *current = state,
+            Err(poisoned) => *poisoned.into_inner() = state
+        }
+    }
+
+    pub async fn refresh(&self, pool: &Pool<Postgres>) -> Result<(), BurchillPostgresError> {
+        let state: Option<MaintenanceState> = get_config_value(MAINTENANCE_CONFIG_KEY, pool).await?;
+        self.set_state(state.unwrap_or_default());
+        Ok(())
+    }
+
+    pub async fn update(&self, pool: &Pool<Postgres>, state: MaintenanceState, user_id: &Uuid) -> Result<(), BurchillPostgresError> {
+        set_config_value(MAINTENANCE_CONFIG_KEY, &state, user_id, pool).await?;
+        self.set_state(state);
+        Ok(())
+    }
+
+    // Runs until the connection fails, spawn it on whatever runtime the service uses.
+    // The state is reloaded after every reconnect in case a notification was missed.
+    pub async fn listen(&self, pool: &Pool<Postgres>) -> Result<(), BurchillPostgresError> {
+        let mut listener = PgListener::connect_with(pool).await?;
+        listener.listen(DB_CONFIG_CHANNEL).await?;
+        self.refresh(pool).await?;
+
+        loop {
+            match listener.try_recv().await? {
+                Some(notification) if notification.payload() == MAINTENANCE_CONFIG_KEY => self.refresh(pool).await?,
+                Some(_) => {}
+                None => self.refresh(pool).await?,
+            }
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use quaint::Value;
+use sqlx::{Postgres, postgres::PgConnection};
+use uuid::{Uuid};
+use crate::postgres::{BurchillPostgresError, create_sqlx_arguments};
+use crate::strings::{quote_identifier, quote_qualified_identifier};
+
+pub const DEFAULT_MERGE_CHUNK_SIZE: usize = 500;
+
+// Postgres allows 65535 bind parameters per statement, chunks are shrunk to stay under it.
+const MAX_BIND_PARAMETERS: usize = 65000;
+
+
+// Entities that can be bulk upserted. Values are the entity's own columns, the audit columns are handled by merge_many.
+pub trait Mergeable {
+    fn get_table_name(&self) -> &'static str;
+
+    fn get_merge_values(&self) -> Vec<(&'static str, Value<'static>)>;
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ConflictPolicy {
+    // Incoming values replace the existing row.
+    #[default]
+    Overwrite,
+    // Existing rows are left alone, only new keys are inserted.
+    KeepExisting,
+    // Incoming values only replace the existing row when the given (merged) column is newer.
+    NewerWins(String),
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MergeResult {
+    pub inserted: u64,
+    // Existing rows that had different values.
+    pub updated: u64,
+    // Existing rows that already matched (or were kept by the conflict policy), they aren't written so last_updated_* stays put.
+    pub unchanged: u64,
+}
+
+impl MergeResult {
+    pub fn add(&mut self, other: MergeResult) {
+        self.inserted += other.inserted;
+        self.updated += other.updated;
+        self.unchanged += other.unchanged;
+    }
+}
+
+// Inserts or updates on the conflict key in chunks, each chunk is its own statement so pass a
+// transaction's connection (&mut *transaction) to make the whole merge atomic. Keys must be
+// unique within the entities, postgres refuses to update the same row twice in one statement.
+pub async fn merge_many<M>(entities: &[M], conflict_key: &[&str], user_id: &Uuid, connection: &mut PgConnection) -> Result<MergeResult, BurchillPostgresError>
+where M: Mergeable {
+    merge_many_with_policy(entities, conflict_key, &ConflictPolicy::Overwrite, user_id, connection).await
+}
+
+pub async fn merge_many_with_policy<M>(entities: &[M], conflict_key: &[&str], policy: &ConflictPolicy, user_id: &Uuid, connection: &mut PgConnection) -> Result<MergeResult, BurchillPostgresError>
+where M: Mergeable {
+    merge_many_in_chunks(entities, conflict_key, policy, user_id, DEFAULT_MERGE_CHUNK_SIZE, connection).await
+}
+
+pub async fn merge_many_in_chunks<M>(entities: &[M], conflict_key: &[&str], policy: &ConflictPolicy, user_id: &Uuid, chunk_size: usize, connection: &mut PgConnection) -> Result<MergeResult, BurchillPostgresError>
+where M: Mergeable {
+    let mut result = MergeResult::default();
+    let first = match entities.first() {
+        Some(first) => first,
+        None => return Ok(result)
+    };
+
+    let chunk_size = get_chunk_size(chunk_size, first.get_merge_values().len());
+    for chunk in entities.chunks(chunk_size) {
+        result.add(merge_chunk(chunk, conflict_key, policy, user_id, connection).await?);
+    }
+    Ok(result)
+}
+
+fn get_chunk_size(chunk_size: usize, column_count: usize) -> usize {
+    chunk_size.max(1).min(MAX_BIND_PARAMETERS / column_count.max(1))
+}
+
+// The target is aliased so the conflict clause doesn't have to repeat a schema qualified name.
+fn create_merge_query(table: &str, columns: &[&str], conflict_key: &[&str], policy: &ConflictPolicy, row_count: usize) -> String {
+    let quoted_table = quote_qualified_identifier(table);
+    let quoted_columns: Vec<String> = columns.iter().map(|column| quote_identifier(column)).collect();
+    let quoted_key: Vec<String> = conflict_key.iter().map(|column| quote_identifier(column)).collect();
+
+    // $1 is the user id, the entity values follow row by row.
+    let rows: Vec<String> = (0..row_count)
+        .map(|row| {
+            let placeholders: Vec<String> = (0..columns.len()).map(|column| format!("${}", 2 + row * columns.len() + column)).collect();
+            format!("({}, $1)", placeholders.join(", "))
+        })
+        .collect();
+
+    let updated_columns: Vec<&String> = quoted_columns.iter().filter(|column| !quoted_key.contains(column)).collect();
+    let assignments: Vec<String> = updated_columns.iter().map(|column| format!("{column} = excluded.{column}", column = column)).collect();
+    let current: Vec<String> = updated_columns.iter().map(|column| format!("target.{}", column)).collect();
+    let excluded: Vec<String> = updated_columns.iter().map(|column| format!("excluded.{}", column)).collect();
+
+    let newer_condition = match policy {
+        ConflictPolicy::NewerWins(column) => format!(
+            " AND excluded.{column} > target.{column}",
+            column = quote_identifier(column)
+        ),
+        _ => String::new()
+    };
+
+    let on_conflict = if updated_columns.is_empty() || *policy == ConflictPolicy::KeepExisting {
+        String::from("DO NOTHING")
+    } else {
+        format!(
+            "DO UPDATE SET {assignments}, \"last_updated_time\" = now(), \"last_updated_by\" = $1 WHERE ({current}) IS DISTINCT FROM ({excluded}){newer_condition}",
+            assignments = assignments.join(", "),
+            current = current.join(", "),
+            excluded = excluded.join(", "),
+            newer_condition = newer_condition
+        )
+    };
+
+    // xmax is 0 for freshly inserted row versions, which tells inserts and updates apart.
+    format!(
+        "INSERT INTO {table} AS target ({columns}, \"created_by\") VALUES {rows} ON CONFLICT ({key}) {on_conflict} RETURNING (xmax = 0) AS inserted",
+        table = quoted_table,
+        columns = quoted_columns.join(", "),
+        rows = rows.join(", "),
+        key = quoted_key.join(", "),
+        on_conflict = on_conflict
+    )
+}
+
+async fn merge_chunk<M>(entities: &[M], conflict_key: &[&str], policy: &ConflictPolicy, user_id: &Uuid, connection: &mut PgConnection) -> Result<MergeResult, BurchillPostgresError>
+where M: Mergeable {
+    let table = entities[0].get_table_name();
+    let first_values = entities[0].get_merge_values();
+    let columns: Vec<&'static str> = first_values.iter().map(|(column, _)| *column).collect();
+
+    let mut bindings: Vec<Value<'static>> = vec![Value::uuid(user_id.to_owned())];
+    for entity in entities.iter() {
+        let values = entity.get_merge_values();
+        let entity_columns: Vec<&'static str> = values.iter().map(|(column, _)| *column).collect();
+        if entity.get_table_name() != table || entity_columns != columns {
+            return Err(BurchillPostgresError::MismatchedMergeColumns {
+                table: entity.get_table_name().to_owned(),
+                expected: columns.iter().map(|column| (*column).to_owned()).collect(),
+                found: entity_columns.iter().map(|column| (*column).to_owned()).collect()
+            });
+        }
+        bindings.extend(values.into_iter().map(|(_, value)| value));
+    }
+
+    let query = create_merge_query(table, &columns, conflict_key, policy, entities.len());
+    let arguments = create_sqlx_arguments(bindings)?;
+    let rows: Vec<(bool,)> = sqlx::query_as_with::<Postgres, (bool,), _>(query.as_str(), arguments)
+        .fetch_all(&mut *connection).await?;
+
+    let inserted = rows.iter().filter(|(inserted,)| *inserted).count() as u64;
+    let updated = rows.len() as u64 - inserted;
+    Ok(MergeResult {
+        inserted,
+        updated,
+        unchanged: entities.len() as u64 - inserted - updated
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overwrite_updates_changed_rows() {
+        assert_eq!(
+            create_merge_query("sales.prices", &["sku", "price"], &["sku"], &ConflictPolicy::Overwrite, 2),
+            "INSERT INTO \"sales\".\"prices\" AS target (\"sku\", \"price\", \"created_by\") VALUES ($2, $3, $1), ($4, $5, $1) \
+            ON CONFLICT (\"sku\") DO UPDATE SET \"price\" = excluded.\"price\", \"last_updated_time\" = now(), \"last_updated_by\" = $1 \
+            WHERE (target.\"price\") IS DISTINCT FROM (excluded.\"price\") RETURNING (xmax = 0) AS inserted"
+        );
+    }
+
+    #[test]
+    fn keep_existing_does_nothing_on_conflict() {
+        assert_eq!(
+            create_merge_query("prices", &["sku", "price"], &["sku"], &ConflictPolicy::KeepExisting, 1),
+            "INSERT INTO \"prices\" AS target (\"sku\", \"price\", \"created_by\") VALUES ($2, $3, $1) ON CONFLICT (\"sku\") DO NOTHING RETURNING (xmax = 0) AS inserted"
+        );
+    }
+
+    #[test]
+    fn newer_wins_compares_against_the_target_row() {
+        assert_eq!(
+            create_merge_query("sales.prices", &["sku", "price", "priced_time"], &["sku"], &ConflictPolicy::NewerWins(String::from("priced_time")), 1),
+            "INSERT INTO \"sales\".\"prices\" AS target (\"sku\", \"price\", \"priced_time\", \"created_by\") VALUES ($2, $3, $4, $1) \
+            ON CONFLICT (\"sku\") DO UPDATE SET \"price\" = excluded.\"price\", \"priced_time\" = excluded.\"priced_time\", \"last_updated_time\" = now(), \"last_updated_by\" = $1 \
+            WHERE (target.\"price\", target.\"priced_time\") IS DISTINCT FROM (excluded.\"price\", excluded.\"priced_time\") \
+            AND excluded.\"priced_time\" > target.\"priced_time\" RETURNING (xmax = 0) AS inserted"
+        );
+    }
+
+    #[test]
+    fn key_only_merges_do_nothing_on_conflict() {
+        assert!(create_merge_query("tags", &["name"], &["name"], &ConflictPolicy::Overwrite, 1).contains("ON CONFLICT (\"name\") DO NOTHING"));
+    }
+
+    #[test]
+    fn chunk_size_stays_under_the_bind_parameter_limit() {
+        assert_eq!(get_chunk_size(500, 10), 500);
+        assert_eq!(get_chunk_size(500, 1000), 65);
+        assert_eq!(get_chunk_size(0, 10), 1);
+        assert_eq!(get_chunk_size(100_000, 0), 65000);
+    }
+}
@@ -0,0 +1,360 @@
+use crate::strings::{quote_identifier, quote_qualified_identifier};
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ColumnType {
+    Uuid,
+    Text,
+    Varchar(u32),
+    Boolean,
+    SmallInt,
+    Integer,
+    BigInt,
+    Numeric(u32, u32),
+    Double,
+    Timestamptz,
+    Date,
+    Jsonb,
+    Bytea,
+    // Anything else, written into the SQL as is, e.g. "citext" or "text[]".
+    Custom(String),
+}
+
+impl ColumnType {
+    pub fn to_sql(&self) -> String {
+        match self {
+            ColumnType::Uuid => String::from("uuid"),
+            ColumnType::Text => String::from("text"),
+            ColumnType::Varchar(length) => format!("varchar({})", length),
+            ColumnType::Boolean => String::from("boolean"),
+            ColumnType::SmallInt => String::from("smallint"),
+            ColumnType::Integer => String::from("integer"),
+            ColumnType::BigInt => String::from("bigint"),
+            ColumnType::Numeric(precision, scale) => format!("numeric({}, {})", precision, scale),
+            ColumnType::Double => String::from("double precision"),
+            ColumnType::Timestamptz => String::from("timestamptz"),
+            ColumnType::Date => String::from("date"),
+            ColumnType::Jsonb => String::from("jsonb"),
+            ColumnType::Bytea => String::from("bytea"),
+            ColumnType::Custom(sql) => sql.to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnDefinition {
+    pub name: String,
+    pub column_type: ColumnType,
+    pub nullable: bool,
+    pub default: Option<String>,
+    pub primary_key: bool,
+    pub unique: bool,
+    pub references: Option<(String, String)>,
+}
+
+// Columns are nullable until not_null is called, same as postgres.
+pub fn column(name: &str, column_type: ColumnType) -> ColumnDefinition {
+    ColumnDefinition {
+        name: name.to_owned(),
+        column_type,
+        nullable: true,
+        default: None,
+        primary_key: false,
+        unique: false,
+        references: None
+    }
+}
+
+impl ColumnDefinition {
+    pub fn not_null(mut self) -> Self {
+        self.nullable = false;
+        self
+    }
+
+    // The default is raw SQL, quote string literals yourself e.g. default_sql("'pending'").
+    pub fn default_sql(mut self, default: &str) -> Self {
+        self.default = Some(default.to_owned());
+        self
+    }
+
+    pub fn primary_key(mut self) -> Self {
+        self.primary_key = true;
+        self.nullable = false;
+        self
+    }
+
+    pub fn unique(mut self) -> Self {
+        self.unique = true;
+        self
+    }
+
+    pub fn references(mut self, table: &str, column: &str) -> Self {
+        self.references = Some((table.to_owned(), column.to_owned()));
+        self
+    }
+
+    pub fn to_sql(&self) -> String {
+        let mut sql = format!("{} {}", quote_identifier(&self.name), self.column_type.to_sql());
+        if self.primary_key {
+            sql.push_str(" PRIMARY KEY");
+        } else if !self.nullable {
+            sql.push_str(" NOT NULL");
+        }
+        if let Some(default) = &self.default {
+            sql.push_str(&format!(" DEFAULT {}", default));
+        }
+        if self.unique {
+            sql.push_str(" UNIQUE");
+        }
+        if let Some((table, column)) = &self.references {
+            sql.push_str(&format!(" REFERENCES {} ({})", quote_qualified_identifier(table), quote_identifier(column)));
+        }
+        sql
+    }
+}
+
+// The columns PostgresEntity and PostgresRepository expect on every entity table.
+pub fn audit_columns() -> Vec<ColumnDefinition> {
+    vec![
+        column("id", ColumnType::Uuid).primary_key().default_sql("gen_random_uuid()"),
+        column("created_time", ColumnType::Timestamptz).not_null().default_sql("now()"),
+        column("created_by", ColumnType::Uuid).not_null(),
+        column("last_updated_time", ColumnType::Timestamptz),
+        column("last_updated_by", ColumnType::Uuid),
+        column("active", ColumnType::Boolean).not_null().default_sql("true"),
+    ]
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CreateTable {
+    pub name: String,
+    pub columns: Vec<ColumnDefinition>,
+    pub if_not_exists: bool,
+}
+
+pub fn create_table(name: &str) -> CreateTable {
+    CreateTable {
+        name: name.to_owned(),
+        columns: Vec::new(),
+        if_not_exists: false
+    }
+}
+
+impl CreateTable {
+    pub fn column(mut self, column: ColumnDefinition) -> Self {
+        self.columns.push(column);
+        self
+    }
+
+    // Adds the audit columns first so entity tables all start the same way.
+    pub fn with_audit_columns(mut self) -> Self {
+        let mut columns = audit_columns();
+        columns.append(&mut self.columns);
+        self.columns = columns;
+        self
+    }
+
+    pub fn if_not_exists(mut self) -> Self {
+        self.if_not_exists = true;
+        self
+    }
+
+    pub fn to_sql(&self) -> String {
+        let columns: Vec<String> = self.columns.iter().map(|column| format!("    {}", column.to_sql())).collect();
+        format!(
+            "CREATE TABLE {}{} (\n{}\n)",
+            if self.if_not_exists { "IF NOT EXISTS " } else { "" },
+            quote_qualified_identifier(&self.name),
+            columns.join(",\n")
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CreateIndex {
+    pub name: String,
+    pub table: String,
+    pub columns: Vec<String>,
+    pub unique: bool,
+    pub concurrently: bool,
+    pub where_clause: Option<String>,
+}
+
+pub fn create_index(name: &str, table: &str, columns: &[&str]) -> CreateIndex {
+    CreateIndex {
+        name: name.to_owned(),
+        table: table.to_owned(),
+        columns: columns.iter().map(|column| (*column).to_owned()).collect(),
+        unique: false,
+        concurrently: false,
+        where_clause: None
+    }
+}
+
+impl CreateIndex {
+    pub fn unique(mut self) -> Self {
+        self.unique = true;
+        self
+    }
+
+    // Can't run inside a transaction, the runner applies migrations containing one without one.
+    pub fn concurrently(mut self) -> Self {
+        self.concurrently = true;
+        self
+    }
+
+    pub fn where_sql(mut self, where_clause: &str) -> Self {
+        self.where_clause = Some(where_clause.to_owned());
+        self
+    }
+
+    pub fn to_sql(&self) -> String {
+        let columns: Vec<String> = self.columns.iter().map(|column| quote_identifier(column)).collect();
+        let mut sql = format!(
+            "CREATE {}INDEX {}{} ON {} ({})",
+            if self.unique { "UNIQUE " } else { "" },
+            if self.concurrently { "CONCURRENTLY " } else { "" },
+            quote_identifier(&self.name),
+            quote_qualified_identifier(&self.table),
+            columns.join(", ")
+        );
+        if let Some(where_clause) = &self.where_clause {
+            sql.push_str(&format!(" WHERE {}", where_clause));
+        }
+        sql
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MigrationStep {
+    CreateTable(CreateTable),
+    DropTable(String),
+    AddColumn {
+        table: String,
+        column: ColumnDefinition
+    },
+    DropColumn {
+        table: String,
+        column: String
+    },
+    AlterColumnType {
+        table: String,
+        column: String,
+        column_type: ColumnType,
+        using: Option<String>
+    },
+    SetNotNull {
+        table: String,
+        column: String
+    },
+    CreateIndex(CreateIndex),
+    DropIndex {
+        name: String,
+        concurrently: bool
+    },
+    // Escape hatch for anything the DSL doesn't cover.
+    Sql(String),
+}
+
+impl MigrationStep {
+    pub fn to_sql(&self) -> String {
+        match self {
+            MigrationStep::CreateTable(create_table) => create_table.to_sql(),
+            MigrationStep::DropTable(table) => format!("DROP TABLE {}", quote_qualified_identifier(table)),
+            MigrationStep::AddColumn { table, column } => format!("ALTER TABLE {} ADD COLUMN {}", quote_qualified_identifier(table), column.to_sql()),
+            MigrationStep::DropColumn { table, column } => format!("ALTER TABLE {} DROP COLUMN {}", quote_qualified_identifier(table), quote_identifier(column)),
+            MigrationStep::AlterColumnType { table, column, column_type, using } => {
+                let mut sql = format!(
+                    "ALTER TABLE {} ALTER COLUMN {} TYPE {}",
+                    quote_qualified_identifier(table),
+                    quote_identifier(column),
+                    column_type.to_sql()
+                );
+                if let Some(using) = using {
+                    sql.push_str(&format!(" USING {}", using));
+                }
+                sql
+            }
+            MigrationStep::SetNotNull { table, column } => format!("ALTER TABLE {} ALTER COLUMN {} SET NOT NULL", quote_qualified_identifier(table), quote_identifier(column)),
+            MigrationStep::CreateIndex(create_index) => create_index.to_sql(),
+            MigrationStep::DropIndex { name, concurrently } => format!(
+                "DROP INDEX {}{}",
+                if *concurrently { "CONCURRENTLY " } else { "" },
+                quote_qualified_identifier(name)
+            ),
+            MigrationStep::Sql(sql) => sql.to_owned(),
+        }
+    }
+
+    pub fn requires_no_transaction(&self) -> bool {
+        match self {
+            MigrationStep::CreateIndex(create_index) => create_index.concurrently,
+            MigrationStep::DropIndex { concurrently, .. } => *concurrently,
+            MigrationStep::Sql(sql) => contains_keyword(sql, "concurrently"),
+            _ => false
+        }
+    }
+}
+
+// Matched as a whole word, the keyword can be followed by a newline or a quoted name.
+pub(crate) fn contains_keyword(sql: &str, keyword: &str) -> bool {
+    sql.split(|character: char| !(character.is_alphanumeric() || character == '_'))
+        .any(|token| token.eq_ignore_ascii_case(keyword))
+}
+
+impl From<CreateTable> for MigrationStep {
+    fn from(create_table: CreateTable) -> Self {
+        MigrationStep::CreateTable(create_table)
+    }
+}
+
+impl From<CreateIndex> for MigrationStep {
+    fn from(create_index: CreateIndex) -> Self {
+        MigrationStep::CreateIndex(create_index)
+    }
+}
+
+pub fn drop_table(table: &str) -> MigrationStep {
+    MigrationStep::DropTable(table.to_owned())
+}
+
+pub fn add_column(table: &str, column: ColumnDefinition) -> MigrationStep {
+    MigrationStep::AddColumn {
+        table: table.to_owned(),
+        column
+    }
+}
+
+pub fn drop_column(table: &str, column: &str) -> MigrationStep {
+    MigrationStep::DropColumn {
+        table: table.to_owned(),
+        column: column.to_owned()
+    }
+}
+
+pub fn alter_column_type(table: &str, column: &str, column_type: ColumnType, using: Option<&str>) -> MigrationStep {
+    MigrationStep::AlterColumnType {
+        table: table.to_owned(),
+        column: column.to_owned(),
+        column_type,
+        using: using.map(|using| using.to_owned())
+    }
+}
+
+pub fn set_not_null(table: &str, column: &str) -> MigrationStep {
+    MigrationStep::SetNotNull {
+        table: table.to_owned(),
+        column: column.to_owned()
+    }
+}
+
+pub fn drop_index(name: &str, concurrently: bool) -> MigrationStep {
+    MigrationStep::DropIndex {
+        name: name.to_owned(),
+        concurrently
+    }
+}
+
+pub fn raw_sql(sql: &str) -> MigrationStep {
+    MigrationStep::Sql(sql.to_owned())
+}
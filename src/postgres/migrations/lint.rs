@@ -0,0 +1,175 @@
+use std::fmt;
+use crate::postgres::migrations::{Migration, dsl::{MigrationStep, contains_keyword}};
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum UnsafeOperation {
+    // Rewrites or scans the whole table under an ACCESS EXCLUSIVE lock, and fails on existing rows anyway.
+    AddNotNullColumnWithoutDefault,
+    // Most type changes rewrite the table and its indexes under an ACCESS EXCLUSIVE lock.
+    ColumnTypeChange,
+    // Scans the whole table under an ACCESS EXCLUSIVE lock, add a NOT VALID check constraint first.
+    SetNotNull,
+    // Blocks all writes to the table until the index is built.
+    IndexWithoutConcurrently,
+}
+
+impl UnsafeOperation {
+    pub fn get_advice(&self) -> &'static str {
+        match self {
+            UnsafeOperation::AddNotNullColumnWithoutDefault => "add the column as nullable (or with a default), backfill it, then set NOT NULL",
+            UnsafeOperation::ColumnTypeChange => "add a new column, backfill it and switch reads over before dropping the old one",
+            UnsafeOperation::SetNotNull => "add a CHECK (column IS NOT NULL) NOT VALID constraint, VALIDATE it, then set NOT NULL",
+            UnsafeOperation::IndexWithoutConcurrently => "create the index CONCURRENTLY",
+        }
+    }
+}
+
+impl fmt::Display for UnsafeOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            UnsafeOperation::AddNotNullColumnWithoutDefault => "adding a NOT NULL column without a default",
+            UnsafeOperation::ColumnTypeChange => "changing a column type",
+            UnsafeOperation::SetNotNull => "setting NOT NULL on an existing column",
+            UnsafeOperation::IndexWithoutConcurrently => "creating an index without CONCURRENTLY",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MigrationLintIssue {
+    pub version: i64,
+    pub step: usize,
+    pub operation: UnsafeOperation,
+    pub allowed: bool,
+}
+
+impl fmt::Display for MigrationLintIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Migration {} step {}: {}, {}.", self.version, self.step + 1, self.operation, self.operation.get_advice())
+    }
+}
+
+fn normalize_sql(sql: &str) -> String {
+    sql.split_whitespace().collect::<Vec<&str>>().join(" ").to_uppercase()
+}
+
+// The comma separated actions of an ALTER TABLE, commas inside parentheses (e.g. numeric(10, 2)) don't split.
+fn split_actions(statement: &str) -> Vec<&str> {
+    let mut actions = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, character) in statement.char_indices() {
+        match character {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                actions.push(&statement[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    actions.push(&statement[start..]);
+    actions
+}
+
+// COLUMN is optional after ADD, anything but a constraint following it is a column definition.
+fn adds_not_null_column_without_default(action: &str) -> bool {
+    let definition = match action.find(" ADD ").map(|index| &action[index + 5..]).or_else(|| action.trim_start().strip_prefix("ADD ")) {
+        Some(definition) => definition.trim_start(),
+        None => return false
+    };
+    let definition = definition.strip_prefix("COLUMN ").unwrap_or(definition);
+    let is_constraint = ["CONSTRAINT ", "PRIMARY ", "UNIQUE", "CHECK", "FOREIGN ", "EXCLUDE "].iter().any(|keyword| definition.starts_with(keyword));
+    !is_constraint && definition.contains(" NOT NULL") && !definition.contains(" DEFAULT ")
+}
+
+// Raw SQL only gets a keyword level check, it catches the common cases rather than parsing SQL.
+fn lint_sql(sql: &str) -> Vec<UnsafeOperation> {
+    let mut operations = Vec::new();
+    for statement in normalize_sql(sql).split(';') {
+        let statement = statement.trim();
+
+        if (statement.starts_with("CREATE INDEX") || statement.starts_with("CREATE UNIQUE INDEX")) && !contains_keyword(statement, "CONCURRENTLY") {
+            operations.push(UnsafeOperation::IndexWithoutConcurrently);
+        }
+        if statement.starts_with("ALTER TABLE") {
+            if statement.contains(" TYPE ") && statement.contains(" ALTER COLUMN ") {
+                operations.push(UnsafeOperation::ColumnTypeChange);
+            }
+            if statement.contains(" SET NOT NULL") {
+                operations.push(UnsafeOperation::SetNotNull);
+            }
+            if split_actions(statement).into_iter().any(adds_not_null_column_without_default) {
+                operations.push(UnsafeOperation::AddNotNullColumnWithoutDefault);
+            }
+        }
+    }
+    operations
+}
+
+// Changes to tables created earlier in the same migration are fine, they are empty and nobody else can see them yet.
+pub fn lint_migration(migration: &Migration) -> Vec<MigrationLintIssue> {
+    let mut created_tables: Vec<&str> = Vec::new();
+    let mut issues = Vec::new();
+
+    for (index, step) in migration.up.iter().enumerate() {
+        let operations = match step {
+            MigrationStep::CreateTable(create_table) => {
+                created_tables.push(&create_table.name);
+                Vec::new()
+            }
+            MigrationStep::AddColumn { table, column } if !created_tables.contains(&table.as_str()) => {
+                if !column.nullable && column.default.is_none() {
+                    vec![UnsafeOperation::AddNotNullColumnWithoutDefault]
+                } else {
+                    Vec::new()
+                }
+            }
+            MigrationStep::AlterColumnType { table, .. } if !created_tables.contains(&table.as_str()) => vec![UnsafeOperation::ColumnTypeChange],
+            MigrationStep::SetNotNull { table, .. } if !created_tables.contains(&table.as_str()) => vec![UnsafeOperation::SetNotNull],
+            MigrationStep::CreateIndex(create_index) if !created_tables.contains(&create_index.table.as_str()) && !create_index.concurrently => {
+                vec![UnsafeOperation::IndexWithoutConcurrently]
+            }
+            MigrationStep::Sql(sql) => lint_sql(sql),
+            _ => Vec::new()
+        };
+
+        for operation in operations {
+            issues.push(MigrationLintIssue {
+                version: migration.version,
+                step: index,
+                operation,
+                allowed: migration.allowed_unsafe.contains(&operation)
+            });
+        }
+    }
+    issues
+}
+
+pub fn lint_migrations<'a, I>(migrations: I) -> Vec<MigrationLintIssue>
+where I: IntoIterator<Item = &'a Migration> {
+    migrations.into_iter().flat_map(lint_migration).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lint_sql_flags_not_null_columns_added_with_or_without_column() {
+        assert_eq!(lint_sql("ALTER TABLE users ADD COLUMN email text NOT NULL"), vec![UnsafeOperation::AddNotNullColumnWithoutDefault]);
+        assert_eq!(lint_sql("alter table users add email text not null"), vec![UnsafeOperation::AddNotNullColumnWithoutDefault]);
+        assert_eq!(lint_sql("ALTER TABLE users ADD price numeric(10, 2), ADD email text NOT NULL"), vec![UnsafeOperation::AddNotNullColumnWithoutDefault]);
+    }
+
+    #[test]
+    fn lint_sql_allows_defaults_nullable_columns_and_constraints() {
+        assert!(lint_sql("ALTER TABLE users ADD email text NOT NULL DEFAULT ''").is_empty());
+        assert!(lint_sql("ALTER TABLE users ADD email text").is_empty());
+        assert!(lint_sql("ALTER TABLE users ADD CONSTRAINT email_present CHECK (email IS NOT NULL)").is_empty());
+        assert!(lint_sql("ALTER TABLE users ADD email text, ADD CHECK (email IS NOT NULL)").is_empty());
+    }
+}
@@ -0,0 +1,332 @@
+use sqlx::{Executor, PgConnection, Pool, Postgres, pool::PoolConnection};
+use crate::environment::Environment;
+use crate::postgres::BurchillPostgresError;
+
+pub mod dsl;
+pub mod lint;
+
+use dsl::MigrationStep;
+use lint::{MigrationLintIssue, UnsafeOperation, lint_migrations};
+
+pub const CREATE_SCHEMA_MIGRATIONS_TABLE: &str = "CREATE TABLE IF NOT EXISTS schema_migrations (
+    version bigint PRIMARY KEY,
+    name text NOT NULL,
+    applied_time timestamptz NOT NULL DEFAULT now()
+)";
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Migration {
+    pub version: i64,
+    pub name: String,
+    pub up: Vec<MigrationStep>,
+    pub down: Vec<MigrationStep>,
+    // Unsafe operations the author has checked are fine, e.g. the table is known to be tiny.
+    pub allowed_unsafe: Vec<UnsafeOperation>,
+}
+
+impl Migration {
+    pub fn new(version: i64, name: &str) -> Self {
+        Migration {
+            version,
+            name: name.to_owned(),
+            up: Vec::new(),
+            down: Vec::new(),
+            allowed_unsafe: Vec::new()
+        }
+    }
+
+    pub fn allow_unsafe(mut self, operation: UnsafeOperation) -> Self {
+        self.allowed_unsafe.push(operation);
+        self
+    }
+
+    pub fn up<S>(mut self, step: S) -> Self
+    where S: Into<MigrationStep> {
+        self.up.push(step.into());
+        self
+    }
+
+    pub fn down<S>(mut self, step: S) -> Self
+    where S: Into<MigrationStep> {
+        self.down.push(step.into());
+        self
+    }
+
+    // For plain SQL migration files.
+    pub fn from_sql(version: i64, name: &str, up: &str, down: Option<&str>) -> Self {
+        let migration = Migration::new(version, name).up(MigrationStep::Sql(up.to_owned()));
+        match down {
+            Some(down) => migration.down(MigrationStep::Sql(down.to_owned())),
+            None => migration
+        }
+    }
+
+    pub fn is_reversible(&self) -> bool {
+        !self.down.is_empty()
+    }
+
+    pub fn to_up_sql(&self) -> String {
+        join_steps(&self.up)
+    }
+
+    pub fn to_down_sql(&self) -> String {
+        join_steps(&self.down)
+    }
+}
+
+const MIGRATION_LOCK_KEY: &str = "schema_migrations";
+
+// A session lock on its own connection, so runners starting together (e.g. several instances of a
+// deploy) apply migrations one after the other and the later ones find nothing pending.
+async fn lock_migrations(pool: &Pool<Postgres>) -> Result<PoolConnection<Postgres>, BurchillPostgresError> {
+    let mut connection = pool.acquire().await?;
+    sqlx::query("SELECT pg_advisory_lock(hashtextextended($1::text, 0))")
+        .bind(MIGRATION_LOCK_KEY)
+        .execute(&mut connection).await?;
+    Ok(connection)
+}
+
+async fn unlock_migrations(connection: &mut PgConnection) {
+    let result = sqlx::query("SELECT pg_advisory_unlock(hashtextextended($1::text, 0))")
+        .bind(MIGRATION_LOCK_KEY)
+        .execute(connection).await;
+    if let Err(err) = result {
+        tracing::error!(error = %err, "failed to release the migration lock, it is held until the connection closes");
+    }
+}
+
+fn join_steps(steps: &[MigrationStep]) -> String {
+    let statements: Vec<String> = steps.iter().map(|step| format!("{};", step.to_sql())).collect();
+    statements.join("\n")
+}
+
+// Consecutive steps that can run in a transaction are grouped, each step that can't is a group on its own.
+fn split_transactions(steps: &[MigrationStep]) -> Vec<&[MigrationStep]> {
+    let mut groups = Vec::new();
+    let mut start = 0;
+    for (index, step) in steps.iter().enumerate() {
+        if step.requires_no_transaction() {
+            if start < index {
+                groups.push(&steps[start..index]);
+            }
+            groups.push(&steps[index..index + 1]);
+            start = index + 1;
+        }
+    }
+    if start < steps.len() {
+        groups.push(&steps[start..]);
+    }
+    groups
+}
+
+// Steps are applied in order inside one transaction per migration. A step that can't run in a
+// transaction (e.g. CREATE INDEX CONCURRENTLY) runs on its own, the steps before and after it still
+// get a transaction each and the migration is recorded with the last one.
+// When a later group fails the earlier ones stay applied and the migration isn't recorded, so the
+// whole migration runs again next time. Steps in such a migration should be idempotent (IF NOT EXISTS,
+// IF EXISTS), and a failed concurrent index build leaves an INVALID index that has to be dropped first.
+async fn run_steps(pool: &Pool<Postgres>, steps: &[MigrationStep], record_sql: &str, version: i64, name: &str) -> Result<(), BurchillPostgresError> {
+    let groups = split_transactions(steps);
+    let mut recorded = false;
+
+    for (index, group) in groups.iter().enumerate() {
+        if group.iter().any(|step| step.requires_no_transaction()) {
+            for step in group.iter() {
+                pool.execute(step.to_sql().as_str()).await?;
+            }
+            continue;
+        }
+
+        let mut transaction = pool.begin().await?;
+        for step in group.iter() {
+            (&mut transaction).execute(step.to_sql().as_str()).await?;
+        }
+        if index + 1 == groups.len() {
+            sqlx::query(record_sql).bind(version).bind(name).execute(&mut transaction).await?;
+            recorded = true;
+        }
+        transaction.commit().await?;
+    }
+
+    if !recorded {
+        sqlx::query(record_sql).bind(version).bind(name).execute(pool).await?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MigrationRunner {
+    migrations: Vec<Migration>,
+    environment: Option<Environment>,
+}
+
+impl MigrationRunner {
+    pub fn new() -> Self {
+        MigrationRunner::default()
+    }
+
+    pub fn add(mut self, migration: Migration) -> Result<Self, BurchillPostgresError> {
+        if self.migrations.iter().any(|existing| existing.version == migration.version) {
+            return Err(BurchillPostgresError::InvalidMigration {
+                version: migration.version,
+                reason: String::from("another migration already uses this version")
+            });
+        }
+
+        self.migrations.push(migration);
+        self.migrations.sort_by_key(|migration| migration.version);
+        Ok(self)
+    }
+
+    // Unsafe operations are refused in production (unless allowed per migration) and only logged elsewhere.
+    // Falls back to Environment::from_env when not set.
+    pub fn with_environment(mut self, environment: Environment) -> Self {
+        self.environment = Some(environment);
+        self
+    }
+
+    pub fn get_migrations(&self) -> &[Migration] {
+        &self.migrations
+    }
+
+    pub async fn get_applied_versions(&self, pool: &Pool<Postgres>) -> Result<Vec<i64>, BurchillPostgresError> {
+        pool.execute(CREATE_SCHEMA_MIGRATIONS_TABLE).await?;
+        let versions: Vec<(i64,)> = sqlx::query_as("SELECT version FROM schema_migrations ORDER BY version")
+            .fetch_all(pool).await?;
+        Ok(versions.into_iter().map(|(version,)| version).collect())
+    }
+
+    pub async fn get_pending(&self, pool: &Pool<Postgres>) -> Result<Vec<&Migration>, BurchillPostgresError> {
+        let applied = self.get_applied_versions(pool).await?;
+        Ok(self.migrations.iter().filter(|migration| !applied.contains(&migration.version)).collect())
+    }
+
+    // Returns the versions applied by this run.
+    pub async fn migrate(&self, pool: &Pool<Postgres>) -> Result<Vec<i64>, BurchillPostgresError> {
+        let mut lock = lock_migrations(pool).await?;
+        let result = self.migrate_locked(pool).await;
+        unlock_migrations(&mut lock).await;
+        result
+    }
+
+    async fn migrate_locked(&self, pool: &Pool<Postgres>) -> Result<Vec<i64>, BurchillPostgresError> {
+        let pending = self.get_pending(pool).await?;
+        self.check_safety(&lint_migrations(pending.iter().copied()))?;
+        let mut applied = Vec::new();
+
+        for migration in pending {
+            tracing::info!(version = migration.version, name = %migration.name, "applying migration");
+            run_steps(
+                pool,
+                &migration.up,
+                "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+                migration.version,
+                &migration.name
+            ).await?;
+            applied.push(migration.version);
+        }
+        Ok(applied)
+    }
+
+    fn check_safety(&self, issues: &[MigrationLintIssue]) -> Result<(), BurchillPostgresError> {
+        // Without an environment from either place it's treated as production, same as Environment::from_env
+        // not guessing development.
+        let is_production = match self.environment.map_or_else(Environment::from_env, Ok) {
+            Ok(environment) => environment.is_production(),
+            Err(err) => {
+                tracing::warn!(error = %err, "could not resolve the environment, refusing unsafe migrations");
+                true
+            }
+        };
+
+        for issue in issues.iter() {
+            if issue.allowed {
+                tracing::info!(version = issue.version, "{} (explicitly allowed)", issue);
+            } else {
+                tracing::warn!(version = issue.version, "{}", issue);
+            }
+        }
+
+        let refused: Vec<String> = issues.iter().filter(|issue| !issue.allowed).map(|issue| issue.to_string()).collect();
+        if is_production && !refused.is_empty() {
+            return Err(BurchillPostgresError::UnsafeMigration(refused));
+        }
+        Ok(())
+    }
+
+    // Reverts the latest applied migrations, newest first. Returns the versions reverted.
+    pub async fn rollback(&self, pool: &Pool<Postgres>, count: usize) -> Result<Vec<i64>, BurchillPostgresError> {
+        let mut lock = lock_migrations(pool).await?;
+        let result = self.rollback_locked(pool, count).await;
+        unlock_migrations(&mut lock).await;
+        result
+    }
+
+    async fn rollback_locked(&self, pool: &Pool<Postgres>, count: usize) -> Result<Vec<i64>, BurchillPostgresError> {
+        let applied = self.get_applied_versions(pool).await?;
+        let mut reverted = Vec::new();
+
+        for version in applied.into_iter().rev().take(count) {
+            let migration = self.migrations.iter().find(|migration| migration.version == version).ok_or_else(|| BurchillPostgresError::InvalidMigration {
+                version,
+                reason: String::from("the migration is applied but not registered with the runner")
+            })?;
+            if !migration.is_reversible() {
+                return Err(BurchillPostgresError::InvalidMigration {
+                    version,
+                    reason: String::from("the migration has no down steps")
+                });
+            }
+
+            tracing::info!(version = migration.version, name = %migration.name, "reverting migration");
+            run_steps(
+                pool,
+                &migration.down,
+                "DELETE FROM schema_migrations WHERE version = $1 AND name = $2",
+                migration.version,
+                &migration.name
+            ).await?;
+            reverted.push(version);
+        }
+        Ok(reverted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sql(sql: &str) -> MigrationStep {
+        MigrationStep::Sql(sql.to_owned())
+    }
+
+    #[test]
+    fn split_transactions_runs_concurrent_steps_on_their_own() {
+        let steps = vec![
+            sql("ALTER TABLE users ADD COLUMN IF NOT EXISTS email text"),
+            sql("UPDATE users SET email = ''"),
+            sql("CREATE INDEX CONCURRENTLY IF NOT EXISTS users_email ON users (email)"),
+            sql("ALTER TABLE users ALTER COLUMN email SET NOT NULL")
+        ];
+
+        let groups = split_transactions(&steps);
+        assert_eq!(groups, vec![&steps[0..2], &steps[2..3], &steps[3..4]]);
+    }
+
+    #[test]
+    fn split_transactions_keeps_a_plain_migration_in_one_group() {
+        let steps = vec![sql("CREATE TABLE a (id uuid)"), sql("CREATE TABLE b (id uuid)")];
+        assert_eq!(split_transactions(&steps), vec![&steps[..]]);
+        assert!(split_transactions(&[]).is_empty());
+    }
+
+    #[test]
+    fn split_transactions_separates_consecutive_concurrent_steps() {
+        let steps = vec![
+            sql("CREATE INDEX CONCURRENTLY a_id ON a (id)"),
+            sql("CREATE INDEX CONCURRENTLY b_id ON b (id)")
+        ];
+        assert_eq!(split_transactions(&steps), vec![&steps[0..1], &steps[1..2]]);
+    }
+}
@@ -1,11 +1,102 @@
-use sqlx::{Executor, FromRow, Pool, Postgres, postgres::{PgArguments, PgConnectOptions, PgPoolOptions, PgRow}, query::{QueryAs}};
+#[cfg(feature = "database")]
+use sqlx::{Arguments, Executor, FromRow, Pool, Postgres, postgres::{PgArguments, PgConnectOptions, PgDatabaseError, PgPoolOptions, PgRow}, query::{Query, QueryAs}, types::Json};
+#[cfg(feature = "database")]
 use quaint::{Value, prelude::{Select, Update}, visitor::Visitor};
 use thiserror::Error;
 use chrono::{DateTime, Utc};
 use uuid::{Uuid};
+#[cfg(feature = "database")]
+use std::time::Instant;
+#[cfg(feature = "database")]
+use crate::web::query_stats::{RequestQueryStats, current_query_stats};
 
+pub mod access;
+#[cfg(feature = "database")]
+pub mod aggregate;
+#[cfg(feature = "database")]
+pub mod backpressure;
+#[cfg(feature = "database")]
+pub mod borrowed;
+#[cfg(feature = "citext")]
+pub mod citext;
+pub mod criteria;
+#[cfg(feature = "database")]
+pub mod cursor;
+#[cfg(feature = "database")]
+pub mod db_config;
+#[cfg(feature = "database")]
+pub mod delete_policy;
+#[cfg(feature = "database")]
+pub mod dsn;
+#[cfg(feature = "database")]
 pub mod entity;
+pub mod entity_stats;
+pub mod etag;
+#[cfg(feature = "database")]
+pub mod events;
+#[cfg(feature = "database")]
+pub mod exchange_rates;
+pub mod fingerprint;
+#[cfg(feature = "hstore")]
+pub mod hstore;
+#[cfg(feature = "database")]
+pub mod large_object;
+#[cfg(feature = "database")]
+pub mod lifecycle;
+#[cfg(feature = "database")]
+pub mod maintenance;
+#[cfg(feature = "database")]
+pub mod merge;
+#[cfg(feature = "database")]
+pub mod migrations;
+#[cfg(feature = "database")]
+pub mod notify;
+#[cfg(feature = "database")]
+pub mod pool;
+#[cfg(feature = "database")]
+pub mod prewarm;
+#[cfg(feature = "database")]
+pub mod profiling;
+#[cfg(feature = "database")]
+pub mod projection;
+#[cfg(feature = "database")]
+pub mod query_diff;
+#[cfg(feature = "database")]
+pub mod query_options;
+#[cfg(feature = "database")]
+pub mod rbac;
+#[cfg(feature = "database")]
+pub mod read_only;
+#[cfg(feature = "database")]
+pub mod references;
+#[cfg(feature = "database")]
+pub mod registry;
+#[cfg(feature = "database")]
 pub mod repository;
+#[cfg(feature = "database")]
+pub mod resilience;
+#[cfg(feature = "database")]
+pub mod security_events;
+#[cfg(feature = "database")]
+pub mod self_test;
+#[cfg(feature = "database")]
+pub mod sequences;
+#[cfg(feature = "database")]
+pub mod sessions;
+#[cfg(feature = "database")]
+pub mod snippets;
+#[cfg(feature = "database")]
+pub mod startup;
+#[cfg(feature = "database")]
+pub mod statement_cache;
+#[cfg(feature = "database")]
+pub mod sync;
+#[cfg(feature = "database")]
+pub mod two_phase;
+#[cfg(feature = "database")]
+pub mod usage;
+#[cfg(feature = "database")]
+pub mod versioned_json;
 
 
 #[derive(Clone)]
@@ -18,6 +109,7 @@ pub struct PostgresBaseEntityData {
     pub active: Option<bool>,
 }
 
+#[cfg(feature = "database")]
 pub async fn get_connection_pool(options: PgConnectOptions, max_connections: u32) -> Result<Pool<Postgres>, BurchillPostgresError> {
     let pool = PgPoolOptions::new()
         .max_connections(max_connections)
@@ -25,6 +117,7 @@ pub async fn get_connection_pool(options: PgConnectOptions, max_connections: u32
     Ok(pool)
 }
 
+#[cfg(feature = "database")]
 pub fn add_bindings_to_query<'b, T>(query: QueryAs<'b, Postgres, T, PgArguments>, params: Vec<Value>) -> Result<QueryAs<'b, Postgres, T, PgArguments>, BurchillPostgresError> {
     let mut new_query = query;
     for value in params.into_iter() {
@@ -33,6 +126,7 @@ pub fn add_bindings_to_query<'b, T>(query: QueryAs<'b, Postgres, T, PgArguments>
     Ok(new_query)
 }
 
+#[cfg(feature = "database")]
 pub fn add_binding_to_query<'b, T>(query: QueryAs<'b, Postgres, T, PgArguments>, value: Value) -> Result<QueryAs<'b, Postgres, T, PgArguments>, BurchillPostgresError> {
     match value {
         Value::Integer(_) => Ok(query.bind(value.as_i64())),
@@ -46,10 +140,32 @@ pub fn add_binding_to_query<'b, T>(query: QueryAs<'b, Postgres, T, PgArguments>,
         Value::Enum(_) => Ok(query.bind(value.into_string())),
         Value::Uuid(_) => Ok(query.bind(value.as_uuid())),
         Value::DateTime(_) => Ok(query.bind(value.as_datetime())),
+        Value::Json(_) => Ok(query.bind(value.into_json().map(Json))),
         _ => Err(BurchillPostgresError::UnknownSqlType)
     }
 }
 
+#[cfg(feature = "database")]
+pub fn create_sqlx_arguments(params: Vec<Value>) -> Result<PgArguments, BurchillPostgresError> {
+    let mut arguments = PgArguments::default();
+    for value in params.into_iter() {
+        match value {
+            Value::Integer(_) => arguments.add(value.as_i64()),
+            Value::Float(_) => arguments.add(value.as_f32()),
+            Value::Double(_) => arguments.add(value.as_f64()),
+            Value::Text(_) => arguments.add(value.into_string()),
+            Value::Boolean(_) => arguments.add(value.as_bool()),
+            Value::Enum(_) => arguments.add(value.into_string()),
+            Value::Uuid(_) => arguments.add(value.as_uuid()),
+            Value::DateTime(_) => arguments.add(value.as_datetime()),
+            Value::Json(_) => arguments.add(value.into_json().map(Json)),
+            _ => return Err(BurchillPostgresError::UnknownSqlType)
+        }
+    }
+    Ok(arguments)
+}
+
+#[cfg(feature = "database")]
 pub fn add_base_fields_to_select(query: Select) -> Select {
     query
         .column("id")
@@ -60,6 +176,7 @@ pub fn add_base_fields_to_select(query: Select) -> Select {
         .column("active")
 }
 
+#[cfg(feature = "database")]
 pub fn create_sqlx_query<'a, T>(query: &'a str, bindings: Vec<Value>) -> Result<QueryAs<'a, sqlx::Postgres, T, PgArguments>, BurchillPostgresError>
 where
     T: for<'r> FromRow<'r, PgRow>
@@ -68,6 +185,60 @@ where
     add_bindings_to_query::<T>(sqlx_query, bindings)
 }
 
+#[cfg(feature = "database")]
+pub fn create_sqlx_row_query(query: &str, bindings: Vec<Value>) -> Result<Query<'_, sqlx::Postgres, PgArguments>, BurchillPostgresError> {
+    let arguments = create_sqlx_arguments(bindings)?;
+    Ok(sqlx::query_with::<Postgres, PgArguments>(query, arguments))
+}
+
+// Times a query into the stats of the request being handled, see web::query_stats::with_query_stats.
+#[cfg(feature = "database")]
+struct QueryRecording {
+    stats: RequestQueryStats,
+    sql: String,
+    bindings_key: String,
+    started: Instant,
+}
+
+#[cfg(feature = "database")]
+fn start_query_recording(sql: &str, bindings: &[Value]) -> Option<QueryRecording> {
+    current_query_stats().map(|stats| QueryRecording {
+        stats,
+        sql: sql.to_owned(),
+        bindings_key: format!("{:?}", bindings),
+        started: Instant::now()
+    })
+}
+
+#[cfg(feature = "database")]
+fn finish_query_recording(recording: Option<QueryRecording>, rows: u64) {
+    if let Some(recording) = recording {
+        recording.stats.record_query(&recording.sql, &recording.bindings_key, recording.started.elapsed(), rows);
+    }
+}
+
+#[cfg(feature = "database")]
+pub async fn fetch_one_row<'a, Q, E>(query: Q, executor: E) -> Result<PgRow, BurchillPostgresError>
+where
+    Q: Into<quaint::prelude::Query<'a>>,
+    E: Executor<'a, Database = Postgres>
+{
+    let (query, bindings) = match quaint::visitor::Postgres::build(query) {
+        Ok(query_and_bindings) => query_and_bindings,
+        Err(err) => return Err(BurchillPostgresError::QuaintError(err))
+    };
+
+    let recording = start_query_recording(&query, &bindings);
+    let query = create_sqlx_row_query(query.as_str(), bindings)?;
+    let result = query.fetch_one(executor).await;
+    finish_query_recording(recording, result.as_ref().map_or(0, |_| 1));
+    match result {
+        Ok(result) => Ok(result),
+        Err(err) => Err(BurchillPostgresError::from(err))
+    }
+}
+
+#[cfg(feature = "database")]
 pub async fn fetch_one<'a, T, Q, E>(query: Q, executor: E) -> Result<T, BurchillPostgresError>
 where
     T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
@@ -79,44 +250,107 @@ where
         Err(err) => return Err(BurchillPostgresError::QuaintError(err))
     };
 
+    let recording = start_query_recording(&query, &bindings);
     let query = create_sqlx_query::<T>(query.as_str(), bindings)?;
-    match query.fetch_one(executor).await {
+    let result = query.fetch_one(executor).await;
+    finish_query_recording(recording, result.as_ref().map_or(0, |_| 1));
+    match result {
         Ok(result) => Ok(result),
-        Err(err) => Err(BurchillPostgresError::SqlxError(err))
+        Err(err) => Err(BurchillPostgresError::from(err))
     }
 }
 
-// Since quaint does not allow returns on an update query I have to hack it in! 🪓🪓🪓
-pub async fn update_and_fetch_one<'a, T, E>(query: Update<'a>, returning_values: Vec<&str>, executor: E) -> Result<T, BurchillPostgresError> 
-where 
+#[cfg(feature = "database")]
+pub async fn fetch_optional<'a, T, Q, E>(query: Q, executor: E) -> Result<Option<T>, BurchillPostgresError>
+where
     T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    Q: Into<quaint::prelude::Query<'a>>,
     E: Executor<'a, Database = Postgres>
 {
-    let (mut query, bindings) = match quaint::visitor::Postgres::build(query) {
+    let (query, bindings) = match quaint::visitor::Postgres::build(query) {
         Ok(query_and_bindings) => query_and_bindings,
         Err(err) => return Err(BurchillPostgresError::QuaintError(err))
     };
 
-    if returning_values.len() > 0 {
-        let mut count = 0;
+    let recording = start_query_recording(&query, &bindings);
+    let query = create_sqlx_query::<T>(query.as_str(), bindings)?;
+    let result = query.fetch_optional(executor).await;
+    finish_query_recording(recording, result.as_ref().map_or(0, |row| row.is_some() as u64));
+    match result {
+        Ok(result) => Ok(result),
+        Err(err) => Err(BurchillPostgresError::from(err))
+    }
+}
 
-        for value in returning_values.into_iter() {
-            if count == 0 {
-                query.push_str(" RETURNING ");
-                count += 1;
-            } else {
-                query.push_str(", ");
-                count += 1;
-            }
+#[cfg(feature = "database")]
+pub async fn fetch_all<'a, T, Q, E>(query: Q, executor: E) -> Result<Vec<T>, BurchillPostgresError>
+where
+    T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    Q: Into<quaint::prelude::Query<'a>>,
+    E: Executor<'a, Database = Postgres>
+{
+    let (query, bindings) = match quaint::visitor::Postgres::build(query) {
+        Ok(query_and_bindings) => query_and_bindings,
+        Err(err) => return Err(BurchillPostgresError::QuaintError(err))
+    };
 
-            query.push_str(value);
-        }
+    let recording = start_query_recording(&query, &bindings);
+    let query = create_sqlx_query::<T>(query.as_str(), bindings)?;
+    let result = query.fetch_all(executor).await;
+    finish_query_recording(recording, result.as_ref().map_or(0, |rows| rows.len() as u64));
+    match result {
+        Ok(result) => Ok(result),
+        Err(err) => Err(BurchillPostgresError::from(err))
     }
+}
 
+// Since quaint does not allow returns on an update query I have to hack it in! 🪓🪓🪓
+// The returning values are raw SQL so *, expressions and aliases work, quote column names that need it.
+#[cfg(feature = "database")]
+pub fn build_update_returning<'a>(query: Update<'a>, returning_values: Vec<&str>) -> Result<(String, Vec<Value<'a>>), BurchillPostgresError> {
+    let (mut query, bindings) = match quaint::visitor::Postgres::build(query) {
+        Ok(query_and_bindings) => query_and_bindings,
+        Err(err) => return Err(BurchillPostgresError::QuaintError(err))
+    };
+
+    if !returning_values.is_empty() {
+        query.push_str(" RETURNING ");
+        query.push_str(&returning_values.join(", "));
+    }
+
+    Ok((query, bindings))
+}
+
+#[cfg(feature = "database")]
+pub async fn update_and_fetch_one<'a, T, E>(query: Update<'a>, returning_values: Vec<&str>, executor: E) -> Result<T, BurchillPostgresError> 
+where 
+    T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    E: Executor<'a, Database = Postgres>
+{
+    let (query, bindings) = build_update_returning(query, returning_values)?;
+
+    let recording = start_query_recording(&query, &bindings);
     let query = create_sqlx_query(query.as_str(), bindings)?;
-    match query.fetch_one(executor).await {
+    let result = query.fetch_one(executor).await;
+    finish_query_recording(recording, result.as_ref().map_or(0, |_| 1));
+    match result {
+        Ok(result) => Ok(result),
+        Err(err) => Err(BurchillPostgresError::from(err))
+    }
+}
+
+#[cfg(feature = "database")]
+pub async fn update_and_fetch_one_row<'a, E>(query: Update<'a>, returning_values: Vec<&str>, executor: E) -> Result<PgRow, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let (query, bindings) = build_update_returning(query, returning_values)?;
+
+    let recording = start_query_recording(&query, &bindings);
+    let query = create_sqlx_row_query(query.as_str(), bindings)?;
+    let result = query.fetch_one(executor).await;
+    finish_query_recording(recording, result.as_ref().map_or(0, |_| 1));
+    match result {
         Ok(result) => Ok(result),
-        Err(err) => Err(BurchillPostgresError::SqlxError(err))
+        Err(err) => Err(BurchillPostgresError::from(err))
     }
 }
 
@@ -130,10 +364,136 @@ pub enum BurchillPostgresError {
         field: String,
         id: Option<Uuid>
     },
+    #[error("The entity was changed by someone else since it was loaded. (Table: {table:?}, Id: {id:?}, Last Updated Time: {last_updated_time:?})")]
+    StaleEntity {
+        table: String,
+        id: Option<Uuid>,
+        last_updated_time: Option<DateTime<Utc>>
+    },
+    #[error("No connection pool is registered for the databases: {0:?}")]
+    MissingDatabases(Vec<String>),
+    #[error("Required postgres extensions are missing. Run CREATE EXTENSION for {not_created:?} as a privileged role and install the packages providing {unavailable:?} on the server.")]
+    MissingExtensions {
+        not_created: Vec<String>,
+        unavailable: Vec<String>
+    },
+    #[error("{0}")]
+    MissingPrivileges(String),
     #[error(transparent)]
     QuaintError(#[from] quaint::error::Error),
+    #[error("Timed out waiting for a lock. (Relation: {relation:?}, Message: {message})")]
+    LockTimeout {
+        message: String,
+        relation: Option<String>
+    },
+    #[error("Deadlock detected. (Blocking Processes: {blocking_pids:?}, Message: {message}, Detail: {detail:?})")]
+    Deadlock {
+        message: String,
+        detail: Option<String>,
+        blocking_pids: Vec<i32>
+    },
+    #[error("Migration {version} can't be run: {reason}")]
+    InvalidMigration {
+        version: i64,
+        reason: String
+    },
+    #[error("Invalid lifecycle rule for {table}: {reason}")]
+    InvalidLifecycleRule {
+        table: String,
+        reason: String
+    },
+    #[error("Refusing to run unsafe migrations in production, allow them per migration if they are known to be fine. {0:?}")]
+    UnsafeMigration(Vec<String>),
+    #[error("Every entity in a merge must write the same columns to the same table. (Table: {table}, Expected: {expected:?}, Found: {found:?})")]
+    MismatchedMergeColumns {
+        table: String,
+        expected: Vec<String>,
+        found: Vec<String>
+    },
+    #[error("No exchange rate is available from {from} to {to} at {at}.")]
+    MissingExchangeRate {
+        from: String,
+        to: String,
+        at: DateTime<Utc>
+    },
+    #[error("Not allowed to {action} this entity. (Table: {table}, Id: {id:?})")]
+    AccessDenied {
+        action: access::AccessAction,
+        table: String,
+        id: Option<Uuid>
+    },
+    #[error("The user is missing the {permission:?} permission. (User: {user_id})")]
+    MissingPermission {
+        user_id: Uuid,
+        permission: String
+    },
+    #[cfg(feature = "database")]
+    #[error("Refusing to hard delete, {reason}. (Table: {table}, Id: {id:?}, Policy: {policy})")]
+    DeletePolicyViolation {
+        table: String,
+        id: Option<Uuid>,
+        policy: delete_policy::DeletePolicy,
+        reason: String
+    },
+    #[error("The circuit breaker for {0:?} is open and there is no cached value to fall back to.")]
+    CircuitOpen(String),
+    #[error("Gave up waiting for replicas to catch up after {waited:?}, replication lag is still {lag:?}.")]
+    ReplicationLagTimeout {
+        lag: std::time::Duration,
+        waited: std::time::Duration
+    },
+    #[cfg(feature = "database")]
     #[error(transparent)]
-    SqlxError(#[from] sqlx::Error),
+    SqlxError(sqlx::Error),
     #[error(transparent)]
     AnyhowError(#[from] anyhow::Error),
 }
+
+// Lock contention gets its own variants so it can be told apart (and retried) without digging through sqlx errors.
+#[cfg(feature = "database")]
+impl From<sqlx::Error> for BurchillPostgresError {
+    fn from(err: sqlx::Error) -> Self {
+        if let sqlx::Error::Database(database_error) = &err {
+            if let Some(pg_error) = database_error.try_downcast_ref::<PgDatabaseError>() {
+                match pg_error.code() {
+                    "55P03" => return BurchillPostgresError::LockTimeout {
+                        message: pg_error.message().to_owned(),
+                        relation: pg_error.table()
+                            .map(|table| table.to_owned())
+                            .or_else(|| pg_error.r#where().and_then(parse_relation_name))
+                    },
+                    "40P01" => return BurchillPostgresError::Deadlock {
+                        message: pg_error.message().to_owned(),
+                        detail: pg_error.detail().map(|detail| detail.to_owned()),
+                        blocking_pids: pg_error.detail().map(parse_blocking_pids).unwrap_or_default()
+                    },
+                    _ => {}
+                }
+            }
+        }
+        BurchillPostgresError::SqlxError(err)
+    }
+}
+
+// Error contexts look like: while updating tuple (0,1) in relation "orders"
+#[cfg(feature = "database")]
+fn parse_relation_name(context: &str) -> Option<String> {
+    let start = context.find("relation \"")? + "relation \"".len();
+    let end = context[start..].find('"')?;
+    Some(context[start..start + end].to_owned())
+}
+
+// Deadlock details look like: Process 123 waits for ShareLock on transaction 456; blocked by process 789.
+#[cfg(feature = "database")]
+fn parse_blocking_pids(detail: &str) -> Vec<i32> {
+    let mut pids: Vec<i32> = detail.split("blocked by process ")
+        .skip(1)
+        .filter_map(|rest| {
+            let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+            digits.parse().ok()
+        })
+        .collect();
+    pids.sort_unstable();
+    pids.dedup();
+    pids
+}
@@ -0,0 +1,151 @@
+use std::future::Future;
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Postgres};
+use thiserror::Error;
+use uuid::{Uuid};
+use crate::postgres::{BurchillPostgresError, versioned_json::{VersionedSchema, upgrade_json}};
+
+// Postgres rejects NOTIFY payloads of 8000 bytes or more.
+pub const MAX_NOTIFY_PAYLOAD_BYTES: usize = 7999;
+
+
+#[derive(Error, Debug)]
+pub enum NotifyCodecError {
+    #[error("The notification payload is {size} bytes, over the {limit} byte limit, and the event has no reference to send instead.")]
+    PayloadTooLarge {
+        size: usize,
+        limit: usize
+    },
+    #[error("Expected a {expected:?} notification but got {found:?}.")]
+    UnexpectedType {
+        expected: String,
+        found: String
+    },
+    #[error("The notification payload could not be upgraded. {0}")]
+    Upgrade(String),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+// Points at the row the listener should load itself when the event was too big to send.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NotifyReference {
+    pub table: String,
+    pub id: Uuid,
+}
+
+pub trait NotifyEvent: VersionedSchema {
+    const EVENT_TYPE: &'static str;
+
+    fn get_reference(&self) -> Option<NotifyReference> {
+        None
+    }
+}
+
+#[derive(Serialize)]
+struct EnvelopeRef<'a, E> {
+    #[serde(rename = "type")]
+    event_type: &'a str,
+    version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<&'a E>,
+    #[serde(rename = "ref", skip_serializing_if = "Option::is_none")]
+    reference: Option<NotifyReference>,
+}
+
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(rename = "type")]
+    event_type: String,
+    version: u32,
+    #[serde(default)]
+    data: serde_json::Value,
+    #[serde(rename = "ref", default)]
+    reference: Option<NotifyReference>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum NotifyPayload<E> {
+    Inline(E),
+    Reference(NotifyReference),
+}
+
+impl<E> NotifyPayload<E> {
+    // Loads referenced events with the given function, inline ones are returned as they are.
+    pub async fn resolve<F, Fut>(self, fetch: F) -> Result<E, BurchillPostgresError>
+    where
+        F: FnOnce(NotifyReference) -> Fut,
+        Fut: Future<Output = Result<E, BurchillPostgresError>>
+    {
+        match self {
+            NotifyPayload::Inline(event) => Ok(event),
+            NotifyPayload::Reference(reference) => fetch(reference).await
+        }
+    }
+}
+
+// Always JSON rather than a CodecFormat, NOTIFY payloads are text and older versions are upgraded as JSON.
+pub fn encode_notify_payload<E>(event: &E) -> Result<String, NotifyCodecError>
+where E: NotifyEvent {
+    let payload = serde_json::to_string(&EnvelopeRef {
+        event_type: E::EVENT_TYPE,
+        version: E::VERSION,
+        data: Some(event),
+        reference: None
+    })?;
+    if payload.len() <= MAX_NOTIFY_PAYLOAD_BYTES {
+        return Ok(payload);
+    }
+
+    let size = payload.len();
+    let reference = event.get_reference().ok_or(NotifyCodecError::PayloadTooLarge { size, limit: MAX_NOTIFY_PAYLOAD_BYTES })?;
+    let payload = serde_json::to_string(&EnvelopeRef::<E> {
+        event_type: E::EVENT_TYPE,
+        version: E::VERSION,
+        data: None,
+        reference: Some(reference)
+    })?;
+
+    if payload.len() > MAX_NOTIFY_PAYLOAD_BYTES {
+        return Err(NotifyCodecError::PayloadTooLarge { size, limit: MAX_NOTIFY_PAYLOAD_BYTES });
+    }
+    tracing::debug!(event_type = E::EVENT_TYPE, size, "notification payload too large, sending a reference instead");
+    Ok(payload)
+}
+
+// Older payload versions go through the event's registered upgrades, same as VersionedJson columns.
+pub fn decode_notify_payload<E>(payload: &str) -> Result<NotifyPayload<E>, NotifyCodecError>
+where E: NotifyEvent {
+    let envelope: Envelope = serde_json::from_str(payload)?;
+    if envelope.event_type != E::EVENT_TYPE {
+        return Err(NotifyCodecError::UnexpectedType {
+            expected: E::EVENT_TYPE.to_owned(),
+            found: envelope.event_type
+        });
+    }
+
+    if let Some(reference) = envelope.reference {
+        return Ok(NotifyPayload::Reference(reference));
+    }
+    let data = upgrade_json::<E>(envelope.version, envelope.data).map_err(|err| NotifyCodecError::Upgrade(err.to_string()))?;
+    Ok(NotifyPayload::Inline(serde_json::from_value(data)?))
+}
+
+// Reads just the type so one listener can route payloads for several event types.
+pub fn get_notify_event_type(payload: &str) -> Result<String, NotifyCodecError> {
+    let envelope: Envelope = serde_json::from_str(payload)?;
+    Ok(envelope.event_type)
+}
+
+pub async fn notify<'a, E, X>(channel: &str, event: &E, executor: X) -> Result<(), BurchillPostgresError>
+where
+    E: NotifyEvent,
+    X: Executor<'a, Database = Postgres>
+{
+    let payload = encode_notify_payload(event).map_err(|err| BurchillPostgresError::AnyhowError(err.into()))?;
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(channel)
+        .bind(payload)
+        .execute(executor).await?;
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use std::{fmt, future::Future, sync::Arc, time::{Duration, Instant}};
+use sqlx::{Pool, Postgres, postgres::PgConnectOptions};
+use tracing::{Instrument, Span};
+use crate::postgres::{BurchillPostgresError, get_connection_pool, statement_cache::{StatementCacheMonitor, StatementCacheStats}};
+
+
+pub trait PoolMetrics: Send + Sync {
+    fn record_operation(&self, pool_name: &str, operation: &str, duration: Duration, success: bool);
+}
+
+#[derive(Clone, Debug)]
+pub struct PoolStatus {
+    pub name: String,
+    pub size: u32,
+    pub idle: usize,
+    pub closed: bool,
+    pub statement_cache: Option<StatementCacheStats>,
+}
+
+#[derive(Clone)]
+pub struct NamedPool {
+    name: Arc<str>,
+    pool: Pool<Postgres>,
+    metrics: Option<Arc<dyn PoolMetrics>>,
+    statement_cache: Option<Arc<StatementCacheMonitor>>
+}
+
+impl fmt::Debug for NamedPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NamedPool")
+            .field("name", &self.name)
+            .field("pool", &self.pool)
+            .finish()
+    }
+}
+
+impl NamedPool {
+    pub fn new(name: &str, pool: Pool<Postgres>) -> Self {
+        NamedPool {
+            name: Arc::from(name),
+            pool,
+            metrics: None,
+            statement_cache: None
+        }
+    }
+
+    // The pool name doubles as the application_name so it shows up in pg_stat_activity too.
+    pub async fn connect(name: &str, options: PgConnectOptions, max_connections: u32) -> Result<Self, BurchillPostgresError> {
+        let pool = get_connection_pool(options.application_name(name), max_connections).await?;
+        Ok(NamedPool::new(name, pool))
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<dyn PoolMetrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn with_statement_cache_monitor(mut self, monitor: Arc<StatementCacheMonitor>) -> Self {
+        self.statement_cache = Some(monitor);
+        self
+    }
+
+    // Call with the SQL of dynamic queries (e.g. built from criteria) to see how well they cache.
+    pub fn record_statement(&self, sql: &str) {
+        if let Some(monitor) = &self.statement_cache {
+            monitor.record(sql);
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_pool(&self) -> &Pool<Postgres> {
+        &self.pool
+    }
+
+    pub fn get_status(&self) -> PoolStatus {
+        PoolStatus {
+            name: self.name.to_string(),
+            size: self.pool.size(),
+            idle: self.pool.num_idle(),
+            closed: self.pool.is_closed(),
+            statement_cache: self.statement_cache.as_ref().map(|monitor| monitor.get_stats())
+        }
+    }
+
+    pub fn create_span(&self, operation: &str) -> Span {
+        tracing::info_span!("postgres", pool = %self.name, operation = %operation)
+    }
+
+    // Runs a database operation inside a span tagged with the pool name, logging and recording how long it took.
+    pub async fn instrument<F, T, E>(&self, operation: &str, future: F) -> Result<T, E>
+    where
+        F: Future<Output = Result<T, E>>,
+        E: fmt::Display
+    {
+        let started = Instant::now();
+        let result = future.instrument(self.create_span(operation)).await;
+        let duration = started.elapsed();
+
+        match &result {
+            Ok(_) => tracing::debug!(pool = %self.name, operation = %operation, duration_ms = duration.as_millis() as u64, "postgres operation finished"),
+            Err(err) => tracing::warn!(pool = %self.name, operation = %operation, duration_ms = duration.as_millis() as u64, error = %err, "postgres operation failed"),
+        }
+
+        if let Some(metrics) = &self.metrics {
+            metrics.record_operation(&self.name, operation, duration, result.is_ok());
+        }
+
+        result
+    }
+}
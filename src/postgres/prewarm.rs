@@ -0,0 +1,82 @@
+use std::sync::{Arc, Mutex, PoisonError};
+use futures::future::BoxFuture;
+use quaint::{prelude::{Comparable, Select}, visitor::Visitor};
+use sqlx::{Executor, PgConnection, postgres::PgPoolOptions};
+use uuid::{Uuid};
+use crate::postgres::{BurchillPostgresError, dsn::{AfterConnect, Dsn}, repository::PostgresRepository};
+
+
+// Statements every new connection prepares before it is handed out, so the first request on a
+// fresh connection doesn't pay for parsing and planning. Keep it to the hot queries, every
+// statement here takes a slot in each connection's statement cache.
+#[derive(Default)]
+pub struct PrewarmRegistry {
+    statements: Mutex<Vec<String>>,
+}
+
+impl PrewarmRegistry {
+    pub fn new() -> Self {
+        PrewarmRegistry::default()
+    }
+
+    pub fn register(&self, sql: &str) {
+        let mut statements = self.statements.lock().unwrap_or_else(PoisonError::into_inner);
+        if !statements.iter().any(|statement| statement == sql) {
+            statements.push(sql.to_owned());
+        }
+    }
+
+    // The SQL has to match what the repository sends byte for byte to hit the cache, which it does
+    // when it's built from the same Select.
+    pub fn register_select(&self, query: Select<'_>) -> Result<(), BurchillPostgresError> {
+        let (sql, _) = quaint::visitor::Postgres::build(query)?;
+        self.register(&sql);
+        Ok(())
+    }
+
+    // The repository's select by id and its unfiltered select.
+    pub fn register_repository<R, T>(&self, repository: &R) -> Result<(), BurchillPostgresError>
+    where R: PostgresRepository<T> {
+        self.register_select(repository.create_select_query()?.so_that("id".equals(Uuid::nil())))?;
+        self.register_select(repository.create_select_query()?)
+    }
+
+    pub fn get_statements(&self) -> Vec<String> {
+        self.statements.lock().unwrap_or_else(PoisonError::into_inner).to_owned()
+    }
+}
+
+// A statement that fails to prepare (e.g. its table isn't migrated yet) is logged and skipped,
+// it shouldn't stop the connection from being used.
+pub async fn prewarm_connection(connection: &mut PgConnection, statements: &[String]) -> usize {
+    let mut prepared = 0;
+    for sql in statements {
+        match connection.prepare(sql.as_str()).await {
+            Ok(_) => prepared += 1,
+            Err(err) => tracing::warn!(error = %err, sql = %sql, "could not prewarm statement")
+        }
+    }
+    prepared
+}
+
+pub fn prewarm_after_connect(registry: Arc<PrewarmRegistry>) -> AfterConnect {
+    Arc::new(move |connection| -> BoxFuture<'_, Result<(), sqlx::Error>> {
+        let statements = registry.get_statements();
+        Box::pin(async move {
+            let prepared = prewarm_connection(connection, &statements).await;
+            tracing::debug!(prepared, total = statements.len(), "prewarmed connection");
+            Ok(())
+        })
+    })
+}
+
+// Replaces any after_connect already on the options, for a pool built from a Dsn use prewarmed_pool_options
+// so the target_session_attrs check still runs.
+pub fn with_prewarmed_statements(options: PgPoolOptions, registry: Arc<PrewarmRegistry>) -> PgPoolOptions {
+    let after_connect = prewarm_after_connect(registry);
+    options.after_connect(move |connection| after_connect(connection))
+}
+
+pub fn prewarmed_pool_options(dsn: &Dsn, registry: Arc<PrewarmRegistry>) -> PgPoolOptions {
+    dsn.to_pool_options_with(Some(prewarm_after_connect(registry)))
+}
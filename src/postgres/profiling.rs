@@ -0,0 +1,151 @@
+use sqlx::{Pool, Postgres};
+use crate::postgres::BurchillPostgresError;
+use crate::strings::{quote_identifier, quote_qualified_identifier};
+
+// Types where min and max mean something, everything else is still counted and gets top values.
+const ORDERED_TYPES: &[&str] = &[
+    "smallint", "integer", "bigint", "numeric", "real", "double precision",
+    "text", "character varying", "character",
+    "date", "time without time zone", "time with time zone", "timestamp without time zone", "timestamp with time zone", "interval",
+];
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProfileOptions {
+    pub top_values: i64,
+    // Profiles a TABLESAMPLE SYSTEM sample instead of the whole table, for the really big ones.
+    // The sample is repeatable so every column is profiled over the same rows.
+    pub sample_percent: Option<f64>,
+    // Long values are cut short in min, max and top values so a text blob doesn't swamp the report.
+    pub max_value_length: i32,
+}
+
+impl Default for ProfileOptions {
+    fn default() -> Self {
+        ProfileOptions {
+            top_values: 5,
+            sample_percent: None,
+            max_value_length: 100
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValueCount {
+    pub value: Option<String>,
+    pub count: i64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnProfile {
+    pub name: String,
+    pub data_type: String,
+    pub is_nullable: bool,
+    pub non_null_count: i64,
+    pub null_count: i64,
+    pub null_ratio: f64,
+    pub distinct_count: i64,
+    pub min: Option<String>,
+    pub max: Option<String>,
+    pub top_values: Vec<ValueCount>,
+}
+
+impl ColumnProfile {
+    // Every non null value is different, e.g. an id or a good candidate for a unique index.
+    pub fn is_unique(&self) -> bool {
+        self.non_null_count > 0 && self.distinct_count == self.non_null_count
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableProfile {
+    pub table: String,
+    pub row_count: i64,
+    pub sampled: bool,
+    pub columns: Vec<ColumnProfile>,
+}
+
+impl TableProfile {
+    pub fn get_column(&self, name: &str) -> Option<&ColumnProfile> {
+        self.columns.iter().find(|column| column.name == name)
+    }
+}
+
+pub async fn profile_table(pool: &Pool<Postgres>, table: &str) -> Result<TableProfile, BurchillPostgresError> {
+    profile_table_with_options(pool, table, &ProfileOptions::default()).await
+}
+
+// Reads every row once per column, run it against a replica or with a sample on anything large.
+pub async fn profile_table_with_options(pool: &Pool<Postgres>, table: &str, options: &ProfileOptions) -> Result<TableProfile, BurchillPostgresError> {
+    let (schema, table_name) = match table.split_once('.') {
+        Some((schema, table_name)) => (schema.to_owned(), table_name.to_owned()),
+        None => (String::from("public"), table.to_owned())
+    };
+
+    let columns: Vec<(String, String, String)> = sqlx::query_as(
+        "SELECT column_name::text, data_type::text, is_nullable::text FROM information_schema.columns
+        WHERE table_schema = $1 AND table_name = $2 ORDER BY ordinal_position"
+    )
+        .bind(&schema)
+        .bind(&table_name)
+        .fetch_all(pool).await?;
+    if columns.is_empty() {
+        return Err(BurchillPostgresError::SqlxError(sqlx::Error::RowNotFound));
+    }
+
+    let source = match options.sample_percent {
+        Some(percent) => format!("{} TABLESAMPLE SYSTEM ({}) REPEATABLE (0)", quote_qualified_identifier(table), percent.max(0.0).min(100.0)),
+        None => quote_qualified_identifier(table)
+    };
+
+    let (row_count,): (i64,) = sqlx::query_as(&format!("SELECT count(*) FROM {}", source))
+        .fetch_one(pool).await?;
+
+    let mut profiles = Vec::with_capacity(columns.len());
+    for (name, data_type, is_nullable) in columns {
+        let column = quote_identifier(&name);
+        let is_ordered = ORDERED_TYPES.contains(&data_type.as_str());
+        let text = format!("{}::text", column);
+        let min_max = if is_ordered {
+            format!("left(min({0})::text, $1), left(max({0})::text, $1)", column)
+        } else {
+            String::from("left(NULL::text, $1), left(NULL::text, $1)")
+        };
+
+        let (non_null_count, distinct_count, min, max): (i64, i64, Option<String>, Option<String>) = sqlx::query_as(&format!(
+            "SELECT count({0}), count(DISTINCT {1}), {2} FROM {3}",
+            column, text, min_max, source
+        ))
+            .bind(options.max_value_length)
+            .fetch_one(pool).await?;
+
+        let top_values: Vec<(Option<String>, i64)> = sqlx::query_as(&format!(
+            "SELECT left({0}, $1), count(*) AS total FROM {1} GROUP BY {0} ORDER BY total DESC, 1 LIMIT $2",
+            text, source
+        ))
+            .bind(options.max_value_length)
+            .bind(options.top_values)
+            .fetch_all(pool).await?;
+
+        let null_count = row_count - non_null_count;
+        profiles.push(ColumnProfile {
+            name,
+            data_type,
+            is_nullable: is_nullable == "YES",
+            non_null_count,
+            null_count,
+            null_ratio: if row_count > 0 { null_count as f64 / row_count as f64 } else { 0.0 },
+            distinct_count,
+            min,
+            max,
+            top_values: top_values.into_iter().map(|(value, count)| ValueCount { value, count }).collect()
+        });
+    }
+
+    Ok(TableProfile {
+        table: table.to_owned(),
+        row_count,
+        sampled: options.sample_percent.is_some(),
+        columns: profiles
+    })
+}
@@ -0,0 +1,198 @@
+use std::{marker::PhantomData, sync::{Arc, Mutex, PoisonError}};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use quaint::{Value, prelude::{Comparable, Select, asterisk}};
+use sqlx::{Arguments, Executor, FromRow, Pool, Postgres, postgres::PgRow};
+use uuid::{Uuid};
+use crate::postgres::{BurchillPostgresError, create_sqlx_arguments, criteria::FindManyOptions, events::EventDispatcher, fetch_all, fetch_optional};
+use crate::strings::quote_qualified_identifier;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RefreshStrategy {
+    // Rows are refreshed by a ProjectionRefresher as the source entities are saved.
+    OnWrite,
+    // The whole projection is rebuilt when refresh_if_due is called after the interval.
+    Scheduled(Duration),
+    // Only refreshed when the app calls refresh_all/refresh_ids itself.
+    OnDemand,
+}
+
+// A denormalized read model of T stored in its own table. The source query must return the
+// table's columns in order, including the id of the entity each row belongs to (and active,
+// so FindManyOptions work the same as on the entity tables).
+#[async_trait]
+pub trait Projection<T, R>: Send + Sync
+where R: for<'r> FromRow<'r, PgRow> + Send + Unpin {
+    fn get_table_name(&self) -> &'static str;
+
+    fn get_source_query(&self) -> String;
+
+    // Bound to $1, $2 and so on in the source query.
+    fn get_source_parameters(&self) -> Vec<Value<'static>> {
+        Vec::new()
+    }
+
+    fn get_refresh_strategy(&self) -> RefreshStrategy {
+        RefreshStrategy::OnDemand
+    }
+
+    // Which rows need refreshing when the given source changes, e.g. the entity id and its parents.
+    fn get_affected_ids(&self, source: &T) -> Vec<Uuid>;
+
+    async fn refresh_all(&self, pool: &Pool<Postgres>) -> Result<u64, BurchillPostgresError> {
+        let table = quote_qualified_identifier(self.get_table_name());
+        let arguments = create_sqlx_arguments(self.get_source_parameters())?;
+        let mut transaction = pool.begin().await?;
+
+        sqlx::query(&format!("DELETE FROM {}", table)).execute(&mut transaction).await?;
+        let result = sqlx::query_with(&create_refresh_query(self.get_table_name(), &self.get_source_query(), None), arguments)
+            .execute(&mut transaction).await?;
+
+        transaction.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    // Ids whose source rows were deleted (or filtered out) are removed from the projection.
+    async fn refresh_ids(&self, pool: &Pool<Postgres>, ids: &[Uuid]) -> Result<u64, BurchillPostgresError> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+
+        let table = quote_qualified_identifier(self.get_table_name());
+        let parameters = self.get_source_parameters();
+        let ids_parameter = parameters.len() + 1;
+        let mut arguments = create_sqlx_arguments(parameters)?;
+        arguments.add(ids.to_vec());
+        let mut transaction = pool.begin().await?;
+
+        sqlx::query(&format!("DELETE FROM {} WHERE id = ANY($1)", table))
+            .bind(ids.to_vec())
+            .execute(&mut transaction).await?;
+        let result = sqlx::query_with(&create_refresh_query(self.get_table_name(), &self.get_source_query(), Some(ids_parameter)), arguments)
+            .execute(&mut transaction).await?;
+
+        transaction.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    fn create_select_query<'a>(&self) -> Select<'a> {
+        Select::from_table(self.get_table_name()).value(asterisk())
+    }
+
+    async fn find_one<'b, E>(&self, executor: E, id: &Uuid) -> Result<Option<R>, BurchillPostgresError>
+    where E: Executor<'b, Database = Postgres> {
+        let query = self.create_select_query().so_that("id".equals(id.to_owned()));
+        fetch_optional(query, executor).await
+    }
+
+    async fn find_many<'b, E>(&self, executor: E, options: &FindManyOptions) -> Result<Vec<R>, BurchillPostgresError>
+    where E: Executor<'b, Database = Postgres> {
+        let query = options.add_to_select(self.create_select_query());
+        fetch_all(query, executor).await
+    }
+}
+
+// The ids are bound after the source query's own parameters, ids_parameter is their number.
+fn create_refresh_query(table: &str, source_query: &str, ids_parameter: Option<usize>) -> String {
+    let insert = format!("INSERT INTO {} SELECT * FROM ({}) source", quote_qualified_identifier(table), source_query);
+    match ids_parameter {
+        Some(ids_parameter) => format!("{} WHERE source.id = ANY(${})", insert, ids_parameter),
+        None => insert
+    }
+}
+
+// Keeps a projection up to date, dispatch it the sources from an EventTransaction for on write
+// projections and call refresh_if_due from the apps scheduler for scheduled ones.
+pub struct ProjectionRefresher<P, T, R> {
+    projection: Arc<P>,
+    pool: Pool<Postgres>,
+    last_refresh: Mutex<Option<DateTime<Utc>>>,
+    marker: PhantomData<fn(T) -> R>
+}
+
+impl<P, T, R> ProjectionRefresher<P, T, R>
+where
+    P: Projection<T, R>,
+    R: for<'r> FromRow<'r, PgRow> + Send + Unpin
+{
+    pub fn new(projection: Arc<P>, pool: Pool<Postgres>) -> Self {
+        ProjectionRefresher {
+            projection,
+            pool,
+            last_refresh: Mutex::new(None),
+            marker: PhantomData
+        }
+    }
+
+    pub fn get_projection(&self) -> &P {
+        &self.projection
+    }
+
+    pub fn get_last_refresh(&self) -> Option<DateTime<Utc>> {
+        *self.last_refresh.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn is_refresh_due(&self) -> bool {
+        match self.projection.get_refresh_strategy() {
+            RefreshStrategy::Scheduled(interval) => match self.get_last_refresh() {
+                Some(last_refresh) => Utc::now() - last_refresh >= interval,
+                None => true
+            },
+            _ => false
+        }
+    }
+
+    pub async fn refresh(&self) -> Result<u64, BurchillPostgresError> {
+        let count = self.projection.refresh_all(&self.pool).await?;
+        *self.last_refresh.lock().unwrap_or_else(PoisonError::into_inner) = Some(Utc::now());
+        Ok(count)
+    }
+
+    pub async fn refresh_if_due(&self) -> Result<Option<u64>, BurchillPostgresError> {
+        if !self.is_refresh_due() {
+            return Ok(None);
+        }
+        Ok(Some(self.refresh().await?))
+    }
+}
+
+#[async_trait]
+impl<P, T, R> EventDispatcher<T> for ProjectionRefresher<P, T, R>
+where
+    P: Projection<T, R>,
+    T: Send + 'static,
+    R: for<'r> FromRow<'r, PgRow> + Send + Unpin
+{
+    async fn dispatch(&self, source: T) -> Result<()> {
+        if self.projection.get_refresh_strategy() != RefreshStrategy::OnWrite {
+            return Ok(());
+        }
+
+        let ids = self.projection.get_affected_ids(&source);
+        self.projection.refresh_ids(&self.pool, &ids).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refresh_query_wraps_the_source_query() {
+        assert_eq!(
+            create_refresh_query("reporting.order_totals", "SELECT id, sum(total) FROM orders GROUP BY id", None),
+            "INSERT INTO \"reporting\".\"order_totals\" SELECT * FROM (SELECT id, sum(total) FROM orders GROUP BY id) source"
+        );
+    }
+
+    #[test]
+    fn refresh_query_binds_the_ids_after_the_source_parameters() {
+        assert_eq!(
+            create_refresh_query("order_totals", "SELECT id, total FROM orders WHERE region = $1 AND active = $2", Some(3)),
+            "INSERT INTO \"order_totals\" SELECT * FROM (SELECT id, total FROM orders WHERE region = $1 AND active = $2) source WHERE source.id = ANY($3)"
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::{BTreeMap, BTreeSet};
+use quaint::prelude::Select;
+use serde::Serialize;
+use serde_json::{Map, Value as JsonValue};
+use sqlx::{Executor, Postgres};
+use crate::postgres::{BurchillPostgresError, create_sqlx_arguments};
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryDiffOptions {
+    // Identifies the same row on both sides, every row needs a value for each.
+    pub key_columns: Vec<String>,
+    // Left out of the comparison, e.g. last_updated_time after a backfill.
+    pub ignore_columns: Vec<String>,
+    // Stops collecting after this many differences, the counts are still complete.
+    pub max_differences: usize,
+}
+
+impl QueryDiffOptions {
+    pub fn new(key_column: &str) -> Self {
+        QueryDiffOptions {
+            key_columns: vec![key_column.to_owned()],
+            ignore_columns: Vec::new(),
+            max_differences: 100
+        }
+    }
+
+    pub fn with_key_columns(mut self, key_columns: &[&str]) -> Self {
+        self.key_columns = key_columns.iter().map(|column| (*column).to_owned()).collect();
+        self
+    }
+
+    pub fn ignore_column(mut self, column: &str) -> Self {
+        self.ignore_columns.push(column.to_owned());
+        self
+    }
+
+    pub fn with_max_differences(mut self, max_differences: usize) -> Self {
+        self.max_differences = max_differences;
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ColumnDifference {
+    pub column: String,
+    // Null when the column only exists on the other side.
+    pub left: JsonValue,
+    pub right: JsonValue,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangedRow {
+    pub key: String,
+    pub columns: Vec<ColumnDifference>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryDiff {
+    pub left_count: usize,
+    pub right_count: usize,
+    pub only_in_left_count: usize,
+    pub only_in_right_count: usize,
+    pub changed_count: usize,
+    pub only_in_left: Vec<JsonValue>,
+    pub only_in_right: Vec<JsonValue>,
+    pub changed: Vec<ChangedRow>,
+    // A key that isn't unique can't be compared reliably, so these are reported rather than diffed.
+    pub duplicate_keys: Vec<String>,
+}
+
+impl QueryDiff {
+    pub fn is_identical(&self) -> bool {
+        self.only_in_left_count == 0 && self.only_in_right_count == 0 && self.changed_count == 0 && self.duplicate_keys.is_empty()
+    }
+
+    pub fn get_difference_count(&self) -> usize {
+        self.only_in_left_count + self.only_in_right_count + self.changed_count
+    }
+}
+
+// Rows are compared as to_jsonb of the result, so both sides need the same column names but types only
+// have to agree on their JSON form. Top level numbers come back as their Postgres text so numeric
+// columns compare exactly, numbers nested in json columns are still parsed as f64.
+// Both results are held in memory, keep the query to what needs checking.
+pub async fn diff_query<'a, 'b, L, R>(sql: &str, left: L, right: R, options: &QueryDiffOptions) -> Result<QueryDiff, BurchillPostgresError>
+where L: Executor<'a, Database = Postgres>, R: Executor<'b, Database = Postgres> {
+    let sql = create_diff_sql(sql);
+    let left_rows: Vec<(JsonValue,)> = sqlx::query_as(&sql).fetch_all(left).await?;
+    let right_rows: Vec<(JsonValue,)> = sqlx::query_as(&sql).fetch_all(right).await?;
+    compare_rows(left_rows, right_rows, options)
+}
+
+pub async fn diff_select<'a, 'b, 'q, L, R>(select: Select<'q>, left: L, right: R, options: &QueryDiffOptions) -> Result<QueryDiff, BurchillPostgresError>
+where L: Executor<'a, Database = Postgres>, R: Executor<'b, Database = Postgres> {
+    let (query, bindings) = match quaint::visitor::Postgres::build(select) {
+        Ok(query_and_bindings) => query_and_bindings,
+        Err(err) => return Err(BurchillPostgresError::QuaintError(err))
+    };
+
+    let sql = create_diff_sql(&query);
+    let left_rows: Vec<(JsonValue,)> = sqlx::query_as_with(&sql, create_sqlx_arguments(bindings.clone())?).fetch_all(left).await?;
+    let right_rows: Vec<(JsonValue,)> = sqlx::query_as_with(&sql, create_sqlx_arguments(bindings)?).fetch_all(right).await?;
+    compare_rows(left_rows, right_rows, options)
+}
+
+fn create_diff_sql(sql: &str) -> String {
+    format!(
+        "SELECT (SELECT COALESCE(jsonb_object_agg(field.key, CASE WHEN jsonb_typeof(field.value) = 'number' THEN to_jsonb(field.value #>> '{{}}') ELSE field.value END), '{{}}'::jsonb) \
+        FROM jsonb_each(to_jsonb(diff_query.*)) field) FROM ({}) diff_query",
+        sql.trim().trim_end_matches(';')
+    )
+}
+
+fn compare_rows(left_rows: Vec<(JsonValue,)>, right_rows: Vec<(JsonValue,)>, options: &QueryDiffOptions) -> Result<QueryDiff, BurchillPostgresError> {
+    let mut diff = QueryDiff {
+        left_count: left_rows.len(),
+        right_count: right_rows.len(),
+        ..QueryDiff::default()
+    };
+
+    let mut duplicate_keys = BTreeSet::new();
+    let left = index_rows(left_rows, options, &mut duplicate_keys)?;
+    let mut right = index_rows(right_rows, options, &mut duplicate_keys)?;
+
+    for (key, left_row) in left {
+        if duplicate_keys.contains(&key) {
+            right.remove(&key);
+            continue;
+        }
+
+        match right.remove(&key) {
+            None => {
+                diff.only_in_left_count += 1;
+                if diff.get_difference_count() <= options.max_differences {
+                    diff.only_in_left.push(JsonValue::Object(left_row));
+                }
+            }
+            Some(right_row) => {
+                let columns = compare_columns(&left_row, &right_row, options);
+                if !columns.is_empty() {
+                    diff.changed_count += 1;
+                    if diff.get_difference_count() <= options.max_differences {
+                        diff.changed.push(ChangedRow { key, columns });
+                    }
+                }
+            }
+        }
+    }
+
+    for (key, right_row) in right {
+        if duplicate_keys.contains(&key) {
+            continue;
+        }
+        diff.only_in_right_count += 1;
+        if diff.get_difference_count() <= options.max_differences {
+            diff.only_in_right.push(JsonValue::Object(right_row));
+        }
+    }
+
+    diff.duplicate_keys = duplicate_keys.into_iter().collect();
+    tracing::debug!(
+        left_count = diff.left_count,
+        right_count = diff.right_count,
+        only_in_left = diff.only_in_left_count,
+        only_in_right = diff.only_in_right_count,
+        changed = diff.changed_count,
+        duplicate_keys = diff.duplicate_keys.len(),
+        "compared query results"
+    );
+    Ok(diff)
+}
+
+// BTreeMap so the report comes out in key order whatever order the two sides returned.
+fn index_rows(rows: Vec<(JsonValue,)>, options: &QueryDiffOptions, duplicate_keys: &mut BTreeSet<String>) -> Result<BTreeMap<String, Map<String, JsonValue>>, BurchillPostgresError> {
+    let mut indexed = BTreeMap::new();
+    for (row,) in rows {
+        let row = match row {
+            JsonValue::Object(row) => row,
+            _ => continue
+        };
+
+        let mut key_values = Vec::with_capacity(options.key_columns.len());
+        for column in &options.key_columns {
+            match row.get(column) {
+                Some(value) => key_values.push(value.to_string()),
+                None => return Err(BurchillPostgresError::AnyhowError(anyhow::anyhow!("The query result has no key column {:?}.", column)))
+            }
+        }
+
+        let key = key_values.join(", ");
+        if indexed.insert(key.to_owned(), row).is_some() {
+            duplicate_keys.insert(key);
+        }
+    }
+    Ok(indexed)
+}
+
+fn compare_columns(left: &Map<String, JsonValue>, right: &Map<String, JsonValue>, options: &QueryDiffOptions) -> Vec<ColumnDifference> {
+    let columns: BTreeSet<&String> = left.keys().chain(right.keys()).collect();
+    columns.into_iter()
+        .filter(|column| !options.ignore_columns.contains(*column))
+        .filter_map(|column| {
+            let left_value = left.get(column).unwrap_or(&JsonValue::Null);
+            let right_value = right.get(column).unwrap_or(&JsonValue::Null);
+            match left_value == right_value && left.contains_key(column) == right.contains_key(column) {
+                true => None,
+                false => Some(ColumnDifference {
+                    column: column.to_owned(),
+                    left: left_value.to_owned(),
+                    right: right_value.to_owned()
+                })
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn rows(values: Vec<JsonValue>) -> Vec<(JsonValue,)> {
+        values.into_iter().map(|value| (value,)).collect()
+    }
+
+    #[test]
+    fn identical_results_have_no_differences() {
+        let left = rows(vec![json!({"id": 1, "name": "a"}), json!({"id": 2, "name": "b"})]);
+        let right = rows(vec![json!({"id": 2, "name": "b"}), json!({"id": 1, "name": "a"})]);
+
+        let diff = compare_rows(left, right, &QueryDiffOptions::new("id")).unwrap();
+        assert!(diff.is_identical());
+        assert_eq!(diff.left_count, 2);
+        assert_eq!(diff.right_count, 2);
+    }
+
+    #[test]
+    fn reports_missing_and_changed_rows() {
+        let left = rows(vec![json!({"id": 1, "name": "a"}), json!({"id": 2, "name": "b"})]);
+        let right = rows(vec![json!({"id": 2, "name": "c"}), json!({"id": 3, "name": "d"})]);
+
+        let diff = compare_rows(left, right, &QueryDiffOptions::new("id")).unwrap();
+        assert_eq!(diff.only_in_left, vec![json!({"id": 1, "name": "a"})]);
+        assert_eq!(diff.only_in_right, vec![json!({"id": 3, "name": "d"})]);
+        assert_eq!(diff.changed, vec![ChangedRow {
+            key: String::from("2"),
+            columns: vec![ColumnDifference { column: String::from("name"), left: json!("b"), right: json!("c") }]
+        }]);
+        assert_eq!(diff.get_difference_count(), 3);
+    }
+
+    #[test]
+    fn numeric_text_keeps_precision() {
+        let left = rows(vec![json!({"id": 1, "amount": "0.10000000000000000001"})]);
+        let right = rows(vec![json!({"id": 1, "amount": "0.10000000000000000002"})]);
+
+        let diff = compare_rows(left, right, &QueryDiffOptions::new("id")).unwrap();
+        assert_eq!(diff.changed_count, 1);
+    }
+
+    #[test]
+    fn ignored_columns_and_missing_columns() {
+        let left = rows(vec![json!({"id": 1, "last_updated_time": "a", "extra": null})]);
+        let right = rows(vec![json!({"id": 1, "last_updated_time": "b"})]);
+
+        let options = QueryDiffOptions::new("id").ignore_column("last_updated_time");
+        let diff = compare_rows(left, right, &options).unwrap();
+        assert_eq!(diff.changed[0].columns, vec![ColumnDifference { column: String::from("extra"), left: JsonValue::Null, right: JsonValue::Null }]);
+    }
+
+    #[test]
+    fn duplicate_keys_are_reported_not_compared() {
+        let left = rows(vec![json!({"id": 1, "name": "a"}), json!({"id": 1, "name": "b"})]);
+        let right = rows(vec![json!({"id": 1, "name": "c"})]);
+
+        let diff = compare_rows(left, right, &QueryDiffOptions::new("id")).unwrap();
+        assert_eq!(diff.duplicate_keys, vec![String::from("1")]);
+        assert_eq!(diff.changed_count, 0);
+        assert!(!diff.is_identical());
+    }
+
+    #[test]
+    fn composite_keys_and_max_differences() {
+        let left = rows(vec![json!({"a": 1, "b": 1}), json!({"a": 1, "b": 2}), json!({"a": 2, "b": 1})]);
+        let right = rows(Vec::new());
+
+        let options = QueryDiffOptions::new("a").with_key_columns(&["a", "b"]).with_max_differences(2);
+        let diff = compare_rows(left, right, &options).unwrap();
+        assert_eq!(diff.only_in_left_count, 3);
+        assert_eq!(diff.only_in_left.len(), 2);
+    }
+
+    #[test]
+    fn missing_key_column_is_an_error() {
+        let left = rows(vec![json!({"name": "a"})]);
+        assert!(compare_rows(left, Vec::new(), &QueryDiffOptions::new("id")).is_err());
+    }
+}
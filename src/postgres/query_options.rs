@@ -0,0 +1,117 @@
+use std::time::Duration;
+use futures::future::BoxFuture;
+use sqlx::{FromRow, Pool, Postgres, Transaction, postgres::{PgConnectOptions, PgRow}};
+use quaint::visitor::Visitor;
+use crate::postgres::{BurchillPostgresError, create_sqlx_query};
+
+
+// Settings applied with SET LOCAL semantics for a single heavy query, they end with the wrapping transaction.
+#[derive(Clone, Debug, Default)]
+pub struct QueryOptions {
+    pub work_mem: Option<String>,
+    pub statement_timeout: Option<Duration>,
+    pub lock_timeout: Option<Duration>,
+    pub enable_seqscan: Option<bool>,
+    pub settings: Vec<(String, String)>,
+}
+
+impl QueryOptions {
+    pub fn new() -> Self {
+        QueryOptions::default()
+    }
+
+    pub fn work_mem(mut self, work_mem: &str) -> Self {
+        self.work_mem = Some(work_mem.to_owned());
+        self
+    }
+
+    pub fn statement_timeout(mut self, timeout: Duration) -> Self {
+        self.statement_timeout = Some(timeout);
+        self
+    }
+
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = Some(timeout);
+        self
+    }
+
+    // Only meant for diagnosing plans, not for production queries.
+    pub fn enable_seqscan(mut self, enabled: bool) -> Self {
+        self.enable_seqscan = Some(enabled);
+        self
+    }
+
+    pub fn setting(mut self, name: &str, value: &str) -> Self {
+        self.settings.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.get_settings().is_empty()
+    }
+
+    pub fn get_settings(&self) -> Vec<(String, String)> {
+        let mut settings = Vec::new();
+        if let Some(work_mem) = &self.work_mem {
+            settings.push((String::from("work_mem"), work_mem.to_owned()));
+        }
+        if let Some(timeout) = &self.statement_timeout {
+            settings.push((String::from("statement_timeout"), format!("{}ms", timeout.as_millis())));
+        }
+        if let Some(timeout) = &self.lock_timeout {
+            settings.push((String::from("lock_timeout"), format!("{}ms", timeout.as_millis())));
+        }
+        if let Some(enabled) = &self.enable_seqscan {
+            settings.push((String::from("enable_seqscan"), String::from(if *enabled { "on" } else { "off" })));
+        }
+        settings.extend(self.settings.iter().cloned());
+        settings
+    }
+
+    // set_config(..., true) is SET LOCAL but lets the values be bound instead of formatted into the SQL.
+    pub async fn apply(&self, transaction: &mut Transaction<'_, Postgres>) -> Result<(), BurchillPostgresError> {
+        for (name, value) in self.get_settings().into_iter() {
+            sqlx::query("SELECT set_config($1, $2, true)")
+                .bind(name)
+                .bind(value)
+                .execute(&mut *transaction).await?;
+        }
+        Ok(())
+    }
+}
+
+pub async fn with_query_options<T, F>(pool: &Pool<Postgres>, options: &QueryOptions, operation: F) -> Result<T, BurchillPostgresError>
+where
+    F: for<'t> FnOnce(&'t mut Transaction<'static, Postgres>) -> BoxFuture<'t, Result<T, BurchillPostgresError>>
+{
+    let mut transaction = pool.begin().await?;
+    options.apply(&mut transaction).await?;
+
+    let result = operation(&mut transaction).await?;
+    transaction.commit().await?;
+    Ok(result)
+}
+
+pub async fn fetch_all_with_options<'a, T, Q>(query: Q, options: &QueryOptions, pool: &Pool<Postgres>) -> Result<Vec<T>, BurchillPostgresError>
+where
+    T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    Q: Into<quaint::prelude::Query<'a>>
+{
+    let (query, bindings) = quaint::visitor::Postgres::build(query)?;
+
+    let mut transaction = pool.begin().await?;
+    options.apply(&mut transaction).await?;
+
+    let result = create_sqlx_query::<T>(query.as_str(), bindings)?.fetch_all(&mut transaction).await?;
+    transaction.commit().await?;
+    Ok(result)
+}
+
+// For pools dedicated to writes, every session gets the lock timeout instead of waiting forever behind a long lock.
+pub fn with_lock_timeout(options: PgConnectOptions, timeout: Duration) -> PgConnectOptions {
+    options.options([("lock_timeout", format!("{}ms", timeout.as_millis()))])
+}
+
+pub async fn apply_lock_timeout(transaction: &mut Transaction<'_, Postgres>, timeout: Duration) -> Result<(), BurchillPostgresError> {
+    QueryOptions::new().lock_timeout(timeout).apply(transaction).await
+}
@@ -0,0 +1,339 @@
+use std::{collections::{HashMap, HashSet}, sync::{Arc, Mutex, PoisonError}, time::{Duration, Instant}};
+use async_trait::async_trait;
+use sqlx::{Executor, Pool, Postgres};
+use uuid::{Uuid};
+use crate::postgres::{BurchillPostgresError, access::{AccessPolicy, Actor}};
+
+pub const CREATE_RBAC_TABLES: &str = "CREATE TABLE IF NOT EXISTS roles (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    name text NOT NULL UNIQUE,
+    description text,
+    created_time timestamptz NOT NULL DEFAULT now(),
+    created_by uuid NOT NULL,
+    last_updated_time timestamptz,
+    last_updated_by uuid,
+    active boolean NOT NULL DEFAULT true
+);
+CREATE TABLE IF NOT EXISTS permissions (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    name text NOT NULL UNIQUE,
+    description text,
+    created_time timestamptz NOT NULL DEFAULT now(),
+    created_by uuid NOT NULL,
+    last_updated_time timestamptz,
+    last_updated_by uuid,
+    active boolean NOT NULL DEFAULT true
+);
+CREATE TABLE IF NOT EXISTS role_permissions (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    role_id uuid NOT NULL REFERENCES roles (id),
+    permission_id uuid NOT NULL REFERENCES permissions (id),
+    created_time timestamptz NOT NULL DEFAULT now(),
+    created_by uuid NOT NULL,
+    last_updated_time timestamptz,
+    last_updated_by uuid,
+    active boolean NOT NULL DEFAULT true,
+    UNIQUE (role_id, permission_id)
+);
+CREATE TABLE IF NOT EXISTS user_roles (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id uuid NOT NULL,
+    role_id uuid NOT NULL REFERENCES roles (id),
+    created_time timestamptz NOT NULL DEFAULT now(),
+    created_by uuid NOT NULL,
+    last_updated_time timestamptz,
+    last_updated_by uuid,
+    active boolean NOT NULL DEFAULT true,
+    UNIQUE (user_id, role_id)
+);
+CREATE INDEX IF NOT EXISTS user_roles_user ON user_roles (user_id) WHERE active";
+
+
+// Inserts the role or reactivates it, returning its id either way.
+pub async fn create_role<'a, E>(name: &str, description: Option<&str>, user_id: &Uuid, executor: E) -> Result<Uuid, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let (id,): (Uuid,) = sqlx::query_as(
+        "INSERT INTO roles (name, description, created_by) VALUES ($1, $2, $3)
+        ON CONFLICT (name) DO UPDATE SET description = COALESCE(excluded.description, roles.description), active = true, last_updated_time = now(), last_updated_by = $3
+        RETURNING id"
+    )
+        .bind(name)
+        .bind(description)
+        .bind(user_id)
+        .fetch_one(executor).await?;
+    Ok(id)
+}
+
+pub async fn create_permission<'a, E>(name: &str, description: Option<&str>, user_id: &Uuid, executor: E) -> Result<Uuid, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let (id,): (Uuid,) = sqlx::query_as(
+        "INSERT INTO permissions (name, description, created_by) VALUES ($1, $2, $3)
+        ON CONFLICT (name) DO UPDATE SET description = COALESCE(excluded.description, permissions.description), active = true, last_updated_time = now(), last_updated_by = $3
+        RETURNING id"
+    )
+        .bind(name)
+        .bind(description)
+        .bind(user_id)
+        .fetch_one(executor).await?;
+    Ok(id)
+}
+
+// Revoking only deactivates the row so the audit columns keep who granted and who revoked it.
+pub async fn grant_permission<'a, E>(role: &str, permission: &str, user_id: &Uuid, executor: E) -> Result<(), BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let result = sqlx::query(
+        "INSERT INTO role_permissions (role_id, permission_id, created_by)
+        SELECT roles.id, permissions.id, $3 FROM roles, permissions WHERE roles.name = $1 AND permissions.name = $2
+        ON CONFLICT (role_id, permission_id) DO UPDATE SET active = true, last_updated_time = now(), last_updated_by = $3
+        WHERE NOT role_permissions.active"
+    )
+        .bind(role)
+        .bind(permission)
+        .bind(user_id)
+        .execute(executor).await?;
+
+    tracing::info!(role = %role, permission = %permission, user_id = %user_id, changed = result.rows_affected(), "granted permission to role");
+    Ok(())
+}
+
+pub async fn revoke_permission<'a, E>(role: &str, permission: &str, user_id: &Uuid, executor: E) -> Result<bool, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let result = sqlx::query(
+        "UPDATE role_permissions SET active = false, last_updated_time = now(), last_updated_by = $3
+        FROM roles, permissions
+        WHERE role_permissions.role_id = roles.id AND role_permissions.permission_id = permissions.id
+        AND roles.name = $1 AND permissions.name = $2 AND role_permissions.active"
+    )
+        .bind(role)
+        .bind(permission)
+        .bind(user_id)
+        .execute(executor).await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_user_roles<'a, E>(user_id: &Uuid, executor: E) -> Result<Vec<String>, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let rows: Vec<(String,)> = sqlx::query_as(
+        "SELECT roles.name FROM user_roles JOIN roles ON roles.id = user_roles.role_id
+        WHERE user_roles.user_id = $1 AND user_roles.active AND roles.active
+        ORDER BY roles.name"
+    )
+        .bind(user_id)
+        .fetch_all(executor).await?;
+    Ok(rows.into_iter().map(|(name,)| name).collect())
+}
+
+pub async fn get_user_permissions<'a, E>(user_id: &Uuid, executor: E) -> Result<HashSet<String>, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let rows: Vec<(String,)> = sqlx::query_as(
+        "SELECT DISTINCT permissions.name FROM user_roles
+        JOIN roles ON roles.id = user_roles.role_id
+        JOIN role_permissions ON role_permissions.role_id = roles.id
+        JOIN permissions ON permissions.id = role_permissions.permission_id
+        WHERE user_roles.user_id = $1 AND user_roles.active AND roles.active AND role_permissions.active AND permissions.active"
+    )
+        .bind(user_id)
+        .fetch_all(executor).await?;
+    Ok(rows.into_iter().map(|(name,)| name).collect())
+}
+
+struct CachedGrants {
+    roles: Vec<String>,
+    permissions: HashSet<String>,
+    loaded: Instant,
+}
+
+// Caches each user's roles and permissions for a short time, role and permission changes made through
+// this struct invalidate the affected users straight away, changes made elsewhere show up after the ttl.
+pub struct Rbac {
+    ttl: Duration,
+    max_entries: usize,
+    cache: Mutex<HashMap<Uuid, CachedGrants>>,
+}
+
+impl Rbac {
+    pub fn new() -> Self {
+        Rbac {
+            ttl: Duration::from_secs(60),
+            max_entries: 10_000,
+            cache: Mutex::new(HashMap::new())
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    async fn load(&self, user_id: &Uuid, pool: &Pool<Postgres>) -> Result<(Vec<String>, HashSet<String>), BurchillPostgresError> {
+        {
+            let cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(cached) = cache.get(user_id) {
+                if cached.loaded.elapsed() < self.ttl {
+                    return Ok((cached.roles.to_owned(), cached.permissions.to_owned()));
+                }
+            }
+        }
+
+        let roles = get_user_roles(user_id, pool).await?;
+        let permissions = get_user_permissions(user_id, pool).await?;
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        if cache.len() >= self.max_entries && !cache.contains_key(user_id) {
+            // Expired entries go first, if that isn't enough the oldest one makes room.
+            let ttl = self.ttl;
+            cache.retain(|_, cached| cached.loaded.elapsed() < ttl);
+            if cache.len() >= self.max_entries {
+                let oldest = cache.iter().min_by_key(|(_, cached)| cached.loaded).map(|(id, _)| *id);
+                if let Some(oldest) = oldest {
+                    cache.remove(&oldest);
+                }
+            }
+        }
+        if self.max_entries > 0 {
+            cache.insert(*user_id, CachedGrants {
+                roles: roles.to_owned(),
+                permissions: permissions.to_owned(),
+                loaded: Instant::now()
+            });
+        }
+        Ok((roles, permissions))
+    }
+
+    pub async fn has_permission(&self, user_id: &Uuid, permission: &str, pool: &Pool<Postgres>) -> Result<bool, BurchillPostgresError> {
+        let (_, permissions) = self.load(user_id, pool).await?;
+        Ok(permissions.contains(permission))
+    }
+
+    pub async fn require_permission(&self, user_id: &Uuid, permission: &str, pool: &Pool<Postgres>) -> Result<(), BurchillPostgresError> {
+        if self.has_permission(user_id, permission, pool).await? {
+            return Ok(());
+        }
+
+        tracing::info!(user_id = %user_id, permission = %permission, "permission denied");
+        Err(BurchillPostgresError::MissingPermission {
+            user_id: *user_id,
+            permission: permission.to_owned()
+        })
+    }
+
+    // Builds the actor the access policies are checked against.
+    pub async fn load_actor(&self, user_id: &Uuid, pool: &Pool<Postgres>) -> Result<Actor, BurchillPostgresError> {
+        let (roles, _) = self.load(user_id, pool).await?;
+        Ok(Actor::new(*user_id).with_roles(roles))
+    }
+
+    pub async fn assign_role(&self, user_id: &Uuid, role: &str, assigned_by: &Uuid, pool: &Pool<Postgres>) -> Result<(), BurchillPostgresError> {
+        sqlx::query(
+            "INSERT INTO user_roles (user_id, role_id, created_by)
+            SELECT $1, roles.id, $3 FROM roles WHERE roles.name = $2
+            ON CONFLICT (user_id, role_id) DO UPDATE SET active = true, last_updated_time = now(), last_updated_by = $3
+            WHERE NOT user_roles.active"
+        )
+            .bind(user_id)
+            .bind(role)
+            .bind(assigned_by)
+            .execute(pool).await?;
+
+        tracing::info!(user_id = %user_id, role = %role, assigned_by = %assigned_by, "assigned role");
+        self.invalidate_user(user_id);
+        Ok(())
+    }
+
+    pub async fn unassign_role(&self, user_id: &Uuid, role: &str, unassigned_by: &Uuid, pool: &Pool<Postgres>) -> Result<bool, BurchillPostgresError> {
+        let result = sqlx::query(
+            "UPDATE user_roles SET active = false, last_updated_time = now(), last_updated_by = $3
+            FROM roles WHERE user_roles.role_id = roles.id AND user_roles.user_id = $1 AND roles.name = $2 AND user_roles.active"
+        )
+            .bind(user_id)
+            .bind(role)
+            .bind(unassigned_by)
+            .execute(pool).await?;
+
+        tracing::info!(user_id = %user_id, role = %role, unassigned_by = %unassigned_by, "unassigned role");
+        self.invalidate_user(user_id);
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Use these rather than the free functions so users holding the role are invalidated.
+    pub async fn grant_permission(&self, role: &str, permission: &str, user_id: &Uuid, pool: &Pool<Postgres>) -> Result<(), BurchillPostgresError> {
+        grant_permission(role, permission, user_id, pool).await?;
+        self.invalidate_role(role);
+        Ok(())
+    }
+
+    pub async fn revoke_permission(&self, role: &str, permission: &str, user_id: &Uuid, pool: &Pool<Postgres>) -> Result<bool, BurchillPostgresError> {
+        let revoked = revoke_permission(role, permission, user_id, pool).await?;
+        tracing::info!(role = %role, permission = %permission, user_id = %user_id, revoked, "revoked permission from role");
+        self.invalidate_role(role);
+        Ok(revoked)
+    }
+
+    pub fn invalidate_role(&self, role: &str) {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, cached| !cached.roles.iter().any(|name| name == role));
+    }
+
+    pub fn invalidate_user(&self, user_id: &Uuid) {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner).remove(user_id);
+    }
+
+    pub fn clear_cache(&self) {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+}
+
+impl Default for Rbac {
+    fn default() -> Self {
+        Rbac::new()
+    }
+}
+
+// Access policy backed by permissions named "<resource>.read", "<resource>.create" and so on.
+pub struct PermissionPolicy {
+    rbac: Arc<Rbac>,
+    pool: Pool<Postgres>,
+    resource: String,
+}
+
+impl PermissionPolicy {
+    pub fn new(rbac: Arc<Rbac>, pool: Pool<Postgres>, resource: &str) -> Self {
+        PermissionPolicy {
+            rbac,
+            pool,
+            resource: resource.to_owned()
+        }
+    }
+
+    pub fn get_permission_name(&self, action: &str) -> String {
+        format!("{}.{}", self.resource, action)
+    }
+
+    async fn check(&self, actor: &Actor, action: &str) -> anyhow::Result<bool> {
+        Ok(self.rbac.has_permission(&actor.user_id, &self.get_permission_name(action), &self.pool).await?)
+    }
+}
+
+#[async_trait]
+impl<T> AccessPolicy<T> for PermissionPolicy
+where T: Sync {
+    async fn can_read(&self, actor: &Actor, _entity: &T) -> anyhow::Result<bool> {
+        self.check(actor, "read").await
+    }
+
+    async fn can_create(&self, actor: &Actor, _entity: &T) -> anyhow::Result<bool> {
+        self.check(actor, "create").await
+    }
+
+    async fn can_update(&self, actor: &Actor, _entity: &T) -> anyhow::Result<bool> {
+        self.check(actor, "update").await
+    }
+
+    async fn can_delete(&self, actor: &Actor, _entity: &T) -> anyhow::Result<bool> {
+        self.check(actor, "delete").await
+    }
+}
@@ -0,0 +1,134 @@
+use futures::future::BoxFuture;
+use quaint::prelude::Select;
+use sqlx::{FromRow, Pool, Postgres, Transaction, postgres::PgRow};
+use uuid::{Uuid};
+use crate::postgres::{BurchillPostgresError, access::Actor, fetch_all, fetch_one, fetch_optional, criteria::{FindManyOptions, Page}, repository::PostgresRepository};
+
+
+// Only hands out read operations, the transaction itself is never exposed so entity saves
+// and update helpers can't be called with it. The database enforces the same thing underneath.
+pub struct ReadOnlyTransaction {
+    transaction: Transaction<'static, Postgres>
+}
+
+impl ReadOnlyTransaction {
+    // DEFERRABLE waits for a snapshot that can't be affected by concurrent serializable
+    // transactions, useful for long reports. Both work against hot standby replicas.
+    pub async fn begin(pool: &Pool<Postgres>, deferrable: bool) -> Result<Self, BurchillPostgresError> {
+        let mut transaction = pool.begin().await?;
+        let statement = if deferrable {
+            "SET TRANSACTION ISOLATION LEVEL SERIALIZABLE, READ ONLY, DEFERRABLE"
+        } else {
+            "SET TRANSACTION READ ONLY"
+        };
+        sqlx::query(statement).execute(&mut transaction).await?;
+
+        Ok(ReadOnlyTransaction { transaction })
+    }
+
+    pub async fn find_one<T, R>(&mut self, repository: &R, id: &Uuid) -> Result<T, BurchillPostgresError>
+    where R: PostgresRepository<T> + Sync {
+        repository.find_one(&mut self.transaction, id).await.map_err(BurchillPostgresError::AnyhowError)
+    }
+
+    pub async fn find_one_as<T, R>(&mut self, repository: &R, id: &Uuid, actor: &Actor) -> Result<T, BurchillPostgresError>
+    where
+        R: PostgresRepository<T> + Sync,
+        T: Send + Sync
+    {
+        repository.find_one_as(&mut self.transaction, id, actor).await
+    }
+
+    pub async fn find_many<T, R>(&mut self, repository: &R, options: &FindManyOptions) -> Result<Vec<T>, BurchillPostgresError>
+    where
+        R: PostgresRepository<T> + Sync,
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin
+    {
+        repository.find_many(&mut self.transaction, options).await
+    }
+
+    pub async fn find_many_as<T, R>(&mut self, repository: &R, options: &FindManyOptions, actor: &Actor) -> Result<Vec<T>, BurchillPostgresError>
+    where
+        R: PostgresRepository<T> + Sync,
+        T: for<'r> FromRow<'r, PgRow> + Send + Sync + Unpin
+    {
+        repository.find_many_as(&mut self.transaction, options, actor).await
+    }
+
+    pub async fn count<T, R>(&mut self, repository: &R, options: &FindManyOptions) -> Result<i64, BurchillPostgresError>
+    where R: PostgresRepository<T> + Sync {
+        repository.count(&mut self.transaction, options).await
+    }
+
+    // find_page needs a Copy executor, so the two queries are made here instead.
+    pub async fn find_page<T, R>(&mut self, repository: &R, options: &FindManyOptions) -> Result<Page<T>, BurchillPostgresError>
+    where
+        R: PostgresRepository<T> + Sync,
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin
+    {
+        let items = repository.find_many(&mut self.transaction, options).await?;
+        let total = if options.with_total {
+            Some(repository.count(&mut self.transaction, options).await?)
+        } else {
+            None
+        };
+        Ok(options.create_page(items, total))
+    }
+
+    pub async fn find_page_as<T, R>(&mut self, repository: &R, options: &FindManyOptions, actor: &Actor) -> Result<Page<T>, BurchillPostgresError>
+    where
+        R: PostgresRepository<T> + Sync,
+        T: for<'r> FromRow<'r, PgRow> + Send + Sync + Unpin
+    {
+        let items = repository.find_many_as(&mut self.transaction, options, actor).await?;
+        let total = if options.with_total {
+            Some(repository.count(&mut self.transaction, options).await?)
+        } else {
+            None
+        };
+        Ok(options.create_page(items, total))
+    }
+
+    pub async fn get_etag<T, R>(&mut self, repository: &R, id: &Uuid) -> Result<String, BurchillPostgresError>
+    where R: PostgresRepository<T> + Sync {
+        repository.get_etag(&mut self.transaction, id).await
+    }
+
+    // Plain selects for anything the repositories don't cover, quaint's Select can't write.
+    pub async fn fetch_one<T>(&mut self, query: Select<'_>) -> Result<T, BurchillPostgresError>
+    where T: for<'r> FromRow<'r, PgRow> + Send + Unpin {
+        fetch_one(query, &mut self.transaction).await
+    }
+
+    pub async fn fetch_optional<T>(&mut self, query: Select<'_>) -> Result<Option<T>, BurchillPostgresError>
+    where T: for<'r> FromRow<'r, PgRow> + Send + Unpin {
+        fetch_optional(query, &mut self.transaction).await
+    }
+
+    pub async fn fetch_all<T>(&mut self, query: Select<'_>) -> Result<Vec<T>, BurchillPostgresError>
+    where T: for<'r> FromRow<'r, PgRow> + Send + Unpin {
+        fetch_all(query, &mut self.transaction).await
+    }
+
+    // Nothing was written so there is nothing to keep, this just releases the snapshot.
+    pub async fn finish(self) -> Result<(), BurchillPostgresError> {
+        self.transaction.commit().await?;
+        Ok(())
+    }
+}
+
+pub async fn read_only_transaction<T, F>(pool: &Pool<Postgres>, operation: F) -> Result<T, BurchillPostgresError>
+where F: for<'t> FnOnce(&'t mut ReadOnlyTransaction) -> BoxFuture<'t, Result<T, BurchillPostgresError>> {
+    let mut transaction = ReadOnlyTransaction::begin(pool, false).await?;
+    let result = operation(&mut transaction).await?;
+    transaction.finish().await?;
+    Ok(result)
+}
+
+pub async fn read_only_snapshot<T, F>(pool: &Pool<Postgres>, operation: F) -> Result<T, BurchillPostgresError>
+where F: for<'t> FnOnce(&'t mut ReadOnlyTransaction) -> BoxFuture<'t, Result<T, BurchillPostgresError>> {
+    let mut transaction = ReadOnlyTransaction::begin(pool, true).await?;
+    let result = operation(&mut transaction).await?;
+    transaction.finish().await?;
+    Ok(result)
+}
@@ -0,0 +1,167 @@
+use std::{collections::HashSet, sync::Arc};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use uuid::{Uuid};
+use crate::postgres::BurchillPostgresError;
+use crate::strings::{quote_identifier, quote_qualified_identifier};
+use crate::web::{client::{HttpRequest, HttpTransport}, response::ApiResponse};
+
+pub const DEFAULT_REFERENCE_BATCH_SIZE: i64 = 1000;
+
+
+// Answers which of the given ids still exist in the service that owns them.
+#[async_trait]
+pub trait ReferenceVerifier: Send + Sync {
+    async fn find_existing(&self, ids: &[Uuid]) -> anyhow::Result<HashSet<Uuid>>;
+}
+
+// For services that replicate their ids into a lookup table we can read.
+pub struct LookupTableVerifier {
+    pub pool: Pool<Postgres>,
+    pub table: String,
+    pub column: String,
+}
+
+#[async_trait]
+impl ReferenceVerifier for LookupTableVerifier {
+    async fn find_existing(&self, ids: &[Uuid]) -> anyhow::Result<HashSet<Uuid>> {
+        let query = format!(
+            "SELECT {column} FROM {table} WHERE {column} = ANY($1)",
+            column = quote_identifier(&self.column),
+            table = quote_qualified_identifier(&self.table)
+        );
+        let existing: Vec<(Uuid,)> = sqlx::query_as(&query)
+            .bind(ids.to_vec())
+            .fetch_all(&self.pool).await?;
+        Ok(existing.into_iter().map(|(id,)| id).collect())
+    }
+}
+
+#[derive(Serialize)]
+struct VerifyRequest<'a> {
+    ids: &'a [Uuid],
+}
+
+#[derive(Deserialize)]
+struct VerifyResponse {
+    existing: Vec<Uuid>,
+}
+
+// POSTs {"ids": [...]} and expects {"data": {"existing": [...]}} back.
+pub struct HttpReferenceVerifier<H> {
+    pub transport: H,
+    pub url: String,
+}
+
+#[async_trait]
+impl<H> ReferenceVerifier for HttpReferenceVerifier<H>
+where H: HttpTransport {
+    async fn find_existing(&self, ids: &[Uuid]) -> anyhow::Result<HashSet<Uuid>> {
+        let body = serde_json::to_vec(&VerifyRequest { ids })?;
+        let response = self.transport.send(HttpRequest::post_json(&self.url, body)).await?;
+        if !response.is_success() {
+            anyhow::bail!("Reference verification failed with status {}. (Url: {})", response.status, self.url);
+        }
+
+        let response: ApiResponse<VerifyResponse> = serde_json::from_slice(&response.body)?;
+        Ok(response.data.existing.into_iter().collect())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DanglingReference {
+    pub row_id: Uuid,
+    pub referenced_id: Uuid,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReferenceReport {
+    pub table: String,
+    pub column: String,
+    pub checked_ids: usize,
+    pub dangling: Vec<DanglingReference>,
+}
+
+impl ReferenceReport {
+    pub fn is_ok(&self) -> bool {
+        self.dangling.is_empty()
+    }
+}
+
+// A column holding ids owned by another service, where no foreign key is possible.
+#[derive(Clone)]
+pub struct SoftReference {
+    pub table: String,
+    pub column: String,
+    pub verifier: Arc<dyn ReferenceVerifier>,
+}
+
+impl SoftReference {
+    pub fn new(table: &str, column: &str, verifier: Arc<dyn ReferenceVerifier>) -> Self {
+        SoftReference {
+            table: table.to_owned(),
+            column: column.to_owned(),
+            verifier
+        }
+    }
+
+    // Walks the distinct referenced ids in batches so large tables don't need one huge request.
+    pub async fn check(&self, pool: &Pool<Postgres>, batch_size: i64) -> Result<ReferenceReport, BurchillPostgresError> {
+        let table = quote_qualified_identifier(&self.table);
+        let column = quote_identifier(&self.column);
+        let distinct_query = format!(
+            "SELECT DISTINCT {column} FROM {table} WHERE {column} IS NOT NULL AND ($1::uuid IS NULL OR {column} > $1) ORDER BY {column} LIMIT $2",
+            column = column,
+            table = table
+        );
+
+        let mut report = ReferenceReport {
+            table: self.table.to_owned(),
+            column: self.column.to_owned(),
+            ..ReferenceReport::default()
+        };
+        let mut missing: Vec<Uuid> = Vec::new();
+        let mut after: Option<Uuid> = None;
+
+        loop {
+            let ids: Vec<(Uuid,)> = sqlx::query_as(&distinct_query)
+                .bind(after)
+                .bind(batch_size.max(1))
+                .fetch_all(pool).await?;
+            if ids.is_empty() {
+                break;
+            }
+
+            let ids: Vec<Uuid> = ids.into_iter().map(|(id,)| id).collect();
+            let existing = self.verifier.find_existing(&ids).await?;
+            missing.extend(ids.iter().filter(|id| !existing.contains(id)));
+            report.checked_ids += ids.len();
+            after = ids.last().copied();
+        }
+
+        if !missing.is_empty() {
+            let rows_query = format!("SELECT id, {column} FROM {table} WHERE {column} = ANY($1) ORDER BY id", column = column, table = table);
+            let rows: Vec<(Uuid, Uuid)> = sqlx::query_as(&rows_query)
+                .bind(missing)
+                .fetch_all(pool).await?;
+            report.dangling = rows.into_iter()
+                .map(|(row_id, referenced_id)| DanglingReference { row_id, referenced_id })
+                .collect();
+        }
+
+        if !report.is_ok() {
+            tracing::warn!(table = %self.table, column = %self.column, dangling = report.dangling.len(), "dangling cross service references");
+        }
+        Ok(report)
+    }
+}
+
+// Meant to be run on a schedule by whatever job runner the app uses.
+pub async fn check_references(references: &[SoftReference], pool: &Pool<Postgres>) -> Result<Vec<ReferenceReport>, BurchillPostgresError> {
+    let mut reports = Vec::new();
+    for reference in references.iter() {
+        reports.push(reference.check(pool, DEFAULT_REFERENCE_BATCH_SIZE).await?);
+    }
+    Ok(reports)
+}
@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use sqlx::postgres::PgConnectOptions;
+use crate::postgres::{BurchillPostgresError, pool::NamedPool, repository::PostgresRepository};
+
+pub const DEFAULT_DATABASE: &str = "default";
+
+
+#[derive(Clone, Debug)]
+pub struct DatabaseConfig {
+    pub name: String,
+    pub options: PgConnectOptions,
+    pub max_connections: u32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DatabaseRegistry {
+    pools: HashMap<String, NamedPool>
+}
+
+impl DatabaseRegistry {
+    pub fn new() -> Self {
+        DatabaseRegistry::default()
+    }
+
+    pub async fn from_configs(configs: Vec<DatabaseConfig>) -> Result<Self, BurchillPostgresError> {
+        let mut registry = DatabaseRegistry::new();
+        for config in configs.into_iter() {
+            let pool = NamedPool::connect(&config.name, config.options, config.max_connections).await?;
+            registry.register(pool);
+        }
+        Ok(registry)
+    }
+
+    pub fn register(&mut self, pool: NamedPool) {
+        self.pools.insert(pool.get_name().to_owned(), pool);
+    }
+
+    pub fn get(&self, name: &str) -> Result<&NamedPool, BurchillPostgresError> {
+        match self.pools.get(name) {
+            Some(pool) => Ok(pool),
+            None => Err(BurchillPostgresError::MissingDatabases(vec![name.to_owned()]))
+        }
+    }
+
+    pub fn get_for_repository<R, T>(&self, repository: &R) -> Result<&NamedPool, BurchillPostgresError>
+    where R: PostgresRepository<T> {
+        self.get(repository.get_database_name())
+    }
+
+    pub fn get_names(&self) -> Vec<&str> {
+        self.pools.keys().map(|name| name.as_str()).collect()
+    }
+
+    // Meant to be called once at startup with every database the service's repositories declare.
+    pub fn validate(&self, required: &[&str]) -> Result<(), BurchillPostgresError> {
+        let mut missing: Vec<String> = required.iter()
+            .filter(|name| !self.pools.contains_key(**name))
+            .map(|name| name.to_string())
+            .collect();
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        missing.sort();
+        missing.dedup();
+        Err(BurchillPostgresError::MissingDatabases(missing))
+    }
+}
@@ -1,15 +1,166 @@
-use quaint::prelude::Select;
-use sqlx::{Executor, Postgres};
+use quaint::prelude::{Aliasable, Comparable, Select, asterisk, count};
+use sqlx::{Executor, FromRow, Postgres, postgres::PgRow};
 use anyhow::Result;
 use async_trait::async_trait;
+use std::{any::type_name, sync::Arc};
 use uuid::{Uuid};
+use chrono::{DateTime, Utc};
+use crate::postgres::{BurchillPostgresError, access::{AccessAction, AccessPolicy, Actor, check_access, filter_readable}, entity_stats::{EntityOperation, EntityStatsRegistry}, fetch_all, fetch_one, criteria::{FindManyOptions, Page}, etag, registry::DEFAULT_DATABASE};
 
 #[async_trait]
 pub trait PostgresRepository<T> {
     fn new() -> Self;
 
+    // Needed by the list, count and etag methods, a repository that only implements find_one can leave it out.
+    fn get_table_name(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn require_table_name(&self) -> Result<&'static str, BurchillPostgresError> {
+        self.get_table_name().ok_or_else(|| BurchillPostgresError::AnyhowError(anyhow::anyhow!("{} has no table name, implement get_table_name to use it.", type_name::<Self>())))
+    }
+
+    // Used for stats and access errors, the same name PostgresEntity::get_stats_name defaults to.
+    fn get_entity_name(&self) -> &'static str {
+        self.get_table_name().unwrap_or_else(type_name::<T>)
+    }
+
+    fn get_database_name(&self) -> &'static str {
+        DEFAULT_DATABASE
+    }
+
+    // Only the base fields unless the repository adds its own columns here.
+    fn add_entity_fields_to_select<'a>(&self, query: Select<'a>) -> Select<'a> {
+        query
+    }
+
+    fn create_select_query<'a>(&self) -> Result<Select<'a>, BurchillPostgresError> {
+        Ok(self.add_entity_fields_to_select(add_base_fields_to_select(Select::from_table(self.require_table_name()?))))
+    }
+
     async fn find_one<'b, E>(&self, executor: E, id: &Uuid) -> Result<T>
     where E: Executor<'b, Database = Postgres>;
+
+    fn get_access_policy(&self) -> Option<Arc<dyn AccessPolicy<T>>> {
+        None
+    }
+
+    fn is_deny_by_default(&self) -> bool {
+        false
+    }
+
+    fn get_stats_registry(&self) -> Option<Arc<EntityStatsRegistry>> {
+        None
+    }
+
+    // find_one checked against the repository's access policy, background jobs pass Actor::system to skip it.
+    async fn find_one_as<'b, E>(&self, executor: E, id: &Uuid, actor: &Actor) -> Result<T, BurchillPostgresError>
+    where
+        E: Executor<'b, Database = Postgres>,
+        T: Send + Sync
+    {
+        let entity = match self.get_stats_registry() {
+            Some(registry) => registry.measure(self.get_entity_name(), EntityOperation::Read, self.find_one(executor, id)).await?,
+            None => self.find_one(executor, id).await?
+        };
+        let policy = self.get_access_policy();
+        check_access(policy.as_ref(), self.is_deny_by_default(), actor, AccessAction::Read, self.get_entity_name(), Some(*id), &entity).await?;
+        Ok(entity)
+    }
+
+    // Rows the actor can't read are left out rather than failing the whole list.
+    async fn find_many_as<'b, E>(&self, executor: E, options: &FindManyOptions, actor: &Actor) -> Result<Vec<T>, BurchillPostgresError>
+    where
+        E: Executor<'b, Database = Postgres>,
+        T: for<'r> FromRow<'r, PgRow> + Send + Sync + Unpin
+    {
+        let entities = self.find_many(executor, options).await?;
+        let policy = self.get_access_policy();
+        filter_readable(policy.as_ref(), self.is_deny_by_default(), actor, entities).await
+    }
+
+    async fn find_many<'b, E>(&self, executor: E, options: &FindManyOptions) -> Result<Vec<T>, BurchillPostgresError>
+    where
+        E: Executor<'b, Database = Postgres>,
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin
+    {
+        let query = options.add_to_select(self.create_select_query()?);
+        match self.get_stats_registry() {
+            Some(registry) => registry.measure_many(self.get_entity_name(), fetch_all(query, executor)).await,
+            None => fetch_all(query, executor).await
+        }
+    }
+
+    async fn count<'b, E>(&self, executor: E, options: &FindManyOptions) -> Result<i64, BurchillPostgresError>
+    where E: Executor<'b, Database = Postgres> {
+        let query = Select::from_table(self.require_table_name()?).value(count(asterisk()).alias("count"));
+        let query = options.add_criteria_to_select(query);
+
+        let (total,): (i64,) = match self.get_stats_registry() {
+            Some(registry) => registry.measure(self.get_entity_name(), EntityOperation::Read, fetch_one(query, executor)).await?,
+            None => fetch_one(query, executor).await?
+        };
+        Ok(total)
+    }
+
+    async fn get_last_modified_time<'b, E>(&self, executor: E, id: &Uuid) -> Result<DateTime<Utc>, BurchillPostgresError>
+    where E: Executor<'b, Database = Postgres> {
+        let query = Select::from_table(self.require_table_name()?)
+            .column("last_updated_time")
+            .column("created_time")
+            .so_that("id".equals(id.to_owned()));
+
+        let (last_updated_time, created_time): (Option<DateTime<Utc>>, DateTime<Utc>) = match self.get_stats_registry() {
+            Some(registry) => registry.measure(self.get_entity_name(), EntityOperation::Read, fetch_one(query, executor)).await?,
+            None => fetch_one(query, executor).await?
+        };
+        Ok(last_updated_time.unwrap_or(created_time))
+    }
+
+    // Cheap check for conditional requests that only reads the audit columns.
+    async fn is_modified_since<'b, E>(&self, executor: E, id: &Uuid, timestamp: &DateTime<Utc>) -> Result<bool, BurchillPostgresError>
+    where E: Executor<'b, Database = Postgres> {
+        let last_modified = self.get_last_modified_time(executor, id).await?;
+        Ok(etag::is_modified_since(&last_modified, timestamp))
+    }
+
+    async fn get_etag<'b, E>(&self, executor: E, id: &Uuid) -> Result<String, BurchillPostgresError>
+    where E: Executor<'b, Database = Postgres> {
+        let last_modified = self.get_last_modified_time(executor, id).await?;
+        Ok(etag::create_etag(id, &last_modified))
+    }
+
+    // The total needs a second round trip so the executor has to be reusable (a pool reference is).
+    async fn find_page<'b, E>(&self, executor: E, options: &FindManyOptions) -> Result<Page<T>, BurchillPostgresError>
+    where
+        E: Executor<'b, Database = Postgres> + Copy,
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin
+    {
+        let items = self.find_many(executor, options).await?;
+        let total = if options.with_total {
+            Some(self.count(executor, options).await?)
+        } else {
+            None
+        };
+
+        Ok(options.create_page(items, total))
+    }
+
+    // Counts before the access policy, so a page can hold fewer items than the total suggests.
+    async fn find_page_as<'b, E>(&self, executor: E, options: &FindManyOptions, actor: &Actor) -> Result<Page<T>, BurchillPostgresError>
+    where
+        E: Executor<'b, Database = Postgres> + Copy,
+        T: for<'r> FromRow<'r, PgRow> + Send + Sync + Unpin
+    {
+        let items = self.find_many_as(executor, options, actor).await?;
+        let total = if options.with_total {
+            Some(self.count(executor, options).await?)
+        } else {
+            None
+        };
+
+        Ok(options.create_page(items, total))
+    }
 }
 
 pub fn add_base_fields_to_select(query: Select) -> Select {
@@ -0,0 +1,280 @@
+use std::{collections::HashMap, future::Future, hash::Hash, sync::{Arc, Mutex, PoisonError}, time::{Duration, Instant}};
+use chrono::{DateTime, Utc};
+use sqlx::{FromRow, Pool, Postgres, postgres::PgRow};
+use uuid::{Uuid};
+use crate::postgres::{BurchillPostgresError, access::{AccessAction, Actor, check_access, filter_readable}, criteria::FindManyOptions, repository::PostgresRepository};
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+struct BreakerState {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened: Option<Instant>,
+    probe_started: Option<Instant>,
+}
+
+// One breaker per database, shared by every reader that talks to it.
+pub struct CircuitBreaker {
+    name: String,
+    failure_threshold: u32,
+    open_duration: Duration,
+    inner: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str) -> Self {
+        CircuitBreaker {
+            name: name.to_owned(),
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(30),
+            inner: Mutex::new(BreakerState {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened: None,
+                probe_started: None
+            })
+        }
+    }
+
+    pub fn with_failure_threshold(mut self, failure_threshold: u32) -> Self {
+        self.failure_threshold = failure_threshold.max(1);
+        self
+    }
+
+    pub fn with_open_duration(mut self, open_duration: Duration) -> Self {
+        self.open_duration = open_duration;
+        self
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_state(&self) -> CircuitState {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner).state
+    }
+
+    // An open breaker lets a single probe request through (half open) once open_duration has passed,
+    // everything else is refused until that probe records its outcome. A probe that never reports back
+    // (e.g. the future was dropped) is replaced after another open_duration.
+    pub fn allow_request(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        match (inner.state, inner.opened, inner.probe_started) {
+            (CircuitState::Open, Some(opened), _) if opened.elapsed() >= self.open_duration => {
+                inner.state = CircuitState::HalfOpen;
+                inner.probe_started = Some(Instant::now());
+                true
+            }
+            (CircuitState::Open, _, _) => false,
+            (CircuitState::HalfOpen, _, Some(probe_started)) if probe_started.elapsed() < self.open_duration => false,
+            (CircuitState::HalfOpen, _, _) => {
+                inner.probe_started = Some(Instant::now());
+                true
+            }
+            (CircuitState::Closed, _, _) => true
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if inner.state != CircuitState::Closed {
+            tracing::info!(breaker = %self.name, "circuit breaker closed");
+        }
+        inner.state = CircuitState::Closed;
+        inner.consecutive_failures = 0;
+        inner.opened = None;
+        inner.probe_started = None;
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.consecutive_failures += 1;
+        if inner.state == CircuitState::HalfOpen || inner.consecutive_failures >= self.failure_threshold {
+            if inner.state != CircuitState::Open {
+                tracing::warn!(breaker = %self.name, failures = inner.consecutive_failures, "circuit breaker opened");
+            }
+            inner.state = CircuitState::Open;
+            inner.opened = Some(Instant::now());
+        }
+        inner.probe_started = None;
+    }
+}
+
+// Only errors that mean the database could not be reached count against the breaker,
+// a constraint violation or a missing row says nothing about availability.
+pub fn is_unavailable_error(err: &sqlx::Error) -> bool {
+    matches!(err, sqlx::Error::Io(_) | sqlx::Error::Tls(_) | sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed | sqlx::Error::WorkerCrashed)
+}
+
+fn is_unavailable(err: &BurchillPostgresError) -> bool {
+    match err {
+        BurchillPostgresError::SqlxError(err) => is_unavailable_error(err),
+        BurchillPostgresError::AnyhowError(err) => match err.downcast_ref::<sqlx::Error>() {
+            Some(err) => is_unavailable_error(err),
+            None => matches!(err.downcast_ref::<BurchillPostgresError>(), Some(BurchillPostgresError::SqlxError(err)) if is_unavailable_error(err))
+        },
+        _ => false
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResilientRead<T> {
+    pub value: T,
+    // Set when the value came from the cache because the database was unavailable.
+    pub stale_since: Option<DateTime<Utc>>,
+}
+
+impl<T> ResilientRead<T> {
+    pub fn is_stale(&self) -> bool {
+        self.stale_since.is_some()
+    }
+
+    pub fn into_value(self) -> T {
+        self.value
+    }
+}
+
+struct StaleCache<K, T> {
+    entries: Mutex<HashMap<K, (T, DateTime<Utc>)>>,
+    max_entries: usize,
+}
+
+impl<K, T> StaleCache<K, T>
+where
+    K: Clone + Eq + Hash,
+    T: Clone
+{
+    fn get(&self, key: &K) -> Option<(T, DateTime<Utc>)> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner).get(key).cloned()
+    }
+
+    // Past max_entries the oldest value is dropped, this is a fallback and not meant to hold everything.
+    fn insert(&self, key: K, value: T) {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let oldest = entries.iter().min_by_key(|(_, (_, fetched))| *fetched).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key, (value, Utc::now()));
+    }
+
+    fn remove(&self, key: &K) {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner).remove(key);
+    }
+}
+
+async fn read_through<K, T, F>(breaker: &CircuitBreaker, cache: &StaleCache<K, T>, key: K, read: F) -> Result<ResilientRead<T>, BurchillPostgresError>
+where
+    K: Clone + Eq + Hash,
+    T: Clone,
+    F: Future<Output = Result<T, BurchillPostgresError>>
+{
+    if !breaker.allow_request() {
+        return match cache.get(&key) {
+            Some((value, fetched)) => Ok(ResilientRead { value, stale_since: Some(fetched) }),
+            None => Err(BurchillPostgresError::CircuitOpen(breaker.get_name().to_owned()))
+        };
+    }
+
+    match read.await {
+        Ok(value) => {
+            breaker.record_success();
+            cache.insert(key, value.clone());
+            Ok(ResilientRead { value, stale_since: None })
+        }
+        Err(err) if is_unavailable(&err) => {
+            breaker.record_failure();
+            match cache.get(&key) {
+                Some((value, fetched)) => {
+                    tracing::warn!(breaker = %breaker.get_name(), error = %err, cached_time = %fetched, "database unavailable, serving a stale cached value");
+                    Ok(ResilientRead { value, stale_since: Some(fetched) })
+                }
+                None => Err(err)
+            }
+        }
+        Err(err) => {
+            breaker.record_success();
+            Err(err)
+        }
+    }
+}
+
+// Opt in wrapper for read mostly endpoints that would rather show stale data than an error.
+// Writes should keep going straight through the repository and call invalidate afterwards.
+pub struct ResilientReader<R, T> {
+    repository: R,
+    breaker: Arc<CircuitBreaker>,
+    entities: StaleCache<Uuid, T>,
+    lists: StaleCache<String, Vec<T>>,
+}
+
+impl<R, T> ResilientReader<R, T>
+where
+    R: PostgresRepository<T> + Sync,
+    T: for<'r> FromRow<'r, PgRow> + Clone + Send + Sync + Unpin
+{
+    pub fn new(repository: R, breaker: Arc<CircuitBreaker>) -> Self {
+        ResilientReader {
+            repository,
+            breaker,
+            entities: StaleCache { entries: Mutex::new(HashMap::new()), max_entries: 10_000 },
+            lists: StaleCache { entries: Mutex::new(HashMap::new()), max_entries: 1_000 }
+        }
+    }
+
+    pub fn with_max_entries(mut self, max_entities: usize, max_lists: usize) -> Self {
+        self.entities.max_entries = max_entities.max(1);
+        self.lists.max_entries = max_lists.max(1);
+        self
+    }
+
+    pub fn get_breaker(&self) -> &CircuitBreaker {
+        &self.breaker
+    }
+
+    pub fn get_repository(&self) -> &R {
+        &self.repository
+    }
+
+    pub async fn find_one(&self, pool: &Pool<Postgres>, id: &Uuid) -> Result<ResilientRead<T>, BurchillPostgresError> {
+        read_through(&self.breaker, &self.entities, *id, async {
+            self.repository.find_one(pool, id).await.map_err(BurchillPostgresError::from)
+        }).await
+    }
+
+    // The caches hold what was read before the access policy, so a cached value is checked
+    // for each actor the same as a fresh one.
+    pub async fn find_one_as(&self, pool: &Pool<Postgres>, id: &Uuid, actor: &Actor) -> Result<ResilientRead<T>, BurchillPostgresError> {
+        let read = self.find_one(pool, id).await?;
+        let policy = self.repository.get_access_policy();
+        check_access(policy.as_ref(), self.repository.is_deny_by_default(), actor, AccessAction::Read, self.repository.get_entity_name(), Some(*id), &read.value).await?;
+        Ok(read)
+    }
+
+    pub async fn find_many(&self, pool: &Pool<Postgres>, options: &FindManyOptions) -> Result<ResilientRead<Vec<T>>, BurchillPostgresError> {
+        let key = format!("{:?}", options);
+        read_through(&self.breaker, &self.lists, key, self.repository.find_many(pool, options)).await
+    }
+
+    pub async fn find_many_as(&self, pool: &Pool<Postgres>, options: &FindManyOptions, actor: &Actor) -> Result<ResilientRead<Vec<T>>, BurchillPostgresError> {
+        let read = self.find_many(pool, options).await?;
+        let policy = self.repository.get_access_policy();
+        Ok(ResilientRead {
+            value: filter_readable(policy.as_ref(), self.repository.is_deny_by_default(), actor, read.value).await?,
+            stale_since: read.stale_since
+        })
+    }
+
+    pub fn invalidate(&self, id: &Uuid) {
+        self.entities.remove(id);
+        self.lists.entries.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+}
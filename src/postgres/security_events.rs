@@ -0,0 +1,341 @@
+use std::{fmt, sync::{Mutex, PoisonError, atomic::{AtomicUsize, Ordering}}};
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, Postgres, types::Json};
+use uuid::{Uuid};
+use crate::postgres::BurchillPostgresError;
+use crate::time_utils::TimeRange;
+
+pub const DEFAULT_BATCH_SIZE: usize = 200;
+pub const DEFAULT_MAX_PENDING: usize = 10_000;
+
+// Rows can only ever be inserted, the triggers reject updates, deletes and truncates even from the app's own role.
+pub const CREATE_SECURITY_EVENTS_TABLE: &str = "CREATE TABLE IF NOT EXISTS security_events (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    event_type text NOT NULL,
+    user_id uuid,
+    subject text,
+    ip_address text,
+    user_agent text,
+    details jsonb NOT NULL DEFAULT '{}',
+    occurred_time timestamptz NOT NULL,
+    created_time timestamptz NOT NULL DEFAULT now(),
+    created_by uuid NOT NULL
+);
+CREATE INDEX IF NOT EXISTS security_events_time ON security_events (occurred_time DESC);
+CREATE INDEX IF NOT EXISTS security_events_user_time ON security_events (user_id, occurred_time DESC);
+CREATE INDEX IF NOT EXISTS security_events_type_time ON security_events (event_type, occurred_time DESC);
+CREATE OR REPLACE FUNCTION security_events_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'security_events is append only';
+END
+$$ LANGUAGE plpgsql;
+DROP TRIGGER IF EXISTS security_events_append_only ON security_events;
+CREATE TRIGGER security_events_append_only BEFORE UPDATE OR DELETE ON security_events
+    FOR EACH ROW EXECUTE FUNCTION security_events_append_only();
+DROP TRIGGER IF EXISTS security_events_no_truncate ON security_events;
+CREATE TRIGGER security_events_no_truncate BEFORE TRUNCATE ON security_events
+    FOR EACH STATEMENT EXECUTE FUNCTION security_events_append_only()";
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SecurityEventType {
+    LoginSucceeded,
+    LoginFailed,
+    Logout,
+    PermissionDenied,
+    ApiKeyUsed,
+    ApiKeyRejected,
+    PasswordChanged,
+    RoleChanged,
+}
+
+impl SecurityEventType {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            SecurityEventType::LoginSucceeded => "login_succeeded",
+            SecurityEventType::LoginFailed => "login_failed",
+            SecurityEventType::Logout => "logout",
+            SecurityEventType::PermissionDenied => "permission_denied",
+            SecurityEventType::ApiKeyUsed => "api_key_used",
+            SecurityEventType::ApiKeyRejected => "api_key_rejected",
+            SecurityEventType::PasswordChanged => "password_changed",
+            SecurityEventType::RoleChanged => "role_changed",
+        }
+    }
+}
+
+impl fmt::Display for SecurityEventType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.get_name())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecurityEvent {
+    pub event_type: SecurityEventType,
+    // Unknown for failed logins against usernames that don't exist, the attempted name goes in subject.
+    pub user_id: Option<Uuid>,
+    pub subject: Option<String>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: serde_json::Value,
+    pub occurred_time: DateTime<Utc>,
+}
+
+impl SecurityEvent {
+    pub fn new(event_type: SecurityEventType) -> Self {
+        SecurityEvent {
+            event_type,
+            user_id: None,
+            subject: None,
+            ip_address: None,
+            user_agent: None,
+            details: serde_json::json!({}),
+            occurred_time: Utc::now()
+        }
+    }
+
+    pub fn login_succeeded(user_id: Uuid) -> Self {
+        SecurityEvent::new(SecurityEventType::LoginSucceeded).user(user_id)
+    }
+
+    pub fn login_failed(attempted: &str) -> Self {
+        SecurityEvent::new(SecurityEventType::LoginFailed).subject(attempted)
+    }
+
+    pub fn permission_denied(user_id: Uuid, permission: &str) -> Self {
+        SecurityEvent::new(SecurityEventType::PermissionDenied).user(user_id).subject(permission)
+    }
+
+    // Only ever pass the key's id or prefix here, never the key itself.
+    pub fn api_key_used(key_id: &str) -> Self {
+        SecurityEvent::new(SecurityEventType::ApiKeyUsed).subject(key_id)
+    }
+
+    pub fn user(mut self, user_id: Uuid) -> Self {
+        self.user_id = Some(user_id);
+        self
+    }
+
+    pub fn subject(mut self, subject: &str) -> Self {
+        self.subject = Some(subject.to_owned());
+        self
+    }
+
+    pub fn client(mut self, ip_address: Option<&str>, user_agent: Option<&str>) -> Self {
+        self.ip_address = ip_address.map(|ip_address| ip_address.to_owned());
+        self.user_agent = user_agent.map(|user_agent| user_agent.to_owned());
+        self
+    }
+
+    pub fn details(mut self, details: serde_json::Value) -> Self {
+        self.details = details;
+        self
+    }
+}
+
+// Same batching as UsageRecorder, login traffic shouldn't cost an insert per request.
+// While the database is down at most max_pending events are held, the oldest are dropped past that.
+pub struct SecurityEventLog {
+    events: Mutex<Vec<SecurityEvent>>,
+    batch_size: usize,
+    max_pending: usize,
+    dropped: AtomicUsize,
+    recorded_by: Uuid,
+}
+
+impl SecurityEventLog {
+    pub fn new(recorded_by: Uuid, batch_size: usize) -> Self {
+        SecurityEventLog {
+            events: Mutex::new(Vec::new()),
+            batch_size: batch_size.max(1),
+            max_pending: DEFAULT_MAX_PENDING.max(batch_size),
+            dropped: AtomicUsize::new(0),
+            recorded_by
+        }
+    }
+
+    pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending.max(1);
+        self
+    }
+
+    // How many events have been dropped since the log was created.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn drop_overflow(&self, events: &mut Vec<SecurityEvent>) {
+        if events.len() <= self.max_pending {
+            return;
+        }
+
+        let overflow = events.len() - self.max_pending;
+        events.drain(..overflow);
+        let dropped = self.dropped.fetch_add(overflow, Ordering::Relaxed) + overflow;
+        tracing::warn!(dropped = overflow, total_dropped = dropped, max_pending = self.max_pending, "dropped security events");
+    }
+
+    // Returns true once a full batch is waiting, the caller decides when to flush.
+    pub fn record(&self, event: SecurityEvent) -> bool {
+        tracing::info!(event_type = %event.event_type, user_id = ?event.user_id, subject = ?event.subject, "security event");
+        let mut events = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+        events.push(event);
+        self.drop_overflow(&mut events);
+        events.len() >= self.batch_size
+    }
+
+    pub fn pending(&self) -> usize {
+        self.events.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    pub async fn flush<'a, E>(&self, executor: E) -> Result<usize, BurchillPostgresError>
+    where E: Executor<'a, Database = Postgres> {
+        let events = std::mem::take(&mut *self.events.lock().unwrap_or_else(PoisonError::into_inner));
+        if events.is_empty() {
+            return Ok(0);
+        }
+
+        match insert_security_events(&events, &self.recorded_by, executor).await {
+            Ok(count) => Ok(count),
+            Err(err) => {
+                let mut pending = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+                let newer = std::mem::replace(&mut *pending, events);
+                pending.extend(newer);
+                self.drop_overflow(&mut pending);
+                Err(err)
+            }
+        }
+    }
+}
+
+pub async fn insert_security_events<'a, E>(events: &[SecurityEvent], recorded_by: &Uuid, executor: E) -> Result<usize, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let event_types: Vec<String> = events.iter().map(|event| event.event_type.get_name().to_owned()).collect();
+    let user_ids: Vec<Option<Uuid>> = events.iter().map(|event| event.user_id).collect();
+    let subjects: Vec<Option<String>> = events.iter().map(|event| event.subject.to_owned()).collect();
+    let ip_addresses: Vec<Option<String>> = events.iter().map(|event| event.ip_address.to_owned()).collect();
+    let user_agents: Vec<Option<String>> = events.iter().map(|event| event.user_agent.to_owned()).collect();
+    let details: Vec<String> = events.iter().map(|event| event.details.to_string()).collect();
+    let occurred_times: Vec<DateTime<Utc>> = events.iter().map(|event| event.occurred_time).collect();
+
+    let result = sqlx::query(
+        "INSERT INTO security_events (event_type, user_id, subject, ip_address, user_agent, details, occurred_time, created_by)
+        SELECT event_type, user_id, subject, ip_address, user_agent, details::jsonb, occurred_time, $8
+        FROM UNNEST($1::text[], $2::uuid[], $3::text[], $4::text[], $5::text[], $6::text[], $7::timestamptz[])
+        AS events (event_type, user_id, subject, ip_address, user_agent, details, occurred_time)"
+    )
+        .bind(event_types)
+        .bind(user_ids)
+        .bind(subjects)
+        .bind(ip_addresses)
+        .bind(user_agents)
+        .bind(details)
+        .bind(occurred_times)
+        .bind(recorded_by)
+        .execute(executor).await?;
+    Ok(result.rows_affected() as usize)
+}
+
+#[derive(Clone, Debug, PartialEq, sqlx::FromRow)]
+pub struct StoredSecurityEvent {
+    pub id: Uuid,
+    pub event_type: String,
+    pub user_id: Option<Uuid>,
+    pub subject: Option<String>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: Json<serde_json::Value>,
+    pub occurred_time: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SecurityEventFilter {
+    pub event_types: Vec<SecurityEventType>,
+    pub user_id: Option<Uuid>,
+    pub ip_address: Option<String>,
+    pub before: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+}
+
+// Newest first, page backwards by passing the last occurred_time as before.
+pub async fn find_security_events<'a, E>(filter: &SecurityEventFilter, range: &TimeRange, executor: E) -> Result<Vec<StoredSecurityEvent>, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let event_types: Vec<String> = filter.event_types.iter().map(|event_type| event_type.get_name().to_owned()).collect();
+    let events = sqlx::query_as::<Postgres, StoredSecurityEvent>(
+        "SELECT id, event_type, user_id, subject, ip_address, user_agent, details, occurred_time FROM security_events
+        WHERE occurred_time >= $1 AND occurred_time < $2
+        AND (cardinality($3::text[]) = 0 OR event_type = ANY($3))
+        AND ($4::uuid IS NULL OR user_id = $4)
+        AND ($5::text IS NULL OR ip_address = $5)
+        AND ($6::timestamptz IS NULL OR occurred_time < $6)
+        ORDER BY occurred_time DESC LIMIT $7"
+    )
+        .bind(range.start)
+        .bind(range.end)
+        .bind(event_types)
+        .bind(filter.user_id)
+        .bind(&filter.ip_address)
+        .bind(filter.before)
+        .bind(filter.limit.unwrap_or(100))
+        .fetch_all(executor).await?;
+    Ok(events)
+}
+
+#[derive(Clone, Debug, PartialEq, sqlx::FromRow)]
+pub struct SecurityEventCount {
+    pub event_type: String,
+    pub bucket: DateTime<Utc>,
+    pub total: i64,
+}
+
+// Bucket is any date_trunc unit, e.g. "hour" or "day". Buckets are cut in UTC whatever the session time zone.
+pub async fn count_security_events<'a, E>(range: &TimeRange, bucket: &str, executor: E) -> Result<Vec<SecurityEventCount>, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let counts = sqlx::query_as::<Postgres, SecurityEventCount>(
+        "SELECT event_type, date_trunc($3, occurred_time AT TIME ZONE 'UTC') AT TIME ZONE 'UTC' AS bucket, count(*) AS total
+        FROM security_events WHERE occurred_time >= $1 AND occurred_time < $2
+        GROUP BY 1, 2 ORDER BY 2, 1"
+    )
+        .bind(range.start)
+        .bind(range.end)
+        .bind(bucket)
+        .fetch_all(executor).await?;
+    Ok(counts)
+}
+
+#[derive(Clone, Debug, PartialEq, sqlx::FromRow)]
+pub struct FailedLoginSource {
+    pub ip_address: Option<String>,
+    pub failures: i64,
+    pub distinct_subjects: i64,
+    pub last_failure_time: DateTime<Utc>,
+}
+
+// Addresses with at least min_failures failed logins, many distinct subjects usually means credential stuffing.
+pub async fn get_failed_login_sources<'a, E>(range: &TimeRange, min_failures: i64, executor: E) -> Result<Vec<FailedLoginSource>, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let sources = sqlx::query_as::<Postgres, FailedLoginSource>(
+        "SELECT ip_address, count(*) AS failures, count(DISTINCT subject) AS distinct_subjects, max(occurred_time) AS last_failure_time
+        FROM security_events WHERE event_type = $1 AND occurred_time >= $2 AND occurred_time < $3
+        GROUP BY ip_address HAVING count(*) >= $4
+        ORDER BY failures DESC"
+    )
+        .bind(SecurityEventType::LoginFailed.get_name())
+        .bind(range.start)
+        .bind(range.end)
+        .bind(min_failures)
+        .fetch_all(executor).await?;
+    Ok(sources)
+}
+
+pub async fn get_last_login_time<'a, E>(user_id: &Uuid, executor: E) -> Result<Option<DateTime<Utc>>, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let (last_login,): (Option<DateTime<Utc>>,) = sqlx::query_as(
+        "SELECT max(occurred_time) FROM security_events WHERE user_id = $1 AND event_type = $2"
+    )
+        .bind(user_id)
+        .bind(SecurityEventType::LoginSucceeded.get_name())
+        .fetch_one(executor).await?;
+    Ok(last_login)
+}
@@ -0,0 +1,219 @@
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use quaint::prelude::{Comparable, Insert, Select, SingleRowInsert, Update};
+use sqlx::{Executor, PgConnection, Pool, Postgres};
+use uuid::{Uuid};
+use crate::postgres::{BurchillPostgresError, access::Actor, criteria::{FindManyOptions, PageRequest, Sort}, delete_policy::CREATE_DELETE_AUDIT_TABLE, entity::{AccessControlledEntity, PostgresEntity, PostgresEntityManager}, fetch_all};
+
+// Temporary, so it only exists on the test's own connection and can't collide with anything real.
+const SELF_TEST_TABLE: &str = "burchill_self_test";
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelfTestStep {
+    pub name: &'static str,
+    pub passed: bool,
+    pub duration: Duration,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SelfTestReport {
+    pub steps: Vec<SelfTestStep>,
+    pub server_version: Option<String>,
+}
+
+impl SelfTestReport {
+    pub fn is_passed(&self) -> bool {
+        !self.steps.is_empty() && self.steps.iter().all(|step| step.passed)
+    }
+
+    pub fn get_failed_step(&self) -> Option<&SelfTestStep> {
+        self.steps.iter().find(|step| !step.passed)
+    }
+
+    fn record(&mut self, name: &'static str, started: Instant, result: Result<(), BurchillPostgresError>) -> bool {
+        let passed = result.is_ok();
+        let error = result.err().map(|err| err.to_string());
+        match &error {
+            Some(error) => tracing::error!(step = name, error = %error, "self test step failed"),
+            None => tracing::debug!(step = name, "self test step passed")
+        }
+
+        self.steps.push(SelfTestStep {
+            name,
+            passed,
+            duration: started.elapsed(),
+            error
+        });
+        passed
+    }
+}
+
+struct SelfTestEntity {
+    manager: PostgresEntityManager,
+    name: String,
+}
+
+#[async_trait]
+impl PostgresEntity<String> for SelfTestEntity {
+    fn new(name: String) -> Self {
+        SelfTestEntity {
+            manager: PostgresEntityManager::new(),
+            name
+        }
+    }
+
+    fn from_db(name: String, manager: PostgresEntityManager) -> Self {
+        SelfTestEntity { manager, name }
+    }
+
+    fn get_entity_manager(&self) -> &PostgresEntityManager {
+        &self.manager
+    }
+
+    fn get_mutable_entity_manager(&mut self) -> &mut PostgresEntityManager {
+        &mut self.manager
+    }
+
+    fn get_table_name(&self) -> Option<&'static str> {
+        Some(SELF_TEST_TABLE)
+    }
+
+    fn create_insert_query<'b>(&self) -> anyhow::Result<SingleRowInsert<'b>> {
+        Ok(Insert::single_into(SELF_TEST_TABLE).value("name", self.name.to_owned()))
+    }
+
+    fn create_update_query<'b>(&self) -> anyhow::Result<Update<'b>> {
+        let id = self.get_id().ok_or_else(|| anyhow::anyhow!("The self test entity has not been inserted."))?;
+        Ok(Update::table(SELF_TEST_TABLE)
+            .set("name", self.name.to_owned())
+            .set("active", self.get_active().unwrap_or(true))
+            .so_that("id".equals(id)))
+    }
+}
+
+#[async_trait]
+impl AccessControlledEntity<String> for SelfTestEntity {
+    async fn find_stored_for_update(&self, connection: &mut PgConnection) -> Result<Option<Self>, BurchillPostgresError> {
+        let row: Option<(String,)> = sqlx::query_as(&format!("SELECT name FROM {} WHERE id = $1 FOR UPDATE", SELF_TEST_TABLE))
+            .bind(self.get_id())
+            .fetch_optional(connection).await?;
+        Ok(row.map(|(name,)| SelfTestEntity::from_db(name, self.manager.to_owned())))
+    }
+}
+
+fn check(condition: bool, message: &str) -> Result<(), BurchillPostgresError> {
+    if condition {
+        Ok(())
+    } else {
+        Err(BurchillPostgresError::AnyhowError(anyhow::anyhow!("{}", message)))
+    }
+}
+
+// Runs the whole stack end to end on one pooled connection and reports every step, meant for a
+// smoke test right after a deploy. Nothing outside a temporary table is written.
+pub async fn self_test(pool: &Pool<Postgres>) -> SelfTestReport {
+    let mut report = SelfTestReport::default();
+
+    let started = Instant::now();
+    let mut connection = match pool.acquire().await {
+        Ok(connection) => {
+            report.record("connect", started, Ok(()));
+            connection
+        }
+        Err(err) => {
+            report.record("connect", started, Err(err.into()));
+            return report;
+        }
+    };
+
+    let started = Instant::now();
+    let version: Result<(String,), sqlx::Error> = sqlx::query_as("SHOW server_version").fetch_one(&mut *connection).await;
+    match version {
+        Ok((version,)) => {
+            report.server_version = Some(version);
+            report.record("query", started, Ok(()));
+        }
+        Err(err) => {
+            report.record("query", started, Err(err.into()));
+            return report;
+        }
+    }
+
+    let started = Instant::now();
+    let created = (&mut *connection).execute(format!(
+        "CREATE TEMPORARY TABLE {} (
+            id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+            name text NOT NULL,
+            created_time timestamptz NOT NULL DEFAULT now(),
+            created_by uuid NOT NULL,
+            last_updated_time timestamptz,
+            last_updated_by uuid,
+            active boolean NOT NULL DEFAULT true
+        );
+        {}",
+        SELF_TEST_TABLE,
+        // Shadows the real delete_audit for this connection, so the soft delete's audit row goes nowhere.
+        CREATE_DELETE_AUDIT_TABLE.split(';').next().unwrap_or_default().replace("CREATE TABLE IF NOT EXISTS", "CREATE TEMPORARY TABLE")
+    ).as_str()).await;
+    if !report.record("create_table", started, created.map(|_| ()).map_err(BurchillPostgresError::from)) {
+        return report;
+    }
+
+    run_entity_steps(&mut report, &mut connection).await;
+
+    let started = Instant::now();
+    let dropped = (&mut *connection).execute(format!("DROP TABLE IF EXISTS {}, pg_temp.delete_audit", SELF_TEST_TABLE).as_str()).await;
+    report.record("cleanup", started, dropped.map(|_| ()).map_err(BurchillPostgresError::from));
+    report
+}
+
+async fn run_entity_steps(report: &mut SelfTestReport, connection: &mut PgConnection) {
+    let user_id = Uuid::nil();
+    let actor = Actor::system(user_id);
+    let mut entity = SelfTestEntity::new(String::from("self test"));
+
+    let started = Instant::now();
+    let result = entity.save(&mut *connection, &user_id).await
+        .and_then(|_| check(entity.get_id().is_some() && entity.get_created_time().is_some(), "The insert did not return the audit columns."));
+    if !report.record("insert", started, result) {
+        return;
+    }
+
+    let started = Instant::now();
+    entity.name = String::from("self test updated");
+    let result = entity.save(&mut *connection, &user_id).await
+        .and_then(|_| check(entity.get_last_updated_time().is_some(), "The update did not set last_updated_time."));
+    if !report.record("update", started, result) {
+        return;
+    }
+
+    let started = Instant::now();
+    let result = entity.soft_delete(&mut *connection, &actor).await
+        .and_then(|_| check(entity.get_active() == Some(false), "The entity is still active after a soft delete."));
+    if !report.record("soft_delete", started, result) {
+        return;
+    }
+
+    let started = Instant::now();
+    let result = run_paginated_select(connection, &user_id).await;
+    report.record("paginated_select", started, result);
+}
+
+async fn run_paginated_select(connection: &mut PgConnection, user_id: &Uuid) -> Result<(), BurchillPostgresError> {
+    for name in &["a", "b", "c"] {
+        let mut entity = SelfTestEntity::new(String::from(*name));
+        entity.save(&mut *connection, user_id).await?;
+    }
+
+    // The soft deleted row from the previous step has to be filtered out of both pages.
+    let options = FindManyOptions::new()
+        .sort(Sort::ascending("name"))
+        .page(PageRequest::offset(1, 2));
+    let query = options.add_to_select(Select::from_table(SELF_TEST_TABLE).column("id").column("name"));
+    let rows: Vec<(Uuid, String)> = fetch_all(query, &mut *connection).await?;
+
+    let names: Vec<&str> = rows.iter().map(|(_, name)| name.as_str()).collect();
+    check(names == vec!["c"], &format!("Expected the second page to be [\"c\"], got {:?}.", names))
+}
@@ -0,0 +1,185 @@
+use chrono::{DateTime, Datelike, Utc};
+use sqlx::{Executor, Pool, Postgres, Transaction};
+use uuid::{Uuid};
+use crate::postgres::BurchillPostgresError;
+
+pub const CREATE_SEQUENCE_COUNTERS_TABLE: &str = "CREATE TABLE IF NOT EXISTS sequence_counters (
+    name text NOT NULL,
+    scope text NOT NULL,
+    value bigint NOT NULL,
+    last_updated_time timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (name, scope)
+)";
+
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SequenceScope {
+    pub tenant_id: Option<Uuid>,
+    pub year: Option<i32>,
+}
+
+impl SequenceScope {
+    pub fn global() -> Self {
+        SequenceScope::default()
+    }
+
+    pub fn tenant(mut self, tenant_id: Uuid) -> Self {
+        self.tenant_id = Some(tenant_id);
+        self
+    }
+
+    pub fn year(mut self, year: i32) -> Self {
+        self.year = Some(year);
+        self
+    }
+
+    pub fn current_year(self) -> Self {
+        let year = Utc::now().year();
+        self.year(year)
+    }
+
+    pub fn at(self, time: &DateTime<Utc>) -> Self {
+        self.year(time.year())
+    }
+
+    // Stable key for the counters table, e.g. "tenant=<uuid>;year=2024" or "global".
+    pub fn to_key(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(tenant_id) = &self.tenant_id {
+            parts.push(format!("tenant={}", tenant_id));
+        }
+        if let Some(year) = self.year {
+            parts.push(format!("year={}", year));
+        }
+
+        if parts.is_empty() {
+            String::from("global")
+        } else {
+            parts.join(";")
+        }
+    }
+}
+
+// Templates use {number}, {number:N} (zero padded to N digits), {year} and {name}, e.g. "INV-{year}-{number:6}".
+#[derive(Clone, Debug)]
+pub struct SequenceFormat {
+    pub template: String,
+}
+
+impl SequenceFormat {
+    pub fn new(template: &str) -> Self {
+        SequenceFormat {
+            template: template.to_owned()
+        }
+    }
+
+    pub fn format(&self, name: &str, scope: &SequenceScope, number: i64) -> String {
+        let mut output = String::with_capacity(self.template.len() + 8);
+        let mut rest = self.template.as_str();
+
+        while let Some(start) = rest.find('{') {
+            output.push_str(&rest[..start]);
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => break
+            };
+
+            let placeholder = &rest[start + 1..end];
+            match placeholder.split_once(':') {
+                Some(("number", width)) => match width.parse::<usize>() {
+                    Ok(width) => output.push_str(&format!("{:0width$}", number, width = width)),
+                    Err(_) => output.push_str(&rest[start..=end])
+                },
+                None if placeholder == "number" => output.push_str(&number.to_string()),
+                None if placeholder == "name" => output.push_str(name),
+                None if placeholder == "year" => match scope.year {
+                    Some(year) => output.push_str(&year.to_string()),
+                    None => output.push_str(&Utc::now().year().to_string())
+                },
+                _ => output.push_str(&rest[start..=end])
+            }
+            rest = &rest[end + 1..];
+        }
+
+        output.push_str(rest);
+        output
+    }
+}
+
+impl Default for SequenceFormat {
+    fn default() -> Self {
+        SequenceFormat::new("{number}")
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Sequence {
+    pub name: String,
+    pub format: SequenceFormat,
+}
+
+impl Sequence {
+    pub fn new(name: &str) -> Self {
+        Sequence {
+            name: name.to_owned(),
+            format: SequenceFormat::default()
+        }
+    }
+
+    pub fn with_format(mut self, template: &str) -> Self {
+        self.format = SequenceFormat::new(template);
+        self
+    }
+
+    // Gapless: the counter row stays locked until the callers transaction ends, so a rollback
+    // gives the number back. Concurrent writers in the same scope queue up behind each other.
+    // Takes a transaction so the increment can't autocommit and leave a gap.
+    pub async fn next_gapless(&self, scope: &SequenceScope, transaction: &mut Transaction<'_, Postgres>) -> Result<String, BurchillPostgresError> {
+        let number = increment_counter(&self.name, &scope.to_key(), &mut *transaction).await?;
+        Ok(self.format.format(&self.name, scope, number))
+    }
+
+    // Gappy: the increment commits on its own straight away so nothing waits on the counter,
+    // numbers taken by transactions that later roll back are skipped.
+    pub async fn next_gappy(&self, scope: &SequenceScope, pool: &Pool<Postgres>) -> Result<String, BurchillPostgresError> {
+        let number = increment_counter(&self.name, &scope.to_key(), pool).await?;
+        Ok(self.format.format(&self.name, scope, number))
+    }
+
+    pub async fn get_current<'a, E>(&self, scope: &SequenceScope, executor: E) -> Result<i64, BurchillPostgresError>
+    where E: Executor<'a, Database = Postgres> {
+        let value: Option<(i64,)> = sqlx::query_as("SELECT value FROM sequence_counters WHERE name = $1 AND scope = $2")
+            .bind(&self.name)
+            .bind(scope.to_key())
+            .fetch_optional(executor).await?;
+        Ok(value.map(|(value,)| value).unwrap_or(0))
+    }
+
+    // For migrating existing numbering, the counter never moves backwards.
+    pub async fn advance_to<'a, E>(&self, scope: &SequenceScope, value: i64, executor: E) -> Result<i64, BurchillPostgresError>
+    where E: Executor<'a, Database = Postgres> {
+        let (value,): (i64,) = sqlx::query_as(
+            "INSERT INTO sequence_counters (name, scope, value) VALUES ($1, $2, $3)
+            ON CONFLICT (name, scope) DO UPDATE SET value = greatest(sequence_counters.value, excluded.value), last_updated_time = now()
+            RETURNING value"
+        )
+            .bind(&self.name)
+            .bind(scope.to_key())
+            .bind(value)
+            .fetch_one(executor).await?;
+        Ok(value)
+    }
+}
+
+pub async fn increment_counter<'a, E>(name: &str, scope: &str, executor: E) -> Result<i64, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let (value,): (i64,) = sqlx::query_as(
+        "INSERT INTO sequence_counters (name, scope, value) VALUES ($1, $2, 1)
+        ON CONFLICT (name, scope) DO UPDATE SET value = sequence_counters.value + 1, last_updated_time = now()
+        RETURNING value"
+    )
+        .bind(name)
+        .bind(scope)
+        .fetch_one(executor).await?;
+    Ok(value)
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{Executor, Postgres, Transaction};
+use uuid::{Uuid};
+use crate::postgres::BurchillPostgresError;
+
+pub const CREATE_SESSIONS_TABLE: &str = "CREATE TABLE IF NOT EXISTS sessions (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id uuid NOT NULL,
+    ip_address text,
+    user_agent text,
+    created_time timestamptz NOT NULL DEFAULT now(),
+    last_seen_time timestamptz NOT NULL DEFAULT now(),
+    expires_time timestamptz NOT NULL,
+    revoked_time timestamptz,
+    revoked_reason text
+);
+CREATE INDEX IF NOT EXISTS sessions_user_active ON sessions (user_id, created_time) WHERE revoked_time IS NULL";
+
+
+#[derive(Clone, Debug, PartialEq, sqlx::FromRow)]
+pub struct Session {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_time: DateTime<Utc>,
+    pub last_seen_time: DateTime<Utc>,
+    pub expires_time: DateTime<Utc>,
+}
+
+pub async fn create_session<'a, E>(user_id: &Uuid, ip_address: Option<&str>, user_agent: Option<&str>, lifetime: Duration, executor: E) -> Result<Session, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let session = sqlx::query_as::<Postgres, Session>(
+        "INSERT INTO sessions (user_id, ip_address, user_agent, expires_time) VALUES ($1, $2, $3, $4)
+        RETURNING id, user_id, ip_address, user_agent, created_time, last_seen_time, expires_time"
+    )
+        .bind(user_id)
+        .bind(ip_address)
+        .bind(user_agent)
+        .bind(Utc::now() + lifetime)
+        .fetch_one(executor).await?;
+    Ok(session)
+}
+
+// Returns None for sessions that are unknown, expired or revoked.
+pub async fn touch_session<'a, E>(id: &Uuid, executor: E) -> Result<Option<Session>, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let session = sqlx::query_as::<Postgres, Session>(
+        "UPDATE sessions SET last_seen_time = now() WHERE id = $1 AND revoked_time IS NULL AND expires_time > now()
+        RETURNING id, user_id, ip_address, user_agent, created_time, last_seen_time, expires_time"
+    )
+        .bind(id)
+        .fetch_optional(executor).await?;
+    Ok(session)
+}
+
+pub async fn revoke_session<'a, E>(id: &Uuid, reason: &str, executor: E) -> Result<bool, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let result = sqlx::query("UPDATE sessions SET revoked_time = now(), revoked_reason = $2 WHERE id = $1 AND revoked_time IS NULL")
+        .bind(id)
+        .bind(reason)
+        .execute(executor).await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_active_sessions<'a, E>(user_id: &Uuid, executor: E) -> Result<Vec<Session>, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let sessions = sqlx::query_as::<Postgres, Session>(
+        "SELECT id, user_id, ip_address, user_agent, created_time, last_seen_time, expires_time FROM sessions
+        WHERE user_id = $1 AND revoked_time IS NULL AND expires_time > now()
+        ORDER BY created_time"
+    )
+        .bind(user_id)
+        .fetch_all(executor).await?;
+    Ok(sessions)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionLimits {
+    pub default_limit: usize,
+    pub role_limits: HashMap<String, usize>,
+}
+
+impl SessionLimits {
+    pub fn new(default_limit: usize) -> Self {
+        SessionLimits {
+            default_limit: default_limit.max(1),
+            role_limits: HashMap::new()
+        }
+    }
+
+    pub fn with_role_limit(mut self, role: &str, limit: usize) -> Self {
+        self.role_limits.insert(role.to_owned(), limit.max(1));
+        self
+    }
+
+    // A user with several roles gets the most generous of their limits.
+    pub fn get_limit(&self, roles: &[String]) -> usize {
+        roles.iter()
+            .filter_map(|role| self.role_limits.get(role))
+            .copied()
+            .max()
+            .unwrap_or(self.default_limit)
+    }
+}
+
+// Revokes the user's oldest active sessions until at most limit remain, returning the revoked ids.
+// Takes a transaction lock on the user so two logins at once can't both squeeze in under the limit.
+pub async fn enforce_session_limit(user_id: &Uuid, limit: usize, transaction: &mut Transaction<'_, Postgres>) -> Result<Vec<Uuid>, BurchillPostgresError> {
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtextextended($1::text, 0))")
+        .bind(user_id)
+        .execute(&mut *transaction).await?;
+
+    let revoked: Vec<(Uuid,)> = sqlx::query_as(
+        "UPDATE sessions SET revoked_time = now(), revoked_reason = 'session_limit'
+        WHERE id IN (
+            SELECT id FROM sessions WHERE user_id = $1 AND revoked_time IS NULL AND expires_time > now()
+            ORDER BY created_time DESC OFFSET $2
+        )
+        RETURNING id"
+    )
+        .bind(user_id)
+        .bind(limit as i64)
+        .fetch_all(&mut *transaction).await?;
+
+    let revoked: Vec<Uuid> = revoked.into_iter().map(|(id,)| id).collect();
+    if !revoked.is_empty() {
+        tracing::info!(user_id = %user_id, limit, evicted = revoked.len(), "evicted sessions over the limit");
+    }
+    Ok(revoked)
+}
+
+// Creates the session and evicts the oldest ones in the same transaction, the new session always survives.
+pub async fn create_limited_session(
+    user_id: &Uuid,
+    roles: &[String],
+    limits: &SessionLimits,
+    ip_address: Option<&str>,
+    user_agent: Option<&str>,
+    lifetime: Duration,
+    transaction: &mut Transaction<'_, Postgres>
+) -> Result<(Session, Vec<Uuid>), BurchillPostgresError> {
+    let session = create_session(user_id, ip_address, user_agent, lifetime, &mut *transaction).await?;
+    let evicted = enforce_session_limit(user_id, limits.get_limit(roles), transaction).await?;
+    Ok((session, evicted))
+}
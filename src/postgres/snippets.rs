@@ -0,0 +1,295 @@
+use quaint::Value;
+use sqlx::{Executor, FromRow, Postgres, postgres::PgRow};
+use uuid::{Uuid};
+use crate::postgres::{BurchillPostgresError, create_sqlx_arguments, criteria::{Sort, SortDirection}};
+use crate::strings::{quote_identifier, quote_qualified_identifier};
+
+
+#[derive(Clone, Debug, PartialEq)]
+enum SqlPart {
+    Text(String),
+    Param(Value<'static>),
+}
+
+// Raw SQL built from pieces where values never end up in the text, each one becomes a numbered
+// parameter when the fragment is built. Only push_sql takes text as is, so keep it to literals in code.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SqlFragment {
+    parts: Vec<SqlPart>,
+}
+
+impl SqlFragment {
+    pub fn new() -> Self {
+        SqlFragment { parts: Vec::new() }
+    }
+
+    pub fn sql(sql: &str) -> Self {
+        SqlFragment::new().push_sql(sql)
+    }
+
+    pub fn push_sql(mut self, sql: &str) -> Self {
+        self.parts.push(SqlPart::Text(sql.to_owned()));
+        self
+    }
+
+    pub fn push_identifier(mut self, identifier: &str) -> Self {
+        self.parts.push(SqlPart::Text(quote_qualified_identifier(identifier)));
+        self
+    }
+
+    pub fn push_value<V>(mut self, value: V) -> Self
+    where V: Into<Value<'static>> {
+        self.parts.push(SqlPart::Param(value.into()));
+        self
+    }
+
+    pub fn push(mut self, fragment: SqlFragment) -> Self {
+        self.parts.extend(fragment.parts);
+        self
+    }
+
+    pub fn join(fragments: Vec<SqlFragment>, separator: &str) -> Self {
+        let mut joined = SqlFragment::new();
+        for (index, fragment) in fragments.into_iter().enumerate() {
+            if index > 0 {
+                joined = joined.push_sql(separator);
+            }
+            joined = joined.push(fragment);
+        }
+        joined
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    pub fn build(self) -> (String, Vec<Value<'static>>) {
+        self.build_from(1)
+    }
+
+    // For appending to a statement that already uses parameters up to first_index - 1.
+    pub fn build_from(self, first_index: usize) -> (String, Vec<Value<'static>>) {
+        let mut sql = String::new();
+        let mut values = Vec::new();
+        for part in self.parts {
+            match part {
+                SqlPart::Text(text) => sql.push_str(&text),
+                SqlPart::Param(value) => {
+                    values.push(value);
+                    sql.push_str(&format!("${}", first_index + values.len() - 1));
+                }
+            }
+        }
+        (sql, values)
+    }
+
+    pub async fn execute<'a, E>(self, executor: E) -> Result<u64, BurchillPostgresError>
+    where E: Executor<'a, Database = Postgres> {
+        let (sql, values) = self.build();
+        let result = sqlx::query_with(&sql, create_sqlx_arguments(values)?)
+            .execute(executor).await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn fetch_all<'a, T, E>(self, executor: E) -> Result<Vec<T>, BurchillPostgresError>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+        E: Executor<'a, Database = Postgres>
+    {
+        let (sql, values) = self.build();
+        let rows = sqlx::query_as_with::<Postgres, T, _>(&sql, create_sqlx_arguments(values)?)
+            .fetch_all(executor).await?;
+        Ok(rows)
+    }
+
+    pub async fn fetch_optional<'a, T, E>(self, executor: E) -> Result<Option<T>, BurchillPostgresError>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+        E: Executor<'a, Database = Postgres>
+    {
+        let (sql, values) = self.build();
+        let row = sqlx::query_as_with::<Postgres, T, _>(&sql, create_sqlx_arguments(values)?)
+            .fetch_optional(executor).await?;
+        Ok(row)
+    }
+}
+
+fn qualified_column(alias: Option<&str>, column: &str) -> String {
+    match alias {
+        Some(alias) => format!("{}.{}", quote_identifier(alias), quote_identifier(column)),
+        None => quote_identifier(column)
+    }
+}
+
+pub fn equals<V>(column: &str, value: V) -> SqlFragment
+where V: Into<Value<'static>> {
+    SqlFragment::new().push_identifier(column).push_sql(" = ").push_value(value)
+}
+
+// An empty list matches nothing rather than producing invalid SQL.
+pub fn in_list(column: &str, values: Vec<Value<'static>>) -> SqlFragment {
+    if values.is_empty() {
+        return SqlFragment::sql("false");
+    }
+
+    let placeholders = values.into_iter().map(|value| SqlFragment::new().push_value(value)).collect();
+    SqlFragment::new()
+        .push_identifier(column)
+        .push_sql(" IN (")
+        .push(SqlFragment::join(placeholders, ", "))
+        .push_sql(")")
+}
+
+pub fn where_all(conditions: Vec<SqlFragment>) -> SqlFragment {
+    let conditions: Vec<SqlFragment> = conditions.into_iter().filter(|condition| !condition.is_empty()).collect();
+    if conditions.is_empty() {
+        return SqlFragment::new();
+    }
+
+    let wrapped = conditions.into_iter().map(|condition| SqlFragment::sql("(").push(condition).push_sql(")")).collect();
+    SqlFragment::sql(" WHERE ").push(SqlFragment::join(wrapped, " AND "))
+}
+
+pub fn active_predicate(alias: Option<&str>) -> SqlFragment {
+    SqlFragment::sql(&qualified_column(alias, "active"))
+}
+
+pub fn soft_delete(table: &str, id: &Uuid, user_id: &Uuid) -> SqlFragment {
+    set_active(table, id, user_id, false)
+}
+
+pub fn restore(table: &str, id: &Uuid, user_id: &Uuid) -> SqlFragment {
+    set_active(table, id, user_id, true)
+}
+
+fn set_active(table: &str, id: &Uuid, user_id: &Uuid, active: bool) -> SqlFragment {
+    SqlFragment::sql("UPDATE ")
+        .push_identifier(table)
+        .push_sql(" SET active = ")
+        .push_value(active)
+        .push_sql(", last_updated_time = now(), last_updated_by = ")
+        .push_value(user_id.to_owned())
+        .push_sql(" WHERE id = ")
+        .push_value(id.to_owned())
+        .push_sql(" AND active IS DISTINCT FROM ")
+        .push_value(active)
+}
+
+// Insert or update on the conflict columns with the usual audit columns, RETURNING the id and
+// whether the row was inserted.
+pub fn upsert(table: &str, values: Vec<(&str, Value<'static>)>, conflict_columns: &[&str], user_id: &Uuid) -> SqlFragment {
+    let columns: Vec<String> = values.iter().map(|(column, _)| quote_identifier(column)).collect();
+    let updates: Vec<String> = values.iter()
+        .filter(|(column, _)| !conflict_columns.contains(column))
+        .map(|(column, _)| format!("{0} = excluded.{0}", quote_identifier(column)))
+        .collect();
+    let conflict: Vec<String> = conflict_columns.iter().map(|column| quote_identifier(column)).collect();
+    let placeholders = values.into_iter().map(|(_, value)| SqlFragment::new().push_value(value)).collect();
+
+    SqlFragment::sql("INSERT INTO ")
+        .push_identifier(table)
+        .push_sql(&format!(" ({}, created_by) VALUES (", columns.join(", ")))
+        .push(SqlFragment::join(placeholders, ", "))
+        .push_sql(", ")
+        .push_value(user_id.to_owned())
+        .push_sql(&format!(") ON CONFLICT ({}) DO UPDATE SET ", conflict.join(", ")))
+        .push_sql(&updates.iter().map(|update| format!("{}, ", update)).collect::<String>())
+        .push_sql("last_updated_time = now(), last_updated_by = excluded.created_by RETURNING id, (xmax = 0) AS inserted")
+}
+
+// Rows after the last row of the previous page for the given sort, e.g. (a > $1) OR (a = $1 AND b < $2).
+// The sort should end in a unique column (usually id) and the sort columns should not be nullable.
+pub fn keyset_after(sorts: &[Sort], last_values: Vec<Value<'static>>) -> SqlFragment {
+    let mut branches = Vec::new();
+    for (index, sort) in sorts.iter().enumerate().take(last_values.len()) {
+        let mut branch = Vec::new();
+        for (previous, value) in sorts.iter().zip(last_values.iter()).take(index) {
+            branch.push(SqlFragment::new().push_identifier(&previous.field).push_sql(" = ").push_value(value.to_owned()));
+        }
+        let operator = match sort.direction {
+            SortDirection::Ascending => " > ",
+            SortDirection::Descending => " < ",
+        };
+        branch.push(SqlFragment::new().push_identifier(&sort.field).push_sql(operator).push_value(last_values[index].to_owned()));
+        branches.push(SqlFragment::sql("(").push(SqlFragment::join(branch, " AND ")).push_sql(")"));
+    }
+    SqlFragment::join(branches, " OR ")
+}
+
+pub fn keyset_order_by(sorts: &[Sort], limit: i64) -> SqlFragment {
+    let order: Vec<SqlFragment> = sorts.iter()
+        .map(|sort| SqlFragment::new().push_identifier(&sort.field).push_sql(match sort.direction {
+            SortDirection::Ascending => " ASC",
+            SortDirection::Descending => " DESC",
+        }))
+        .collect();
+    SqlFragment::sql(" ORDER BY ").push(SqlFragment::join(order, ", ")).push_sql(" LIMIT ").push_value(limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_parameters_in_order() {
+        let (sql, values) = where_all(vec![equals("org.name", "acme"), SqlFragment::new(), in_list("status", vec![Value::text("open"), Value::text("held")])]).build();
+        assert_eq!(sql, " WHERE (\"org\".\"name\" = $1) AND (\"status\" IN ($2, $3))");
+        assert_eq!(values, vec![Value::text("acme"), Value::text("open"), Value::text("held")]);
+
+        let (sql, values) = equals("id", 7i64).build_from(4);
+        assert_eq!(sql, "\"id\" = $4");
+        assert_eq!(values, vec![Value::integer(7)]);
+    }
+
+    #[test]
+    fn handles_empty_conditions() {
+        assert_eq!(in_list("id", Vec::new()).build(), (String::from("false"), Vec::new()));
+        assert!(where_all(vec![SqlFragment::new()]).is_empty());
+        assert_eq!(active_predicate(Some("t")).build().0, "\"t\".\"active\"");
+    }
+
+    #[test]
+    fn builds_keyset_after() {
+        let sorts = vec![Sort::descending("created_time"), Sort::ascending("id")];
+        let (sql, values) = keyset_after(&sorts, vec![Value::integer(10), Value::integer(3)]).build();
+        assert_eq!(sql, "(\"created_time\" < $1) OR (\"created_time\" = $2 AND \"id\" > $3)");
+        assert_eq!(values, vec![Value::integer(10), Value::integer(10), Value::integer(3)]);
+
+        // Missing trailing values only compare on the sorts that have one.
+        let (sql, values) = keyset_after(&sorts, vec![Value::integer(10)]).build();
+        assert_eq!(sql, "(\"created_time\" < $1)");
+        assert_eq!(values.len(), 1);
+
+        assert!(keyset_after(&sorts, Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn appends_keyset_order_after_conditions() {
+        let sorts = vec![Sort::ascending("name"), Sort::descending("id")];
+        let (sql, values) = SqlFragment::sql("SELECT * FROM people WHERE ")
+            .push(keyset_after(&sorts, vec![Value::text("m"), Value::integer(5)]))
+            .push(keyset_order_by(&sorts, 20))
+            .build();
+        assert_eq!(sql, "SELECT * FROM people WHERE (\"name\" > $1) OR (\"name\" = $2 AND \"id\" < $3) ORDER BY \"name\" ASC, \"id\" DESC LIMIT $4");
+        assert_eq!(values.last(), Some(&Value::integer(20)));
+    }
+
+    #[test]
+    fn quotes_qualified_keyset_fields_the_same_in_both_clauses() {
+        let sorts = vec![Sort::ascending("people.name")];
+        let (sql, _) = keyset_after(&sorts, vec![Value::text("m")]).push(keyset_order_by(&sorts, 20)).build();
+        assert_eq!(sql, "(\"people\".\"name\" > $1) ORDER BY \"people\".\"name\" ASC LIMIT $2");
+    }
+
+    #[test]
+    fn builds_upserts() {
+        let user_id = Uuid::nil();
+        let (sql, values) = upsert("app.tags", vec![("name", Value::text("rust")), ("color", Value::text("orange"))], &["name"], &user_id).build();
+        assert_eq!(
+            sql,
+            "INSERT INTO \"app\".\"tags\" (\"name\", \"color\", created_by) VALUES ($1, $2, $3) ON CONFLICT (\"name\") DO UPDATE SET \
+            \"color\" = excluded.\"color\", last_updated_time = now(), last_updated_by = excluded.created_by RETURNING id, (xmax = 0) AS inserted"
+        );
+        assert_eq!(values.len(), 3);
+    }
+}
@@ -0,0 +1,212 @@
+use sqlx::{Pool, Postgres};
+use crate::postgres::BurchillPostgresError;
+use crate::strings::{quote_identifier, quote_qualified_identifier};
+
+
+// Extensions required by the crate features that are turned on.
+pub fn get_feature_extensions() -> Vec<&'static str> {
+    #[allow(unused_mut)]
+    let mut extensions = Vec::new();
+    #[cfg(feature = "citext")]
+    extensions.push("citext");
+    #[cfg(feature = "hstore")]
+    extensions.push("hstore");
+    extensions
+}
+
+async fn get_installed_extensions(pool: &Pool<Postgres>) -> Result<Vec<String>, BurchillPostgresError> {
+    let extensions: Vec<(String,)> = sqlx::query_as("SELECT extname::text FROM pg_extension")
+        .fetch_all(pool).await?;
+    Ok(extensions.into_iter().map(|(name,)| name).collect())
+}
+
+async fn get_available_extensions(pool: &Pool<Postgres>) -> Result<Vec<String>, BurchillPostgresError> {
+    let extensions: Vec<(String,)> = sqlx::query_as("SELECT name::text FROM pg_available_extensions")
+        .fetch_all(pool).await?;
+    Ok(extensions.into_iter().map(|(name,)| name).collect())
+}
+
+// Fails with the full list of problems up front instead of at the first query that needs an extension.
+// With create_missing set it will try CREATE EXTENSION, which needs the matching privileges.
+pub async fn require_extensions(pool: &Pool<Postgres>, extensions: &[&str], create_missing: bool) -> Result<(), BurchillPostgresError> {
+    let installed = get_installed_extensions(pool).await?;
+    let missing: Vec<&str> = extensions.iter()
+        .filter(|extension| !installed.iter().any(|installed| installed == **extension))
+        .copied()
+        .collect();
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let available = get_available_extensions(pool).await?;
+    let mut not_created = Vec::new();
+    let mut unavailable = Vec::new();
+
+    for extension in missing.into_iter() {
+        if !available.iter().any(|available| available == extension) {
+            unavailable.push(extension.to_owned());
+            continue;
+        }
+
+        if create_missing {
+            let query = format!("CREATE EXTENSION IF NOT EXISTS {}", quote_identifier(extension));
+            match sqlx::query(query.as_str()).execute(pool).await {
+                Ok(_) => continue,
+                Err(err) => tracing::warn!(extension = %extension, error = %err, "failed to create postgres extension"),
+            }
+        }
+        not_created.push(extension.to_owned());
+    }
+
+    if not_created.is_empty() && unavailable.is_empty() {
+        return Ok(());
+    }
+
+    Err(BurchillPostgresError::MissingExtensions {
+        not_created,
+        unavailable
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TablePrivilege {
+    Select,
+    Insert,
+    Update,
+    Delete,
+}
+
+impl TablePrivilege {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            TablePrivilege::Select => "SELECT",
+            TablePrivilege::Insert => "INSERT",
+            TablePrivilege::Update => "UPDATE",
+            TablePrivilege::Delete => "DELETE",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PrivilegeRequirement {
+    pub table: String,
+    pub privileges: Vec<TablePrivilege>,
+}
+
+impl PrivilegeRequirement {
+    pub fn new(table: &str, privileges: Vec<TablePrivilege>) -> Self {
+        PrivilegeRequirement {
+            table: table.to_owned(),
+            privileges
+        }
+    }
+
+    // What saving and loading an audited entity needs, entities are soft deleted so no DELETE.
+    pub fn for_entity_table(table: &str) -> Self {
+        PrivilegeRequirement::new(table, vec![TablePrivilege::Select, TablePrivilege::Insert, TablePrivilege::Update])
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PrivilegeReport {
+    pub role: String,
+    pub missing_schema_usage: Vec<String>,
+    pub missing_tables: Vec<String>,
+    pub missing_table_privileges: Vec<(String, TablePrivilege)>,
+}
+
+impl PrivilegeReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing_schema_usage.is_empty() && self.missing_tables.is_empty() && self.missing_table_privileges.is_empty()
+    }
+
+    // The statements a DBA would need to run to fix the report.
+    pub fn create_grant_statements(&self) -> Vec<String> {
+        let role = quote_identifier(&self.role);
+        let mut statements: Vec<String> = self.missing_schema_usage.iter()
+            .map(|schema| format!("GRANT USAGE ON SCHEMA {} TO {};", quote_identifier(schema), role))
+            .collect();
+
+        for (table, privilege) in self.missing_table_privileges.iter() {
+            statements.push(format!("GRANT {} ON {} TO {};", privilege.as_sql(), quote_qualified_identifier(table), role));
+        }
+        statements
+    }
+
+    pub fn to_message(&self) -> String {
+        let mut lines = vec![format!("Role {} is missing required privileges.", self.role)];
+        for table in self.missing_tables.iter() {
+            lines.push(format!("Table {} does not exist or is not visible.", table));
+        }
+        lines.extend(self.create_grant_statements());
+        lines.join("\n")
+    }
+}
+
+pub async fn check_privileges(pool: &Pool<Postgres>, schemas: &[&str], requirements: &[PrivilegeRequirement]) -> Result<PrivilegeReport, BurchillPostgresError> {
+    let (role,): (String,) = sqlx::query_as("SELECT current_user::text").fetch_one(pool).await?;
+    let mut report = PrivilegeReport {
+        role,
+        ..PrivilegeReport::default()
+    };
+
+    for schema in schemas.iter() {
+        let (has_usage,): (bool,) = sqlx::query_as("SELECT coalesce(has_schema_privilege(to_regnamespace($1), 'USAGE'), false)")
+            .bind(*schema)
+            .fetch_one(pool).await?;
+        if !has_usage {
+            report.missing_schema_usage.push(schema.to_string());
+        }
+    }
+
+    for requirement in requirements.iter() {
+        // has_table_privilege errors on unknown tables so existence is checked first.
+        let (exists,): (bool,) = sqlx::query_as("SELECT to_regclass($1) IS NOT NULL")
+            .bind(&requirement.table)
+            .fetch_one(pool).await?;
+        if !exists {
+            report.missing_tables.push(requirement.table.to_owned());
+            continue;
+        }
+
+        for privilege in requirement.privileges.iter() {
+            let (has_privilege,): (bool,) = sqlx::query_as("SELECT has_table_privilege($1, $2)")
+                .bind(&requirement.table)
+                .bind(privilege.as_sql())
+                .fetch_one(pool).await?;
+            if !has_privilege {
+                report.missing_table_privileges.push((requirement.table.to_owned(), *privilege));
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+pub async fn require_privileges(pool: &Pool<Postgres>, schemas: &[&str], requirements: &[PrivilegeRequirement]) -> Result<PrivilegeReport, BurchillPostgresError> {
+    let report = check_privileges(pool, schemas, requirements).await?;
+    if !report.is_ok() {
+        return Err(BurchillPostgresError::MissingPrivileges(report.to_message()));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_grant_statements() {
+        let report = PrivilegeReport {
+            role: String::from("app"),
+            missing_schema_usage: vec![String::from("billing")],
+            missing_tables: vec![],
+            missing_table_privileges: vec![(String::from("billing.Invoices"), TablePrivilege::Select)]
+        };
+        assert_eq!(report.create_grant_statements(), vec![
+            String::from("GRANT USAGE ON SCHEMA \"billing\" TO \"app\";"),
+            String::from("GRANT SELECT ON \"billing\".\"Invoices\" TO \"app\";")
+        ]);
+    }
+}
@@ -0,0 +1,171 @@
+use std::{collections::{HashSet, VecDeque, hash_map::DefaultHasher}, hash::{Hash, Hasher}, sync::{Mutex, PoisonError}};
+use sqlx::postgres::PgConnectOptions;
+use crate::postgres::fingerprint::fingerprint;
+
+// Same as sqlx's own default.
+pub const DEFAULT_STATEMENT_CACHE_CAPACITY: usize = 100;
+// Distinct statements and shapes are counted per window of this many statements so the sets stay bounded.
+pub const DEFAULT_DISTINCT_WINDOW: u64 = 10_000;
+
+pub fn with_statement_cache_capacity(options: PgConnectOptions, capacity: usize) -> PgConnectOptions {
+    options.statement_cache_capacity(capacity)
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StatementCacheStats {
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+    // Only counted over the current window, see with_distinct_window.
+    pub distinct_statements: usize,
+    // Statements left after normalizing literals, far fewer than distinct_statements means values are
+    // being inlined into the SQL instead of bound.
+    pub distinct_shapes: usize,
+}
+
+impl StatementCacheStats {
+    pub fn get_hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            1.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+#[derive(Default)]
+struct MonitorState {
+    recent: VecDeque<u64>,
+    seen: HashSet<u64>,
+    shapes: HashSet<String>,
+    window_count: u64,
+    hits: u64,
+    misses: u64,
+}
+
+// sqlx doesn't report cache hits, so this replays the statements through an LRU of the same capacity.
+// Every connection has its own cache, the numbers are the best case for a single busy connection.
+pub struct StatementCacheMonitor {
+    pool_name: String,
+    capacity: usize,
+    state: Mutex<MonitorState>,
+    distinct_window: u64,
+    // Guidance mode, logs the hit ratio (and a suggested capacity when it is poor) every n statements.
+    log_every: Option<u64>,
+    warn_below_ratio: f64,
+}
+
+impl StatementCacheMonitor {
+    pub fn new(pool_name: &str, capacity: usize) -> Self {
+        StatementCacheMonitor {
+            pool_name: pool_name.to_owned(),
+            capacity,
+            state: Mutex::new(MonitorState::default()),
+            distinct_window: DEFAULT_DISTINCT_WINDOW,
+            log_every: None,
+            warn_below_ratio: 0.9
+        }
+    }
+
+    pub fn with_guidance(mut self, log_every: u64, warn_below_ratio: f64) -> Self {
+        self.log_every = Some(log_every.max(1));
+        self.warn_below_ratio = warn_below_ratio;
+        self
+    }
+
+    pub fn with_distinct_window(mut self, statements: u64) -> Self {
+        self.distinct_window = statements.max(1);
+        self
+    }
+
+    // Returns whether the statement would have been served from the cache.
+    pub fn record(&self, sql: &str) -> bool {
+        let mut hasher = DefaultHasher::new();
+        sql.hash(&mut hasher);
+        let key = hasher.finish();
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        // Cleared before recording rather than after so guidance logged at the end of a window still sees it.
+        if state.window_count >= self.distinct_window {
+            state.seen.clear();
+            state.shapes.clear();
+            state.window_count = 0;
+        }
+        state.window_count += 1;
+        if state.seen.insert(key) {
+            state.shapes.insert(fingerprint(sql));
+        }
+
+        let hit = match state.recent.iter().position(|recent| *recent == key) {
+            Some(index) => {
+                state.recent.remove(index);
+                true
+            }
+            None => false
+        };
+        state.recent.push_front(key);
+        state.recent.truncate(self.capacity);
+
+        if hit {
+            state.hits += 1;
+        } else {
+            state.misses += 1;
+        }
+
+        if let Some(log_every) = self.log_every {
+            let total = state.hits + state.misses;
+            if total % log_every == 0 {
+                let stats = self.create_stats(&state);
+                drop(state);
+                self.log_stats(&stats);
+            }
+        }
+
+        hit
+    }
+
+    fn create_stats(&self, state: &MonitorState) -> StatementCacheStats {
+        StatementCacheStats {
+            capacity: self.capacity,
+            hits: state.hits,
+            misses: state.misses,
+            distinct_statements: state.seen.len(),
+            distinct_shapes: state.shapes.len()
+        }
+    }
+
+    pub fn get_stats(&self) -> StatementCacheStats {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        self.create_stats(&state)
+    }
+
+    pub fn reset(&self) {
+        *self.state.lock().unwrap_or_else(PoisonError::into_inner) = MonitorState::default();
+    }
+
+    fn log_stats(&self, stats: &StatementCacheStats) {
+        let hit_ratio = stats.get_hit_ratio();
+        if hit_ratio >= self.warn_below_ratio {
+            tracing::info!(pool = %self.pool_name, hits = stats.hits, misses = stats.misses, hit_ratio, capacity = stats.capacity, "statement cache");
+        } else if stats.distinct_shapes < stats.distinct_statements / 2 {
+            tracing::warn!(
+                pool = %self.pool_name, hits = stats.hits, misses = stats.misses, hit_ratio, capacity = stats.capacity,
+                distinct_statements = stats.distinct_statements, distinct_shapes = stats.distinct_shapes,
+                "statement cache is missing because values are inlined into the SQL, bind them as parameters instead"
+            );
+        } else if stats.distinct_statements > stats.capacity {
+            tracing::warn!(
+                pool = %self.pool_name, hits = stats.hits, misses = stats.misses, hit_ratio, capacity = stats.capacity,
+                distinct_statements = stats.distinct_statements,
+                "statement cache is thrashing, raise statement_cache_capacity to about {} or make the dynamic queries less varied",
+                stats.distinct_statements
+            );
+        } else {
+            tracing::warn!(
+                pool = %self.pool_name, hits = stats.hits, misses = stats.misses, hit_ratio, capacity = stats.capacity,
+                "statement cache hit ratio is low but every statement fits, most statements only run once"
+            );
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, Pool, Postgres};
+use uuid::{Uuid};
+use crate::postgres::{BurchillPostgresError, merge::{ConflictPolicy, MergeResult, Mergeable, merge_many_with_policy}};
+
+pub const DEFAULT_SYNC_BATCH_SIZE: usize = 500;
+
+pub const CREATE_SYNC_WATERMARKS_TABLE: &str = "CREATE TABLE IF NOT EXISTS sync_watermarks (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    source_name text NOT NULL UNIQUE,
+    cursor text,
+    updated_after timestamptz,
+    created_time timestamptz NOT NULL DEFAULT now(),
+    created_by uuid NOT NULL,
+    last_updated_time timestamptz,
+    last_updated_by uuid,
+    active boolean NOT NULL DEFAULT true
+)";
+
+
+// Where the last sync stopped, sources use whichever of the two their API supports.
+#[derive(Clone, Debug, Default, PartialEq, sqlx::FromRow)]
+pub struct SyncWatermark {
+    pub cursor: Option<String>,
+    pub updated_after: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SyncBatch<R> {
+    pub records: Vec<R>,
+    pub next_watermark: SyncWatermark,
+    pub has_more: bool,
+}
+
+#[async_trait]
+pub trait SyncSource: Send + Sync {
+    type Record: Mergeable + Send + Sync;
+
+    // Unique per source, it keys the stored watermark.
+    fn get_name(&self) -> &str;
+
+    fn get_conflict_key(&self) -> Vec<&'static str>;
+
+    fn get_conflict_policy(&self) -> ConflictPolicy {
+        ConflictPolicy::Overwrite
+    }
+
+    async fn fetch_changes(&self, watermark: &SyncWatermark, limit: usize) -> anyhow::Result<SyncBatch<Self::Record>>;
+}
+
+pub async fn get_watermark<'a, E>(source_name: &str, executor: E) -> Result<SyncWatermark, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let watermark: Option<SyncWatermark> = sqlx::query_as(
+        "SELECT cursor, updated_after FROM sync_watermarks WHERE source_name = $1 AND active"
+    )
+        .bind(source_name)
+        .fetch_optional(executor).await?;
+    Ok(watermark.unwrap_or_default())
+}
+
+pub async fn save_watermark<'a, E>(source_name: &str, watermark: &SyncWatermark, user_id: &Uuid, executor: E) -> Result<(), BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    sqlx::query(
+        "INSERT INTO sync_watermarks (source_name, cursor, updated_after, created_by) VALUES ($1, $2, $3, $4)
+        ON CONFLICT (source_name) DO UPDATE SET cursor = excluded.cursor, updated_after = excluded.updated_after,
+            last_updated_time = now(), last_updated_by = excluded.created_by, active = true"
+    )
+        .bind(source_name)
+        .bind(&watermark.cursor)
+        .bind(watermark.updated_after)
+        .bind(user_id)
+        .execute(executor).await?;
+    Ok(())
+}
+
+// Forgets the watermark so the next run starts from the beginning.
+pub async fn reset_watermark<'a, E>(source_name: &str, user_id: &Uuid, executor: E) -> Result<(), BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    save_watermark(source_name, &SyncWatermark::default(), user_id, executor).await
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SyncReport {
+    pub source_name: String,
+    pub batches: usize,
+    pub merged: MergeResult,
+    pub watermark: SyncWatermark,
+}
+
+#[derive(Clone, Debug)]
+pub struct SyncRunner {
+    pub batch_size: usize,
+    // Stops a source that always reports more from running forever, the next run carries on.
+    pub max_batches: Option<usize>,
+}
+
+impl Default for SyncRunner {
+    fn default() -> Self {
+        SyncRunner {
+            batch_size: DEFAULT_SYNC_BATCH_SIZE,
+            max_batches: None
+        }
+    }
+}
+
+impl SyncRunner {
+    pub fn new() -> Self {
+        SyncRunner::default()
+    }
+
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    pub fn max_batches(mut self, max_batches: usize) -> Self {
+        self.max_batches = Some(max_batches);
+        self
+    }
+
+    // Each batch is merged and its watermark saved in the same transaction, so a failure part way
+    // through resumes from the last committed batch without skipping or double applying anything.
+    pub async fn run<S>(&self, source: &S, user_id: &Uuid, pool: &Pool<Postgres>) -> Result<SyncReport, BurchillPostgresError>
+    where S: SyncSource + ?Sized {
+        let source_name = source.get_name().to_owned();
+        let conflict_key = source.get_conflict_key();
+        let policy = source.get_conflict_policy();
+
+        let mut report = SyncReport {
+            source_name: source_name.to_owned(),
+            watermark: get_watermark(&source_name, pool).await?,
+            ..SyncReport::default()
+        };
+
+        loop {
+            if self.max_batches.is_some_and(|max_batches| report.batches >= max_batches) {
+                break;
+            }
+
+            let batch = source.fetch_changes(&report.watermark, self.batch_size).await?;
+
+            let mut transaction = pool.begin().await?;
+            let merged = merge_many_with_policy(&batch.records, &conflict_key, &policy, user_id, &mut *transaction).await?;
+            save_watermark(&source_name, &batch.next_watermark, user_id, &mut transaction).await?;
+            transaction.commit().await?;
+
+            tracing::info!(
+                source = %source_name, inserted = merged.inserted, updated = merged.updated, unchanged = merged.unchanged,
+                "synced batch"
+            );
+            report.batches += 1;
+            report.merged.add(merged);
+            report.watermark = batch.next_watermark;
+
+            if !batch.has_more || batch.records.is_empty() {
+                break;
+            }
+        }
+        Ok(report)
+    }
+}
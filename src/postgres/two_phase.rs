@@ -0,0 +1,167 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{Executor, Pool, Postgres, Transaction};
+use uuid::{Uuid};
+use crate::postgres::BurchillPostgresError;
+use crate::strings::quote_literal;
+
+// Requires max_prepared_transactions > 0 on every participating server.
+
+// Lives on the coordinator's database. A row is written once every participant prepared, so a prepared
+// transaction without one was never decided and is rolled back on recovery.
+pub const CREATE_TWO_PHASE_DECISIONS_TABLE: &str = "CREATE TABLE IF NOT EXISTS two_phase_decisions (
+    transaction_id UUID PRIMARY KEY,
+    prefix TEXT NOT NULL,
+    participants INTEGER NOT NULL,
+    committed BOOLEAN NOT NULL,
+    decided_time TIMESTAMPTZ NOT NULL DEFAULT now()
+)";
+
+pub struct TwoPhaseParticipant {
+    pub pool: Pool<Postgres>,
+    pub transaction: Transaction<'static, Postgres>,
+}
+
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct PreparedTransactionInfo {
+    pub gid: String,
+    pub prepared: DateTime<Utc>,
+    pub owner: String,
+    pub database: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RecoveryReport {
+    pub committed: Vec<String>,
+    pub rolled_back: Vec<String>,
+    pub skipped: Vec<String>,
+}
+
+pub fn create_gid(prefix: &str, transaction_id: &Uuid, participant: usize) -> String {
+    format!("{}:{}:{}", prefix, transaction_id, participant)
+}
+
+// Split from the right since the prefix may contain colons itself.
+pub fn parse_gid(gid: &str) -> Option<(&str, Uuid, usize)> {
+    let mut parts = gid.rsplitn(3, ':');
+    let participant = parts.next()?.parse().ok()?;
+    let transaction_id = Uuid::parse_str(parts.next()?).ok()?;
+    let prefix = parts.next()?;
+    Some((prefix, transaction_id, participant))
+}
+
+// PREPARE TRANSACTION and friends don't take bind parameters so the gid is quoted into the SQL.
+pub async fn prepare_transaction(mut transaction: Transaction<'_, Postgres>, gid: &str) -> Result<(), BurchillPostgresError> {
+    let query = format!("PREPARE TRANSACTION {}", quote_literal(gid));
+    transaction.execute(query.as_str()).await?;
+
+    // The session already left the transaction, this only settles sqlx's own bookkeeping.
+    transaction.commit().await?;
+    Ok(())
+}
+
+pub async fn commit_prepared<'a, E>(gid: &str, executor: E) -> Result<(), BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let query = format!("COMMIT PREPARED {}", quote_literal(gid));
+    executor.execute(query.as_str()).await?;
+    Ok(())
+}
+
+pub async fn rollback_prepared<'a, E>(gid: &str, executor: E) -> Result<(), BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let query = format!("ROLLBACK PREPARED {}", quote_literal(gid));
+    executor.execute(query.as_str()).await?;
+    Ok(())
+}
+
+// Prepares every participant, durably records the commit decision on the coordinator and only then commits
+// the participants. If the commit phase is interrupted recover_prepared_transactions finishes it from that record.
+pub async fn commit_two_phase(coordinator: &Pool<Postgres>, prefix: &str, participants: Vec<TwoPhaseParticipant>) -> Result<Uuid, BurchillPostgresError> {
+    let transaction_id = Uuid::new_v4();
+    let participant_count = participants.len();
+    let mut prepared: Vec<(Pool<Postgres>, String)> = Vec::new();
+
+    for (index, participant) in participants.into_iter().enumerate() {
+        let gid = create_gid(prefix, &transaction_id, index);
+        if let Err(err) = prepare_transaction(participant.transaction, &gid).await {
+            rollback_all_prepared(&prepared).await;
+            return Err(err);
+        }
+        prepared.push((participant.pool, gid));
+    }
+
+    let decision = sqlx::query("INSERT INTO two_phase_decisions (transaction_id, prefix, participants, committed) VALUES ($1, $2, $3, true)")
+        .bind(transaction_id)
+        .bind(prefix)
+        .bind(participant_count as i32)
+        .execute(coordinator).await;
+    if let Err(err) = decision {
+        rollback_all_prepared(&prepared).await;
+        return Err(err.into());
+    }
+
+    for (pool, gid) in prepared.iter() {
+        commit_prepared(gid, pool).await?;
+    }
+
+    // Only cleanup, a leftover decision just means recovery looks it up for nothing.
+    if let Err(err) = sqlx::query("DELETE FROM two_phase_decisions WHERE transaction_id = $1").bind(transaction_id).execute(coordinator).await {
+        tracing::warn!(transaction_id = %transaction_id, error = %err, "failed to remove two phase decision");
+    }
+
+    Ok(transaction_id)
+}
+
+async fn rollback_all_prepared(prepared: &[(Pool<Postgres>, String)]) {
+    for (pool, gid) in prepared.iter() {
+        if let Err(err) = rollback_prepared(gid, pool).await {
+            tracing::error!(gid = %gid, error = %err, "failed to roll back prepared transaction, it will need recovery");
+        }
+    }
+}
+
+pub async fn find_prepared_transactions<'a, E>(prefix: &str, older_than: Duration, executor: E) -> Result<Vec<PreparedTransactionInfo>, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let transactions = sqlx::query_as::<Postgres, PreparedTransactionInfo>(
+        "SELECT gid, prepared, owner::text AS owner, database::text AS database FROM pg_prepared_xacts WHERE database = current_database() AND starts_with(gid, $1) AND prepared < $2 ORDER BY prepared"
+    )
+        .bind(format!("{}:", prefix))
+        .bind(Utc::now() - older_than)
+        .fetch_all(executor).await?;
+    Ok(transactions)
+}
+
+// Resolves orphans on one participant from the coordinator's decisions: committed ones are committed and
+// anything without a decision is rolled back. older_than has to cover how long the coordinator may take to
+// prepare every participant, or an in flight transaction could be rolled back before its decision is written.
+pub async fn recover_prepared_transactions(coordinator: &Pool<Postgres>, pool: &Pool<Postgres>, prefix: &str, older_than: Duration) -> Result<RecoveryReport, BurchillPostgresError> {
+    let mut report = RecoveryReport::default();
+
+    for transaction in find_prepared_transactions(prefix, older_than, pool).await?.into_iter() {
+        let transaction_id = match parse_gid(&transaction.gid) {
+            Some((gid_prefix, transaction_id, _)) if gid_prefix == prefix => transaction_id,
+            _ => {
+                report.skipped.push(transaction.gid);
+                continue;
+            }
+        };
+
+        let decision: Option<(bool,)> = sqlx::query_as("SELECT committed FROM two_phase_decisions WHERE transaction_id = $1 AND prefix = $2")
+            .bind(transaction_id)
+            .bind(prefix)
+            .fetch_optional(coordinator).await?;
+
+        match decision {
+            Some((true,)) => {
+                commit_prepared(&transaction.gid, pool).await?;
+                report.committed.push(transaction.gid);
+            }
+            _ => {
+                rollback_prepared(&transaction.gid, pool).await?;
+                report.rolled_back.push(transaction.gid);
+            }
+        }
+    }
+
+    tracing::info!(prefix = %prefix, committed = report.committed.len(), rolled_back = report.rolled_back.len(), skipped = report.skipped.len(), "recovered prepared transactions");
+    Ok(report)
+}
@@ -0,0 +1,223 @@
+use std::sync::{Mutex, PoisonError};
+use chrono::{DateTime, Utc};
+use quaint::prelude::{Aliasable, Select, asterisk, count};
+use sqlx::{Executor, Postgres};
+use uuid::{Uuid};
+use crate::postgres::{BurchillPostgresError, criteria::Criteria, fetch_one};
+use crate::time_utils::TimeRange;
+
+pub const DEFAULT_BATCH_SIZE: usize = 500;
+
+pub const CREATE_USAGE_EVENTS_TABLE: &str = "CREATE TABLE IF NOT EXISTS usage_events (
+    tenant_id uuid NOT NULL,
+    metric text NOT NULL,
+    quantity bigint NOT NULL,
+    recorded_time timestamptz NOT NULL DEFAULT now()
+)";
+
+// Kept separate from the table so each constant is a single statement for prepared execution.
+pub const CREATE_USAGE_EVENTS_INDEX: &str = "CREATE INDEX IF NOT EXISTS usage_events_tenant_metric_time ON usage_events (tenant_id, metric, recorded_time)";
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UsageEvent {
+    pub tenant_id: Uuid,
+    pub metric: String,
+    pub quantity: i64,
+    pub recorded_time: DateTime<Utc>,
+}
+
+impl UsageEvent {
+    pub fn new(tenant_id: Uuid, metric: &str, quantity: i64) -> Self {
+        UsageEvent {
+            tenant_id,
+            metric: metric.to_owned(),
+            quantity,
+            recorded_time: Utc::now()
+        }
+    }
+}
+
+// Collects events in memory and writes them in one statement per batch.
+pub struct UsageRecorder {
+    events: Mutex<Vec<UsageEvent>>,
+    batch_size: usize
+}
+
+impl Default for UsageRecorder {
+    fn default() -> Self {
+        UsageRecorder::new(DEFAULT_BATCH_SIZE)
+    }
+}
+
+impl UsageRecorder {
+    pub fn new(batch_size: usize) -> Self {
+        UsageRecorder {
+            events: Mutex::new(Vec::new()),
+            batch_size: batch_size.max(1)
+        }
+    }
+
+    // Returns true once a full batch is waiting, the caller decides when to flush.
+    pub fn record(&self, event: UsageEvent) -> bool {
+        let mut events = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+        events.push(event);
+        events.len() >= self.batch_size
+    }
+
+    pub fn pending(&self) -> usize {
+        self.events.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    // Events are put back if the insert fails so a flaky connection doesn't lose usage.
+    pub async fn flush<'a, E>(&self, executor: E) -> Result<usize, BurchillPostgresError>
+    where E: Executor<'a, Database = Postgres> {
+        let events = std::mem::take(&mut *self.events.lock().unwrap_or_else(PoisonError::into_inner));
+        if events.is_empty() {
+            return Ok(0);
+        }
+
+        match insert_usage_events(&events, executor).await {
+            Ok(count) => Ok(count),
+            Err(err) => {
+                let mut pending = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+                let newer = std::mem::replace(&mut *pending, events);
+                pending.extend(newer);
+                Err(err)
+            }
+        }
+    }
+}
+
+pub async fn insert_usage_events<'a, E>(events: &[UsageEvent], executor: E) -> Result<usize, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let tenant_ids: Vec<Uuid> = events.iter().map(|event| event.tenant_id).collect();
+    let metrics: Vec<String> = events.iter().map(|event| event.metric.to_owned()).collect();
+    let quantities: Vec<i64> = events.iter().map(|event| event.quantity).collect();
+    let recorded_times: Vec<DateTime<Utc>> = events.iter().map(|event| event.recorded_time).collect();
+
+    let result = sqlx::query(
+        "INSERT INTO usage_events (tenant_id, metric, quantity, recorded_time)
+        SELECT * FROM UNNEST($1::uuid[], $2::text[], $3::bigint[], $4::timestamptz[])"
+    )
+        .bind(tenant_ids)
+        .bind(metrics)
+        .bind(quantities)
+        .bind(recorded_times)
+        .execute(executor).await?;
+    Ok(result.rows_affected() as usize)
+}
+
+#[derive(Clone, Debug, PartialEq, sqlx::FromRow)]
+pub struct UsageTotal {
+    pub metric: String,
+    pub total: i64,
+    pub events: i64,
+}
+
+pub async fn get_usage_totals<'a, E>(tenant_id: &Uuid, range: &TimeRange, executor: E) -> Result<Vec<UsageTotal>, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let totals = sqlx::query_as::<Postgres, UsageTotal>(
+        "SELECT metric, coalesce(sum(quantity), 0)::bigint AS total, count(*) AS events
+        FROM usage_events WHERE tenant_id = $1 AND recorded_time >= $2 AND recorded_time < $3
+        GROUP BY metric ORDER BY metric"
+    )
+        .bind(tenant_id)
+        .bind(range.start)
+        .bind(range.end)
+        .fetch_all(executor).await?;
+    Ok(totals)
+}
+
+#[derive(Clone, Debug, PartialEq, sqlx::FromRow)]
+pub struct DailyUsage {
+    pub day: DateTime<Utc>,
+    pub metric: String,
+    pub total: i64,
+}
+
+// Days are bucketed in UTC, billing periods in other zones should pass ranges from time_utils.
+pub async fn get_daily_usage<'a, E>(tenant_id: &Uuid, range: &TimeRange, executor: E) -> Result<Vec<DailyUsage>, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let usage = sqlx::query_as::<Postgres, DailyUsage>(
+        "SELECT date_trunc('day', recorded_time AT TIME ZONE 'UTC') AT TIME ZONE 'UTC' AS day, metric, coalesce(sum(quantity), 0)::bigint AS total
+        FROM usage_events WHERE tenant_id = $1 AND recorded_time >= $2 AND recorded_time < $3
+        GROUP BY 1, 2 ORDER BY 1, 2"
+    )
+        .bind(tenant_id)
+        .bind(range.start)
+        .bind(range.end)
+        .fetch_all(executor).await?;
+    Ok(usage)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QuotaMeasure {
+    // Sum of the recorded quantities, e.g. storage bytes.
+    Quantity,
+    // Number of recorded events, e.g. API calls.
+    Events,
+}
+
+#[derive(Clone, Debug)]
+pub struct Quota {
+    pub metric: String,
+    pub limit: i64,
+    pub measure: QuotaMeasure,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuotaStatus {
+    pub metric: String,
+    pub used: i64,
+    pub limit: i64,
+}
+
+impl QuotaStatus {
+    pub fn get_remaining(&self) -> i64 {
+        (self.limit - self.used).max(0)
+    }
+
+    pub fn is_exceeded(&self) -> bool {
+        self.used >= self.limit
+    }
+}
+
+pub fn create_usage_criteria(tenant_id: &Uuid, metric: &str, range: &TimeRange) -> Criteria {
+    let criteria = Criteria::new()
+        .equals("tenant_id", tenant_id.to_owned())
+        .equals("metric", metric.to_owned());
+    range.add_to_criteria(criteria, "recorded_time")
+}
+
+pub async fn check_quota<'a, E>(tenant_id: &Uuid, quota: &Quota, range: &TimeRange, executor: E) -> Result<QuotaStatus, BurchillPostgresError>
+where E: Executor<'a, Database = Postgres> {
+    let criteria = create_usage_criteria(tenant_id, &quota.metric, range);
+
+    let used = match quota.measure {
+        QuotaMeasure::Events => {
+            let query = Select::from_table("usage_events")
+                .value(count(asterisk()).alias("count"))
+                .so_that(criteria.into_condition_tree());
+            let (used,): (i64,) = fetch_one(query, executor).await?;
+            used
+        }
+        QuotaMeasure::Quantity => {
+            let (used,): (i64,) = sqlx::query_as(
+                "SELECT coalesce(sum(quantity), 0)::bigint FROM usage_events WHERE tenant_id = $1 AND metric = $2 AND recorded_time >= $3 AND recorded_time < $4"
+            )
+                .bind(tenant_id)
+                .bind(&quota.metric)
+                .bind(range.start)
+                .bind(range.end)
+                .fetch_one(executor).await?;
+            used
+        }
+    };
+
+    Ok(QuotaStatus {
+        metric: quota.metric.to_owned(),
+        used,
+        limit: quota.limit
+    })
+}
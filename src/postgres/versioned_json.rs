@@ -0,0 +1,178 @@
+use std::{convert::TryFrom, ops::{Deref, DerefMut}};
+use quaint::Value;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use sqlx::{Decode, Encode, Postgres, Type, encode::IsNull, error::BoxDynError, postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef}, types::Json};
+
+pub type JsonUpgrade = fn(serde_json::Value) -> Result<serde_json::Value, String>;
+
+// Payloads written before the column was versioned have no envelope and are read as version 0.
+pub const UNVERSIONED: u32 = 0;
+
+// Reserved key marking a stored envelope, a payload that happens to have "version" and "data"
+// fields is never mistaken for one.
+pub const ENVELOPE_KEY: &str = "__envelope";
+
+pub trait VersionedSchema: Serialize + DeserializeOwned {
+    const VERSION: u32;
+
+    // Each entry upgrades the payload from the given version to the next one.
+    fn get_upgrades() -> Vec<(u32, JsonUpgrade)>;
+}
+
+#[derive(Serialize)]
+struct EnvelopeRef<'a, T> {
+    #[serde(rename = "__envelope")]
+    version: u32,
+    data: &'a T,
+}
+
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(rename = "__envelope")]
+    version: u32,
+    data: serde_json::Value,
+}
+
+// Stored as {"__envelope": n, "data": ...} in a jsonb column. Older versions are upgraded when the
+// value is read and written back at the current version the next time the entity is saved.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VersionedJson<T> {
+    pub data: T,
+    stored_version: u32,
+}
+
+impl<T> VersionedJson<T>
+where T: VersionedSchema {
+    pub fn new(data: T) -> Self {
+        VersionedJson {
+            data,
+            stored_version: T::VERSION
+        }
+    }
+
+    pub fn get_stored_version(&self) -> u32 {
+        self.stored_version
+    }
+
+    pub fn was_upgraded(&self) -> bool {
+        self.stored_version != T::VERSION
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data
+    }
+
+    pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> {
+        serde_json::to_value(EnvelopeRef { version: T::VERSION, data: &self.data })
+    }
+
+    pub fn from_json(value: serde_json::Value) -> Result<Self, BoxDynError> {
+        let (stored_version, data) = split_envelope(value);
+        let data = upgrade_json::<T>(stored_version, data)?;
+
+        Ok(VersionedJson {
+            data: serde_json::from_value(data)?,
+            stored_version
+        })
+    }
+}
+
+fn split_envelope(value: serde_json::Value) -> (u32, serde_json::Value) {
+    let is_envelope = value.as_object()
+        .map(|object| object.len() == 2 && object.get(ENVELOPE_KEY).is_some_and(|version| version.is_u64()) && object.contains_key("data"))
+        .unwrap_or(false);
+
+    if is_envelope {
+        if let Ok(envelope) = serde_json::from_value::<Envelope>(value.clone()) {
+            return (envelope.version, envelope.data);
+        }
+    }
+    (UNVERSIONED, value)
+}
+
+pub fn upgrade_json<T>(from_version: u32, data: serde_json::Value) -> Result<serde_json::Value, BoxDynError>
+where T: VersionedSchema {
+    if from_version > T::VERSION {
+        return Err(format!("Stored payload version {} is newer than the supported version {}.", from_version, T::VERSION).into());
+    }
+
+    let upgrades = T::get_upgrades();
+    let mut version = from_version;
+    let mut data = data;
+    while version < T::VERSION {
+        let upgrade = upgrades.iter()
+            .find(|(upgrade_version, _)| *upgrade_version == version)
+            .map(|(_, upgrade)| upgrade)
+            .ok_or_else(|| format!("No upgrade is registered from payload version {}.", version))?;
+
+        data = upgrade(data).map_err(|err| format!("Upgrading payload from version {} failed: {}", version, err))?;
+        version += 1;
+    }
+    Ok(data)
+}
+
+impl<T> Deref for VersionedJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.data
+    }
+}
+
+impl<T> DerefMut for VersionedJson<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.data
+    }
+}
+
+// Bound as jsonb, a failed serialization is returned rather than writing an empty payload.
+impl<T> TryFrom<VersionedJson<T>> for Value<'static>
+where T: VersionedSchema {
+    type Error = serde_json::Error;
+
+    fn try_from(json: VersionedJson<T>) -> Result<Self, Self::Error> {
+        json.to_json().map(Value::json)
+    }
+}
+
+impl<T> Type<Postgres> for VersionedJson<T> {
+    fn type_info() -> PgTypeInfo {
+        <Json<serde_json::Value> as Type<Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <Json<serde_json::Value> as Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl<T> Encode<'_, Postgres> for VersionedJson<T>
+where T: VersionedSchema {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+        Json(EnvelopeRef { version: T::VERSION, data: &self.data }).encode_by_ref(buf)
+    }
+}
+
+impl<'r, T> Decode<'r, Postgres> for VersionedJson<T>
+where T: VersionedSchema {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        let Json(value) = <Json<serde_json::Value> as Decode<Postgres>>::decode(value)?;
+        VersionedJson::from_json(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn splits_marked_envelopes_only() {
+        assert_eq!(split_envelope(json!({"__envelope": 2, "data": {"name": "a"}})), (2, json!({"name": "a"})));
+
+        let payload = json!({"version": 3, "data": "user content"});
+        assert_eq!(split_envelope(payload.clone()), (UNVERSIONED, payload));
+
+        let payload = json!({"__envelope": 1, "data": 1, "extra": true});
+        assert_eq!(split_envelope(payload.clone()), (UNVERSIONED, payload));
+    }
+}
@@ -0,0 +1,155 @@
+// The supported way to use the crate, the crate's own types here are covered by its semver.
+// The aliases and deps below are the dependency types themselves, they only save naming the
+// versions, so a major bump of sqlx, quaint, chrono or uuid is a major bump of this crate too.
+// Id, Timestamp and DbError are the crate's own and stay put across those bumps.
+
+use std::{fmt, str::FromStr};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+pub use crate::environment::Environment;
+pub use crate::postgres::{BurchillPostgresError, access::{AccessAction, AccessPolicy, Actor}};
+pub use crate::postgres::criteria::{Criteria, FindManyOptions, Page, PageRequest, Sort, SortDirection};
+pub use crate::time_utils::TimeRange;
+pub use crate::validation::{Strictness, ValidationError, ValidationErrors};
+pub use crate::web::response::{ApiResponse, ErrorResponse, PagedResponse};
+
+#[cfg(feature = "database")]
+pub use crate::postgres::{PostgresBaseEntityData, entity::{PostgresEntity, PostgresEntityManager}, repository::PostgresRepository};
+
+// Ids and timestamps at the crate boundary, convert with into() from and to the dependency types.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "database", derive(sqlx::Type))]
+#[cfg_attr(feature = "database", sqlx(transparent))]
+#[serde(transparent)]
+pub struct Id(uuid::Uuid);
+
+impl Id {
+    pub fn generate() -> Self {
+        Id(uuid::Uuid::new_v4())
+    }
+
+    pub fn nil() -> Self {
+        Id(uuid::Uuid::nil())
+    }
+}
+
+impl From<uuid::Uuid> for Id {
+    fn from(id: uuid::Uuid) -> Self {
+        Id(id)
+    }
+}
+
+impl From<Id> for uuid::Uuid {
+    fn from(id: Id) -> Self {
+        id.0
+    }
+}
+
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for Id {
+    type Err = ValidationError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        uuid::Uuid::parse_str(value).map(Id).map_err(|_| ValidationError::InvalidUuid(value.to_owned()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "database", derive(sqlx::Type))]
+#[cfg_attr(feature = "database", sqlx(transparent))]
+#[serde(transparent)]
+pub struct Timestamp(chrono::DateTime<chrono::Utc>);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        Timestamp(chrono::Utc::now())
+    }
+}
+
+impl From<chrono::DateTime<chrono::Utc>> for Timestamp {
+    fn from(time: chrono::DateTime<chrono::Utc>) -> Self {
+        Timestamp(time)
+    }
+}
+
+impl From<Timestamp> for chrono::DateTime<chrono::Utc> {
+    fn from(time: Timestamp) -> Self {
+        time.0
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0.to_rfc3339())
+    }
+}
+
+// Keeps the sqlx and quaint errors inside BurchillPostgresError out of the signatures, into_inner
+// gets them back for code that is happy to depend on those versions.
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct DbError(BurchillPostgresError);
+
+impl DbError {
+    pub fn into_inner(self) -> BurchillPostgresError {
+        self.0
+    }
+}
+
+impl From<BurchillPostgresError> for DbError {
+    fn from(err: BurchillPostgresError) -> Self {
+        DbError(err)
+    }
+}
+
+pub type DbResult<T> = std::result::Result<T, DbError>;
+
+#[cfg(feature = "database")]
+pub type DbPool = sqlx::Pool<sqlx::Postgres>;
+#[cfg(feature = "database")]
+pub type DbRow = sqlx::postgres::PgRow;
+#[cfg(feature = "database")]
+pub type DbTransaction<'c> = sqlx::Transaction<'c, sqlx::Postgres>;
+
+// The exact dependency versions this crate was built against, use these instead of adding the
+// crates separately so the two can't drift apart within one release of this crate.
+pub mod deps {
+    pub use chrono;
+    pub use quaint;
+    pub use serde_json;
+    pub use uuid;
+
+    #[cfg(feature = "database")]
+    pub use sqlx;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_converts_and_serializes_as_the_uuid() {
+        let uuid = uuid::Uuid::new_v4();
+        let id: Id = uuid.into();
+        assert_eq!(uuid::Uuid::from(id), uuid);
+        assert_eq!(id.to_string(), uuid.to_string());
+        assert_eq!(serde_json::to_string(&id).unwrap(), serde_json::to_string(&uuid).unwrap());
+        assert_eq!(uuid.to_string().parse::<Id>().unwrap(), id);
+        assert_eq!("nope".parse::<Id>(), Err(ValidationError::InvalidUuid(String::from("nope"))));
+    }
+
+    #[test]
+    fn timestamp_converts_and_serializes_as_the_date_time() {
+        let time = chrono::Utc::now();
+        let timestamp = Timestamp::from(time);
+        assert_eq!(chrono::DateTime::<chrono::Utc>::from(timestamp), time);
+        assert_eq!(serde_json::to_string(&timestamp).unwrap(), serde_json::to_string(&time).unwrap());
+        assert_eq!(serde_json::from_str::<Timestamp>(&serde_json::to_string(&time).unwrap()).unwrap(), timestamp);
+    }
+}
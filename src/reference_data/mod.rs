@@ -0,0 +1,232 @@
+#[cfg(feature = "database")]
+use sqlx::{Pool, Postgres};
+#[cfg(feature = "database")]
+use crate::postgres::BurchillPostgresError;
+use crate::validation::{Strictness, ValidationError, ValidationErrors};
+
+pub mod countries;
+pub mod subdivisions;
+
+pub const CREATE_REFERENCE_TABLES: &str = "CREATE TABLE IF NOT EXISTS countries (
+    alpha2 char(2) PRIMARY KEY,
+    alpha3 char(3) NOT NULL UNIQUE,
+    numeric_code smallint NOT NULL UNIQUE,
+    name text NOT NULL
+);
+CREATE TABLE IF NOT EXISTS country_subdivisions (
+    code text PRIMARY KEY,
+    country char(2) NOT NULL REFERENCES countries (alpha2),
+    name text NOT NULL
+)";
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Country {
+    pub alpha2: &'static str,
+    pub alpha3: &'static str,
+    pub numeric: u16,
+    pub name: &'static str,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Subdivision {
+    pub country: &'static str,
+    pub code: &'static str,
+    pub name: &'static str,
+}
+
+impl Subdivision {
+    // The part after the country prefix, e.g. "CA" for "US-CA".
+    pub fn get_local_code(&self) -> &'static str {
+        self.code.split_once('-').map(|(_, local)| local).unwrap_or(self.code)
+    }
+}
+
+pub fn get_countries() -> impl Iterator<Item = Country> {
+    countries::COUNTRIES.iter().map(|&(alpha2, alpha3, numeric, name)| Country {
+        alpha2,
+        alpha3,
+        numeric,
+        name
+    })
+}
+
+// Accepts alpha-2, alpha-3 or numeric codes in any case.
+pub fn find_country(code: &str) -> Option<Country> {
+    let code = code.trim().to_uppercase();
+    match code.len() {
+        2 => get_countries().find(|country| country.alpha2 == code),
+        3 if code.chars().all(|c| c.is_ascii_digit()) => {
+            let numeric: u16 = code.parse().ok()?;
+            get_countries().find(|country| country.numeric == numeric)
+        }
+        3 => get_countries().find(|country| country.alpha3 == code),
+        _ => None
+    }
+}
+
+pub fn get_subdivisions(country: &str) -> Vec<Subdivision> {
+    let country = match find_country(country) {
+        Some(country) => country,
+        None => return Vec::new()
+    };
+
+    subdivisions::SUBDIVISIONS.iter()
+        .filter(|(subdivision_country, _, _)| *subdivision_country == country.alpha2)
+        .map(|&(country, code, name)| Subdivision { country, code, name })
+        .collect()
+}
+
+pub fn has_subdivision_data(country: &str) -> bool {
+    !get_subdivisions(country).is_empty()
+}
+
+// Accepts either the full ISO code ("US-CA") or just the local part ("CA").
+pub fn find_subdivision(country: &str, code: &str) -> Option<Subdivision> {
+    let code = code.trim().to_uppercase();
+    get_subdivisions(country).into_iter()
+        .find(|subdivision| subdivision.code == code || subdivision.get_local_code() == code)
+}
+
+pub fn validate_country(code: &str) -> Result<Country, ValidationError> {
+    if code.trim().is_empty() {
+        return Err(ValidationError::Required);
+    }
+    find_country(code).ok_or_else(|| ValidationError::InvalidCountry(code.to_owned()))
+}
+
+// Countries without bundled subdivisions accept any non empty value.
+pub fn validate_subdivision(country: &str, code: &str) -> Result<Option<Subdivision>, ValidationError> {
+    if code.trim().is_empty() {
+        return Err(ValidationError::Required);
+    }
+    if !has_subdivision_data(country) {
+        return Ok(None);
+    }
+    find_subdivision(country, code)
+        .map(Some)
+        .ok_or_else(|| ValidationError::InvalidSubdivision(code.to_owned()))
+}
+
+pub fn normalize_postal_code(postal_code: &str) -> String {
+    postal_code.split_whitespace().collect::<Vec<&str>>().join(" ").to_uppercase()
+}
+
+fn matches_pattern(value: &str, pattern: &str) -> bool {
+    value.len() == pattern.len() && value.chars().zip(pattern.chars()).all(|(c, p)| match p {
+        '9' => c.is_ascii_digit(),
+        'A' => c.is_ascii_alphabetic(),
+        _ => c == p
+    })
+}
+
+// Patterns use 9 for a digit and A for a letter, countries not listed only get the lenient checks.
+fn get_postal_code_patterns(country: &str) -> &'static [&'static str] {
+    match country {
+        "US" => &["99999", "99999-9999"],
+        "CA" => &["A9A 9A9"],
+        "AU" => &["9999"],
+        "DE" | "FR" | "ES" | "IT" => &["99999"],
+        "NL" => &["9999 AA"],
+        "JP" => &["999-9999"],
+        "IN" => &["999999"],
+        "BR" => &["99999-999"],
+        "GB" => &["A9 9AA", "A99 9AA", "AA9 9AA", "AA99 9AA", "A9A 9AA", "AA9A 9AA"],
+        _ => &[]
+    }
+}
+
+pub fn validate_postal_code(country: &str, postal_code: &str, strictness: Strictness) -> Result<String, ValidationError> {
+    let normalized = normalize_postal_code(postal_code);
+    let invalid = || ValidationError::InvalidPostalCode(postal_code.to_owned());
+    if normalized.is_empty() {
+        return Err(ValidationError::Required);
+    }
+
+    let lenient_valid = normalized.len() <= 12 && normalized.chars().all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-');
+    if !lenient_valid {
+        return Err(invalid());
+    }
+
+    let patterns = find_country(country).map(|country| get_postal_code_patterns(country.alpha2)).unwrap_or_default();
+    if strictness == Strictness::Strict && !patterns.is_empty() && !patterns.iter().any(|pattern| matches_pattern(&normalized, pattern)) {
+        return Err(invalid());
+    }
+    Ok(normalized)
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Address {
+    pub line1: String,
+    pub line2: Option<String>,
+    pub city: String,
+    pub subdivision: Option<String>,
+    pub postal_code: Option<String>,
+    pub country: String,
+}
+
+// Returns the address with the country and subdivision as ISO codes and the postal code normalized.
+pub fn validate_address(address: &Address, strictness: Strictness) -> Result<Address, ValidationErrors> {
+    let mut errors = ValidationErrors::new();
+    let mut normalized = address.clone();
+
+    if address.line1.trim().is_empty() {
+        errors.add("line1", ValidationError::Required);
+    }
+    if address.city.trim().is_empty() {
+        errors.add("city", ValidationError::Required);
+    }
+
+    if let Some(country) = errors.check("country", validate_country(&address.country)) {
+        normalized.country = country.alpha2.to_owned();
+
+        if let Some(subdivision) = &address.subdivision {
+            if let Some(Some(subdivision)) = errors.check("subdivision", validate_subdivision(country.alpha2, subdivision)) {
+                normalized.subdivision = Some(subdivision.code.to_owned());
+            }
+        } else if strictness == Strictness::Strict && has_subdivision_data(country.alpha2) {
+            errors.add("subdivision", ValidationError::Required);
+        }
+
+        if let Some(postal_code) = &address.postal_code {
+            normalized.postal_code = errors.check("postal_code", validate_postal_code(country.alpha2, postal_code, strictness));
+        }
+    }
+
+    errors.into_result()?;
+    Ok(normalized)
+}
+
+// Upserts the bundled data so it can run on every deploy, rows removed from the dataset are left in place
+// since addresses may still reference them.
+#[cfg(feature = "database")]
+pub async fn seed_reference_data(pool: &Pool<Postgres>) -> Result<(), BurchillPostgresError> {
+    let mut transaction = pool.begin().await?;
+
+    let countries: Vec<Country> = get_countries().collect();
+    sqlx::query(
+        "INSERT INTO countries (alpha2, alpha3, numeric_code, name)
+        SELECT * FROM UNNEST($1::text[], $2::text[], $3::smallint[], $4::text[])
+        ON CONFLICT (alpha2) DO UPDATE SET alpha3 = excluded.alpha3, numeric_code = excluded.numeric_code, name = excluded.name
+        WHERE (countries.alpha3, countries.numeric_code, countries.name) IS DISTINCT FROM (excluded.alpha3, excluded.numeric_code, excluded.name)"
+    )
+        .bind(countries.iter().map(|country| country.alpha2).collect::<Vec<&str>>())
+        .bind(countries.iter().map(|country| country.alpha3).collect::<Vec<&str>>())
+        .bind(countries.iter().map(|country| country.numeric as i16).collect::<Vec<i16>>())
+        .bind(countries.iter().map(|country| country.name).collect::<Vec<&str>>())
+        .execute(&mut transaction).await?;
+
+    sqlx::query(
+        "INSERT INTO country_subdivisions (country, code, name)
+        SELECT * FROM UNNEST($1::text[], $2::text[], $3::text[])
+        ON CONFLICT (code) DO UPDATE SET country = excluded.country, name = excluded.name
+        WHERE (country_subdivisions.country, country_subdivisions.name) IS DISTINCT FROM (excluded.country, excluded.name)"
+    )
+        .bind(subdivisions::SUBDIVISIONS.iter().map(|(country, _, _)| *country).collect::<Vec<&str>>())
+        .bind(subdivisions::SUBDIVISIONS.iter().map(|(_, code, _)| *code).collect::<Vec<&str>>())
+        .bind(subdivisions::SUBDIVISIONS.iter().map(|(_, _, name)| *name).collect::<Vec<&str>>())
+        .execute(&mut transaction).await?;
+
+    transaction.commit().await?;
+    Ok(())
+}
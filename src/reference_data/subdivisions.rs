@@ -0,0 +1,97 @@
+// ISO 3166-2 (country alpha-2, subdivision code, name) for the countries our apps ship addresses to.
+pub const SUBDIVISIONS: &[(&str, &str, &str)] = &[
+    ("AU", "AU-ACT", "Australian Capital Territory"),
+    ("AU", "AU-NSW", "New South Wales"),
+    ("AU", "AU-NT", "Northern Territory"),
+    ("AU", "AU-QLD", "Queensland"),
+    ("AU", "AU-SA", "South Australia"),
+    ("AU", "AU-TAS", "Tasmania"),
+    ("AU", "AU-VIC", "Victoria"),
+    ("AU", "AU-WA", "Western Australia"),
+    ("CA", "CA-AB", "Alberta"),
+    ("CA", "CA-BC", "British Columbia"),
+    ("CA", "CA-MB", "Manitoba"),
+    ("CA", "CA-NB", "New Brunswick"),
+    ("CA", "CA-NL", "Newfoundland and Labrador"),
+    ("CA", "CA-NS", "Nova Scotia"),
+    ("CA", "CA-NT", "Northwest Territories"),
+    ("CA", "CA-NU", "Nunavut"),
+    ("CA", "CA-ON", "Ontario"),
+    ("CA", "CA-PE", "Prince Edward Island"),
+    ("CA", "CA-QC", "Quebec"),
+    ("CA", "CA-SK", "Saskatchewan"),
+    ("CA", "CA-YT", "Yukon"),
+    ("DE", "DE-BB", "Brandenburg"),
+    ("DE", "DE-BE", "Berlin"),
+    ("DE", "DE-BW", "Baden-Württemberg"),
+    ("DE", "DE-BY", "Bayern"),
+    ("DE", "DE-HB", "Bremen"),
+    ("DE", "DE-HE", "Hessen"),
+    ("DE", "DE-HH", "Hamburg"),
+    ("DE", "DE-MV", "Mecklenburg-Vorpommern"),
+    ("DE", "DE-NI", "Niedersachsen"),
+    ("DE", "DE-NW", "Nordrhein-Westfalen"),
+    ("DE", "DE-RP", "Rheinland-Pfalz"),
+    ("DE", "DE-SH", "Schleswig-Holstein"),
+    ("DE", "DE-SL", "Saarland"),
+    ("DE", "DE-SN", "Sachsen"),
+    ("DE", "DE-ST", "Sachsen-Anhalt"),
+    ("DE", "DE-TH", "Thüringen"),
+    ("US", "US-AK", "Alaska"),
+    ("US", "US-AL", "Alabama"),
+    ("US", "US-AR", "Arkansas"),
+    ("US", "US-AS", "American Samoa"),
+    ("US", "US-AZ", "Arizona"),
+    ("US", "US-CA", "California"),
+    ("US", "US-CO", "Colorado"),
+    ("US", "US-CT", "Connecticut"),
+    ("US", "US-DC", "District of Columbia"),
+    ("US", "US-DE", "Delaware"),
+    ("US", "US-FL", "Florida"),
+    ("US", "US-GA", "Georgia"),
+    ("US", "US-GU", "Guam"),
+    ("US", "US-HI", "Hawaii"),
+    ("US", "US-IA", "Iowa"),
+    ("US", "US-ID", "Idaho"),
+    ("US", "US-IL", "Illinois"),
+    ("US", "US-IN", "Indiana"),
+    ("US", "US-KS", "Kansas"),
+    ("US", "US-KY", "Kentucky"),
+    ("US", "US-LA", "Louisiana"),
+    ("US", "US-MA", "Massachusetts"),
+    ("US", "US-MD", "Maryland"),
+    ("US", "US-ME", "Maine"),
+    ("US", "US-MI", "Michigan"),
+    ("US", "US-MN", "Minnesota"),
+    ("US", "US-MO", "Missouri"),
+    ("US", "US-MP", "Northern Mariana Islands"),
+    ("US", "US-MS", "Mississippi"),
+    ("US", "US-MT", "Montana"),
+    ("US", "US-NC", "North Carolina"),
+    ("US", "US-ND", "North Dakota"),
+    ("US", "US-NE", "Nebraska"),
+    ("US", "US-NH", "New Hampshire"),
+    ("US", "US-NJ", "New Jersey"),
+    ("US", "US-NM", "New Mexico"),
+    ("US", "US-NV", "Nevada"),
+    ("US", "US-NY", "New York"),
+    ("US", "US-OH", "Ohio"),
+    ("US", "US-OK", "Oklahoma"),
+    ("US", "US-OR", "Oregon"),
+    ("US", "US-PA", "Pennsylvania"),
+    ("US", "US-PR", "Puerto Rico"),
+    ("US", "US-RI", "Rhode Island"),
+    ("US", "US-SC", "South Carolina"),
+    ("US", "US-SD", "South Dakota"),
+    ("US", "US-TN", "Tennessee"),
+    ("US", "US-TX", "Texas"),
+    ("US", "US-UM", "United States Minor Outlying Islands"),
+    ("US", "US-UT", "Utah"),
+    ("US", "US-VA", "Virginia"),
+    ("US", "US-VI", "Virgin Islands, U.S."),
+    ("US", "US-VT", "Vermont"),
+    ("US", "US-WA", "Washington"),
+    ("US", "US-WI", "Wisconsin"),
+    ("US", "US-WV", "West Virginia"),
+    ("US", "US-WY", "Wyoming"),
+];
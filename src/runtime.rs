@@ -0,0 +1,91 @@
+use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}, time::Duration};
+use futures::future::BoxFuture;
+use futures_timer::Delay;
+
+
+// The little the scheduled pieces need from an executor, so they run on whichever runtime the
+// app picked with the runtime-* features instead of assuming tokio.
+pub trait AsyncRuntime: Send + Sync {
+    fn spawn(&self, future: BoxFuture<'static, ()>);
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(Delay::new(duration))
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioRuntime;
+
+// Needs to be called from inside a tokio runtime, same as tokio::spawn.
+#[cfg(feature = "tokio")]
+impl AsyncRuntime for TokioRuntime {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        tokio::spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+#[cfg(feature = "async-std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AsyncStdRuntime;
+
+#[cfg(feature = "async-std")]
+impl AsyncRuntime for AsyncStdRuntime {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        async_std::task::spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(async_std::task::sleep(duration))
+    }
+}
+
+#[cfg(feature = "tokio")]
+pub fn get_default_runtime() -> Arc<dyn AsyncRuntime> {
+    Arc::new(TokioRuntime)
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+pub fn get_default_runtime() -> Arc<dyn AsyncRuntime> {
+    Arc::new(AsyncStdRuntime)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PeriodicHandle {
+    stopped: Arc<AtomicBool>,
+}
+
+impl PeriodicHandle {
+    // Takes effect after the current run and sleep, a running task is never cut off half way.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+// Runs the task every interval until stopped, errors are logged and the schedule carries on.
+// This is what the refresh and cleanup helpers (exchange rates, lifecycle policies, projections)
+// are meant to be driven by.
+pub fn spawn_periodic<F>(runtime: Arc<dyn AsyncRuntime>, name: &'static str, interval: Duration, mut task: F) -> PeriodicHandle
+where F: FnMut() -> BoxFuture<'static, anyhow::Result<()>> + Send + 'static {
+    let handle = PeriodicHandle::default();
+    let stopped = handle.clone();
+    let sleeper = runtime.clone();
+    runtime.spawn(Box::pin(async move {
+        while !stopped.is_stopped() {
+            if let Err(err) = task().await {
+                tracing::error!(task = name, error = %err, "periodic task failed");
+            }
+            sleeper.sleep(interval).await;
+        }
+        tracing::info!(task = name, "periodic task stopped");
+    }));
+    handle
+}
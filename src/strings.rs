@@ -0,0 +1,168 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+
+fn split_words(text: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut previous: Option<char> = None;
+    let characters: Vec<char> = text.chars().collect();
+
+    for (index, c) in characters.iter().copied().enumerate() {
+        if !c.is_alphanumeric() {
+            if !current.is_empty() {
+                words.push(std::mem::take(&mut current));
+            }
+            previous = None;
+            continue;
+        }
+
+        // Split fooBar and the end of acronyms like HTTPServer, but keep runs of capitals together.
+        let next_is_lowercase = characters.get(index + 1).map(|next| next.is_lowercase()).unwrap_or(false);
+        let starts_word = match previous {
+            Some(previous) if c.is_uppercase() => previous.is_lowercase() || previous.is_numeric() || (previous.is_uppercase() && next_is_lowercase),
+            _ => false
+        };
+
+        if starts_word && !current.is_empty() {
+            words.push(std::mem::take(&mut current));
+        }
+        current.push(c);
+        previous = Some(c);
+    }
+
+    if !current.is_empty() {
+        words.push(current);
+    }
+    words
+}
+
+fn capitalize(word: &str) -> String {
+    let mut characters = word.chars();
+    match characters.next() {
+        Some(first) => first.to_uppercase().chain(characters.flat_map(char::to_lowercase)).collect(),
+        None => String::new()
+    }
+}
+
+pub fn to_snake_case(text: &str) -> String {
+    split_words(text).iter().map(|word| word.to_lowercase()).collect::<Vec<String>>().join("_")
+}
+
+pub fn to_kebab_case(text: &str) -> String {
+    split_words(text).iter().map(|word| word.to_lowercase()).collect::<Vec<String>>().join("-")
+}
+
+pub fn to_screaming_snake_case(text: &str) -> String {
+    split_words(text).iter().map(|word| word.to_uppercase()).collect::<Vec<String>>().join("_")
+}
+
+pub fn to_pascal_case(text: &str) -> String {
+    split_words(text).iter().map(|word| capitalize(word)).collect()
+}
+
+pub fn to_camel_case(text: &str) -> String {
+    let words = split_words(text);
+    let mut result = String::new();
+    for (index, word) in words.iter().enumerate() {
+        if index == 0 {
+            result.push_str(&word.to_lowercase());
+        } else {
+            result.push_str(&capitalize(word));
+        }
+    }
+    result
+}
+
+// Truncates to at most max_graphemes user visible characters, so accents and emoji are never cut in half.
+pub fn truncate_graphemes(text: &str, max_graphemes: usize) -> &str {
+    match text.grapheme_indices(true).nth(max_graphemes) {
+        Some((index, _)) => &text[..index],
+        None => text
+    }
+}
+
+// Same as above but appends the ellipsis inside the limit when the text had to be cut.
+pub fn truncate_with_ellipsis(text: &str, max_graphemes: usize, ellipsis: &str) -> String {
+    if text.graphemes(true).count() <= max_graphemes {
+        return text.to_owned();
+    }
+
+    let ellipsis_length = ellipsis.graphemes(true).count();
+    let kept = max_graphemes.saturating_sub(ellipsis_length);
+    format!("{}{}", truncate_graphemes(text, kept), ellipsis)
+}
+
+// Truncates to a byte budget (e.g. Postgres' 63 byte identifiers or an 8000 byte NOTIFY payload)
+// without splitting a grapheme.
+pub fn truncate_bytes(text: &str, max_bytes: usize) -> &str {
+    if text.len() <= max_bytes {
+        return text;
+    }
+
+    let mut end = 0;
+    for (index, grapheme) in text.grapheme_indices(true) {
+        if index + grapheme.len() > max_bytes {
+            break;
+        }
+        end = index + grapheme.len();
+    }
+    &text[..end]
+}
+
+// Double quotes an identifier for Postgres, doubling any embedded quotes.
+pub fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+// Quotes each part of a dotted name separately, e.g. schema.table becomes "schema"."table".
+pub fn quote_qualified_identifier(identifier: &str) -> String {
+    identifier.split('.').map(quote_identifier).collect::<Vec<String>>().join(".")
+}
+
+// Single quotes a string literal for the few statements that can't take bind parameters.
+pub fn quote_literal(literal: &str) -> String {
+    format!("'{}'", literal.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_case() {
+        assert_eq!(to_snake_case("HTTPServerError"), "http_server_error");
+        assert_eq!(to_snake_case("userId2fa"), "user_id2fa");
+        assert_eq!(to_kebab_case("  last updated_time "), "last-updated-time");
+        assert_eq!(to_screaming_snake_case("maxPageSize"), "MAX_PAGE_SIZE");
+        assert_eq!(to_pascal_case("created-by user"), "CreatedByUser");
+        assert_eq!(to_camel_case("Order_ID"), "orderId");
+        assert_eq!(to_camel_case("ÉTAT civil"), "étatCivil");
+        assert_eq!(to_snake_case(""), "");
+    }
+
+    #[test]
+    fn truncates_graphemes() {
+        assert_eq!(truncate_graphemes("héllo", 2), "hé");
+        assert_eq!(truncate_graphemes("e\u{301}tude", 1), "e\u{301}");
+        assert_eq!(truncate_graphemes("short", 10), "short");
+        assert_eq!(truncate_with_ellipsis("hello world", 8, "..."), "hello...");
+        assert_eq!(truncate_with_ellipsis("hello", 5, "..."), "hello");
+        assert_eq!(truncate_with_ellipsis("hello", 2, "..."), "...");
+    }
+
+    #[test]
+    fn truncates_bytes() {
+        assert_eq!(truncate_bytes("abc", 3), "abc");
+        assert_eq!(truncate_bytes("aé", 2), "a");
+        assert_eq!(truncate_bytes("a👍🏽b", 5), "a");
+        assert_eq!(truncate_bytes("a👍🏽b", 9), "a👍🏽");
+    }
+
+    #[test]
+    fn quotes_identifiers_and_literals() {
+        assert_eq!(quote_identifier("user"), "\"user\"");
+        assert_eq!(quote_identifier("we\"ird"), "\"we\"\"ird\"");
+        assert_eq!(quote_qualified_identifier("app.orders"), "\"app\".\"orders\"");
+        assert_eq!(quote_literal("it's"), "'it''s'");
+    }
+}
@@ -0,0 +1,278 @@
+use std::fmt;
+use futures::future::BoxFuture;
+use serde::Serialize;
+use sqlx::{Executor, PgConnection, Pool, Postgres};
+use uuid::{Uuid};
+use crate::postgres::{BurchillPostgresError, entity::PostgresEntity};
+use crate::strings::quote_qualified_identifier;
+
+pub type ScenarioStep = Box<dyn for<'c> FnOnce(&'c mut PgConnection) -> BoxFuture<'c, Result<(), BurchillPostgresError>> + Send>;
+
+// Only needed to help closures infer the higher ranked connection lifetime.
+pub fn scenario_step<F>(step: F) -> ScenarioStep
+where F: for<'c> FnOnce(&'c mut PgConnection) -> BoxFuture<'c, Result<(), BurchillPostgresError>> + Send + 'static {
+    Box::new(step)
+}
+
+pub enum ScenarioAssertion {
+    RowCount {
+        table: String,
+        where_sql: Option<String>,
+        expected: i64
+    },
+    Field {
+        table: String,
+        id: Uuid,
+        column: String,
+        expected: serde_json::Value
+    },
+    // Should return an error describing what didn't match.
+    Check(String, ScenarioStep),
+}
+
+impl ScenarioAssertion {
+    pub fn row_count(table: &str, expected: i64) -> Self {
+        ScenarioAssertion::RowCount {
+            table: table.to_owned(),
+            where_sql: None,
+            expected
+        }
+    }
+
+    // where_sql is trusted test code and is used as is.
+    pub fn row_count_where(table: &str, where_sql: &str, expected: i64) -> Self {
+        ScenarioAssertion::RowCount {
+            table: table.to_owned(),
+            where_sql: Some(where_sql.to_owned()),
+            expected
+        }
+    }
+
+    // Compared against the column as it appears in to_jsonb of the row, so timestamps are strings.
+    // Panics if expected can't be serialized rather than quietly comparing against null.
+    pub fn field<V>(table: &str, id: Uuid, column: &str, expected: V) -> Self
+    where V: Serialize {
+        let expected = serde_json::to_value(expected)
+            .unwrap_or_else(|err| panic!("expected value for {}.{} could not be serialized: {}", table, column, err));
+        ScenarioAssertion::Field {
+            table: table.to_owned(),
+            id,
+            column: column.to_owned(),
+            expected
+        }
+    }
+
+    pub fn check(name: &str, check: ScenarioStep) -> Self {
+        ScenarioAssertion::Check(name.to_owned(), check)
+    }
+
+    async fn verify(self, connection: &mut PgConnection) -> Result<Option<String>, BurchillPostgresError> {
+        match self {
+            ScenarioAssertion::RowCount { table, where_sql, expected } => {
+                let mut sql = format!("SELECT count(*) FROM {}", quote_qualified_identifier(&table));
+                if let Some(where_sql) = &where_sql {
+                    sql.push_str(&format!(" WHERE {}", where_sql));
+                }
+                let (actual,): (i64,) = sqlx::query_as(&sql).fetch_one(connection).await?;
+                Ok(match actual == expected {
+                    true => None,
+                    false => Some(format!("expected {} rows in {}{}, found {}", expected, table, where_sql.map(|where_sql| format!(" where {}", where_sql)).unwrap_or_default(), actual))
+                })
+            }
+            ScenarioAssertion::Field { table, id, column, expected } => {
+                let sql = format!("SELECT to_jsonb(t.*) -> $2 FROM {} t WHERE t.id = $1", quote_qualified_identifier(&table));
+                let row: Option<(Option<serde_json::Value>,)> = sqlx::query_as(&sql)
+                    .bind(id)
+                    .bind(&column)
+                    .fetch_optional(connection).await?;
+                Ok(match row {
+                    None => Some(format!("expected {} {} to exist", table, id)),
+                    Some((actual,)) => {
+                        let actual = actual.unwrap_or(serde_json::Value::Null);
+                        match actual == expected {
+                            true => None,
+                            false => Some(format!("expected {}.{} of {} to be {}, found {}", table, column, id, expected, actual))
+                        }
+                    }
+                })
+            }
+            ScenarioAssertion::Check(name, check) => Ok(check(connection).await.err().map(|err| format!("{}: {}", name, err)))
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScenarioReport {
+    pub name: String,
+    pub failures: Vec<String>,
+}
+
+impl ScenarioReport {
+    pub fn is_passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    // For tests, panics with every failed assertion rather than just the first.
+    pub fn assert_passed(&self) {
+        if !self.is_passed() {
+            panic!("{}", self);
+        }
+    }
+}
+
+impl fmt::Display for ScenarioReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.is_passed() {
+            true => write!(f, "Scenario {:?} passed.", self.name),
+            false => write!(f, "Scenario {:?} failed:\n  {}", self.name, self.failures.join("\n  "))
+        }
+    }
+}
+
+// Everything runs in one transaction which is always rolled back, so scenarios can share a database.
+pub struct Scenario {
+    name: String,
+    fixtures: Vec<String>,
+    factories: Vec<ScenarioStep>,
+    action: Option<ScenarioStep>,
+    assertions: Vec<ScenarioAssertion>,
+}
+
+impl Scenario {
+    pub fn new(name: &str) -> Self {
+        Scenario {
+            name: name.to_owned(),
+            fixtures: Vec::new(),
+            factories: Vec::new(),
+            action: None,
+            assertions: Vec::new()
+        }
+    }
+
+    // Raw SQL, may contain several statements.
+    pub fn fixture(mut self, sql: &str) -> Self {
+        self.fixtures.push(sql.to_owned());
+        self
+    }
+
+    pub fn factory(mut self, factory: ScenarioStep) -> Self {
+        self.factories.push(factory);
+        self
+    }
+
+    pub fn entity<T, D>(self, mut entity: T, user_id: Uuid) -> Self
+    where T: PostgresEntity<D> + Send + Sync + 'static, D: 'static {
+        self.factory(scenario_step(move |connection| Box::pin(async move {
+            entity.save(connection, &user_id).await
+        })))
+    }
+
+    pub fn action(mut self, action: ScenarioStep) -> Self {
+        self.action = Some(action);
+        self
+    }
+
+    pub fn expect(mut self, assertion: ScenarioAssertion) -> Self {
+        self.assertions.push(assertion);
+        self
+    }
+
+    // Setup and action errors are returned as is, only failed assertions end up in the report.
+    pub async fn run(self, pool: &Pool<Postgres>) -> Result<ScenarioReport, BurchillPostgresError> {
+        let mut transaction = pool.begin().await?;
+
+        for fixture in &self.fixtures {
+            (&mut *transaction).execute(fixture.as_str()).await?;
+        }
+        for factory in self.factories {
+            factory(&mut *transaction).await?;
+        }
+        if let Some(action) = self.action {
+            action(&mut *transaction).await?;
+        }
+
+        let mut report = ScenarioReport {
+            name: self.name,
+            failures: Vec::new()
+        };
+        for assertion in self.assertions {
+            if let Some(failure) = assertion.verify(&mut *transaction).await? {
+                report.failures.push(failure);
+            }
+        }
+
+        transaction.rollback().await?;
+        tracing::debug!(scenario = %report.name, failures = report.failures.len(), "ran test scenario");
+        Ok(report)
+    }
+}
+
+// scenario!("name", {
+//     fixtures: ["INSERT INTO ..."],
+//     factories: [|connection| { ... Ok(()) }],
+//     action: |connection| { ... Ok(()) },
+//     expect: [ScenarioAssertion::row_count("users", 1)],
+// }).run(&pool).await?.assert_passed();
+// Every section is optional but they have to be in this order.
+#[macro_export]
+macro_rules! scenario {
+    ($name:expr, {
+        $(fixtures: [$($fixture:expr),* $(,)?],)?
+        $(factories: [$(|$factory_connection:ident| $factory:block),* $(,)?],)?
+        $(action: |$action_connection:ident| $action:block,)?
+        $(expect: [$($assertion:expr),* $(,)?] $(,)?)?
+    }) => {{
+        let scenario = $crate::testing::Scenario::new($name);
+        $($(let scenario = scenario.fixture($fixture);)*)?
+        $($(let scenario = scenario.factory($crate::testing::scenario_step(move |$factory_connection| Box::pin(async move $factory)));)*)?
+        $(let scenario = scenario.action($crate::testing::scenario_step(move |$action_connection| Box::pin(async move $action)));)?
+        $($(let scenario = scenario.expect($assertion);)*)?
+        scenario
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+
+    #[test]
+    fn builds_scenarios_with_the_macro() {
+        let id = Uuid::nil();
+        let scenario = crate::scenario!("macro", {
+            fixtures: ["CREATE TEMPORARY TABLE users (id uuid, name text)"],
+            factories: [
+                |connection| {
+                    connection.execute("INSERT INTO users VALUES ('00000000-0000-0000-0000-000000000000', 'a')").await?;
+                    Ok(())
+                },
+            ],
+            action: |connection| {
+                connection.execute("UPDATE users SET name = 'b'").await?;
+                Ok(())
+            },
+            expect: [
+                ScenarioAssertion::row_count("users", 1),
+                ScenarioAssertion::field("users", id, "name", "b"),
+            ],
+        });
+
+        assert_eq!(scenario.name, "macro");
+        assert_eq!(scenario.fixtures.len(), 1);
+        assert_eq!(scenario.factories.len(), 1);
+        assert!(scenario.action.is_some());
+        assert_eq!(scenario.assertions.len(), 2);
+
+        let scenario = crate::scenario!("empty", {});
+        assert!(scenario.action.is_none());
+        assert!(scenario.assertions.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "expected value for users.name could not be serialized")]
+    fn panics_on_unserializable_field_expectations() {
+        let mut expected = HashMap::new();
+        expected.insert((1, 2), "a");
+        ScenarioAssertion::field("users", Uuid::nil(), "name", expected);
+    }
+}
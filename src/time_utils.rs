@@ -0,0 +1,215 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, TimeZone, Utc, Weekday};
+use crate::postgres::criteria::Criteria;
+
+
+pub fn is_weekend(date: &NaiveDate) -> bool {
+    matches!(date.weekday(), Weekday::Sat | Weekday::Sun)
+}
+
+pub fn is_business_day(date: &NaiveDate, holidays: &[NaiveDate]) -> bool {
+    !is_weekend(date) && !holidays.contains(date)
+}
+
+// Moves forwards (or backwards for negative days) counting only business days.
+// None when the result would fall outside the representable dates.
+pub fn add_business_days(date: &NaiveDate, days: i64, holidays: &[NaiveDate]) -> Option<NaiveDate> {
+    let step = if days < 0 { Duration::days(-1) } else { Duration::days(1) };
+    let mut remaining = days.abs();
+    let mut current = *date;
+
+    while remaining > 0 {
+        current = current.checked_add_signed(step)?;
+        if is_business_day(&current, holidays) {
+            remaining -= 1;
+        }
+    }
+    Some(current)
+}
+
+// Business days in [start, end), negative when end is before start.
+pub fn business_days_between(start: &NaiveDate, end: &NaiveDate, holidays: &[NaiveDate]) -> i64 {
+    if end < start {
+        return -business_days_between(end, start, holidays);
+    }
+
+    let mut count = 0;
+    let mut current = *start;
+    while current < *end {
+        if is_business_day(&current, holidays) {
+            count += 1;
+        }
+        current += Duration::days(1);
+    }
+    count
+}
+
+// Local midnight can be skipped or repeated around DST changes, the earliest valid instant wins
+// and a skipped midnight falls forward an hour at a time until the day actually starts.
+fn local_to_utc<Tz: TimeZone>(naive: &NaiveDateTime, tz: &Tz) -> DateTime<Utc> {
+    let mut candidate = *naive;
+    for _ in 0..24 {
+        if let Some(time) = tz.from_local_datetime(&candidate).earliest() {
+            return time.with_timezone(&Utc);
+        }
+        candidate += Duration::hours(1);
+    }
+    Utc.from_utc_datetime(naive)
+}
+
+fn midnight(date: &NaiveDate) -> NaiveDateTime {
+    match date.and_hms_opt(0, 0, 0) {
+        Some(midnight) => midnight,
+        None => unreachable!("midnight is always a valid time")
+    }
+}
+
+pub fn start_of_day<Tz: TimeZone>(time: &DateTime<Utc>, tz: &Tz) -> DateTime<Utc> {
+    let local_date = time.with_timezone(tz).naive_local().date();
+    local_to_utc(&midnight(&local_date), tz)
+}
+
+// Exclusive, i.e. the start of the following day. None on the last representable date.
+pub fn end_of_day<Tz: TimeZone>(time: &DateTime<Utc>, tz: &Tz) -> Option<DateTime<Utc>> {
+    let local_date = time.with_timezone(tz).naive_local().date();
+    local_date.succ_opt().map(|next_date| local_to_utc(&midnight(&next_date), tz))
+}
+
+pub fn start_of_month<Tz: TimeZone>(time: &DateTime<Utc>, tz: &Tz) -> DateTime<Utc> {
+    let local_date = time.with_timezone(tz).naive_local().date();
+    local_to_utc(&midnight(&local_date.with_day(1).unwrap_or(local_date)), tz)
+}
+
+fn pluralize(count: i64, unit: &str) -> String {
+    if count == 1 {
+        format!("{} {}", count, unit)
+    } else {
+        format!("{} {}s", count, unit)
+    }
+}
+
+// Human readable with the two largest units, e.g. "2 days, 3 hours" or "45 seconds".
+pub fn format_duration(duration: &Duration) -> String {
+    let total_seconds = duration.num_seconds().abs();
+    if total_seconds == 0 {
+        return String::from("0 seconds");
+    }
+
+    let units = [("day", 86_400), ("hour", 3_600), ("minute", 60), ("second", 1)];
+    let mut remaining = total_seconds;
+    let mut parts = Vec::new();
+
+    for (unit, seconds) in units.iter() {
+        let count = remaining / seconds;
+        remaining %= seconds;
+        if count > 0 {
+            parts.push(pluralize(count, unit));
+        }
+        if parts.len() == 2 {
+            break;
+        }
+    }
+    parts.join(", ")
+}
+
+// Half open [start, end) range, which is what timestamp comparisons and tstzrange default to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeRange {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+impl TimeRange {
+    pub fn new(start: DateTime<Utc>, end: DateTime<Utc>) -> Self {
+        TimeRange { start, end }
+    }
+
+    pub fn day<Tz: TimeZone>(time: &DateTime<Utc>, tz: &Tz) -> Option<Self> {
+        end_of_day(time, tz).map(|end| TimeRange::new(start_of_day(time, tz), end))
+    }
+
+    pub fn last(duration: Duration) -> Self {
+        let end = Utc::now();
+        TimeRange::new(end - duration, end)
+    }
+
+    pub fn contains(&self, time: &DateTime<Utc>) -> bool {
+        *time >= self.start && *time < self.end
+    }
+
+    pub fn get_duration(&self) -> Duration {
+        self.end - self.start
+    }
+
+    pub fn add_to_criteria(&self, criteria: Criteria, field: &str) -> Criteria {
+        criteria
+            .greater_than_or_equals(field, self.start)
+            .less_than(field, self.end)
+    }
+
+    pub fn to_tstzrange_literal(&self) -> String {
+        format!("[{},{})", self.start.to_rfc3339(), self.end.to_rfc3339())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::FixedOffset;
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&date(year, month, day).and_hms_opt(hour, 0, 0).unwrap())
+    }
+
+    #[test]
+    fn counts_business_days() {
+        // 2021-12-24 is a Friday.
+        let holidays = [date(2021, 12, 27)];
+        assert!(is_weekend(&date(2021, 12, 25)));
+        assert!(!is_business_day(&date(2021, 12, 27), &holidays));
+        assert_eq!(add_business_days(&date(2021, 12, 24), 1, &holidays), Some(date(2021, 12, 28)));
+        assert_eq!(add_business_days(&date(2021, 12, 28), -1, &holidays), Some(date(2021, 12, 24)));
+        assert_eq!(add_business_days(&date(2021, 12, 25), 0, &holidays), Some(date(2021, 12, 25)));
+        assert_eq!(add_business_days(&NaiveDate::MAX, 1, &holidays), None);
+        assert_eq!(add_business_days(&NaiveDate::MIN, -1, &holidays), None);
+        assert_eq!(business_days_between(&date(2021, 12, 24), &date(2021, 12, 31), &holidays), 4);
+        assert_eq!(business_days_between(&date(2021, 12, 31), &date(2021, 12, 24), &holidays), -4);
+    }
+
+    #[test]
+    fn finds_local_day_bounds() {
+        let tz = FixedOffset::west_opt(5 * 3600).unwrap();
+        let time = utc(2021, 3, 1, 3);
+        assert_eq!(start_of_day(&time, &tz), utc(2021, 2, 28, 5));
+        assert_eq!(end_of_day(&time, &tz), Some(utc(2021, 3, 1, 5)));
+        assert_eq!(start_of_month(&time, &tz), utc(2021, 2, 1, 5));
+
+        let range = TimeRange::day(&time, &tz).unwrap();
+        assert!(range.contains(&time));
+        assert!(!range.contains(&range.end));
+        assert_eq!(range.get_duration(), Duration::days(1));
+    }
+
+    #[test]
+    fn has_no_end_on_the_last_day() {
+        let mut last_date = date(262_000, 1, 1);
+        while let Some(next_date) = last_date.succ_opt() {
+            last_date = next_date;
+        }
+        let last = Utc.from_utc_datetime(&last_date.and_hms_opt(12, 0, 0).unwrap());
+        assert_eq!(end_of_day(&last, &Utc), None);
+        assert_eq!(TimeRange::day(&last, &Utc), None);
+    }
+
+    #[test]
+    fn formats_durations() {
+        assert_eq!(format_duration(&Duration::seconds(0)), "0 seconds");
+        assert_eq!(format_duration(&Duration::seconds(45)), "45 seconds");
+        assert_eq!(format_duration(&Duration::seconds(-61)), "1 minute, 1 second");
+        assert_eq!(format_duration(&Duration::seconds(2 * 86_400 + 3 * 3_600 + 59)), "2 days, 3 hours");
+        assert_eq!(format_duration(&Duration::seconds(86_400 + 30)), "1 day, 30 seconds");
+    }
+}
@@ -0,0 +1,30 @@
+use sqlx::{Pool, Postgres};
+use uuid::{Uuid};
+use crate::postgres::{access::Actor, rbac::Rbac};
+use crate::web::response::ErrorResponse;
+
+
+// For handlers to call up front, after authentication has produced the user id.
+pub async fn require_permission(rbac: &Rbac, user_id: &Uuid, permission: &str, pool: &Pool<Postgres>) -> Result<Actor, ErrorResponse> {
+    match rbac.has_permission(user_id, permission, pool).await {
+        Ok(true) => rbac.load_actor(user_id, pool).await.map_err(|err| ErrorResponse::from(&err)),
+        Ok(false) => {
+            tracing::info!(user_id = %user_id, permission = %permission, "permission denied");
+            Err(ErrorResponse::forbidden("You do not have permission to do this."))
+        }
+        Err(err) => Err(ErrorResponse::from(&err))
+    }
+}
+
+pub async fn require_any_permission(rbac: &Rbac, user_id: &Uuid, permissions: &[&str], pool: &Pool<Postgres>) -> Result<Actor, ErrorResponse> {
+    for permission in permissions {
+        match rbac.has_permission(user_id, permission, pool).await {
+            Ok(true) => return rbac.load_actor(user_id, pool).await.map_err(|err| ErrorResponse::from(&err)),
+            Ok(false) => {}
+            Err(err) => return Err(ErrorResponse::from(&err))
+        }
+    }
+
+    tracing::info!(user_id = %user_id, permissions = ?permissions, "permission denied");
+    Err(ErrorResponse::forbidden("You do not have permission to do this."))
+}
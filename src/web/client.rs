@@ -0,0 +1,61 @@
+use async_trait::async_trait;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Vec<u8>>,
+}
+
+impl HttpRequest {
+    pub fn get(url: &str) -> Self {
+        HttpRequest {
+            method: String::from("GET"),
+            url: url.to_owned(),
+            headers: Vec::new(),
+            body: None
+        }
+    }
+
+    pub fn post_json(url: &str, body: Vec<u8>) -> Self {
+        HttpRequest {
+            method: String::from("POST"),
+            url: url.to_owned(),
+            headers: vec![(String::from("content-type"), String::from("application/json"))],
+            body: Some(body)
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_lowercase(), value.to_owned()));
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+// The crate doesn't pick an HTTP client, apps implement this over the one they already use
+// (with their own auth headers, timeouts and retries).
+#[async_trait]
+pub trait HttpTransport: Send + Sync {
+    async fn send(&self, request: HttpRequest) -> anyhow::Result<HttpResponse>;
+}
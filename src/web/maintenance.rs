@@ -0,0 +1,44 @@
+use crate::postgres::maintenance::{MaintenanceLevel, MaintenanceState};
+use crate::web::response::ErrorResponse;
+
+
+#[derive(Clone, Debug)]
+pub struct MaintenanceGuardConfig {
+    pub allowed_paths: Vec<String>,
+    pub status: u16,
+    pub default_message: String,
+}
+
+impl Default for MaintenanceGuardConfig {
+    fn default() -> Self {
+        MaintenanceGuardConfig {
+            allowed_paths: vec![String::from("/health"), String::from("/ready")],
+            status: 503,
+            default_message: String::from("The service is down for maintenance, please try again later.")
+        }
+    }
+}
+
+fn is_read_method(method: &str) -> bool {
+    matches!(method.to_uppercase().as_str(), "GET" | "HEAD" | "OPTIONS")
+}
+
+// Framework agnostic check to call from a middleware before the handler runs.
+pub fn check_maintenance(state: &MaintenanceState, method: &str, path: &str, config: &MaintenanceGuardConfig) -> Result<(), ErrorResponse> {
+    if config.allowed_paths.iter().any(|allowed| path == allowed || path.starts_with(&format!("{}/", allowed))) {
+        return Ok(());
+    }
+
+    let blocked = match state.level {
+        MaintenanceLevel::Off => false,
+        MaintenanceLevel::ReadOnly => !is_read_method(method),
+        MaintenanceLevel::Full => true,
+    };
+
+    if !blocked {
+        return Ok(());
+    }
+
+    let message = state.message.as_deref().unwrap_or(&config.default_message);
+    Err(ErrorResponse::new(config.status, "maintenance", message))
+}
@@ -0,0 +1,9 @@
+#[cfg(feature = "database")]
+pub mod authorization;
+pub mod client;
+#[cfg(feature = "database")]
+pub mod maintenance;
+pub mod query_params;
+pub mod query_stats;
+pub mod remote_entity;
+pub mod response;
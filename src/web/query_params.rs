@@ -0,0 +1,374 @@
+use chrono::{DateTime, Utc};
+use quaint::Value;
+use thiserror::Error;
+use uuid::{Uuid};
+use crate::postgres::criteria::{Criteria, FindManyOptions, PageRequest, Sort};
+
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+pub const DEFAULT_MAX_PAGE_SIZE: usize = 100;
+// Deep offsets are slow to scan anyway, past this clients should page by keyset.
+pub const DEFAULT_MAX_PAGE: usize = 10_000;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterKind {
+    Text,
+    Integer,
+    Float,
+    Boolean,
+    Uuid,
+    DateTime,
+}
+
+#[derive(Clone, Debug)]
+pub struct FilterField {
+    pub name: &'static str,
+    pub kind: FilterKind,
+}
+
+// Only fields listed here can be sorted or filtered on, everything else in the query string is rejected.
+#[derive(Clone, Debug)]
+pub struct ListQueryConfig {
+    pub default_size: usize,
+    pub max_size: usize,
+    pub max_page: usize,
+    pub default_sort: Vec<Sort>,
+    pub sortable_fields: Vec<&'static str>,
+    pub filterable_fields: Vec<FilterField>,
+}
+
+impl Default for ListQueryConfig {
+    fn default() -> Self {
+        ListQueryConfig {
+            default_size: DEFAULT_PAGE_SIZE,
+            max_size: DEFAULT_MAX_PAGE_SIZE,
+            max_page: DEFAULT_MAX_PAGE,
+            default_sort: vec![Sort::descending("created_time")],
+            sortable_fields: vec!["created_time", "last_updated_time"],
+            filterable_fields: Vec::new()
+        }
+    }
+}
+
+impl ListQueryConfig {
+    pub fn new() -> Self {
+        ListQueryConfig::default()
+    }
+
+    pub fn sortable(mut self, field: &'static str) -> Self {
+        self.sortable_fields.push(field);
+        self
+    }
+
+    pub fn filterable(mut self, field: &'static str, kind: FilterKind) -> Self {
+        self.filterable_fields.push(FilterField { name: field, kind });
+        self
+    }
+
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn default_size(mut self, default_size: usize) -> Self {
+        self.default_size = default_size;
+        self
+    }
+
+    pub fn max_page(mut self, max_page: usize) -> Self {
+        self.max_page = max_page;
+        self
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum QueryParamError {
+    #[error("The query string could not be decoded. (Value: {0:?})")]
+    InvalidEncoding(String),
+    #[error("Expected a non negative number. (Parameter: {param:?}, Value: {value:?})")]
+    InvalidNumber {
+        param: String,
+        value: String
+    },
+    #[error("The page size must be between 1 and {max}. (Value: {value})")]
+    InvalidPageSize {
+        value: usize,
+        max: usize
+    },
+    #[error("The page must be at most {max}. (Value: {value})")]
+    InvalidPage {
+        value: usize,
+        max: usize
+    },
+    #[error("Sorting on this field is not allowed. (Field: {0:?})")]
+    UnknownSortField(String),
+    #[error("Expected the sort direction to be asc or desc. (Value: {0:?})")]
+    InvalidSortDirection(String),
+    #[error("Filtering on this field is not allowed. (Field: {0:?})")]
+    UnknownFilterField(String),
+    #[error("Expected filters in the form field:operator:value. (Value: {0:?})")]
+    InvalidFilter(String),
+    #[error("Unknown filter operator. (Operator: {0:?})")]
+    UnknownFilterOperator(String),
+    #[error("The filter value does not match the field type. (Field: {field:?}, Value: {value:?})")]
+    InvalidFilterValue {
+        field: String,
+        value: String
+    },
+}
+
+fn from_hex(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None
+    }
+}
+
+pub fn decode_component(component: &str) -> Result<String, QueryParamError> {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        match bytes[index] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let high = bytes.get(index + 1).copied().and_then(from_hex);
+                let low = bytes.get(index + 2).copied().and_then(from_hex);
+                match (high, low) {
+                    (Some(high), Some(low)) => decoded.push(high * 16 + low),
+                    _ => return Err(QueryParamError::InvalidEncoding(component.to_owned()))
+                }
+                index += 2;
+            }
+            byte => decoded.push(byte),
+        }
+        index += 1;
+    }
+
+    String::from_utf8(decoded).map_err(|_| QueryParamError::InvalidEncoding(component.to_owned()))
+}
+
+pub fn parse_query_string(query: &str) -> Result<Vec<(String, String)>, QueryParamError> {
+    query.trim_start_matches('?')
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            let key = decode_component(parts.next().unwrap_or_default())?;
+            let value = decode_component(parts.next().unwrap_or_default())?;
+            Ok((key, value))
+        })
+        .collect()
+}
+
+fn parse_number(param: &str, value: &str) -> Result<usize, QueryParamError> {
+    value.trim().parse().map_err(|_| QueryParamError::InvalidNumber {
+        param: param.to_owned(),
+        value: value.to_owned()
+    })
+}
+
+pub fn parse_sort(value: &str, config: &ListQueryConfig) -> Result<Sort, QueryParamError> {
+    let mut parts = value.splitn(2, ',');
+    let field = parts.next().unwrap_or_default().trim();
+    if !config.sortable_fields.contains(&field) {
+        return Err(QueryParamError::UnknownSortField(field.to_owned()));
+    }
+
+    match parts.next().map(|direction| direction.trim().to_lowercase()) {
+        None => Ok(Sort::ascending(field)),
+        Some(direction) if direction == "asc" => Ok(Sort::ascending(field)),
+        Some(direction) if direction == "desc" => Ok(Sort::descending(field)),
+        Some(direction) => Err(QueryParamError::InvalidSortDirection(direction)),
+    }
+}
+
+fn parse_filter_value(field: &FilterField, value: &str) -> Result<Value<'static>, QueryParamError> {
+    let invalid = || QueryParamError::InvalidFilterValue {
+        field: field.name.to_owned(),
+        value: value.to_owned()
+    };
+
+    match field.kind {
+        FilterKind::Text => Ok(Value::text(value.to_owned())),
+        FilterKind::Integer => value.parse::<i64>().map(Value::integer).map_err(|_| invalid()),
+        FilterKind::Float => value.parse::<f64>().map(Value::double).map_err(|_| invalid()),
+        FilterKind::Boolean => value.parse::<bool>().map(Value::boolean).map_err(|_| invalid()),
+        FilterKind::Uuid => Uuid::parse_str(value).map(Value::uuid).map_err(|_| invalid()),
+        FilterKind::DateTime => DateTime::parse_from_rfc3339(value)
+            .map(|time| Value::datetime(time.with_timezone(&Utc)))
+            .map_err(|_| invalid()),
+    }
+}
+
+// Filters look like field:operator:value, e.g. status:eq:open or total:gte:100 or id:in:a|b|c
+pub fn parse_filter(filter: &str, criteria: Criteria, config: &ListQueryConfig) -> Result<Criteria, QueryParamError> {
+    let mut parts = filter.splitn(3, ':');
+    let (name, operator) = match (parts.next(), parts.next()) {
+        (Some(name), Some(operator)) => (name.trim(), operator.trim().to_lowercase()),
+        _ => return Err(QueryParamError::InvalidFilter(filter.to_owned()))
+    };
+    let value = parts.next();
+
+    let field = match config.filterable_fields.iter().find(|field| field.name == name) {
+        Some(field) => field,
+        None => return Err(QueryParamError::UnknownFilterField(name.to_owned()))
+    };
+
+    match operator.as_str() {
+        "null" => return Ok(criteria.is_null(field.name)),
+        "notnull" => return Ok(criteria.is_not_null(field.name)),
+        _ => {}
+    }
+
+    let value = match value {
+        Some(value) => value,
+        None => return Err(QueryParamError::InvalidFilter(filter.to_owned()))
+    };
+
+    match operator.as_str() {
+        "eq" => Ok(criteria.equals(field.name, parse_filter_value(field, value)?)),
+        "ne" => Ok(criteria.not_equals(field.name, parse_filter_value(field, value)?)),
+        "gt" => Ok(criteria.greater_than(field.name, parse_filter_value(field, value)?)),
+        "gte" => Ok(criteria.greater_than_or_equals(field.name, parse_filter_value(field, value)?)),
+        "lt" => Ok(criteria.less_than(field.name, parse_filter_value(field, value)?)),
+        "lte" => Ok(criteria.less_than_or_equals(field.name, parse_filter_value(field, value)?)),
+        "like" if field.kind == FilterKind::Text => Ok(criteria.like(field.name, value)),
+        "in" => {
+            let values = value.split('|')
+                .map(|value| parse_filter_value(field, value))
+                .collect::<Result<Vec<Value<'static>>, QueryParamError>>()?;
+            Ok(criteria.in_values(field.name, values))
+        }
+        _ => Err(QueryParamError::UnknownFilterOperator(operator))
+    }
+}
+
+// Parses ?page=0&size=20&sort=field,desc&filter=field:eq:value into find_many options.
+// Pages are zero based like PageRequest, sort and filter can be repeated.
+pub fn parse_list_query(query: &str, config: &ListQueryConfig) -> Result<FindManyOptions, QueryParamError> {
+    let mut page = 0;
+    let mut size = config.default_size;
+    let mut sort = Vec::new();
+    let mut criteria = Criteria::new();
+
+    for (key, value) in parse_query_string(query)?.into_iter() {
+        match key.as_str() {
+            "page" => page = parse_number(&key, &value)?,
+            "size" => size = parse_number(&key, &value)?,
+            "sort" => sort.push(parse_sort(&value, config)?),
+            "filter" => criteria = parse_filter(&value, criteria, config)?,
+            _ => {}
+        }
+    }
+
+    if size == 0 || size > config.max_size {
+        return Err(QueryParamError::InvalidPageSize {
+            value: size,
+            max: config.max_size
+        });
+    }
+
+    if page > config.max_page {
+        return Err(QueryParamError::InvalidPage {
+            value: page,
+            max: config.max_page
+        });
+    }
+
+    if sort.is_empty() {
+        sort = config.default_sort.clone();
+    }
+
+    let mut options = FindManyOptions::new()
+        .criteria(criteria)
+        .page(PageRequest::offset(page, size))
+        .with_total(true);
+    options.sort = sort;
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::postgres::criteria::SortDirection;
+
+    fn config() -> ListQueryConfig {
+        ListQueryConfig::new()
+            .sortable("name")
+            .filterable("name", FilterKind::Text)
+            .filterable("total", FilterKind::Integer)
+            .filterable("id", FilterKind::Uuid)
+            .max_size(50)
+            .max_page(100)
+    }
+
+    #[test]
+    fn decodes_query_strings() {
+        assert_eq!(parse_query_string("?name=Jane+Doe&tag=a%26b&flag&&empty=").unwrap(), vec![
+            (String::from("name"), String::from("Jane Doe")),
+            (String::from("tag"), String::from("a&b")),
+            (String::from("flag"), String::new()),
+            (String::from("empty"), String::new())
+        ]);
+        assert_eq!(decode_component("caf%C3%A9").unwrap(), "café");
+        assert_eq!(decode_component("100%"), Err(QueryParamError::InvalidEncoding(String::from("100%"))));
+        assert_eq!(decode_component("%zz"), Err(QueryParamError::InvalidEncoding(String::from("%zz"))));
+        assert_eq!(decode_component("%FF"), Err(QueryParamError::InvalidEncoding(String::from("%FF"))));
+    }
+
+    #[test]
+    fn parses_defaults() {
+        let options = parse_list_query("", &config()).unwrap();
+        assert!(matches!(options.page, Some(PageRequest::Offset { page: 0, size: DEFAULT_PAGE_SIZE })));
+        assert_eq!(options.sort.len(), 1);
+        assert_eq!(options.sort[0].field, "created_time");
+        assert_eq!(options.sort[0].direction, SortDirection::Descending);
+        assert!(options.criteria.is_empty());
+        assert!(options.with_total);
+    }
+
+    #[test]
+    fn parses_pages_sorts_and_filters() {
+        let options = parse_list_query("page=3&size=50&sort=name&sort=created_time,DESC&filter=total:gte:10&filter=id:notnull", &config()).unwrap();
+        assert!(matches!(options.page, Some(PageRequest::Offset { page: 3, size: 50 })));
+        let sorts: Vec<(&str, SortDirection)> = options.sort.iter().map(|sort| (sort.field.as_str(), sort.direction)).collect();
+        assert_eq!(sorts, vec![("name", SortDirection::Ascending), ("created_time", SortDirection::Descending)]);
+        assert!(!options.criteria.is_empty());
+    }
+
+    #[test]
+    fn enforces_page_bounds() {
+        let config = config();
+        assert_eq!(parse_list_query("size=0", &config).unwrap_err(), QueryParamError::InvalidPageSize { value: 0, max: 50 });
+        assert_eq!(parse_list_query("size=51", &config).unwrap_err(), QueryParamError::InvalidPageSize { value: 51, max: 50 });
+        assert!(parse_list_query("page=100", &config).is_ok());
+        assert_eq!(parse_list_query("page=101", &config).unwrap_err(), QueryParamError::InvalidPage { value: 101, max: 100 });
+        assert_eq!(parse_list_query("page=-1", &config).unwrap_err(), QueryParamError::InvalidNumber {
+            param: String::from("page"),
+            value: String::from("-1")
+        });
+        assert!(matches!(parse_list_query("page=99999999999999999999999", &config), Err(QueryParamError::InvalidNumber { .. })));
+    }
+
+    #[test]
+    fn rejects_unknown_sorts_and_filters() {
+        let config = config();
+        assert_eq!(parse_list_query("sort=password", &config).unwrap_err(), QueryParamError::UnknownSortField(String::from("password")));
+        assert_eq!(parse_list_query("sort=name,up", &config).unwrap_err(), QueryParamError::InvalidSortDirection(String::from("up")));
+        assert_eq!(parse_list_query("filter=password:eq:x", &config).unwrap_err(), QueryParamError::UnknownFilterField(String::from("password")));
+        assert_eq!(parse_list_query("filter=name", &config).unwrap_err(), QueryParamError::InvalidFilter(String::from("name")));
+        assert_eq!(parse_list_query("filter=name:eq", &config).unwrap_err(), QueryParamError::InvalidFilter(String::from("name:eq")));
+        assert_eq!(parse_list_query("filter=name:regex:x", &config).unwrap_err(), QueryParamError::UnknownFilterOperator(String::from("regex")));
+        assert_eq!(parse_list_query("filter=total:like:1%25", &config).unwrap_err(), QueryParamError::UnknownFilterOperator(String::from("like")));
+        assert_eq!(parse_list_query("filter=total:in:1|two", &config).unwrap_err(), QueryParamError::InvalidFilterValue {
+            field: String::from("total"),
+            value: String::from("two")
+        });
+        assert!(matches!(parse_list_query("filter=id:eq:nope", &config), Err(QueryParamError::InvalidFilterValue { .. })));
+        assert!(parse_list_query("filter=name:like:Ja%25&filter=total:in:1|2|3", &config).is_ok());
+    }
+}
@@ -0,0 +1,185 @@
+use std::{cell::RefCell, future::Future, pin::Pin, sync::{Arc, atomic::{AtomicU64, Ordering}}, task::{Context, Poll}, time::{Duration, Instant}};
+use crate::postgres::fingerprint::QueryShapeTracker;
+
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueryBudget {
+    pub max_queries: Option<u64>,
+    pub max_db_time: Option<Duration>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestQuerySummary {
+    pub method: String,
+    pub path: String,
+    pub query_count: u64,
+    pub db_time: Duration,
+    pub rows_fetched: u64,
+    pub request_time: Duration,
+    // Queries that ran with the same text and bindings more than once in this request.
+    pub duplicate_queries: u64,
+    pub over_budget: bool,
+}
+
+// Implemented by the apps metrics backend to get the summary as a metric instead of (or as well as) a log line.
+pub trait RequestQueryMetrics: Send + Sync {
+    fn record_request(&self, summary: &RequestQuerySummary);
+}
+
+#[derive(Default)]
+struct Counters {
+    query_count: AtomicU64,
+    db_time_micros: AtomicU64,
+    rows_fetched: AtomicU64,
+}
+
+// Cheap to clone, handed to the handler (e.g. as a request extension). Queries through fetch_* and
+// update_and_fetch_* record themselves while the handler runs under with_query_stats.
+#[derive(Clone)]
+pub struct RequestQueryStats {
+    counters: Arc<Counters>,
+    shapes: Arc<QueryShapeTracker>,
+    started: Instant,
+}
+
+impl Default for RequestQueryStats {
+    fn default() -> Self {
+        RequestQueryStats::new()
+    }
+}
+
+impl RequestQueryStats {
+    pub fn new() -> Self {
+        RequestQueryStats {
+            counters: Arc::new(Counters::default()),
+            shapes: Arc::new(QueryShapeTracker::new()),
+            started: Instant::now()
+        }
+    }
+
+    pub fn record(&self, duration: Duration, rows: u64) {
+        self.counters.query_count.fetch_add(1, Ordering::Relaxed);
+        self.counters.db_time_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.counters.rows_fetched.fetch_add(rows, Ordering::Relaxed);
+    }
+
+    // Also tracks the query shape so repeated and duplicated queries show up in the summary.
+    pub fn record_query(&self, sql: &str, bindings_key: &str, duration: Duration, rows: u64) {
+        self.record(duration, rows);
+        self.shapes.record(sql, bindings_key, duration);
+    }
+
+    // For queries that don't go through fetch_*, e.g. plain sqlx ones. Times a query returning rows,
+    // e.g. stats.measure(sqlx::query_as(sql).fetch_all(&pool)).await
+    pub async fn measure<T, E, F>(&self, future: F) -> Result<Vec<T>, E>
+    where F: Future<Output = Result<Vec<T>, E>> {
+        let started = Instant::now();
+        let result = future.await;
+        let rows = result.as_ref().map(|rows| rows.len() as u64).unwrap_or(0);
+        self.record(started.elapsed(), rows);
+        result
+    }
+
+    pub async fn measure_one<T, E, F>(&self, future: F) -> Result<T, E>
+    where F: Future<Output = Result<T, E>> {
+        let started = Instant::now();
+        let result = future.await;
+        self.record(started.elapsed(), if result.is_ok() { 1 } else { 0 });
+        result
+    }
+
+    pub fn get_shapes(&self) -> &QueryShapeTracker {
+        &self.shapes
+    }
+
+    pub fn create_summary(&self, method: &str, path: &str, budget: &QueryBudget) -> RequestQuerySummary {
+        let query_count = self.counters.query_count.load(Ordering::Relaxed);
+        let db_time = Duration::from_micros(self.counters.db_time_micros.load(Ordering::Relaxed));
+        let over_budget = budget.max_queries.is_some_and(|max_queries| query_count > max_queries)
+            || budget.max_db_time.is_some_and(|max_db_time| db_time > max_db_time);
+
+        RequestQuerySummary {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            query_count,
+            db_time,
+            rows_fetched: self.counters.rows_fetched.load(Ordering::Relaxed),
+            request_time: self.started.elapsed(),
+            duplicate_queries: self.shapes.get_stats().iter().map(|shape| shape.duplicates).sum(),
+            over_budget
+        }
+    }
+}
+
+pub fn log_summary(summary: &RequestQuerySummary) {
+    let db_time_ms = summary.db_time.as_millis() as u64;
+    let request_time_ms = summary.request_time.as_millis() as u64;
+    if summary.over_budget {
+        tracing::warn!(
+            method = %summary.method, path = %summary.path, queries = summary.query_count, db_time_ms, rows = summary.rows_fetched,
+            request_time_ms, duplicates = summary.duplicate_queries, "request went over its query budget"
+        );
+    } else {
+        tracing::info!(
+            method = %summary.method, path = %summary.path, queries = summary.query_count, db_time_ms, rows = summary.rows_fetched,
+            request_time_ms, duplicates = summary.duplicate_queries, "request query stats"
+        );
+    }
+}
+
+thread_local! {
+    static CURRENT_STATS: RefCell<Option<RequestQueryStats>> = RefCell::new(None);
+}
+
+// The stats of the request whose handler is being polled on this thread, if any.
+pub fn current_query_stats() -> Option<RequestQueryStats> {
+    CURRENT_STATS.with(|current| current.borrow().clone())
+}
+
+// Works like a task local but on any runtime, the stats are current while the handler is polled.
+// Work the handler spawns onto other tasks isn't counted.
+struct QueryStatsScope<F> {
+    stats: RequestQueryStats,
+    future: Pin<Box<F>>,
+}
+
+// Puts the previous stats back even if the handler panics.
+struct RestoreStats(Option<RequestQueryStats>);
+
+impl Drop for RestoreStats {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CURRENT_STATS.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+impl<F> Future for QueryStatsScope<F>
+where F: Future {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let stats = self.stats.clone();
+        let _restore = RestoreStats(CURRENT_STATS.with(|current| current.replace(Some(stats))));
+        self.future.as_mut().poll(cx)
+    }
+}
+
+// Framework agnostic middleware body, wraps the handler and logs (and records) the summary once it finishes.
+pub async fn with_query_stats<T, F, Fut>(method: &str, path: &str, budget: &QueryBudget, metrics: Option<&dyn RequestQueryMetrics>, handler: F) -> T
+where
+    F: FnOnce(RequestQueryStats) -> Fut,
+    Fut: Future<Output = T>
+{
+    let stats = RequestQueryStats::new();
+    let output = QueryStatsScope {
+        stats: stats.clone(),
+        future: Box::pin(handler(stats.clone()))
+    }.await;
+
+    let summary = stats.create_summary(method, path, budget);
+    log_summary(&summary);
+    if let Some(metrics) = metrics {
+        metrics.record_request(&summary);
+    }
+    output
+}
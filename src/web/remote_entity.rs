@@ -0,0 +1,244 @@
+use std::{collections::HashMap, sync::{Mutex, PoisonError}, time::{Duration, Instant}};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, de::DeserializeOwned};
+use thiserror::Error;
+use uuid::{Uuid};
+use crate::codec::{Codec, CodecError, CodecFormat};
+use crate::web::{client::{HttpRequest, HttpTransport}, response::ApiResponse};
+
+pub const DEFAULT_MAX_CACHED_ENTITIES: usize = 1000;
+
+
+#[derive(Error, Debug)]
+pub enum RemoteEntityError {
+    #[error("The remote entity was not found. (Url: {0})")]
+    NotFound(String),
+    #[error("The remote service responded with status {status}. (Url: {url})")]
+    Status {
+        url: String,
+        status: u16
+    },
+    #[error("The remote entity could not be decoded. (Url: {url}, Error: {source})")]
+    Decode {
+        url: String,
+        source: CodecError
+    },
+    #[error(transparent)]
+    Transport(#[from] anyhow::Error),
+}
+
+// Same audit fields as PostgresBaseEntityData, as the other services serialize them.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteEntityData {
+    pub id: Uuid,
+    pub created_time: DateTime<Utc>,
+    pub created_by: Uuid,
+    pub last_updated_time: Option<DateTime<Utc>>,
+    pub last_updated_by: Option<Uuid>,
+    #[serde(default = "default_active")]
+    pub active: bool,
+}
+
+fn default_active() -> bool {
+    true
+}
+
+// Read only on purpose, changes go through the owning service's API.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemoteEntity<T> {
+    base: RemoteEntityData,
+    data: T,
+    etag: Option<String>,
+}
+
+impl<T> RemoteEntity<T> {
+    pub fn get_id(&self) -> Uuid {
+        self.base.id
+    }
+
+    pub fn get_created_time(&self) -> DateTime<Utc> {
+        self.base.created_time
+    }
+
+    pub fn get_created_by(&self) -> Uuid {
+        self.base.created_by
+    }
+
+    pub fn get_last_updated_time(&self) -> Option<DateTime<Utc>> {
+        self.base.last_updated_time
+    }
+
+    pub fn get_last_updated_by(&self) -> Option<Uuid> {
+        self.base.last_updated_by
+    }
+
+    pub fn get_active(&self) -> bool {
+        self.base.active
+    }
+
+    pub fn get_etag(&self) -> Option<&str> {
+        self.etag.as_deref()
+    }
+
+    pub fn get_data(&self) -> &T {
+        &self.data
+    }
+
+    pub fn into_data(self) -> T {
+        self.data
+    }
+}
+
+#[derive(Deserialize)]
+struct RemoteEntityBody<T> {
+    #[serde(flatten)]
+    base: RemoteEntityData,
+    #[serde(flatten)]
+    data: T,
+}
+
+struct CachedResponse {
+    body: Vec<u8>,
+    format: CodecFormat,
+    etag: Option<String>,
+    fetched: Instant,
+}
+
+pub struct RemoteEntityClient<H> {
+    transport: H,
+    base_url: String,
+    // Cached entities are used without asking within max_age, after that they are revalidated with If-None-Match.
+    max_age: Duration,
+    // Asked for in the accept header, responses are still decoded by their own content type.
+    format: CodecFormat,
+    // Past this many the least recently fetched entity is dropped to make room.
+    max_entries: usize,
+    cache: Mutex<HashMap<String, CachedResponse>>,
+}
+
+impl<H> RemoteEntityClient<H>
+where H: HttpTransport {
+    pub fn new(transport: H, base_url: &str) -> Self {
+        RemoteEntityClient {
+            transport,
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            max_age: Duration::from_secs(30),
+            format: CodecFormat::Json,
+            max_entries: DEFAULT_MAX_CACHED_ENTITIES,
+            cache: Mutex::new(HashMap::new())
+        }
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    pub fn with_format(mut self, format: CodecFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    pub fn create_url(&self, resource: &str, id: &Uuid) -> String {
+        format!("{}/{}/{}", self.base_url, resource.trim_matches('/'), id)
+    }
+
+    pub fn invalidate(&self, resource: &str, id: &Uuid) {
+        let url = self.create_url(resource, id);
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner).remove(&url);
+    }
+
+    pub fn clear_cache(&self) {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+
+    // Expects the usual {"data": {...}} envelope from ApiResponse.
+    pub async fn get<T>(&self, resource: &str, id: &Uuid) -> Result<RemoteEntity<T>, RemoteEntityError>
+    where T: DeserializeOwned {
+        let url = self.create_url(resource, id);
+
+        let cached_etag = {
+            let cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+            match cache.get(&url) {
+                Some(cached) if cached.fetched.elapsed() < self.max_age => return decode_entity(&url, cached.format, &cached.body, cached.etag.to_owned()),
+                Some(cached) => cached.etag.to_owned(),
+                None => None
+            }
+        };
+
+        let mut request = HttpRequest::get(&url).header("accept", self.format.get_content_type());
+        if let Some(etag) = &cached_etag {
+            request = request.header("if-none-match", etag);
+        }
+        let response = self.transport.send(request).await?;
+
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        match response.status {
+            304 => match cache.get_mut(&url) {
+                Some(cached) => {
+                    cached.fetched = Instant::now();
+                    decode_entity(&url, cached.format, &cached.body, cached.etag.to_owned())
+                }
+                None => Err(RemoteEntityError::Status { url, status: 304 })
+            },
+            404 | 410 => {
+                cache.remove(&url);
+                Err(RemoteEntityError::NotFound(url))
+            }
+            _ if response.is_success() => {
+                let etag = response.get_header("etag").map(|etag| etag.to_owned());
+                let format = match response.get_header("content-type") {
+                    Some(content_type) => CodecFormat::from_content_type(content_type).map_err(|source| RemoteEntityError::Decode { url: url.to_owned(), source })?,
+                    None => self.format
+                };
+                let entity = decode_entity(&url, format, &response.body, etag.to_owned())?;
+                if self.max_entries == 0 {
+                    return Ok(entity);
+                }
+                if cache.len() >= self.max_entries && !cache.contains_key(&url) {
+                    let oldest = cache.iter().min_by_key(|(_, cached)| cached.fetched).map(|(url, _)| url.to_owned());
+                    if let Some(oldest) = oldest {
+                        cache.remove(&oldest);
+                    }
+                }
+                cache.insert(url, CachedResponse {
+                    body: response.body,
+                    format,
+                    etag,
+                    fetched: Instant::now()
+                });
+                Ok(entity)
+            }
+            status => Err(RemoteEntityError::Status { url, status })
+        }
+    }
+
+    pub async fn get_optional<T>(&self, resource: &str, id: &Uuid) -> Result<Option<RemoteEntity<T>>, RemoteEntityError>
+    where T: DeserializeOwned {
+        match self.get(resource, id).await {
+            Ok(entity) => Ok(Some(entity)),
+            Err(RemoteEntityError::NotFound(_)) => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+}
+
+fn decode_entity<T>(url: &str, format: CodecFormat, body: &[u8], etag: Option<String>) -> Result<RemoteEntity<T>, RemoteEntityError>
+where T: DeserializeOwned {
+    let response: ApiResponse<RemoteEntityBody<T>> = format.decode(body).map_err(|source| RemoteEntityError::Decode {
+        url: url.to_owned(),
+        source
+    })?;
+
+    Ok(RemoteEntity {
+        base: response.data.base,
+        data: response.data.data,
+        etag
+    })
+}
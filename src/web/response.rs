@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+use crate::postgres::{BurchillPostgresError, criteria::Page};
+use crate::web::query_params::QueryParamError;
+
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiResponse<T> {
+    pub data: T,
+}
+
+impl<T> ApiResponse<T> {
+    pub fn new(data: T) -> Self {
+        ApiResponse { data }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PageMeta {
+    pub page: Option<usize>,
+    pub size: Option<usize>,
+    pub total: Option<i64>,
+    pub total_pages: Option<i64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PagedResponse<T> {
+    pub data: Vec<T>,
+    pub page: PageMeta,
+}
+
+impl<T> From<Page<T>> for PagedResponse<T> {
+    fn from(page: Page<T>) -> Self {
+        let total_pages = match (page.total, page.size) {
+            (Some(total), Some(size)) if size > 0 => Some((total + size as i64 - 1) / size as i64),
+            _ => None
+        };
+
+        PagedResponse {
+            data: page.items,
+            page: PageMeta {
+                page: page.page,
+                size: page.size,
+                total: page.total,
+                total_pages
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorDetail {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<ErrorDetail>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorResponse {
+    #[serde(skip)]
+    pub status: u16,
+    pub error: ErrorBody,
+}
+
+impl ErrorResponse {
+    pub fn new(status: u16, code: &str, message: &str) -> Self {
+        ErrorResponse {
+            status,
+            error: ErrorBody {
+                code: code.to_owned(),
+                message: message.to_owned(),
+                details: Vec::new()
+            }
+        }
+    }
+
+    pub fn with_detail(mut self, field: Option<&str>, message: &str) -> Self {
+        self.error.details.push(ErrorDetail {
+            field: field.map(|field| field.to_owned()),
+            message: message.to_owned()
+        });
+        self
+    }
+
+    pub fn bad_request(message: &str) -> Self {
+        ErrorResponse::new(400, "bad_request", message)
+    }
+
+    pub fn forbidden(message: &str) -> Self {
+        ErrorResponse::new(403, "forbidden", message)
+    }
+
+    pub fn not_found(message: &str) -> Self {
+        ErrorResponse::new(404, "not_found", message)
+    }
+
+    pub fn conflict(message: &str) -> Self {
+        ErrorResponse::new(409, "conflict", message)
+    }
+
+    pub fn internal() -> Self {
+        ErrorResponse::new(500, "internal_error", "An unexpected error occurred.")
+    }
+}
+
+impl From<&QueryParamError> for ErrorResponse {
+    fn from(err: &QueryParamError) -> Self {
+        ErrorResponse::new(400, "invalid_query", &err.to_string())
+    }
+}
+
+// Database details stay out of the response body, anything unexpected becomes a generic 500.
+impl From<&BurchillPostgresError> for ErrorResponse {
+    fn from(err: &BurchillPostgresError) -> Self {
+        match err {
+            #[cfg(feature = "database")]
+            BurchillPostgresError::SqlxError(sqlx::Error::RowNotFound) => ErrorResponse::not_found("The requested resource does not exist."),
+            BurchillPostgresError::AccessDenied { .. } | BurchillPostgresError::MissingPermission { .. } => ErrorResponse::forbidden("You do not have access to this resource."),
+            #[cfg(feature = "database")]
+            BurchillPostgresError::DeletePolicyViolation { .. } => ErrorResponse::forbidden("This resource can not be permanently deleted."),
+            BurchillPostgresError::StaleEntity { .. } => ErrorResponse::conflict("The resource was modified by someone else, reload it and try again."),
+            BurchillPostgresError::LockTimeout { .. } | BurchillPostgresError::Deadlock { .. } => ErrorResponse::new(503, "busy", "The resource is busy, try again shortly."),
+            _ => ErrorResponse::internal()
+        }
+    }
+}